@@ -0,0 +1,113 @@
+extern crate criterion;
+extern crate secd;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use secd::*;
+
+const FIB: &'static str = "
+(letrec fib
+  (lambda n
+    (if (eq n 0)
+      0
+    (if (eq n 1)
+      1
+    (+ (fib (- n 1)) (fib (- n 2))))))
+  (fib 18))";
+
+const ACKERMANN: &'static str = "
+(letrec ack
+  (lambda (m n)
+    (if (eq m 0)
+      (+ n 1)
+    (if (eq n 0)
+      (ack (- m 1) 1)
+    (ack (- m 1) (ack m (- n 1))))))
+  (ack 2 3))";
+
+const BUILD_LIST: &'static str = "
+(do ((i 0 (+ i 1))
+     (acc nil (cons i acc)))
+    ((eq i 2000) acc))";
+
+const TAIL_LOOP: &'static str = "
+(letrec loop
+  (lambda (i acc)
+    (if (eq i 0)
+      acc
+      (loop (- i 1) (+ acc i))))
+  (loop 10000 0))";
+
+// the native map against the prelude's, which is what a call through a binding gets
+const MAP_NATIVE: &'static str = "
+(let ls (do ((i 0 (+ i 1)) (acc nil (cons i acc))) ((eq i 2000) acc))
+  (map (lambda x (+ x 1)) ls))";
+
+const MAP_PRELUDE: &'static str = "
+(let ls (do ((i 0 (+ i 1)) (acc nil (cons i acc))) ((eq i 2000) acc))
+  (let m map (m (lambda x (+ x 1)) ls)))";
+
+// the same value taken apart over and over, which common subexpression elimination binds once
+const REPEATED: &'static str = "
+(letrec loop
+  (lambda (i acc)
+    (if (eq i 0)
+      acc
+      (let x (cons i acc)
+        (loop (- i 1)
+              (- (+ (cdr x) (+ (- (car x) (cdr x)) (+ (car x) (cdr x))))
+                 (+ (- (car x) (cdr x)) (+ (car x) (cdr x))))))))
+  (loop 2000 0))";
+
+fn compile(src: &str) -> secd::data::Code {
+  return Compiler::new().compile(&Parser::new(&src.to_string()).parse().unwrap()).unwrap();
+}
+
+// compiles once and only measures the machine; output is discarded
+fn bench_run(c: &mut Criterion, name: &str, src: &str) {
+  bench_code(c, name, compile(src));
+}
+
+fn bench_code(c: &mut Criterion, name: &str, code: secd::data::Code) {
+  c.bench_function(name, |b| b.iter(|| {
+    let mut vm = SECD::new(code.clone());
+    vm.output = Output::Null;
+    vm.run().unwrap()
+  }));
+}
+
+fn vm(c: &mut Criterion) {
+  bench_run(c, "run fib 18", FIB);
+  bench_run(c, "run ackermann 2 3", ACKERMANN);
+  bench_run(c, "run build list 2000", BUILD_LIST);
+  bench_run(c, "run tail loop 10000", TAIL_LOOP);
+  bench_run(c, "run map 2000 native", MAP_NATIVE);
+  bench_run(c, "run map 2000 prelude", MAP_PRELUDE);
+  bench_run(c, "run repeated 2000", REPEATED);
+
+  let mut cse = Compiler::new();
+  cse.cse = true;
+  let code = cse.compile(&Parser::new(&REPEATED.to_string()).parse().unwrap()).unwrap();
+  bench_code(c, "run repeated 2000 cse", code);
+}
+
+#[cfg(feature = "jit")]
+fn jit(c: &mut Criterion) {
+  let code = compile(FIB);
+  c.bench_function("run fib 18 jit", |b| b.iter(|| {
+    let mut vm = SECD::new(code.clone());
+    vm.jit = Some(std::rc::Rc::new(std::cell::RefCell::new(secd::jit::Jit::new(2).unwrap())));
+    vm.run().unwrap()
+  }));
+}
+
+#[cfg(not(feature = "jit"))]
+fn jit(_: &mut Criterion) {}
+
+fn compiler(c: &mut Criterion) {
+  for &(name, src) in &[("compile fib", FIB), ("compile ackermann", ACKERMANN)] {
+    c.bench_function(name, |b| b.iter(|| compile(src)));
+  }
+}
+
+criterion_group!(benches, vm, jit, compiler);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+(letrec countdown
+  (lambda n
+    (if (eq n 0)
+      (quote liftoff)
+      (begin
+        (puts n)
+        (countdown (- n 1)))))
+  (countdown 3))
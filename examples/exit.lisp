@@ -0,0 +1,3 @@
+(begin
+  (puts "bye")
+  (exit 3))
@@ -0,0 +1,4 @@
+(begin
+  (puts "hello")
+  (puts (+ 1 2))
+  "done")
@@ -0,0 +1,4 @@
+(do ((i 1 (+ i 1))
+     (acc 0 (+ acc i)))
+    ((eq i 11) acc)
+  (puts acc))
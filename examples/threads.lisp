@@ -0,0 +1,5 @@
+(let c (chan)
+  (let t (spawn (lambda () (begin (puts "worker") (send c 42))))
+    (begin
+      (join t)
+      (recv c))))
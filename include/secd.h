@@ -0,0 +1,43 @@
+/* The C interface of libsecd, built with `cargo build --release --features capi`.
+   No function unwinds into C: a panic inside one is answered as a failure. */
+#ifndef SECD_H
+#define SECD_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct secd secd;
+typedef struct secd_value secd_value;
+
+/* gets borrowed arguments and returns a new value, or NULL to fail the call */
+typedef secd_value *(*secd_fn)(void *data, const secd_value *const *args, size_t nargs);
+
+/* a handle with no capabilities */
+secd *secd_new(void);
+void secd_free(secd *secd);
+void secd_allow_all(secd *secd);
+
+/* the value of the program, or NULL; then secd_error says why, or secd_exit_status
+   gives the status the program exited with */
+secd_value *secd_eval(secd *secd, const char *source);
+/* owned by the handle until its next secd_eval */
+const char *secd_error(const secd *secd);
+/* 1 and the status in *out if the last secd_eval ended with exit, otherwise 0 */
+int secd_exit_status(const secd *secd, int32_t *out);
+/* 0 on success */
+int secd_register_fn(secd *secd, const char *name, size_t arity, secd_fn run, void *data);
+
+secd_value *secd_int(int32_t n);
+secd_value *secd_string(const char *s);
+secd_value *secd_nil(void);
+void secd_free_value(secd_value *v);
+
+/* 1 and the int in *out if v is one, otherwise 0 */
+int secd_value_int(const secd_value *v, int32_t *out);
+/* a static string: "int", "string", "cons", ... */
+const char *secd_value_type(const secd_value *v);
+/* free with secd_free_string */
+char *secd_value_display(const secd_value *v);
+void secd_free_string(char *s);
+
+#endif
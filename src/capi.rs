@@ -0,0 +1,352 @@
+use data::{SECD, Lisp, RunResult, Capabilities, CodeOP, CodeOPInfo};
+use compiler::Compiler;
+use diagnostic::Diagnostic;
+use parser::Parser;
+use scheduler::Scheduler;
+use vm::Primitives;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::rc::Rc;
+
+// The C interface, built with `--features capi` into the cdylib, for hosts that aren't
+// Rust; include/secd.h declares it. A `secd` handle holds the functions the host has
+// registered and the message of its last error, and every secd_eval compiles and runs a
+// program of its own on a fresh machine. Values cross as owned `secd_value` pointers the
+// host frees with secd_free_value, strings from here with secd_free_string. None of it
+// is safe to share between threads. No entry point unwinds into C: a panic is caught
+// and answered as a failure.
+
+pub struct Secd {
+    primitives: Primitives,
+    host: HashMap<String, Host>,
+    capabilities: Capabilities,
+    error: Option<CString>,
+    exit: Option<i32>,
+}
+
+pub struct SecdValue(Rc<Lisp>);
+
+pub type SecdFn = extern "C" fn(data: *mut c_void, args: *const *const SecdValue, nargs: usize) -> *mut SecdValue;
+
+#[derive(Clone, Copy)]
+struct Host {
+    run: SecdFn,
+    data: *mut c_void,
+}
+
+thread_local! {
+    // the host functions of the handle evaluating on this thread, for `call` to find
+    static HOST: RefCell<HashMap<String, Host>> = RefCell::new(HashMap::new());
+}
+
+// the primitive every host function is registered as; it calls the one the PRIM names
+fn call(vm: &mut SECD, c: &CodeOPInfo) -> Result<(), Box<Error>> {
+    let (id, n) = match c.op {
+        CodeOP::PRIM(id, n) => (id, n),
+        _ => return Err(From::from(Diagnostic::error("vm", Some(c.info), "PRIM: not a call".to_string()))),
+    };
+    let name = vm.primitives.name(id).unwrap_or("").to_string();
+    let host = match HOST.with(|h| h.borrow().get(&name).cloned()) {
+        Some(host) => host,
+        None => {
+            return Err(From::from(Diagnostic::error("vm", Some(c.info), format!("{}: not registered", name))))
+        }
+    };
+    let at = vm.stack.len() - n;
+    let args: Vec<SecdValue> = vm.stack.split_off(at).into_iter().map(SecdValue).collect();
+    let ptrs: Vec<*const SecdValue> = args.iter().map(|a| a as *const SecdValue).collect();
+    let r = (host.run)(host.data, ptrs.as_ptr(), n);
+    if r.is_null() {
+        return Err(From::from(Diagnostic::error("vm", Some(c.info), format!("{}: host function failed", name))));
+    }
+    vm.stack.push(unsafe { Box::from_raw(r) }.0);
+    return Ok(());
+}
+
+fn eval(secd: &Secd, s: &str) -> Result<RunResult, Box<Error>> {
+    let ast = try!(Parser::new(&s.to_string()).parse());
+    let mut compiler = Compiler::new();
+    compiler.primitives = Rc::new(secd.primitives.clone());
+    let code = try!(compiler.compile(&ast));
+    let mut vm = SECD::new(code);
+    vm.primitives = compiler.primitives.clone();
+    vm.capabilities = secd.capabilities;
+
+    let saved = HOST.with(|h| h.replace(secd.host.clone()));
+    let r = panic::catch_unwind(AssertUnwindSafe(|| Scheduler::new().run(vm)));
+    HOST.with(|h| h.replace(saved));
+    match r {
+        Ok(r) => return r,
+        Err(_) => return Err(From::from("vm panic")),
+    }
+}
+
+// runs an entry point's body, answering `failed` if it panics rather than unwinding
+// into the host
+fn guard<T, F: FnOnce() -> T>(failed: T, f: F) -> T {
+    return panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(failed);
+}
+
+fn value(a: Rc<Lisp>) -> *mut SecdValue {
+    return Box::into_raw(Box::new(SecdValue(a)));
+}
+
+fn string(s: String) -> *mut c_char {
+    // an interior NUL can't cross, so the text stops there
+    let s = s.split('\0').next().unwrap_or("").to_string();
+    return CString::new(s).map(|s| s.into_raw()).unwrap_or(ptr::null_mut());
+}
+
+unsafe fn text<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    return CStr::from_ptr(s).to_str().ok();
+}
+
+// a handle with no capabilities; secd_allow_all grants them all
+#[no_mangle]
+pub extern "C" fn secd_new() -> *mut Secd {
+    return guard(ptr::null_mut(), || {
+        let secd = Secd {
+            primitives: Primitives::standard(),
+            host: HashMap::new(),
+            capabilities: Capabilities::none(),
+            error: None,
+            exit: None,
+        };
+        return Box::into_raw(Box::new(secd));
+    });
+}
+
+/// Frees a handle.
+///
+/// # Safety
+///
+/// `secd` is NULL or a handle from secd_new not freed yet, used on the thread that made
+/// it. The handle, and the strings secd_error gave for it, are not to be used after.
+#[no_mangle]
+pub unsafe extern "C" fn secd_free(secd: *mut Secd) {
+    guard((), || if !secd.is_null() {
+        drop(Box::from_raw(secd));
+    });
+}
+
+/// Grants the handle every capability.
+///
+/// # Safety
+///
+/// `secd` is NULL or a live handle from secd_new, used on the thread that made it.
+#[no_mangle]
+pub unsafe extern "C" fn secd_allow_all(secd: *mut Secd) {
+    guard((), || if let Some(secd) = secd.as_mut() {
+        secd.capabilities = Capabilities::all();
+    });
+}
+
+/// The value of the program in `source`, or NULL: then either secd_error says why or
+/// secd_exit_status gives the status the program exited with.
+///
+/// # Safety
+///
+/// `secd` is NULL or a live handle from secd_new, used on the thread that made it, and
+/// `source` is NULL or a NUL-terminated string that stays valid for the call. The value
+/// returned is the host's, to free with secd_free_value; it outlives the handle.
+#[no_mangle]
+pub unsafe extern "C" fn secd_eval(secd: *mut Secd, source: *const c_char) -> *mut SecdValue {
+    let secd = match secd.as_mut() {
+        Some(secd) => secd,
+        None => return ptr::null_mut(),
+    };
+    let r = match text(source) {
+        Some(s) => guard(Err(From::from("vm panic")), || eval(secd, s)),
+        None => Err(From::from("source is not UTF-8")),
+    };
+    secd.error = None;
+    secd.exit = None;
+    match r {
+        Ok(RunResult::Value(a)) | Ok(RunResult::Yield(a)) => return value(a),
+        Ok(RunResult::Exit(n)) => {
+            secd.exit = Some(n);
+            return ptr::null_mut();
+        }
+        Err(e) => {
+            secd.error = CString::new(format!("{}", e).replace('\0', " ")).ok();
+            return ptr::null_mut();
+        }
+    }
+}
+
+/// The last error's message; NULL if that succeeded.
+///
+/// # Safety
+///
+/// `secd` is NULL or a live handle from secd_new. The string is the handle's, not to be
+/// freed, and valid only until its next secd_eval or secd_free.
+#[no_mangle]
+pub unsafe extern "C" fn secd_error(secd: *const Secd) -> *const c_char {
+    return guard(ptr::null(), || match secd.as_ref().and_then(|secd| secd.error.as_ref()) {
+        Some(e) => e.as_ptr(),
+        None => ptr::null(),
+    });
+}
+
+/// 1 and the status in `out` if the last secd_eval ended with exit, otherwise 0.
+///
+/// # Safety
+///
+/// `secd` is NULL or a live handle from secd_new, and `out` is NULL or points to an
+/// `int32_t` the host can write.
+#[no_mangle]
+pub unsafe extern "C" fn secd_exit_status(secd: *const Secd, out: *mut i32) -> c_int {
+    return guard(0, || match secd.as_ref().and_then(|secd| secd.exit) {
+        Some(n) => {
+            if !out.is_null() {
+                *out = n;
+            }
+            1
+        }
+        None => 0,
+    });
+}
+
+/// Makes `name` a primitive of `arity` arguments calling `run` with `data` and borrowed
+/// arguments; `run` returns a new value, or NULL to fail the call. 0 on success.
+///
+/// # Safety
+///
+/// `secd` is NULL or a live handle from secd_new, used on the thread that made it, and
+/// `name` is NULL or a NUL-terminated string, copied before this returns. `run` must not
+/// unwind, and `data`, which stays the host's, must be valid for as long as programs
+/// evaluated with the handle may call it. The arguments `run` gets are valid only for
+/// that call and are not to be freed; what it returns, from secd_int and the like, is
+/// taken over and not to be used again.
+#[no_mangle]
+pub unsafe extern "C" fn secd_register_fn(secd: *mut Secd,
+                                          name: *const c_char,
+                                          arity: usize,
+                                          run: SecdFn,
+                                          data: *mut c_void)
+                                          -> c_int {
+    let (secd, name) = match (secd.as_mut(), text(name)) {
+        (Some(secd), Some(name)) if !name.is_empty() => (secd, name),
+        _ => return -1,
+    };
+    return guard(-1, || {
+        secd.primitives.register(name, arity, call);
+        secd.host.insert(name.to_string(), Host { run, data });
+        return 0;
+    });
+}
+
+/// Frees a value.
+///
+/// # Safety
+///
+/// `v` is NULL or a value this library returned to the host and not freed yet; it is
+/// not to be used after. The arguments a registered function gets are not the host's
+/// to free.
+#[no_mangle]
+pub unsafe extern "C" fn secd_free_value(v: *mut SecdValue) {
+    guard((), || if !v.is_null() {
+        drop(Box::from_raw(v));
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn secd_int(n: i32) -> *mut SecdValue {
+    return guard(ptr::null_mut(), || value(Lisp::int(n)));
+}
+
+/// A string value holding a copy of `s`, or NULL if `s` is NULL or not UTF-8.
+///
+/// # Safety
+///
+/// `s` is NULL or a NUL-terminated string valid for the call. The value returned is the
+/// host's, to free with secd_free_value or to return from a registered function.
+#[no_mangle]
+pub unsafe extern "C" fn secd_string(s: *const c_char) -> *mut SecdValue {
+    match text(s) {
+        Some(s) => return guard(ptr::null_mut(), || value(Rc::new(Lisp::Str(s.to_string())))),
+        None => return ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn secd_nil() -> *mut SecdValue {
+    return guard(ptr::null_mut(), || value(Lisp::nil()));
+}
+
+/// 1 and the int in `out` if `v` is one, otherwise 0.
+///
+/// # Safety
+///
+/// `v` is NULL or a value from this library not freed yet, the host's or an argument
+/// of a registered function during its call, and `out` is NULL or points to an
+/// `int32_t` the host can write.
+#[no_mangle]
+pub unsafe extern "C" fn secd_value_int(v: *const SecdValue, out: *mut i32) -> c_int {
+    return guard(0, || match v.as_ref().map(|v| &*v.0) {
+        Some(&Lisp::Int(n)) => {
+            if !out.is_null() {
+                *out = n;
+            }
+            1
+        }
+        _ => 0,
+    });
+}
+
+// the names type_name gives, NUL-terminated for C
+const TYPES: [&[u8]; 14] = [b"nil\0", b"bool\0", b"int\0", b"string\0", b"symbol\0", b"list\0", b"closure\0",
+                            b"cons\0", b"thread\0", b"chan\0", b"port\0", b"condition\0", b"cell\0", b"weak\0"];
+
+/// The name of the value's type, or NULL if `v` is.
+///
+/// # Safety
+///
+/// `v` is NULL or a value from this library not freed yet, as for secd_value_int. The
+/// name is static, not to be freed.
+#[no_mangle]
+pub unsafe extern "C" fn secd_value_type(v: *const SecdValue) -> *const c_char {
+    let name = match v.as_ref() {
+        Some(v) => v.0.type_name().as_bytes(),
+        None => return ptr::null(),
+    };
+    return guard(ptr::null(), || match TYPES.iter().find(|t| &t[..t.len() - 1] == name) {
+        Some(t) => t.as_ptr() as *const c_char,
+        None => ptr::null(),
+    });
+}
+
+/// The value printed as puts prints it, for the host to free with secd_free_string.
+///
+/// # Safety
+///
+/// `v` is NULL or a value from this library not freed yet, as for secd_value_int.
+#[no_mangle]
+pub unsafe extern "C" fn secd_value_display(v: *const SecdValue) -> *mut c_char {
+    match v.as_ref() {
+        Some(v) => return guard(ptr::null_mut(), || string(format!("{}", v.0))),
+        None => return ptr::null_mut(),
+    }
+}
+
+/// Frees a string secd_value_display returned.
+///
+/// # Safety
+///
+/// `s` is NULL or a string from secd_value_display not freed yet; it is not to be used
+/// after. Strings from secd_error and secd_value_type are not the host's to free.
+#[no_mangle]
+pub unsafe extern "C" fn secd_free_string(s: *mut c_char) {
+    guard((), || if !s.is_null() {
+        drop(CString::from_raw(s));
+    });
+}
@@ -1,33 +1,802 @@
-use data::{AST, SExpr, Lisp, Code, CodeOPInfo, CodeOP};
+use data::{AST, SExpr, Lisp, Code, CodeOPInfo, CodeOP, Handler, Header, ProcInfo, Info, Env};
+use diagnostic::{Diagnostic, Diagnostics};
+use link::Unit;
+use sourcemap::{self, SourceMap, BlockPath};
+use parser::{self, Parser, Piece};
+use prelude;
+use types;
+use vm;
+use cse;
 
 use std::rc::Rc;
+use std::cell::RefCell;
 use std::error::Error;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::OnceLock;
 
 pub struct Compiler {
     pub code: Code,
+    // keep each lambda's source on its closures, for procedure-source
+    pub retain_source: bool,
+    // build the closure of a lambda with no free variables once, at compile time
+    pub lift_lambdas: bool,
+    // bind expressions repeated in a let body to a name first; see cse.rs
+    pub cse: bool,
+    // a call of a lambda with fewer arguments than it takes gives the curried closure
+    // instead of being an error; see compile_partial
+    pub curry: bool,
+    // the primitives a call may name; a machine running the code needs the same ones
+    pub primitives: Rc<vm::Primitives>,
+    // what cond-expand may ask for; see features
+    pub features: BTreeSet<String>,
     letrec_id_list: Vec<String>,
+    // the names bound where the code being compiled is, innermost last, with the
+    // number of arguments of those bound to a lambda
+    scope: Vec<(String, Option<usize>)>,
+    tail: bool,
+    emitted: Vec<Emitted>,
+    // shared with the nested compilers of lambda bodies, so the program has one pool
+    consts: Rc<RefCell<Consts>>,
+    // the errors found so far, likewise shared
+    errors: Rc<RefCell<Vec<Diagnostic>>>,
+    warnings: Rc<RefCell<Vec<Diagnostic>>>,
+    symbols: Rc<RefCell<Vec<(String, Info)>>>,
+    // the names of the test-groups around the code being compiled, outermost first
+    groups: Vec<String>,
+    // the module whose bindings are being compiled, where its names need no qualifier
+    module: Option<String>,
+    // while compiling a unit, the names it uses without binding and where; see
+    // compile_unit
+    imports: Option<Rc<RefCell<Vec<(String, Info)>>>>,
+    // the variants defined where the code being compiled is, each with its cases and
+    // their numbers of fields, for match to check it has a clause for every case
+    variants: Vec<(String, Vec<(String, usize)>)>,
+}
+
+// a program compiled, with what tools such as debuggers and disassemblers need besides
+#[derive(Debug, Clone)]
+pub struct CompiledProgram {
+    pub code: Code,
+    // nothing that stops the program compiling, like a binding shadowing a form
+    pub warnings: Vec<Diagnostic>,
+    pub source_map: SourceMap,
+    // every name the program binds and where, in the order they were compiled
+    pub symbols: Vec<(String, Info)>,
+}
+
+// each distinct constant once, in the order they were first used
+#[derive(Default)]
+struct Consts {
+    values: Vec<Rc<Lisp>>,
+    index: HashMap<Rc<Lisp>, usize>,
+}
+
+// the instructions one node compiled to; the node is only compared, never read
+struct Emitted {
+    node: *const AST,
+    block: BlockPath,
+    start: usize,
+    end: usize,
 }
 
 type CompilerResult = Result<(), Box<Error>>;
 
+// what a list headed by one of these names compiles to; everything else is a call.
+// A new special form or primitive only needs a row here
+enum Form {
+    // a form of its own, given the list and whether it is in tail position
+    Special(fn(&mut Compiler, &Vec<AST>, bool) -> CompilerResult),
+    // a call of the primitive of the same name
+    Prim,
+    // the arguments, then the instruction
+    Op(usize, CodeOP),
+}
+
+const FORMS: &[(&str, Form)] = &[
+    ("lambda", Form::Special(|c, ls, _| c.compile_lambda(ls))),
+    ("let", Form::Special(|c, ls, tail| c.compile_let(ls, tail))),
+    ("letrec", Form::Special(|c, ls, tail| c.compile_letrec(ls, tail))),
+    ("module", Form::Special(|c, ls, tail| c.compile_module(ls, tail))),
+    ("puts", Form::Special(|c, ls, _| c.compile_puts(ls))),
+    ("if", Form::Special(|c, ls, tail| c.compile_if(ls, tail))),
+    ("eq", Form::Special(|c, ls, _| c.compile_eq(ls))),
+    ("+", Form::Special(|c, ls, _| c.compile_add(ls))),
+    ("-", Form::Special(|c, ls, _| c.compile_sub(ls))),
+    ("cons", Form::Special(|c, ls, _| c.compile_cons(ls))),
+    ("car", Form::Special(|c, ls, _| c.compile_car(ls))),
+    ("cdr", Form::Special(|c, ls, _| c.compile_cdr(ls))),
+    ("min", Form::Prim),
+    ("max", Form::Prim),
+    ("abs", Form::Prim),
+    ("quotient", Form::Prim),
+    ("remainder", Form::Prim),
+    ("bit-and", Form::Prim),
+    ("bit-or", Form::Prim),
+    ("bit-xor", Form::Prim),
+    ("bit-not", Form::Prim),
+    ("shl", Form::Prim),
+    ("shr", Form::Prim),
+    ("current-time", Form::Op(0, CodeOP::CURTIME)),
+    ("clock", Form::Op(0, CodeOP::CLOCK)),
+    ("time", Form::Special(|c, ls, _| c.compile_time(ls))),
+    ("assert", Form::Special(|c, ls, _| c.compile_assert(ls))),
+    ("test", Form::Special(|c, ls, _| c.compile_test(ls))),
+    ("test-group", Form::Special(|c, ls, tail| c.compile_test_group(ls, tail))),
+    ("quote", Form::Special(|c, ls, _| c.compile_quote(ls))),
+    ("number->string", Form::Prim),
+    ("string-append", Form::Prim),
+    ("value->string", Form::Prim),
+    ("string->number", Form::Prim),
+    ("symbol->string", Form::Prim),
+    ("string->symbol", Form::Prim),
+    ("yield", Form::Op(1, CodeOP::YIELD)),
+    ("spawn", Form::Op(1, CodeOP::SPAWN)),
+    ("join", Form::Op(1, CodeOP::TJOIN)),
+    ("chan", Form::Op(0, CodeOP::CHAN)),
+    ("send", Form::Op(2, CodeOP::SEND)),
+    ("recv", Form::Op(1, CodeOP::RECV)),
+    ("random", Form::Op(1, CodeOP::RANDOM)),
+    ("exit", Form::Op(1, CodeOP::EXIT)),
+    ("getenv", Form::Op(1, CodeOP::GETENV)),
+    ("system", Form::Op(1, CodeOP::SYSTEM)),
+    ("process", Form::Op(2, CodeOP::PROCESS)),
+    ("compare", Form::Prim),
+    ("sort", Form::Prim),
+    ("sort-by", Form::Prim),
+    ("map", Form::Prim),
+    ("filter", Form::Prim),
+    ("foldl", Form::Prim),
+    ("foldr", Form::Prim),
+    ("range", Form::Prim),
+    ("with-output-to-string", Form::Op(1, CodeOP::OUTSTR)),
+    ("tcp-connect", Form::Op(2, CodeOP::TCPCONNECT)),
+    ("tcp-listen", Form::Op(2, CodeOP::TCPLISTEN)),
+    ("tcp-accept", Form::Op(1, CodeOP::TCPACCEPT)),
+    ("tcp-read", Form::Op(1, CodeOP::TCPREAD)),
+    ("tcp-write", Form::Op(2, CodeOP::TCPWRITE)),
+    ("tcp-close", Form::Op(1, CodeOP::TCPCLOSE)),
+    ("http-get", Form::Op(1, CodeOP::HTTPGET)),
+    ("date-now", Form::Op(0, CodeOP::DATENOW)),
+    ("date->string", Form::Op(2, CodeOP::DATE2STR)),
+    ("string->date", Form::Op(2, CodeOP::STR2DATE)),
+    ("try", Form::Special(|c, ls, _| c.compile_try(ls))),
+    ("error", Form::Special(|c, ls, _| c.compile_error(ls))),
+    ("unwind-protect", Form::Special(|c, ls, _| c.compile_protect(ls))),
+    ("dynamic-wind", Form::Special(|c, ls, _| c.compile_dynamic_wind(ls))),
+    ("make-parameter", Form::Op(1, CodeOP::MKPARAM)),
+    ("parameterize", Form::Special(|c, ls, _| c.compile_parameterize(ls))),
+    ("raise", Form::Op(1, CodeOP::RAISE)),
+    ("condition-type", Form::Prim),
+    ("condition-message", Form::Prim),
+    ("condition-payload", Form::Prim),
+    ("condition-location", Form::Prim),
+    ("the", Form::Special(|c, ls, tail| c.compile_the(ls, tail))),
+    ("help", Form::Special(|c, ls, _| c.compile_help(ls))),
+    ("procedure?", Form::Prim),
+    ("procedure-arity", Form::Prim),
+    ("curry", Form::Special(|c, ls, _| c.compile_curry(ls))),
+    ("procedure-source", Form::Prim),
+    ("copy", Form::Prim),
+    ("freeze", Form::Prim),
+    ("make-weak-ref", Form::Prim),
+    ("weak-deref", Form::Prim),
+    ("checkpoint", Form::Prim),
+    ("restore", Form::Prim),
+    ("secd-stack", Form::Op(0, CodeOP::SECDSTACK)),
+    ("secd-env", Form::Op(0, CodeOP::SECDENV)),
+    ("secd-where", Form::Op(0, CodeOP::SECDWHERE)),
+    ("define/contract", Form::Special(|c, ls, tail| c.compile_contract(ls, tail))),
+    ("case", Form::Special(|c, ls, tail| c.compile_case(ls, tail))),
+    ("begin", Form::Special(|c, ls, tail| c.compile_begin(ls, tail))),
+    ("cond-expand", Form::Special(|c, ls, tail| c.compile_cond_expand(ls, tail))),
+    ("do", Form::Special(|c, ls, tail| c.compile_do(ls, tail))),
+    ("define-record", Form::Special(|c, ls, tail| c.compile_record(ls, tail))),
+    ("define-variant", Form::Special(|c, ls, tail| c.compile_variant(ls, tail))),
+    ("match", Form::Special(|c, ls, tail| c.compile_match(ls, tail))),
+    ("->", Form::Special(|c, ls, tail| c.compile_thread(ls, tail))),
+    ("->>", Form::Special(|c, ls, tail| c.compile_thread(ls, tail))),
+    ("delay", Form::Special(|c, ls, _| c.compile_delay(ls))),
+    ("make-promise", Form::Prim),
+    ("promise?", Form::Prim),
+    ("force", Form::Prim),
+    ("stream-cons", Form::Special(|c, ls, _| c.compile_stream_cons(ls))),
+    ("trace", Form::Special(|c, ls, _| c.compile_trace(ls))),
+    ("untrace", Form::Prim),
+    ("break", Form::Prim),
+    ("break-if", Form::Prim),
+];
+
+fn form(name: &str) -> Option<&'static Form> {
+    static INDEX: OnceLock<HashMap<&'static str, usize>> = OnceLock::new();
+    let index = INDEX.get_or_init(|| FORMS.iter().enumerate().map(|(i, f)| (f.0, i)).collect());
+    return index.get(name).map(|&i| &FORMS[i].1);
+}
+
+// the names with a meaning of their own, for completion and the like
+pub fn keywords() -> impl Iterator<Item = &'static str> {
+    return FORMS.iter().map(|f| f.0).chain(["nil", "true", "false"]);
+}
+
+pub fn is_keyword(name: &str) -> bool {
+    return form(name).is_some() || name == "nil" || name == "true" || name == "false";
+}
+
+// the features every compiler starts with: secd, and the cargo features it was built
+// with that a program can tell apart
+pub fn features() -> BTreeSet<String> {
+    let mut features = BTreeSet::new();
+    features.insert("secd".to_string());
+    if cfg!(feature = "http") {
+        features.insert("http".to_string());
+    }
+    if cfg!(feature = "jit") {
+        features.insert("jit".to_string());
+    }
+    if cfg!(feature = "threaded") {
+        features.insert("threaded".to_string());
+    }
+    return features;
+}
+
+// the most nodes a literal lambda's body may have to be compiled in place of a call
+const INLINE_LIMIT: usize = 64;
+
 impl Compiler {
     pub fn new() -> Self {
         return Compiler {
                    code: vec![],
+                   retain_source: false,
+                   lift_lambdas: true,
+                   cse: false,
+                   curry: false,
+                   primitives: Rc::new(vm::Primitives::standard()),
+                   features: features(),
                    letrec_id_list: vec![],
+                   scope: vec![],
+                   tail: false,
+                   emitted: vec![],
+                   consts: Rc::new(RefCell::new(Consts::default())),
+                   errors: Rc::new(RefCell::new(vec![])),
+                   warnings: Rc::new(RefCell::new(vec![])),
+                   symbols: Rc::new(RefCell::new(vec![])),
+                   groups: vec![],
+                   module: None,
+                   imports: None,
+                   variants: vec![],
                };
     }
 
-    fn error(&self, ast: &AST, msg: &str) -> CompilerResult {
-        return Err(From::from(format!("{}:{}:compile error: {}", ast.info[0], ast.info[1], msg)));
+    fn error<T>(&self, ast: &AST, msg: &str) -> Result<T, Box<Error>> {
+        return Err(From::from(Diagnostic::error("compile", Some(ast.info), msg.to_string())));
+    }
+
+    // the program with do, cond-expand and the threading forms rewritten to the forms they compile as, for
+    // `secd expand`; the forms are known by name, so a binding shadowing one is not seen
+    pub fn expand(&self, ast: &AST) -> Result<AST, Box<Error>> {
+        let ls = match ast.sexpr {
+            SExpr::List(ref ls) if ls.len() > 0 => ls,
+            _ => return Ok(ast.clone()),
+        };
+        match ls[0].sexpr {
+            SExpr::Atom(ref id) if id.trim_start() == "quote" => return Ok(ast.clone()),
+            SExpr::Atom(ref id) if id.trim_start() == "do" => return self.expand(&try!(self.expand_do(ls))),
+            SExpr::Atom(ref id) if id.trim_start() == "cond-expand" => {
+                return self.expand(&try!(self.expand_cond_expand(ls)))
+            }
+            SExpr::Atom(ref id) if id.trim_start() == "->" || id.trim_start() == "->>" => {
+                return self.expand(&try!(self.expand_thread(ls)))
+            }
+            _ => {}
+        }
+        let mut expanded = vec![];
+        for a in ls {
+            expanded.push(try!(self.expand(a)));
+        }
+        return Ok(AST { info: ast.info, sexpr: SExpr::List(expanded) });
+    }
+
+    // compiles a whole program, starting over from whatever this compiler did before
+    pub fn compile_program(&mut self, ast: &AST) -> Result<CompiledProgram, Box<Error>> {
+        self.reset();
+        let code = try!(self.program(ast));
+        return Ok(CompiledProgram {
+                      code,
+                      warnings: self.warnings.borrow().clone(),
+                      source_map: self.source_map(ast),
+                      symbols: self.symbols.borrow().clone(),
+                  });
     }
 
+    // the code alone
     pub fn compile(&mut self, ast: &AST) -> Result<Code, Box<Error>> {
+        return self.compile_program(ast).map(|p| p.code);
+    }
+
+    // the code and where every expression of `ast` went in it
+    pub fn compile_with_map(&mut self, ast: &AST) -> Result<(Code, SourceMap), Box<Error>> {
+        return self.compile_program(ast).map(|p| (p.code, p.source_map));
+    }
+
+    // forgets the last program and the session of compile_incremental, keeping the
+    // settings
+    pub fn reset(&mut self) {
+        self.code.clear();
+        self.letrec_id_list.clear();
+        self.scope.clear();
+        self.tail = false;
+        self.emitted.clear();
+        self.consts = Rc::new(RefCell::new(Consts::default()));
+        self.errors = Rc::new(RefCell::new(vec![]));
+        self.warnings = Rc::new(RefCell::new(vec![]));
+        self.symbols = Rc::new(RefCell::new(vec![]));
+        self.groups.clear();
+        self.module = None;
+        self.imports = None;
+        self.variants.clear();
+    }
+
+    // the code of the last program, without copying it
+    pub fn into_code(self) -> Code {
+        return self.code;
+    }
+
+    // the pool, the prelude the program uses and `ast`, appended to the code
+    fn program(&mut self, ast: &AST) -> Result<Code, Box<Error>> {
+        let rewritten;
+        let ast = if self.cse {
+            rewritten = cse::eliminate(ast);
+            &rewritten
+        } else {
+            ast
+        };
+        let at = self.code.len();
+        self.code.push(CodeOPInfo {
+                           info: ast.info,
+                           op: CodeOP::HEADER(Header {
+                                                  version: vm::VERSION,
+                                                  features: vec![],
+                                              }),
+                       });
+        self.code.push(CodeOPInfo {
+                           info: ast.info,
+                           op: CodeOP::CONSTS(Rc::new(vec![])),
+                       });
+        try!(self.compile_prelude(ast));
+        // the prelude's own bindings are not the program's
+        self.symbols.borrow_mut().clear();
+        self.warnings.borrow_mut().clear();
         try!(self.compile_(ast));
+        try!(self.found_errors());
+        let pool = self.consts.borrow().values.clone();
+        debug!("compiled {} instructions and {} constants", self.code.len(), pool.len());
+        self.code[at + 1].op = CodeOP::CONSTS(Rc::new(pool));
+        let features = vm::features(&self.code);
+        self.code[at].op = CodeOP::HEADER(Header {
+                                              version: vm::VERSION,
+                                              features,
+                                          });
         return Ok(self.code.clone());
     }
 
+    // the errors compile_ kept, as one error
+    fn found_errors(&self) -> CompilerResult {
+        let mut errors = self.errors.replace(vec![]);
+        errors.sort_by_key(|d| d.span);
+        match errors.len() {
+            0 => return Ok(()),
+            1 => return Err(From::from(errors.remove(0))),
+            _ => return Err(From::from(Diagnostics(errors))),
+        }
+    }
+
+    fn source_map(&self, ast: &AST) -> SourceMap {
+        let mut ids = HashMap::new();
+        sourcemap::number(ast, &mut ids);
+
+        let mut map = SourceMap::default();
+        for e in self.emitted.iter() {
+            // nodes the compiler made up itself, like the desugaring of do, have no id
+            if let Some(&(expr, span)) = ids.get(&e.node) {
+                map.ranges.push(sourcemap::Range {
+                                    expr,
+                                    span,
+                                    block: e.block.clone(),
+                                    start: e.start,
+                                    end: e.end,
+                                });
+            }
+        }
+        return map;
+    }
+
     pub fn compile_(&mut self, ast: &AST) -> CompilerResult {
+        let start = self.code.len();
+        // the error is kept and the compile goes on with the next expression, so one
+        // compile reports all the mistakes it can find
+        if let Err(e) = self.compile_expr(ast) {
+            match e.downcast::<Diagnostic>() {
+                Ok(d) => self.errors.borrow_mut().push(*d),
+                Err(e) => return Err(e),
+            }
+        }
+        self.emitted.push(Emitted {
+                              node: ast as *const AST,
+                              block: vec![],
+                              start,
+                              end: self.code.len(),
+                          });
+        return Ok(());
+    }
+
+    // compiles the next entry of a session such as a REPL, to run with SECD::run_code on
+    // the machine that ran the entries before it. (define <id> <expr>) binds <id> like
+    // letrec for every later entry and gives its value, (define-record <name> (<field>*))
+    // and (define-variant <name> (<case> <field>*)+) their functions and give the name;
+    // an entry that does not compile leaves the session as it was
+    pub fn compile_incremental(&mut self, ast: &AST) -> Result<Code, Box<Error>> {
+        self.code.clear();
+        self.emitted.clear();
+        let defined = match try!(self.definitions(ast)) {
+            Some(defined) => defined,
+            None => {
+                let (scope, letrec) = (self.scope.len(), self.letrec_id_list.len());
+                let r = self.program(ast);
+                self.scope.truncate(scope);
+                self.letrec_id_list.truncate(letrec);
+                return r;
+            }
+        };
+        let atom = |id: &str| AST { info: ast.info, sexpr: SExpr::Atom(id.into()) };
+        let mut letrec = match ast.sexpr {
+            // a record's or variant's functions are at least two
+            SExpr::List(ref ls) if defined.len() > 1 => {
+                AST { info: ast.info, sexpr: SExpr::List(vec![atom(&core("quote")), ls[1].clone()]) }
+            }
+            _ => atom(&defined[0].0),
+        };
+        let mut arities = vec![];
+        for &(ref id, _, ref value) in defined.iter().rev() {
+            arities.push((id.clone(), self.arity(value)));
+            letrec = AST {
+                info: ast.info,
+                sexpr: SExpr::List(vec![atom(&core("letrec")), atom(id), value.clone(), letrec]),
+            };
+        }
+        let code = try!(self.program(&letrec));
+        self.define_variant(ast);
+        for (id, arity) in arities.into_iter().rev() {
+            self.letrec_id_list.push(id.clone());
+            self.scope.push((id, arity));
+        }
+        return Ok(code);
+    }
+
+    // the names, places and values of (define <id> <expr>), or of the functions of
+    // (define-record <name> (<field>*)) or (define-variant <name> (<case> <field>*)+),
+    // when `ast` is one
+    fn definitions(&self, ast: &AST) -> Result<Option<Vec<(String, Info, AST)>>, Box<Error>> {
+        let ls = match ast.sexpr {
+            SExpr::List(ref ls) if ls.len() >= 3 => ls,
+            _ => return Ok(None),
+        };
+        match ls[0].sexpr {
+            SExpr::Atom(ref head) if head == "define-variant" && !self.bound("define-variant") => {
+                return self.variant(ls).map(Some)
+            }
+            _ if ls.len() != 3 => return Ok(None),
+            SExpr::Atom(ref head) if head == "define" && !self.bound("define") => {
+                match ls[1].sexpr {
+                    SExpr::Atom(ref id) => return Ok(Some(vec![(id.clone(), ls[1].info, ls[2].clone())])),
+                    _ => return self.error(&ls[1], "define id syntax"),
+                }
+            }
+            SExpr::Atom(ref head) if head == "define-record" && !self.bound("define-record") => {
+                return self.record(ls).map(Some)
+            }
+            _ => return Ok(None),
+        }
+    }
+
+    // notes the variant a REPL entry or unit defines, for the matches after it
+    fn define_variant(&mut self, ast: &AST) {
+        if let SExpr::List(ref ls) = ast.sexpr {
+            if ls[0].sexpr == SExpr::Atom("define-variant".into()) {
+                let variant = variant_cases(ls);
+                self.variants.push(variant);
+            }
+        }
+    }
+
+    // compiles one file of a whole program for link::link. Each (define <id> <expr>) is
+    // exported, bound like letrec in all of the file, and one other expression may be
+    // the program's entry. A name the file uses without binding is an import, called
+    // like a letrec name since the link binds it before the entry runs
+    pub fn compile_unit(&mut self, name: &str, forms: &[AST]) -> Result<Unit, Box<Error>> {
+        self.reset();
+        let mut defines = vec![];
+        let mut entry = None;
+        for form in forms {
+            match try!(self.definitions(form)) {
+                Some(defined) => {
+                    self.define_variant(form);
+                    for (id, at, value) in defined {
+                        if self.bound(&id) {
+                            return self.error(form, &format!("{} is defined twice", id));
+                        }
+                        self.letrec_id_list.push(id.clone());
+                        let arity = self.arity(&value);
+                        self.bind(&id, arity, at);
+                        defines.push((id, at, value));
+                    }
+                }
+                None if entry.is_some() => {
+                    return self.error(form, "a unit has one entry expression; begin can make one of several");
+                }
+                None => entry = Some(form),
+            }
+        }
+        self.imports = Some(Rc::new(RefCell::new(vec![])));
+
+        let mut names = vec![];
+        for form in forms {
+            prelude::uses(form, &mut names);
+        }
+        names.retain(|name| !self.bound(name));
+        let mut prelude = vec![];
+        for name in names {
+            let (info, code) = try!(self.prelude_definition(&name));
+            prelude.push((name, info, code));
+        }
+
+        let mut exports = vec![];
+        for &(ref id, at, ref value) in defines.iter() {
+            self.code.clear();
+            try!(self.compile_(value));
+            exports.push((id.clone(), at, self.code.split_off(0)));
+        }
+        let entry = match entry {
+            Some(ast) => {
+                self.code.clear();
+                try!(self.compile_(ast));
+                Some(self.code.split_off(0))
+            }
+            None => None,
+        };
+        try!(self.found_errors());
+
+        let mut imports: Vec<(String, Info)> = vec![];
+        for (id, at) in self.imports.take().unwrap().borrow().iter() {
+            if !imports.iter().any(|a| a.0 == *id) {
+                imports.push((id.clone(), *at));
+            }
+        }
+        return Ok(Unit {
+                      name: name.to_string(),
+                      pool: self.consts.borrow().values.clone(),
+                      prelude,
+                      defines: exports,
+                      imports,
+                      entry,
+                  });
+    }
+
+    // binds the prelude definitions the program uses as values; a binding of its own
+    // shadows them like any other, as does a definition of an earlier entry
+    fn compile_prelude(&mut self, ast: &AST) -> CompilerResult {
+        let mut names = vec![];
+        prelude::uses(ast, &mut names);
+        names.retain(|name| !self.bound(name));
+        for name in names {
+            let (info, code) = try!(self.prelude_definition(&name));
+            self.code.extend(code);
+            self.code
+                .push(CodeOPInfo {
+                          info,
+                          op: CodeOP::LET(name.to_string()),
+                      });
+        }
+
+        return Ok(());
+    }
+
+    // the code of the value of the prelude's definition of `name`, and where it is
+    fn prelude_definition(&mut self, name: &str) -> Result<(Info, Code), Box<Error>> {
+        let src = prelude::DEFINITIONS.iter().find(|d| d.0 == name.trim_start_matches("prelude:")).unwrap().1;
+        let def = try!(Parser::new(&src.to_string()).parse());
+
+        let mut c = self.nested();
+        c.imports = None;
+        try!(c.compile_(&def));
+        return Ok((def.info, c.code));
+    }
+
+    // a compiler for a block of this one's code, seeing the same letrec bindings
+    fn nested(&self) -> Compiler {
+        let mut c = Compiler::new();
+        c.letrec_id_list = self.letrec_id_list.clone();
+        c.scope = self.scope.clone();
+        c.retain_source = self.retain_source;
+        c.lift_lambdas = self.lift_lambdas;
+        c.curry = self.curry;
+        c.primitives = self.primitives.clone();
+        c.features = self.features.clone();
+        c.consts = self.consts.clone();
+        c.errors = self.errors.clone();
+        c.warnings = self.warnings.clone();
+        c.symbols = self.symbols.clone();
+        c.groups = self.groups.clone();
+        c.module = self.module.clone();
+        c.imports = self.imports.clone();
+        c.variants = self.variants.clone();
+        return c;
+    }
+
+    // loads `lisp` from the pool, adding it the first time
+    fn constant(&self, lisp: Rc<Lisp>) -> CodeOP {
+        let mut consts = self.consts.borrow_mut();
+        if let Some(&i) = consts.index.get(&lisp) {
+            return CodeOP::LDC(i);
+        }
+        let i = consts.values.len();
+        consts.values.push(lisp.clone());
+        consts.index.insert(lisp, i);
+        return CodeOP::LDC(i);
+    }
+
+    // whether `ast` refers to nothing but `bound`, builtins and constants
+    fn closed(&self, ast: &AST, bound: &[String]) -> bool {
+        let ls = match ast.sexpr {
+            SExpr::Int(_) => return true,
+            // an expression in the string may use anything
+            SExpr::Str(ref s) => return !s.contains('{'),
+            SExpr::Atom(ref id) => {
+                return (bound.contains(id) && self.resolve(id) == *id) || id == "nil" || id == "true" ||
+                       id == "false" || id.starts_with("#:");
+            }
+            SExpr::List(ref ls) => ls,
+        };
+        let head = match ls.first().map(|a| &a.sexpr) {
+            None => return true,
+            Some(&SExpr::Atom(ref head)) if bound.contains(head) || self.bound(&self.resolve(head)) => {
+                // a call of a variable that shadows the form
+                return ls.iter().all(|a| self.closed(a, bound));
+            }
+            Some(&SExpr::Atom(ref head)) => head.trim_start(),
+            Some(_) => return ls.iter().all(|a| self.closed(a, bound)),
+        };
+
+        let mut inner = bound.to_vec();
+        match head {
+            "quote" => return true,
+            // sees every variable in scope
+            "secd-env" => return false,
+            "lambda" if ls.len() == 3 || ls.len() == 4 => {
+                match ls[1].sexpr {
+                    SExpr::Atom(ref a) => inner.push(a.clone()),
+                    SExpr::List(ref aa) => {
+                        for a in aa {
+                            match types::param(a) {
+                                Some((a, _)) => inner.push(a.clone()),
+                                None => return false,
+                            }
+                        }
+                    }
+                    _ => return false,
+                }
+                return self.closed(&ls[ls.len() - 1], &inner);
+            }
+            "let" | "letrec" if ls.len() == 4 => {
+                match ls[1].sexpr {
+                    SExpr::Atom(ref id) => inner.push(id.clone()),
+                    _ => return false,
+                }
+                let value = if head == "let" { bound } else { &inner[..] };
+                return self.closed(&ls[2], value) && self.closed(&ls[3], &inner);
+            }
+            // forms that bind names of their own (do, try, ...) count them as free
+            _ if is_keyword(head) || self.primitives.id(head).is_some() => return ls[1..].iter().all(|a| self.closed(a, bound)),
+            _ => return ls.iter().all(|a| self.closed(a, bound)),
+        }
+    }
+
+    // puts `id` in scope, noting where the program binds it
+    fn bind(&mut self, id: &str, arity: Option<usize>, at: Info) {
+        if !id.starts_with(' ') {
+            self.declare(id, at);
+        }
+        self.scope.push((id.to_string(), arity));
+    }
+
+    // records a binding of `id` the program wrote, for tools and the shadowing warning
+    fn declare(&self, id: &str, at: Info) {
+        self.symbols.borrow_mut().push((id.to_string(), at));
+        if is_keyword(id) {
+            let msg = format!("{} shadows the form of the same name", id);
+            self.warnings.borrow_mut().push(Diagnostic::warning("compile", Some(at), msg));
+        }
+    }
+
+    fn bound(&self, id: &str) -> bool {
+        return self.scope.iter().any(|a| a.0 == id);
+    }
+
+    // the number of arguments `ast` takes when it is a lambda
+    fn arity(&self, ast: &AST) -> Option<usize> {
+        let ls = match ast.sexpr {
+            SExpr::List(ref ls) if ls.len() == 3 || ls.len() == 4 => ls,
+            _ => return None,
+        };
+        match ls[0].sexpr {
+            SExpr::Atom(ref id) if id.trim_start() == "lambda" && !self.bound(id) => {}
+            _ => return None,
+        }
+        match ls[1].sexpr {
+            SExpr::Atom(_) => return Some(1),
+            // a call may leave out the parameters with defaults
+            SExpr::List(ref ps) if ps.iter().any(|p| p.sexpr == SExpr::Atom("#:key".into()) || defaulted(p).is_some()) => {
+                return None
+            }
+            SExpr::List(ref ps) => return Some(ps.len()),
+            _ => return None,
+        }
+    }
+
+    // what `id` names where the code being compiled is: in the bindings of a module, one
+    // of the module's own names needs no qualifier unless something nearer binds it, and
+    // a parameter of an inlined lambda has a hidden name; see compile_direct
+    fn resolve(&self, id: &str) -> String {
+        let qualified = self.module.as_ref().map(|module| format!("{}:{}", module, id));
+        let inlined = format!(" inline {} ", id);
+        for a in self.scope.iter().rev() {
+            if a.0 == id {
+                break;
+            }
+            if Some(&a.0) == qualified.as_ref() || a.0.starts_with(&inlined) {
+                return a.0.clone();
+            }
+        }
+        return id.to_string();
+    }
+
+    // a module:name that no module in scope binds; prelude: names the prelude's own
+    // definitions, whatever the program binds. The compiler's hidden names aren't either
+    fn unknown_qualified(&self, id: &str) -> bool {
+        if id.contains(' ') {
+            return false;
+        }
+        match id.split_once(':') {
+            Some(("prelude", name)) => return !prelude::DEFINITIONS.iter().any(|d| d.0 == name),
+            Some((module, name)) if !module.is_empty() && !name.is_empty() => return !self.bound(id),
+            _ => return false,
+        }
+    }
+
+    fn rec_bound(&self, id: &str) -> bool {
+        return self.letrec_id_list.iter().any(|a| a == id);
+    }
+
+    // whether `id` is a name the unit being compiled takes from another: one it uses
+    // without binding that isn't the prelude's, a form's or a primitive's
+    fn imported(&self, id: &str) -> bool {
+        return self.imports.is_some() && !self.bound(id) && !id.starts_with(' ') && !id.starts_with("prelude:") &&
+               !prelude::DEFINITIONS.iter().any(|d| d.0 == id) && !is_keyword(id) &&
+               self.primitives.id(id).is_none();
+    }
+
+    // takes over what a nested compiler emitted, now that its code is branch
+    // `branch` of the instruction at `index`
+    fn adopt(&mut self, emitted: Vec<Emitted>, index: usize, branch: usize) {
+        for mut e in emitted {
+            e.block.insert(0, (index, branch));
+            self.emitted.push(e);
+        }
+    }
+
+    fn compile_expr(&mut self, ast: &AST) -> CompilerResult {
+        // only the forms that pass it on explicitly keep their subexpressions in tail position
+        let tail = self.tail;
+        self.tail = false;
+
         match ast.sexpr {
             SExpr::Int(n) => {
                 return self.compile_int(ast, n);
@@ -37,6 +806,10 @@ impl Compiler {
                 return self.compile_atom(ast, id);
             }
 
+            SExpr::Str(ref s) => {
+                return self.compile_str(ast, s);
+            }
+
             SExpr::List(ref ls) => {
                 if ls.len() == 0 {
                     return self.compile_nil(ast);
@@ -46,60 +819,37 @@ impl Compiler {
                             return self.error(&ls[0], "apply unexpect int");
                         }
 
-                        SExpr::Atom(ref id) => {
-                            match id.as_str() {
-                                "lambda" => {
-                                    return self.compile_lambda(ls);
-                                }
-
-                                "let" => {
-                                    return self.compile_let(ls);
-                                }
-
-                                "letrec" => {
-                                    return self.compile_letrec(ls);
-                                }
-
-                                "puts" => {
-                                    return self.compile_puts(ls);
-                                }
-
-                                "if" => {
-                                    return self.compile_if(ls);
-                                }
-
-                                "eq" => {
-                                    return self.compile_eq(ls);
-                                }
-
-                                "+" => {
-                                    return self.compile_add(ls);
-                                }
-
-                                "-" => {
-                                    return self.compile_sub(ls);
-                                }
-
-                                "cons" => {
-                                    return self.compile_cons(ls);
-                                }
+                        SExpr::Str(_) => {
+                            return self.error(&ls[0], "apply unexpect string");
+                        }
 
-                                "car" => {
-                                    return self.compile_car(ls);
-                                }
+                        // a local binding of the name wins over the form
+                        SExpr::Atom(ref id) if self.bound(&self.resolve(id)) => {
+                            return self.compile_apply(ls, tail);
+                        }
 
-                                "cdr" => {
-                                    return self.compile_cdr(ls);
-                                }
+                        SExpr::Atom(ref id) if id.starts_with("native:") => {
+                            let name = &id["native:".len()..];
+                            if self.primitives.id(name).is_none() {
+                                return self.error(&ls[0], &format!("no primitive {}", name));
+                            }
+                            return self.compile_prim(ls, name);
+                        }
 
-                                _ => {
-                                    return self.compile_apply(ls);
+                        SExpr::Atom(ref id) => {
+                            match form(id.trim_start()) {
+                                Some(&Form::Special(compile)) => return compile(self, ls, tail),
+                                Some(&Form::Prim) => return self.compile_prim(ls, id.trim_start()),
+                                Some(&Form::Op(arity, ref op)) => {
+                                    return self.compile_op(ls, arity, op.clone())
                                 }
+                                None if self.primitives.id(id).is_some() => return self.compile_prim(ls, id),
+                                _ => return self.compile_apply(ls, tail),
                             }
                         }
 
                         SExpr::List(_) => {
-                            return self.compile_apply(&ls);
+                            return self.compile_apply(&ls, tail);
                         }
                     }
                 }
@@ -111,7 +861,7 @@ impl Compiler {
         self.code
             .push(CodeOPInfo {
                       info: ast.info,
-                      op: CodeOP::LDC(Rc::new(Lisp::Int(n))),
+                      op: self.constant(Lisp::int(n)),
                   });
         return Ok(());
     }
@@ -122,7 +872,7 @@ impl Compiler {
                 self.code
                     .push(CodeOPInfo {
                               info: ast.info,
-                              op: CodeOP::LDC(Rc::new(Lisp::Nil)),
+                              op: self.constant(Lisp::nil()),
                           });
             }
 
@@ -130,7 +880,7 @@ impl Compiler {
                 self.code
                     .push(CodeOPInfo {
                               info: ast.info,
-                              op: CodeOP::LDC(Rc::new(Lisp::True)),
+                              op: self.constant(Lisp::bool(true)),
                           });
             }
 
@@ -138,15 +888,31 @@ impl Compiler {
                 self.code
                     .push(CodeOPInfo {
                               info: ast.info,
-                              op: CodeOP::LDC(Rc::new(Lisp::False)),
+                              op: self.constant(Lisp::bool(false)),
+                          });
+            }
+
+            // a keyword, which names a keyword argument in a call
+            _ if id.starts_with("#:") => {
+                self.code
+                    .push(CodeOPInfo {
+                              info: ast.info,
+                              op: self.constant(Rc::new(Lisp::Symbol(id.clone()))),
                           });
             }
 
             _ => {
+                let id = self.resolve(id);
+                if self.unknown_qualified(&id) {
+                    return self.error(ast, &format!("no module binds {}", id));
+                }
+                if self.imported(&id) {
+                    self.imports.as_ref().unwrap().borrow_mut().push((id.clone(), ast.info));
+                }
                 self.code
                     .push(CodeOPInfo {
                               info: ast.info,
-                              op: CodeOP::LD(id.clone()),
+                              op: CodeOP::LD(id),
                           });
             }
         }
@@ -154,31 +920,97 @@ impl Compiler {
         return Ok(());
     }
 
+    // a literal with {<expr>} in it is the string-append of its pieces, each expression
+    // made a string by number->string
+    fn compile_str(&mut self, ast: &AST, s: &String) -> CompilerResult {
+        let append = self.primitives.id("string-append").unwrap();
+        let show = self.primitives.id("value->string").unwrap();
+        for (i, piece) in try!(parser::interpolation(s, ast.info)).into_iter().enumerate() {
+            match piece {
+                Piece::Text(text) => {
+                    self.code
+                        .push(CodeOPInfo {
+                                  info: ast.info,
+                                  op: self.constant(Rc::new(Lisp::Str(text))),
+                              });
+                }
+                Piece::Hole(expr) => {
+                    try!(self.compile_(&expr));
+                    self.code
+                        .push(CodeOPInfo {
+                                  info: ast.info,
+                                  op: CodeOP::PRIM(show, 1),
+                              });
+                }
+            }
+            if i > 0 {
+                self.code
+                    .push(CodeOPInfo {
+                              info: ast.info,
+                              op: CodeOP::PRIM(append, 2),
+                          });
+            }
+        }
+        return Ok(());
+    }
+
     fn compile_nil(&mut self, ast: &AST) -> CompilerResult {
         self.code
             .push(CodeOPInfo {
                       info: ast.info,
-                      op: CodeOP::LDC(Rc::new(Lisp::Nil)),
+                      op: self.constant(Lisp::nil()),
                   });
         return Ok(());
     }
 
+    // (lambda <params> <doc>? <body>)
     fn compile_lambda(&mut self, ls: &Vec<AST>) -> CompilerResult {
-        if ls.len() != 3 {
-            return self.error(&ls[0], "lambda syntax");
-        }
+        let doc = match (ls.len(), ls.get(2).map(|a| &a.sexpr)) {
+            (3, _) => None,
+            (4, Some(&SExpr::Str(ref doc))) => Some(doc.clone()),
+            _ => return self.error(&ls[0], "lambda syntax"),
+        };
 
         let mut args: Vec<String> = vec![];
+        let mut at = vec![];
+        // the parameters with a default, the optional ones and then the keyword ones
+        // after #:key, each with the name the machine knows it by
+        let mut defaults = vec![];
+        let mut patterns = vec![];
         match ls[1].sexpr {
             SExpr::Atom(ref a) => {
                 args.push(a.clone());
+                at.push(ls[1].info);
             }
 
             SExpr::List(ref aa) => {
-                for ast in aa.iter() {
-                    match ast.sexpr {
-                        SExpr::Atom(ref a) => {
+                let key = aa.iter().position(|a| a.sexpr == SExpr::Atom("#:key".into())).unwrap_or(aa.len());
+                for ast in aa[..key].iter() {
+                    match (types::param(ast), defaulted(ast)) {
+                        (Some((a, _)), _) if !a.starts_with("#:") && defaults.is_empty() => {
                             args.push(a.clone());
+                            at.push(ast.info);
+                        }
+
+                        (Some(_), _) if !defaults.is_empty() => {
+                            return self.error(&ast, "a parameter without a default after one with");
+                        }
+
+                        (None, Some((a, info, default))) => {
+                            self.ambiguous_default(ast, a, default, &args, &defaults);
+                            defaults.push((a.clone(), format!("[{}]", a), info, default));
+                        }
+
+                        // a pattern, which the machine knows by its text; one like
+                        // (<id> : <type>) is a mistyped annotation
+                        (None, None) if is_list(ast) && !annotated(ast) && defaults.is_empty() => {
+                            args.push(format!("{}", ast));
+                            at.push(ast.info);
+                            patterns.push(ast);
+                        }
+
+                        (None, None) if is_list(ast) && !annotated(ast) => {
+                            return self.error(&ast, "a parameter without a default after one with");
                         }
 
                         _ => {
@@ -186,6 +1018,12 @@ impl Compiler {
                         }
                     }
                 }
+                for ast in aa[key..].iter().skip(1) {
+                    match defaulted(ast) {
+                        Some((a, info, default)) => defaults.push((a.clone(), format!("#:{}", a), info, default)),
+                        None => return self.error(&ast, "keyword parameter syntax"),
+                    }
+                }
             }
 
             _ => {
@@ -193,49 +1031,214 @@ impl Compiler {
             }
         }
 
-        let mut body = Compiler::new();
-        body.letrec_id_list = self.letrec_id_list.clone();
-        try!(body.compile_(&ls[2]));
+        let mut body = self.nested();
+        for (a, &info) in args.iter().zip(at.iter()).filter(|p| !p.0.starts_with('(')) {
+            body.bind(a, None, info);
+        }
+        for pattern in patterns {
+            body.code
+                .push(CodeOPInfo {
+                          info: pattern.info,
+                          op: CodeOP::LD(format!("{}", pattern)),
+                      });
+            try!(body.destructure(pattern));
+        }
+        for &(ref a, _, info, _) in defaults.iter() {
+            body.bind(a, None, info);
+        }
+        // a parameter the call left out is MISSING until it is given its default, which
+        // sees the closure's environment and the parameters before it
+        for &(ref a, _, info, default) in defaults.iter() {
+            let node = |sexpr| AST { info, sexpr };
+            let atom = |a: &str| node(SExpr::Atom(a.to_string()));
+            let missing = node(SExpr::List(vec![atom(&core("quote")), atom(vm::MISSING)]));
+            let given = node(SExpr::List(vec![atom(&core("eq")), atom(a), missing]));
+            try!(body.compile_(&node(SExpr::List(vec![atom(&core("if")), given, default.clone(), atom(a)]))));
+            body.code
+                .push(CodeOPInfo {
+                          info,
+                          op: CodeOP::LET(a.clone()),
+                      });
+        }
+        args.extend(defaults.iter().map(|d| d.1.clone()));
+        body.tail = true;
+        try!(body.compile_(&ls[ls.len() - 1]));
+        let proc_info = ProcInfo {
+            doc,
+            source: if self.retain_source {
+                Some(list_datum(ls))
+            } else {
+                None
+            },
+        };
         body.code
             .push(CodeOPInfo {
                       info: ls[0].info,
                       op: CodeOP::RET,
                   });
 
+        let op = if self.lift_lambdas && defaults.is_empty() && self.closed(&ls[ls.len() - 1], &args) {
+            let closure = Lisp::Closure(args, body.code, Env::new(), Rc::new(proc_info));
+            self.constant(Rc::new(closure))
+        } else {
+            CodeOP::LDF(args, body.code, Rc::new(proc_info))
+        };
+        let i = self.code.len();
+        self.adopt(body.emitted, i, 0);
         self.code
             .push(CodeOPInfo {
                       info: ls[0].info,
-                      op: CodeOP::LDF(args, body.code),
+                      op,
                   });
 
         return Ok(());
     }
 
-    fn compile_let(&mut self, ls: &Vec<AST>) -> CompilerResult {
+    // (a b) as a parameter is a with the default b, though it reads like the pattern of
+    // a two-element list; when nothing binds b it was most likely meant as one
+    fn ambiguous_default(&self, ast: &AST, a: &str, default: &AST, args: &[String], defaults: &[(String, String, Info, &AST)]) {
+        let d = match default.sexpr {
+            SExpr::Atom(ref d) => d,
+            _ => return,
+        };
+        let known = d == "nil" || d == "true" || d == "false" || d.starts_with("#:") || d.contains(':') ||
+                    args.iter().any(|p| p == d) || defaults.iter().any(|p| p.0 == *d) ||
+                    self.bound(&self.resolve(d)) || prelude::DEFINITIONS.iter().any(|p| p.0 == d) ||
+                    self.primitives.id(d).is_some() || is_keyword(d);
+        if !known {
+            let msg = format!("({} {}) is the parameter {} with the default {}, which nothing binds; ({} {} . ()) is the pattern of a two-element list",
+                              a,
+                              d,
+                              a,
+                              d,
+                              a,
+                              d);
+            self.warnings.borrow_mut().push(Diagnostic::warning("compile", Some(ast.info), msg));
+        }
+    }
+
+    fn compile_let(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        if ls.len() == 3 {
+            return self.compile_let_bindings(ls, tail);
+        }
         if ls.len() != 4 {
             return self.error(&ls[0], "let syntax");
         }
 
         let id = match ls[1].sexpr {
             SExpr::Atom(ref id) => id.clone(),
+            SExpr::List(_) => {
+                try!(self.compile_(&ls[2]));
+                let depth = self.scope.len();
+                try!(self.destructure(&ls[1]));
+                self.tail = tail;
+                try!(self.compile_(&ls[3]));
+                self.scope.truncate(depth);
+                return Ok(());
+            }
             _ => return self.error(&ls[0], "let bind id sytax"),
         };
 
         self.letrec_id_list.retain(|a| *a != id);
 
         try!(self.compile_(&ls[2]));
+        let arity = self.arity(&ls[2]);
+        self.bind(&id, arity, ls[1].info);
         self.code
             .push(CodeOPInfo {
                       info: ls[0].info,
                       op: CodeOP::LET(id),
                   });
 
+        self.tail = tail;
         try!(self.compile_(&ls[3]));
+        self.scope.pop();
+
+        return Ok(());
+    }
+
+    // (let ((<pattern> <expr>)*) <body>) is a let of each binding in turn, so a binding
+    // sees the ones before it
+    fn compile_let_bindings(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        let bindings = match ls[1].sexpr {
+            SExpr::List(ref bindings) => bindings,
+            _ => return self.error(&ls[0], "let syntax"),
+        };
+        let mut body = ls[2].clone();
+        for b in bindings.iter().rev() {
+            match b.sexpr {
+                SExpr::List(ref b) if b.len() == 2 => {
+                    let at = |sexpr| AST { info: ls[0].info, sexpr };
+                    let let_ = vec![at(SExpr::Atom(core("let"))), b[0].clone(), b[1].clone(), body];
+                    body = at(SExpr::List(let_));
+                }
+                _ => return self.error(b, "let binding syntax"),
+            }
+        }
+        return self.compile_begin(&vec![AST { info: ls[0].info, sexpr: SExpr::Atom(core("begin")) }, body], tail);
+    }
+
+    // binds the ids of `pattern` to the parts of the value on the stack: the machine's
+    // check-shape makes sure the value has the pattern's shape, raising a type-error
+    // at the pattern if not, and each id is loaded through the CARs and CDRs to it. A
+    // pattern is an id, (<pattern>*) for a list of that many, (<pattern>+ . <pattern>)
+    // for one of at least that many with the rest matching the last, or () for nil
+    fn destructure(&mut self, pattern: &AST) -> CompilerResult {
+        let mut ids = vec![];
+        try!(self.pattern_ids(pattern, &mut vec![], &mut ids));
+        let whole = format!("{}", pattern);
+        let info = pattern.info;
+        let check = self.constant(datum(pattern));
+        let id = self.primitives.id("check-shape").unwrap();
+        for op in vec![check, CodeOP::PRIM(id, 2), CodeOP::LET(whole.clone())] {
+            self.code.push(CodeOPInfo { info, op });
+        }
+        for (id, path, at) in ids {
+            self.code.push(CodeOPInfo { info, op: CodeOP::LD(whole.clone()) });
+            for op in path {
+                self.code.push(CodeOPInfo { info, op });
+            }
+            self.code.push(CodeOPInfo { info, op: CodeOP::LET(id.clone()) });
+            self.letrec_id_list.retain(|a| *a != id);
+            self.bind(&id, None, at);
+        }
+        return Ok(());
+    }
 
+    // each id of `pattern` with the CARs and CDRs from the whole value to it
+    fn pattern_ids(&self, pattern: &AST, path: &mut Vec<CodeOP>, ids: &mut Vec<(String, Vec<CodeOP>, Info)>) -> CompilerResult {
+        let ps = match pattern.sexpr {
+            SExpr::Atom(ref id) if id != "." => {
+                if ids.iter().any(|i| i.0 == *id) {
+                    return self.error(pattern, &format!("{} appears twice in the pattern", id));
+                }
+                ids.push((id.clone(), path.clone(), pattern.info));
+                return Ok(());
+            }
+            SExpr::List(ref ps) => ps,
+            _ => return self.error(pattern, "pattern syntax"),
+        };
+        let dot = ps.iter().position(|p| p.sexpr == SExpr::Atom(".".into()));
+        let n = match dot {
+            Some(d) if d == 0 || d + 2 != ps.len() => return self.error(pattern, "pattern syntax"),
+            Some(d) => d,
+            None => ps.len(),
+        };
+        let depth = path.len();
+        for p in &ps[..n] {
+            path.push(CodeOP::CAR);
+            try!(self.pattern_ids(p, path, ids));
+            path.pop();
+            path.push(CodeOP::CDR);
+        }
+        if dot.is_some() {
+            try!(self.pattern_ids(&ps[n + 1], path, ids));
+        }
+        path.truncate(depth);
         return Ok(());
     }
 
-    fn compile_letrec(&mut self, ls: &Vec<AST>) -> CompilerResult {
+    fn compile_letrec(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
         if ls.len() != 4 {
             return self.error(&ls[0], "let syntax");
         }
@@ -246,6 +1249,8 @@ impl Compiler {
         };
 
         self.letrec_id_list.push(id.clone());
+        let arity = self.arity(&ls[2]);
+        self.bind(&id, arity, ls[1].info);
 
         try!(self.compile_(&ls[2]));
         self.code
@@ -253,7 +1258,9 @@ impl Compiler {
                       info: ls[0].info,
                       op: CodeOP::LET(id),
                   });
+        self.tail = tail;
         try!(self.compile_(&ls[3]));
+        self.scope.pop();
 
         return Ok(());
     }
@@ -273,10 +1280,83 @@ impl Compiler {
     }
 
 
-    fn compile_apply(&mut self, ls: &Vec<AST>) -> CompilerResult {
-        let (lambda, args) = ls.split_first().unwrap();
-        for arg in args {
-            try!(self.compile_(arg));
+    // (module <name> ((<id> <expr>)*) <body>) binds each <id> as <name>:<id> like letrec,
+    // in order; the bindings may leave off the qualifier for the module's own names, the
+    // body may not
+    fn compile_module(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        if ls.len() != 4 {
+            return self.error(&ls[0], "module syntax");
+        }
+        let name = match ls[1].sexpr {
+            SExpr::Atom(ref name) if !name.contains(':') => name.clone(),
+            _ => return self.error(&ls[1], "module name syntax"),
+        };
+        let bindings = match ls[2].sexpr {
+            SExpr::List(ref bindings) => bindings,
+            _ => return self.error(&ls[2], "module bindings syntax"),
+        };
+
+        let depth = self.scope.len();
+        let module = self.module.replace(name.clone());
+        for b in bindings {
+            let (id, expr) = match b.sexpr {
+                SExpr::List(ref b) if b.len() == 2 => {
+                    match b[0].sexpr {
+                        SExpr::Atom(ref id) if !id.contains(':') => (format!("{}:{}", name, id), &b[1]),
+                        _ => return self.error(&b[0], "module binding id syntax"),
+                    }
+                }
+                _ => return self.error(b, "module binding syntax"),
+            };
+            self.letrec_id_list.push(id.clone());
+            let arity = self.arity(expr);
+            self.bind(&id, arity, b.info);
+            try!(self.compile_(expr));
+            self.code
+                .push(CodeOPInfo {
+                          info: b.info,
+                          op: CodeOP::LET(id),
+                      });
+        }
+        self.module = module;
+
+        self.tail = tail;
+        try!(self.compile_(&ls[3]));
+        self.scope.truncate(depth);
+
+        return Ok(());
+    }
+
+    fn compile_apply(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        if try!(self.compile_direct(ls, tail)) {
+            return Ok(());
+        }
+
+        let (lambda, args) = ls.split_first().unwrap();
+        let arity = match lambda.sexpr {
+            // the innermost binding of the name, when it is to a lambda
+            SExpr::Atom(ref id) => {
+                let resolved = self.resolve(id);
+                self.scope.iter().rev().find(|a| a.0 == resolved).and_then(|a| a.1)
+            }
+            _ => self.arity(lambda),
+        };
+        match arity {
+            Some(n) if self.curry && args.len() < n => return self.compile_partial(lambda, args),
+            _ => {}
+        }
+        if let SExpr::Atom(ref id) = lambda.sexpr {
+            match arity {
+                Some(n) if n != args.len() => {
+                    let plural = if n == 1 { "" } else { "s" };
+                    let msg = format!("{} takes {} argument{}, not {}", id, n, plural, args.len());
+                    return self.error(&ls[0], &msg);
+                }
+                _ => {}
+            }
+        }
+        for arg in args {
+            try!(self.compile_(arg));
         }
         self.code
             .push(CodeOPInfo {
@@ -285,64 +1365,190 @@ impl Compiler {
                   });
         try!(self.compile_(lambda));
 
-        match lambda.sexpr {
+        let rec = match lambda.sexpr {
             SExpr::Atom(ref id) => {
-                if self.letrec_id_list.iter().any(|a| a == id) {
-                    self.code
-                        .push(CodeOPInfo {
-                                  info: ls[0].info,
-                                  op: CodeOP::RAP,
-                              });
-                } else {
-                    self.code
-                        .push(CodeOPInfo {
-                                  info: ls[0].info,
-                                  op: CodeOP::AP,
-                              });
-                }
+                let id = self.resolve(id);
+                self.rec_bound(&id) || self.imported(&id)
             }
+            _ => false,
+        };
 
-            _ => {
-                self.code
-                    .push(CodeOPInfo {
-                              info: ls[0].info,
-                              op: CodeOP::AP,
-                          });
-            }
+        let op = match (rec, tail) {
+            (true, true) => CodeOP::TRAP,
+            (true, false) => CodeOP::RAP,
+            (false, true) => CodeOP::TAP,
+            (false, false) => CodeOP::AP,
+        };
+
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op,
+                  });
+
+        return Ok(());
+    }
+
+    // (curry <closure> <expr>*)
+    fn compile_curry(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() < 2 {
+            return self.error(&ls[0], "curry syntax");
         }
+        return self.compile_partial(&ls[1], &ls[2..]);
+    }
 
+    // (delay <expr>) is the machine's promise of a thunk of <expr>
+    fn compile_delay(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() != 2 {
+            return self.error(&ls[0], "delay syntax");
+        }
+        try!(self.compile_(&lambda(vec![], ls[1].clone())));
+        let id = self.primitives.id("promise").unwrap();
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: CodeOP::PRIM(id, 1),
+                  });
         return Ok(());
     }
 
-    fn compile_if(&mut self, ls: &Vec<AST>) -> CompilerResult {
+    // (trace <id>) has the machine trace the closure under the name
+    fn compile_trace(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        match ls.get(1).map(|a| &a.sexpr) {
+            Some(&SExpr::Atom(_)) if ls.len() == 2 => {}
+            _ => return self.error(&ls[0], "trace syntax"),
+        }
+        let info = ls[0].info;
+        let name = AST { info, sexpr: SExpr::List(vec![atom(&core("quote"), info), ls[1].clone()]) };
+        return self.compile_(&AST { info, sexpr: SExpr::List(vec![atom("native:trace", info), ls[1].clone(), name]) });
+    }
+
+    // (stream-cons <expr> <expr>) is (cons <expr> (delay <expr>)); the prelude's stream
+    // functions take the streams it makes
+    fn compile_stream_cons(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() != 3 {
+            return self.error(&ls[0], "stream-cons syntax");
+        }
+        let info = ls[0].info;
+        let rest = AST { info, sexpr: SExpr::List(vec![atom(&core("delay"), info), ls[2].clone()]) };
+        return self.compile_(&AST { info, sexpr: SExpr::List(vec![atom(&core("cons"), info), ls[1].clone(), rest]) });
+    }
+
+    // the closure `f` gives with `args` bound to its first parameters: the machine's
+    // curry makes it, from the closure and the arguments consed into a list
+    fn compile_partial(&mut self, f: &AST, args: &[AST]) -> CompilerResult {
+        try!(self.compile_(f));
+        for arg in args {
+            try!(self.compile_(arg));
+        }
+        self.code
+            .push(CodeOPInfo {
+                      info: f.info,
+                      op: self.constant(Lisp::nil()),
+                  });
+        for _ in args {
+            self.code
+                .push(CodeOPInfo {
+                          info: f.info,
+                          op: CodeOP::CONS,
+                      });
+        }
+        let id = self.primitives.id("curry").unwrap();
+        self.code
+            .push(CodeOPInfo {
+                      info: f.info,
+                      op: CodeOP::PRIM(id, 2),
+                  });
+        return Ok(());
+    }
+
+    // ((lambda (<id>*) <body>) <arg>*) with a small body binds the arguments with LET
+    // and compiles the body in place, the way let does, so no closure is made and
+    // called; false when the call is not of that shape
+    fn compile_direct(&mut self, ls: &Vec<AST>, tail: bool) -> Result<bool, Box<Error>> {
+        let (lambda, args) = ls.split_first().unwrap();
+        let fl = match lambda.sexpr {
+            SExpr::List(ref fl) if fl.len() >= 3 && fl[0].sexpr == SExpr::Atom("lambda".into()) &&
+                                   !self.bound("lambda") => fl,
+            _ => return Ok(false),
+        };
+        let body = &fl[fl.len() - 1];
+        match (fl.len(), &fl[2].sexpr) {
+            (3, _) |
+            (4, &SExpr::Str(_)) => {}
+            _ => return Ok(false),
+        }
+        let params: Vec<&AST> = match fl[1].sexpr {
+            SExpr::Atom(_) => vec![&fl[1]],
+            SExpr::List(ref ps) => ps.iter().collect(),
+            _ => return Ok(false),
+        };
+        let mut ids = vec![];
+        for p in params {
+            match types::param(p) {
+                Some((id, _)) if !id.starts_with("#:") => ids.push((id.clone(), p.info)),
+                _ => return Ok(false),
+            }
+        }
+        if ids.len() != args.len() || size(body) > INLINE_LIMIT {
+            return Ok(false);
+        }
+
+        for arg in args {
+            try!(self.compile_(arg));
+        }
+        // the parameters get hidden names, as LET binds them where the call is and the
+        // caller's own bindings of the same names must survive it
+        let depth = self.scope.len();
+        for (id, at) in ids.into_iter().rev() {
+            let hidden = format!(" inline {} {}:{}", id, at[0], at[1]);
+            self.declare(&id, at);
+            self.scope.push((hidden.clone(), None));
+            self.code
+                .push(CodeOPInfo {
+                          info: fl[0].info,
+                          op: CodeOP::LET(hidden),
+                      });
+        }
+
+        self.tail = tail;
+        try!(self.compile_(body));
+        self.scope.truncate(depth);
+        return Ok(true);
+    }
+
+    fn compile_if(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
         if ls.len() != 4 {
             return self.error(&ls[0], "if syntax");
         }
 
         try!(self.compile_(&ls[1]));
 
-        let mut tc = Compiler::new();
-        tc.letrec_id_list = self.letrec_id_list.clone();
+        let mut tc = self.nested();
+        tc.tail = tail;
         try!(tc.compile_(&ls[2]));
         tc.code
             .push(CodeOPInfo {
                       info: ls[2].info,
-                      op: CodeOP::JOIN,
+                      op: branch_end(tail),
                   });
 
-        let mut fc = Compiler::new();
-        fc.letrec_id_list = self.letrec_id_list.clone();
+        let mut fc = self.nested();
+        fc.tail = tail;
         try!(fc.compile_(&ls[3]));
         fc.code
             .push(CodeOPInfo {
                       info: ls[3].info,
-                      op: CodeOP::JOIN,
+                      op: branch_end(tail),
                   });
 
+        let i = self.code.len();
+        self.adopt(tc.emitted, i, 0);
+        self.adopt(fc.emitted, i, 1);
         self.code
             .push(CodeOPInfo {
                       info: ls[0].info,
-                      op: CodeOP::SEL(tc.code, fc.code),
+                      op: select(tc.code, fc.code, tail),
                   });
 
 
@@ -367,8 +1573,46 @@ impl Compiler {
     }
 
     fn compile_add(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        return self.compile_fold(ls, CodeOP::ADD);
+    }
+
+    fn compile_sub(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() < 2 {
+            return self.error(&ls[0], "sub syntax");
+        }
+
+        return self.compile_fold(ls, CodeOP::SUB);
+    }
+
+    // (op a b c) as ((a op b) op c); with fewer than two arguments 0 comes first, so
+    // (+) is 0 and (- n) negates
+    fn compile_fold(&mut self, ls: &Vec<AST>, op: CodeOP) -> CompilerResult {
+        let zero = ls.len() < 3;
+        if zero {
+            self.code
+                .push(CodeOPInfo {
+                          info: ls[0].info,
+                          op: self.constant(Lisp::int(0)),
+                      });
+        }
+
+        for (i, arg) in ls[1..].iter().enumerate() {
+            try!(self.compile_(arg));
+            if i > 0 || zero {
+                self.code
+                    .push(CodeOPInfo {
+                              info: ls[0].info,
+                              op: op.clone(),
+                          });
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn compile_cons(&mut self, ls: &Vec<AST>) -> CompilerResult {
         if ls.len() != 3 {
-            return self.error(&ls[0], "add syntax");
+            return self.error(&ls[0], "cons syntax");
         }
 
         try!(self.compile_(&ls[1]));
@@ -376,71 +1620,1093 @@ impl Compiler {
         self.code
             .push(CodeOPInfo {
                       info: ls[0].info,
-                      op: CodeOP::ADD,
+                      op: CodeOP::CONS,
                   });
 
         return Ok(());
     }
 
-    fn compile_sub(&mut self, ls: &Vec<AST>) -> CompilerResult {
-        if ls.len() != 3 {
-            return self.error(&ls[0], "sub syntax");
+    fn compile_car(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() != 2 {
+            return self.error(&ls[0], "car syntax");
         }
 
         try!(self.compile_(&ls[1]));
-        try!(self.compile_(&ls[2]));
         self.code
             .push(CodeOPInfo {
                       info: ls[0].info,
-                      op: CodeOP::SUB,
+                      op: CodeOP::CAR,
                   });
 
         return Ok(());
     }
 
-    fn compile_cons(&mut self, ls: &Vec<AST>) -> CompilerResult {
-        if ls.len() != 3 {
-            return self.error(&ls[0], "cons syntax");
+    fn compile_cdr(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() != 2 {
+            return self.error(&ls[0], "cdr syntax");
         }
 
         try!(self.compile_(&ls[1]));
-        try!(self.compile_(&ls[2]));
         self.code
             .push(CodeOPInfo {
                       info: ls[0].info,
-                      op: CodeOP::CONS,
+                      op: CodeOP::CDR,
                   });
 
         return Ok(());
     }
 
-    fn compile_car(&mut self, ls: &Vec<AST>) -> CompilerResult {
+    // operands are pushed left to right, then `op` consumes them
+    fn compile_op(&mut self, ls: &Vec<AST>, arity: usize, op: CodeOP) -> CompilerResult {
+        if ls.len() != arity + 1 {
+            return self.error(&ls[0], &format!("{} syntax", ls[0]));
+        }
+
+        for arg in &ls[1..] {
+            try!(self.compile_(arg));
+        }
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op,
+                  });
+
+        return Ok(());
+    }
+
+    // (error type message payload) raises the condition it makes
+    fn compile_error(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        try!(self.compile_op(ls, 3, CodeOP::MKCOND));
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: CodeOP::RAISE,
+                  });
+        return Ok(());
+    }
+
+    fn compile_prim(&mut self, ls: &Vec<AST>, name: &str) -> CompilerResult {
+        let id = self.primitives.id(name).unwrap();
+        let arity = self.primitives.arity(id).unwrap();
+        if ls.len() != arity + 1 {
+            return self.error(&ls[0], &format!("{} syntax", ls[0]));
+        }
+
+        for arg in &ls[1..] {
+            try!(self.compile_(arg));
+        }
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: CodeOP::PRIM(id, arity),
+                  });
+
+        return Ok(());
+    }
+
+    fn compile_time(&mut self, ls: &Vec<AST>) -> CompilerResult {
         if ls.len() != 2 {
-            return self.error(&ls[0], "car syntax");
+            return self.error(&ls[0], "time syntax");
         }
 
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: CodeOP::CLOCK,
+                  });
         try!(self.compile_(&ls[1]));
         self.code
             .push(CodeOPInfo {
                       info: ls[0].info,
-                      op: CodeOP::CAR,
+                      op: CodeOP::TIME,
                   });
 
         return Ok(());
     }
 
-    fn compile_cdr(&mut self, ls: &Vec<AST>) -> CompilerResult {
+    fn compile_assert(&mut self, ls: &Vec<AST>) -> CompilerResult {
         if ls.len() != 2 {
-            return self.error(&ls[0], "cdr syntax");
+            return self.error(&ls[0], "assert syntax");
         }
 
         try!(self.compile_(&ls[1]));
         self.code
             .push(CodeOPInfo {
                       info: ls[0].info,
-                      op: CodeOP::CDR,
+                      op: CodeOP::ASSERT(ls[1].info, format!("{}", ls[1])),
+                  });
+
+        return Ok(());
+    }
+
+    fn compile_quote(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() != 2 {
+            return self.error(&ls[0], "quote syntax");
+        }
+
+        let lisp = match ls[1].sexpr {
+            SExpr::Atom(ref id) => Rc::new(Lisp::Symbol(id.clone())),
+            _ => try!(self.compile_datum(&ls[1])),
+        };
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: self.constant(lisp),
+                  });
+
+        return Ok(());
+    }
+
+    fn compile_datum(&self, ast: &AST) -> Result<Rc<Lisp>, Box<Error>> {
+        match ast.sexpr {
+            SExpr::Int(n) => return Ok(Rc::new(Lisp::Int(n))),
+            SExpr::Str(ref s) => return Ok(Rc::new(Lisp::Str(s.clone()))),
+            SExpr::Atom(ref id) if id == "nil" => return Ok(Rc::new(Lisp::Nil)),
+            SExpr::Atom(ref id) if id == "true" => return Ok(Rc::new(Lisp::True)),
+            SExpr::Atom(ref id) if id == "false" => return Ok(Rc::new(Lisp::False)),
+            SExpr::List(ref ls) if ls.len() == 0 => return Ok(Rc::new(Lisp::Nil)),
+            _ => return self.error(ast, "case datum"),
+        }
+    }
+
+    // (try <body> (<kind> <id> <handler>)+); neither the body nor a handler is in
+    // tail position, the try frame has to outlive them
+    fn compile_try(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() < 3 {
+            return self.error(&ls[0], "try syntax");
+        }
+
+        let mut body = self.nested();
+        try!(body.compile_(&ls[1]));
+        body.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: CodeOP::ENDTRY,
+                  });
+
+        let i = self.code.len();
+        let mut handlers = vec![];
+        for (n, clause) in ls[2..].iter().enumerate() {
+            let (kind, id, at, expr) = match clause.sexpr {
+                SExpr::List(ref cl) if cl.len() == 3 => {
+                    match (&cl[0].sexpr, &cl[1].sexpr) {
+                        (&SExpr::Atom(ref kind), &SExpr::Atom(ref id)) => (kind, id, cl[1].info, &cl[2]),
+                        _ => return self.error(clause, "try handler syntax"),
+                    }
+                }
+                _ => return self.error(clause, "try handler syntax"),
+            };
+
+            let mut hc = self.nested();
+            hc.bind(id, None, at);
+            try!(hc.compile_(expr));
+            hc.code
+                .push(CodeOPInfo {
+                          info: clause.info,
+                          op: CodeOP::JOIN,
+                      });
+            self.adopt(hc.emitted, i, n + 1);
+            handlers.push(Handler {
+                              kind: kind.clone(),
+                              id: id.clone(),
+                              code: hc.code,
+                          });
+        }
+
+        self.adopt(body.emitted, i, 0);
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: CodeOP::TRY(body.code, handlers),
+                  });
+
+        return Ok(());
+    }
+
+    // the cleanup's own value is dropped; the VM ends its block with JOIN when the
+    // body returned and with RERAISE when it raised
+    fn compile_protect(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() != 3 {
+            return self.error(&ls[0], "unwind-protect syntax");
+        }
+
+        let mut body = self.nested();
+        try!(body.compile_(&ls[1]));
+        body.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: CodeOP::ENDPROTECT,
+                  });
+
+        let mut cleanup = self.nested();
+        try!(cleanup.compile_(&ls[2]));
+        cleanup.code
+            .push(CodeOPInfo {
+                      info: ls[2].info,
+                      op: CodeOP::POP,
+                  });
+
+        let i = self.code.len();
+        self.adopt(body.emitted, i, 0);
+        self.adopt(cleanup.emitted, i, 1);
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: CodeOP::PROTECT(body.code, cleanup.code),
+                  });
+
+        return Ok(());
+    }
+
+    // (dynamic-wind <before> <thunk> <after>) evaluates the three thunks in order, then
+    // calls before and calls thunk under an unwind-protect whose cleanup calls after
+    fn compile_dynamic_wind(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() != 4 {
+            return self.error(&ls[0], "dynamic-wind syntax");
+        }
+
+        let info = ls[0].info;
+        let node = |sexpr| AST { info, sexpr };
+        let call = |id: &str| node(SExpr::List(vec![node(SExpr::Atom(id.into()))]));
+        let names: Vec<String> = ["before", "thunk", "after"]
+            .iter()
+            .map(|n| format!(" {} {}:{}", n, info[0], info[1]))
+            .collect();
+        for (ast, name) in ls[1..].iter().zip(names.iter()) {
+            try!(self.compile_(ast));
+            self.code
+                .push(CodeOPInfo {
+                          info,
+                          op: CodeOP::LET(name.clone()),
+                      });
+        }
+
+        let protect = node(SExpr::List(vec![node(SExpr::Atom(core("unwind-protect"))),
+                                            call(&names[1]),
+                                            call(&names[2])]));
+        return self.compile_(&node(SExpr::List(vec![node(SExpr::Atom(core("begin"))),
+                                                     call(&names[0]),
+                                                     protect])));
+    }
+
+    // (define/contract <id> (<pred>* -> <pred>) <expr> <body>) binds <id> as letrec
+    // does, to a closure that checks the arguments, calls <expr>'s closure and checks
+    // its result. The predicates and the closure are kept in hidden bindings, and
+    // recursive calls go through the checks too.
+    fn compile_contract(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        if ls.len() != 5 {
+            return self.error(&ls[0], "define/contract syntax");
+        }
+        let id = match ls[1].sexpr {
+            SExpr::Atom(ref id) => id.clone(),
+            _ => return self.error(&ls[1], "define/contract id syntax"),
+        };
+        let preds = match ls[2].sexpr {
+            SExpr::List(ref preds) if preds.len() >= 2 &&
+                                      preds[preds.len() - 2].sexpr ==
+                                      SExpr::Atom("->".into()) => preds,
+            _ => return self.error(&ls[2], "define/contract contract syntax"),
+        };
+        let (result, args) = (&preds[preds.len() - 1], &preds[..preds.len() - 2]);
+
+        let info = ls[0].info;
+        let hidden = |n: &str| format!(" {} {}:{}", n, info[0], info[1]);
+        let node = |sexpr| AST { info, sexpr };
+        let atom = |id: &str| node(SExpr::Atom(id.into()));
+        let list = |ls: Vec<AST>| node(SExpr::List(ls));
+        // raised where the predicate that failed is written
+        let violation = |what: String, pred: &AST, value: AST| {
+            let at = |sexpr| AST { info: pred.info, sexpr };
+            let kind = vec![at(SExpr::Atom(core("quote"))),
+                            at(SExpr::Atom("contract-violation".into()))];
+            at(SExpr::List(vec![at(SExpr::Atom(core("error"))),
+                                at(SExpr::List(kind)),
+                                at(SExpr::Str(format!("{}: {} failed {}", id, what, pred).replace('{', "{{").replace('}', "}}"))),
+                                value]))
+        };
+
+        let pred_name = |i: usize| hidden(&format!("pred {}", i));
+        let names: Vec<String> = (0..args.len()).map(|i| hidden(&format!("arg {}", i))).collect();
+        for (i, pred) in args.iter().chain(Some(result)).enumerate() {
+            try!(self.compile_(pred));
+            self.code
+                .push(CodeOPInfo {
+                          info,
+                          op: CodeOP::LET(pred_name(i)),
+                      });
+        }
+
+        let mut call = vec![atom(&hidden("impl"))];
+        call.extend(names.iter().map(|n| atom(n)));
+        let res = hidden("result");
+        let mut checked =
+            list(vec![atom(&core("let")),
+                      atom(&res),
+                      list(call),
+                      list(vec![atom(&core("if")),
+                                list(vec![atom(&pred_name(args.len())), atom(&res)]),
+                                atom(&res),
+                                violation("result".into(), result, atom(&res))])]);
+        for (i, pred) in args.iter().enumerate().rev() {
+            checked = list(vec![atom(&core("if")),
+                                list(vec![atom(&pred_name(i)), atom(&names[i])]),
+                                checked,
+                                violation(format!("argument {}", i + 1), pred, atom(&names[i]))]);
+        }
+
+        let params = names.iter().map(|n| atom(n)).collect();
+        let wrapper = list(vec![atom(&core("lambda")), list(params), checked]);
+
+        self.letrec_id_list.push(id.clone());
+        self.letrec_id_list.push(hidden("impl"));
+        self.bind(&id, Some(args.len()), ls[1].info);
+        try!(self.compile_(&ls[3]));
+        self.code
+            .push(CodeOPInfo {
+                      info,
+                      op: CodeOP::LET(hidden("impl")),
+                  });
+        try!(self.compile_(&wrapper));
+        self.code
+            .push(CodeOPInfo {
+                      info,
+                      op: CodeOP::LET(id),
+                  });
+
+        self.tail = tail;
+        try!(self.compile_(&ls[4]));
+        self.scope.pop();
+        return Ok(());
+    }
+
+    // (parameterize ((<parameter> <expr>)*) <body>) sets the parameters for the body
+    // and puts the old values back however it is left; PARAMBIND leaves what to put
+    // back in a binding the cleanup reads
+    fn compile_parameterize(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() != 3 {
+            return self.error(&ls[0], "parameterize syntax");
+        }
+
+        let bindings = match ls[1].sexpr {
+            SExpr::List(ref bindings) => bindings,
+            _ => return self.error(&ls[1], "parameterize bindings syntax"),
+        };
+        for b in bindings {
+            match b.sexpr {
+                SExpr::List(ref pv) if pv.len() == 2 => {
+                    try!(self.compile_(&pv[0]));
+                    try!(self.compile_(&pv[1]));
+                }
+                _ => return self.error(b, "parameterize binding syntax"),
+            }
+        }
+
+        let info = ls[0].info;
+        let saved = format!(" parameterize {}:{}", info[0], info[1]);
+        self.code
+            .push(CodeOPInfo {
+                      info,
+                      op: CodeOP::PARAMBIND(bindings.len()),
+                  });
+        self.code
+            .push(CodeOPInfo {
+                      info,
+                      op: CodeOP::LET(saved.clone()),
+                  });
+
+        let mut body = self.nested();
+        try!(body.compile_(&ls[2]));
+        body.code
+            .push(CodeOPInfo {
+                      info,
+                      op: CodeOP::ENDPROTECT,
                   });
+        let cleanup = vec![CodeOPInfo {
+                               info,
+                               op: CodeOP::LD(saved),
+                           },
+                           CodeOPInfo {
+                               info,
+                               op: CodeOP::PARAMRESTORE,
+                           },
+                           CodeOPInfo {
+                               info,
+                               op: CodeOP::POP,
+                           }];
+
+        let i = self.code.len();
+        self.adopt(body.emitted, i, 0);
+        self.code
+            .push(CodeOPInfo {
+                      info,
+                      op: CodeOP::PROTECT(body.code, cleanup),
+                  });
+
+        return Ok(());
+    }
+
+    // the name is only known here, so HELP carries how to spell the call
+    fn compile_help(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        if ls.len() != 2 {
+            return self.error(&ls[0], "help syntax");
+        }
 
+        let name = match ls[1].sexpr {
+            SExpr::Atom(ref id) => id.clone(),
+            _ => "lambda".to_string(),
+        };
+        try!(self.compile_(&ls[1]));
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: CodeOP::HELP(name),
+                  });
         return Ok(());
     }
+
+    // the annotation is for the type checker; here it only has to name a type
+    fn compile_the(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        if ls.len() != 3 {
+            return self.error(&ls[0], "the syntax");
+        }
+        if types::annotation(&ls[1]).is_none() {
+            return self.error(&ls[1], "unknown type");
+        }
+
+        self.tail = tail;
+        return self.compile_(&ls[2]);
+    }
+
+    fn compile_case(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        if ls.len() < 2 {
+            return self.error(&ls[0], "case syntax");
+        }
+
+        // the key is evaluated once and kept in a binding no identifier can spell
+        let key = format!(" case {}:{}", ls[0].info[0], ls[0].info[1]);
+
+        try!(self.compile_(&ls[1]));
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: CodeOP::LET(key.clone()),
+                  });
+
+        return self.compile_case_clauses(&key, &ls[2..], tail);
+    }
+
+    fn compile_case_clauses(&mut self,
+                            key: &String,
+                            clauses: &[AST],
+                            tail: bool)
+                            -> CompilerResult {
+        let (clause, rest) = match clauses.split_first() {
+            Some(c) => c,
+            None => {
+                self.code
+                    .push(CodeOPInfo {
+                              info: [0; 2],
+                              op: self.constant(Lisp::nil()),
+                          });
+                return Ok(());
+            }
+        };
+
+        let cl = match clause.sexpr {
+            SExpr::List(ref cl) if cl.len() == 2 => cl,
+            _ => return self.error(clause, "case clause syntax"),
+        };
+
+        let datums = match cl[0].sexpr {
+            SExpr::Atom(ref id) if id == "else" => {
+                if rest.len() != 0 {
+                    return self.error(&cl[0], "case else must be last");
+                }
+                self.tail = tail;
+                return self.compile_(&cl[1]);
+            }
+
+            SExpr::List(ref ds) if ds.len() > 0 => ds,
+            _ => return self.error(&cl[0], "case datum list"),
+        };
+
+        // test each datum in turn; any match yields true without testing the rest
+        let mut test = vec![];
+        for d in datums.iter().rev() {
+            let mut t = vec![CodeOPInfo {
+                                 info: d.info,
+                                 op: CodeOP::LD(key.clone()),
+                             },
+                             CodeOPInfo {
+                                 info: d.info,
+                                 op: self.constant(try!(self.compile_datum(d))),
+                             },
+                             CodeOPInfo {
+                                 info: d.info,
+                                 op: CodeOP::EQ,
+                             }];
+            if test.len() > 0 {
+                test.push(CodeOPInfo {
+                              info: d.info,
+                              op: CodeOP::JOIN,
+                          });
+                t.push(CodeOPInfo {
+                           info: d.info,
+                           op: CodeOP::SEL(vec![CodeOPInfo {
+                                                    info: d.info,
+                                                    op: self.constant(Lisp::bool(true)),
+                                                },
+                                                CodeOPInfo {
+                                                    info: d.info,
+                                                    op: CodeOP::JOIN,
+                                                }],
+                                           test),
+                       });
+            }
+            test = t;
+        }
+        self.code.extend(test);
+
+        let mut tc = self.nested();
+        tc.tail = tail;
+        try!(tc.compile_(&cl[1]));
+        tc.code
+            .push(CodeOPInfo {
+                      info: cl[1].info,
+                      op: branch_end(tail),
+                  });
+
+        let mut fc = self.nested();
+        try!(fc.compile_case_clauses(key, rest, tail));
+        fc.code
+            .push(CodeOPInfo {
+                      info: clause.info,
+                      op: branch_end(tail),
+                  });
+
+        let i = self.code.len();
+        self.adopt(tc.emitted, i, 0);
+        self.adopt(fc.emitted, i, 1);
+        self.code
+            .push(CodeOPInfo {
+                      info: clause.info,
+                      op: select(tc.code, fc.code, tail),
+                  });
+
+        return Ok(());
+    }
+
+    // (test "name" expected actual) records whether the two are equal under the name,
+    // prefixed with the names of the groups it is in, and gives that
+    fn compile_test(&mut self, ls: &Vec<AST>) -> CompilerResult {
+        let name = match ls.get(1).map(|a| &a.sexpr) {
+            Some(&SExpr::Str(ref name)) if ls.len() == 4 => name,
+            _ => return self.error(&ls[0], "test syntax"),
+        };
+        let mut names = self.groups.clone();
+        names.push(name.clone());
+
+        try!(self.compile_(&ls[2]));
+        try!(self.compile_(&ls[3]));
+        self.code
+            .push(CodeOPInfo {
+                      info: ls[0].info,
+                      op: CodeOP::TEST(names.join(" / ")),
+                  });
+        return Ok(());
+    }
+
+    // (test-group "name" <expr>+) is begin, naming the tests in it
+    fn compile_test_group(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        let name = match ls.get(1).map(|a| &a.sexpr) {
+            Some(&SExpr::Str(ref name)) if ls.len() > 2 => name,
+            _ => return self.error(&ls[0], "test-group syntax"),
+        };
+
+        self.groups.push(name.clone());
+        try!(self.compile_begin(&ls[1..].to_vec(), tail));
+        self.groups.pop();
+        return Ok(());
+    }
+
+    fn compile_begin(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        if ls.len() < 2 {
+            return self.error(&ls[0], "begin syntax");
+        }
+
+        let (last, init) = ls[1..].split_last().unwrap();
+        for ast in init {
+            try!(self.compile_(ast));
+            self.code
+                .push(CodeOPInfo {
+                          info: ast.info,
+                          op: CodeOP::POP,
+                      });
+        }
+
+        self.tail = tail;
+        return self.compile_(last);
+    }
+
+    // (cond-expand (<requirement> <expr>+)+) compiles only the clause whose requirement
+    // the features meet first, like begin; a requirement is a feature, else, or and, or
+    // and not of requirements
+    fn compile_cond_expand(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        match try!(self.expand_cond_expand(ls)).sexpr {
+            SExpr::List(ref begin) => return self.compile_begin(begin, tail),
+            _ => unreachable!(),
+        }
+    }
+
+    // the begin of the clause cond-expand picks
+    fn expand_cond_expand(&self, ls: &Vec<AST>) -> Result<AST, Box<Error>> {
+        for clause in &ls[1..] {
+            let c = match clause.sexpr {
+                SExpr::List(ref c) if c.len() >= 2 => c,
+                _ => return self.error(clause, "cond-expand clause syntax"),
+            };
+            if try!(self.requirement(&c[0])) {
+                let mut begin = vec![AST { info: c[0].info, sexpr: SExpr::Atom(core("begin")) }];
+                begin.extend(c[1..].iter().cloned());
+                return Ok(AST { info: clause.info, sexpr: SExpr::List(begin) });
+            }
+        }
+        return self.error(&ls[0], "cond-expand: no clause matches the features");
+    }
+
+    fn requirement(&self, ast: &AST) -> Result<bool, Box<Error>> {
+        let ls = match ast.sexpr {
+            SExpr::Atom(ref f) => return Ok(f == "else" || self.features.contains(f)),
+            SExpr::List(ref ls) if ls.len() >= 1 => ls,
+            _ => return self.error(ast, "cond-expand requirement syntax"),
+        };
+        let mut met = vec![];
+        for r in &ls[1..] {
+            met.push(try!(self.requirement(r)));
+        }
+        match (&ls[0].sexpr, met.len()) {
+            (&SExpr::Atom(ref op), _) if op == "and" => return Ok(met.iter().all(|&m| m)),
+            (&SExpr::Atom(ref op), _) if op == "or" => return Ok(met.iter().any(|&m| m)),
+            (&SExpr::Atom(ref op), 1) if op == "not" => return Ok(!met[0]),
+            _ => return self.error(ast, "cond-expand requirement syntax"),
+        }
+    }
+
+    // (define-record <name> (<field>*) <body>) is a let of each of the record's
+    // functions around the body
+    fn compile_record(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        if ls.len() != 4 {
+            return self.error(&ls[0], "define-record syntax; in a program it takes a body");
+        }
+        let mut body = ls[3].clone();
+        for (id, at, value) in try!(self.record(&ls[..3])).into_iter().rev() {
+            let node = |sexpr| AST { info: at, sexpr };
+            body = node(SExpr::List(vec![node(SExpr::Atom(core("let"))), node(SExpr::Atom(id)), value, body]));
+        }
+        return self.compile_begin(&vec![AST { info: ls[0].info, sexpr: SExpr::Atom(core("begin")) }, body], tail);
+    }
+
+    // the functions of a record: make-<name> of the fields, <name>? and <name>-<field>
+    // for each field. A record is a list of its fields after a symbol no program can
+    // read, a space and the name, which the predicate and accessors check for
+    fn record(&self, ls: &[AST]) -> Result<Vec<(String, Info, AST)>, Box<Error>> {
+        let name = match ls[1].sexpr {
+            SExpr::Atom(ref name) => name,
+            _ => return self.error(&ls[1], "define-record name syntax"),
+        };
+        let fields = match ls[2].sexpr {
+            SExpr::List(ref fields) => fields,
+            _ => return self.error(&ls[2], "define-record fields syntax"),
+        };
+        for (i, f) in fields.iter().enumerate() {
+            match f.sexpr {
+                SExpr::Atom(_) if fields[..i].iter().any(|g| g.sexpr == f.sexpr) => {
+                    return self.error(f, &format!("field {} appears twice", f))
+                }
+                SExpr::Atom(_) => {}
+                _ => return self.error(f, "define-record field syntax"),
+            }
+        }
+
+        let info = ls[1].info;
+        let v = core("record");
+        let names = record_names(name, fields);
+        let mut defs = vec![(names[0].clone(), info, lambda(fields.clone(), tagged(name, fields, info)))];
+        defs.push((names[1].clone(), info, lambda(vec![atom(&v, info)], tag_test(&v, name, info))));
+        for (i, (f, accessor)) in fields.iter().zip(names[2..].iter().cloned()).enumerate() {
+            let node = |sexpr| AST { info: f.info, sexpr };
+            let mut field = atom(&v, f.info);
+            for _ in 0..i + 1 {
+                field = node(SExpr::List(vec![atom(&core("cdr"), f.info), field]));
+            }
+            let field = node(SExpr::List(vec![atom(&core("car"), f.info), field]));
+            let message = format!("{}: expected {}", accessor, name);
+            let body = node(SExpr::List(vec![atom(&core("if"), f.info),
+                                             tag_test(&v, name, f.info),
+                                             field,
+                                             type_error(&message, &v, f.info)]));
+            defs.push((accessor, f.info, lambda(vec![atom(&v, f.info)], body)));
+        }
+        return Ok(defs);
+    }
+
+    // (define-variant <name> (<case> <field>*)+ <body>) is a let of each of the
+    // variant's functions around the body, in which match knows its cases
+    fn compile_variant(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        if ls.len() < 4 {
+            return self.error(&ls[0], "define-variant syntax; in a program it takes a body");
+        }
+        let (last, cases) = ls.split_last().unwrap();
+        let mut body = last.clone();
+        for (id, at, value) in try!(self.variant(cases)).into_iter().rev() {
+            let node = |sexpr| AST { info: at, sexpr };
+            body = node(SExpr::List(vec![atom(&core("let"), at), atom(&id, at), value, body]));
+        }
+        self.variants.push(variant_cases(cases));
+        let r = self.compile_begin(&vec![atom(&core("begin"), ls[0].info), body], tail);
+        self.variants.pop();
+        return r;
+    }
+
+    // the functions of a variant: <name>? and, for each case, <case> of its fields and
+    // <case>?. A case's value is like a record's, so match takes records too
+    fn variant(&self, ls: &[AST]) -> Result<Vec<(String, Info, AST)>, Box<Error>> {
+        let name = match ls[1].sexpr {
+            SExpr::Atom(ref name) => name,
+            _ => return self.error(&ls[1], "define-variant name syntax"),
+        };
+        let v = core("variant");
+        let mut cases = vec![];
+        for (i, case) in ls[2..].iter().enumerate() {
+            let (id, fields) = match case.sexpr {
+                SExpr::List(ref c) if !c.is_empty() => (&c[0], &c[1..]),
+                _ => return self.error(case, "define-variant case syntax"),
+            };
+            match id.sexpr {
+                SExpr::Atom(_) if ls[2..2 + i].iter().any(|c| variant_case(c).map(|c| &c.sexpr) == Some(&id.sexpr)) => {
+                    return self.error(id, &format!("case {} appears twice", id))
+                }
+                SExpr::Atom(_) => {}
+                _ => return self.error(id, "define-variant case syntax"),
+            }
+            for (j, f) in fields.iter().enumerate() {
+                match f.sexpr {
+                    SExpr::Atom(_) if fields[..j].iter().any(|g| g.sexpr == f.sexpr) => {
+                        return self.error(f, &format!("field {} appears twice", f))
+                    }
+                    SExpr::Atom(_) => {}
+                    _ => return self.error(f, "define-variant field syntax"),
+                }
+            }
+            cases.push((format!("{}", id), id.info, fields));
+        }
+
+        let info = ls[1].info;
+        let mut any = atom("false", info);
+        for &(ref case, _, _) in cases.iter().rev() {
+            any = AST { info, sexpr: SExpr::List(vec![atom(&core("if"), info), tag_test(&v, case, info), atom("true", info), any]) };
+        }
+        let mut defs = vec![(format!("{}?", name), info, lambda(vec![atom(&v, info)], any))];
+        for (case, at, fields) in cases {
+            defs.push((case.clone(), at, lambda(fields.to_vec(), tagged(&case, fields, at))));
+            defs.push((format!("{}?", case), at, lambda(vec![atom(&v, at)], tag_test(&v, &case, at))));
+        }
+        return Ok(defs);
+    }
+
+    // (match <expr> ((<case> <pattern>*) <expr>)* (else <expr>)?) gives the expression
+    // of the first clause for the record or variant case the value is, with the
+    // patterns bound to its fields as let binds them, or raises a type-error when none
+    // is. Without an else, a clause for a case of a variant in scope has the others
+    // warned about when they have none
+    fn compile_match(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        if ls.len() < 3 {
+            return self.error(&ls[0], "match syntax");
+        }
+        let info = ls[0].info;
+        let v = format!(" match {}:{}", info[0], info[1]);
+
+        let mut cases = vec![];
+        let mut otherwise = None;
+        for (i, clause) in ls[2..].iter().enumerate() {
+            let c = match clause.sexpr {
+                SExpr::List(ref c) if c.len() == 2 => c,
+                _ => return self.error(clause, "match clause syntax"),
+            };
+            match c[0].sexpr {
+                SExpr::Atom(ref e) if e == "else" && i + 3 == ls.len() => otherwise = Some(c[1].clone()),
+                SExpr::Atom(ref e) if e == "else" => return self.error(clause, "else must be the last match clause"),
+                SExpr::List(ref p) if !p.is_empty() => {
+                    let case = match p[0].sexpr {
+                        SExpr::Atom(ref case) => case,
+                        _ => return self.error(&p[0], "match clause syntax"),
+                    };
+                    let known = self.variants.iter().rev().flat_map(|v| v.1.iter()).find(|c| c.0 == *case);
+                    match known {
+                        Some(&(_, n)) if n != p.len() - 1 => {
+                            let plural = if n == 1 { "" } else { "s" };
+                            return self.error(&c[0], &format!("{} has {} field{}, not {}", case, n, plural, p.len() - 1));
+                        }
+                        _ => {}
+                    }
+                    cases.push((case.clone(), p[1..].to_vec(), c[0].info, c[1].clone()));
+                }
+                _ => return self.error(clause, "match clause syntax"),
+            }
+        }
+
+        if otherwise.is_none() {
+            let variant = self.variants.iter().rev().find(|v| v.1.iter().any(|c| cases.iter().any(|m| m.0 == c.0)));
+            if let Some(&(ref name, ref all)) = variant {
+                let missing: Vec<&str> = all.iter().map(|c| c.0.as_str()).filter(|c| !cases.iter().any(|m| m.0 == *c)).collect();
+                if !missing.is_empty() {
+                    let msg = format!("match on {} has no clause for {}", name, missing.join(", "));
+                    self.warnings.borrow_mut().push(Diagnostic::warning("compile", Some(info), msg));
+                }
+            }
+        }
+
+        let node = |sexpr| AST { info, sexpr };
+        let mut chain = otherwise.unwrap_or_else(|| type_error("match: no clause for the value", &v, info));
+        for (case, fields, at, body) in cases.into_iter().rev() {
+            let rest = AST { info: at, sexpr: SExpr::List(vec![atom(&core("cdr"), at), atom(&v, at)]) };
+            let bind = AST { info: at, sexpr: SExpr::List(vec![atom(&core("let"), at), AST { info: at, sexpr: SExpr::List(fields) }, rest, body]) };
+            chain = node(SExpr::List(vec![atom(&core("if"), at), tag_test(&v, &case, at), bind, chain]));
+        }
+        return self.compile_let(&vec![atom(&core("let"), info), atom(&v, info), ls[1].clone(), chain], tail);
+    }
+
+    // (do ((<id> <init> <step>?)*) (<test> <expr>) <body>*) is rewritten to
+    // (letrec loop (lambda (<id>*) (if <test> <expr> (begin <body>* (loop <step>*)))) (loop <init>*))
+    // so every iteration is a tail call and the dump does not grow
+    fn compile_do(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        match try!(self.expand_do(ls)).sexpr {
+            SExpr::List(ref letrec) => return self.compile_letrec(letrec, tail),
+            _ => unreachable!(),
+        }
+    }
+
+    fn expand_do(&self, ls: &Vec<AST>) -> Result<AST, Box<Error>> {
+        if ls.len() < 3 {
+            return self.error(&ls[0], "do syntax");
+        }
+
+        let info = ls[0].info;
+        let node = |sexpr| AST { info, sexpr };
+        let atom = |id: &str| AST { info, sexpr: SExpr::Atom(id.into()) };
+        let name = format!(" do {}:{}", info[0], info[1]);
+
+        let mut ids = vec![];
+        let mut inits = vec![atom(&name)];
+        let mut steps = vec![atom(&name)];
+        match ls[1].sexpr {
+            SExpr::List(ref vars) => {
+                for var in vars {
+                    match var.sexpr {
+                        SExpr::List(ref v) if v.len() == 2 || v.len() == 3 => {
+                            match v[0].sexpr {
+                                SExpr::Atom(_) => {}
+                                _ => return self.error(&v[0], "do variable"),
+                            }
+                            ids.push(v[0].clone());
+                            inits.push(v[1].clone());
+                            steps.push(v.get(2).unwrap_or(&v[0]).clone());
+                        }
+
+                        _ => return self.error(var, "do variable syntax"),
+                    }
+                }
+            }
+
+            _ => return self.error(&ls[1], "do variables syntax"),
+        }
+
+        let (test, result) = match ls[2].sexpr {
+            SExpr::List(ref t) if t.len() == 2 => (t[0].clone(), t[1].clone()),
+            _ => return self.error(&ls[2], "do test syntax"),
+        };
+
+        let mut body = vec![atom(&core("begin"))];
+        body.extend(ls[3..].iter().cloned());
+        body.push(node(SExpr::List(steps)));
+
+        let lambda = node(SExpr::List(vec![atom(&core("lambda")),
+                                           node(SExpr::List(ids)),
+                                           node(SExpr::List(vec![atom(&core("if")),
+                                                                 test,
+                                                                 result,
+                                                                 node(SExpr::List(body))]))]));
+
+        return Ok(node(SExpr::List(vec![atom(&core("letrec")), atom(&name), lambda, node(SExpr::List(inits))])));
+    }
+
+    fn compile_thread(&mut self, ls: &Vec<AST>, tail: bool) -> CompilerResult {
+        let call = try!(self.expand_thread(ls));
+        return self.compile_begin(&vec![AST { info: ls[0].info, sexpr: SExpr::Atom(core("begin")) }, call], tail);
+    }
+
+    // (-> <expr> <step>*) makes <expr> the first argument of the first step's call, that
+    // call the first of the next one's and so on; ->> makes each the last. A step is
+    // (<fn> <arg>*) or <fn> alone, for (<fn>)
+    fn expand_thread(&self, ls: &Vec<AST>) -> Result<AST, Box<Error>> {
+        if ls.len() < 2 {
+            return self.error(&ls[0], &format!("{} syntax", ls[0]));
+        }
+        let last = match ls[0].sexpr {
+            SExpr::Atom(ref id) => id.trim_start() == "->>",
+            _ => false,
+        };
+
+        let mut threaded = ls[1].clone();
+        for step in &ls[2..] {
+            let mut call = match step.sexpr {
+                SExpr::Atom(_) => vec![step.clone()],
+                SExpr::List(ref call) if !call.is_empty() => call.clone(),
+                _ => return self.error(step, &format!("{} step syntax", ls[0])),
+            };
+            if last {
+                call.push(threaded);
+            } else {
+                call.insert(1, threaded);
+            }
+            threaded = AST { info: step.info, sexpr: SExpr::List(call) };
+        }
+        return Ok(threaded);
+    }
+}
+
+// (<id> <expr>), a parameter with a default: the name, where it is and the default
+fn defaulted(ast: &AST) -> Option<(&String, Info, &AST)> {
+    match ast.sexpr {
+        SExpr::List(ref pd) if pd.len() == 2 => {
+            match pd[0].sexpr {
+                SExpr::Atom(ref id) if !id.starts_with("#:") => return Some((id, pd[0].info, &pd[1])),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    }
+}
+
+// what (define-record <name> (<field>*)) binds: the constructor, the predicate and an
+// accessor for each field
+pub fn record_names(name: &str, fields: &[AST]) -> Vec<String> {
+    let mut names = vec![format!("make-{}", name), format!("{}?", name)];
+    names.extend(fields.iter().map(|f| format!("{}-{}", name, f)));
+    return names;
+}
+
+fn variant_case(case: &AST) -> Option<&AST> {
+    match case.sexpr {
+        SExpr::List(ref c) => return c.first(),
+        _ => return None,
+    }
+}
+
+// the name and cases of a well-formed (define-variant <name> (<case> <field>*)*)
+fn variant_cases(ls: &[AST]) -> (String, Vec<(String, usize)>) {
+    let cases = ls[2..]
+        .iter()
+        .filter_map(|c| match c.sexpr {
+                        SExpr::List(ref c) => Some((format!("{}", c[0]), c.len() - 1)),
+                        _ => None,
+                    })
+        .collect();
+    return (format!("{}", ls[1]), cases);
+}
+
+fn atom(id: &str, info: Info) -> AST {
+    return AST { info, sexpr: SExpr::Atom(id.to_string()) };
+}
+
+fn lambda(params: Vec<AST>, body: AST) -> AST {
+    let info = body.info;
+    return AST { info, sexpr: SExpr::List(vec![atom(&core("lambda"), info), AST { info, sexpr: SExpr::List(params) }, body]) };
+}
+
+// a value of the record or variant case `name`: its fields after a symbol no program
+// can read or make, a space and the name (see core)
+fn tagged(name: &str, fields: &[AST], info: Info) -> AST {
+    let node = |sexpr| AST { info, sexpr };
+    let mut list = atom("nil", info);
+    for f in fields.iter().rev() {
+        list = node(SExpr::List(vec![atom(&core("cons"), info), f.clone(), list]));
+    }
+    return node(SExpr::List(vec![atom(&core("cons"), info), tag(name, info), list]));
+}
+
+fn tag(name: &str, info: Info) -> AST {
+    return AST { info, sexpr: SExpr::List(vec![atom(&core("quote"), info), atom(&core(name), info)]) };
+}
+
+// whether `v` is a value of the record or variant case `name`
+fn tag_test(v: &str, name: &str, info: Info) -> AST {
+    return AST { info, sexpr: SExpr::List(vec![atom("native:tagged?", info), atom(v, info), tag(name, info)]) };
+}
+
+// raises a type-error carrying `v`
+fn type_error(message: &str, v: &str, info: Info) -> AST {
+    let kind = AST { info, sexpr: SExpr::List(vec![atom(&core("quote"), info), atom("type-error", info)]) };
+    let message = AST { info, sexpr: SExpr::Str(message.to_string()) };
+    return AST { info, sexpr: SExpr::List(vec![atom(&core("error"), info), kind, message, atom(v, info)]) };
+}
+
+fn is_list(ast: &AST) -> bool {
+    match ast.sexpr {
+        SExpr::List(_) => return true,
+        _ => return false,
+    }
+}
+
+fn annotated(ast: &AST) -> bool {
+    match ast.sexpr {
+        SExpr::List(ref ls) => return ls.len() == 3 && ls[1].sexpr == SExpr::Atom(":".into()),
+        _ => return false,
+    }
+}
+
+// a name for the form `name` in code the compiler writes itself, which no binding in
+// the program can shadow since the parser never makes a name with a space. Nor can a
+// program make the symbol, as string->symbol refuses a space, so a quoted core name
+// is a tag only the compiler's own code can write
+fn core(name: &str) -> String {
+    return format!(" {}", name);
+}
+
+// In tail position of a lambda body a branch returns from the lambda itself, so
+// TSEL saves no continuation to JOIN back to; whatever follows it is only the RET
+// the branches already did.
+fn select(t: Code, f: Code, tail: bool) -> CodeOP {
+    if tail {
+        return CodeOP::TSEL(t, f);
+    }
+    return CodeOP::SEL(t, f);
+}
+
+fn branch_end(tail: bool) -> CodeOP {
+    if tail {
+        return CodeOP::RET;
+    }
+    return CodeOP::JOIN;
+}
+
+fn size(ast: &AST) -> usize {
+    match ast.sexpr {
+        SExpr::List(ref ls) => return 1 + ls.iter().map(size).sum::<usize>(),
+        _ => return 1,
+    }
+}
+
+// source code as a cons list, every atom a symbol
+fn datum(ast: &AST) -> Rc<Lisp> {
+    match ast.sexpr {
+        SExpr::Int(n) => return Rc::new(Lisp::Int(n)),
+        SExpr::Str(ref s) => return Rc::new(Lisp::Str(s.clone())),
+        SExpr::Atom(ref id) => return Rc::new(Lisp::Symbol(id.clone())),
+        SExpr::List(ref ls) => return list_datum(ls),
+    }
+}
+
+fn list_datum(ls: &[AST]) -> Rc<Lisp> {
+    return ls.iter().rev().fold(Rc::new(Lisp::Nil), |cdr, a| Rc::new(Lisp::Cons(datum(a), cdr)));
 }
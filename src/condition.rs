@@ -0,0 +1,73 @@
+use data::{Lisp, Condition};
+use diagnostic::Diagnostic;
+
+use std::rc::Rc;
+use std::error::Error;
+
+// Condition kinds form a tree under `condition`; a handler for a kind also catches
+// everything below it. Kinds a program makes up with error are errors.
+
+const PARENTS: &[(&str, &str)] = &[("error", "condition"),
+                                   ("vm-error", "error"),
+                                   ("type-error", "vm-error"),
+                                   ("arity-error", "vm-error"),
+                                   ("unbound-variable", "vm-error"),
+                                   ("arithmetic-error", "vm-error"),
+                                   ("capability-error", "vm-error"),
+                                   ("frozen-error", "vm-error"),
+                                   ("assertion-error", "error"),
+                                   ("contract-violation", "error")];
+
+pub fn parent(kind: &str) -> Option<&'static str> {
+    if kind == "condition" {
+        return None;
+    }
+    match PARENTS.iter().find(|p| p.0 == kind) {
+        Some(p) => return Some(p.1),
+        None => return Some("error"),
+    }
+}
+
+pub fn is_a(kind: &str, ancestor: &str) -> bool {
+    let mut kind = Some(kind);
+    while let Some(k) = kind {
+        if k == ancestor {
+            return true;
+        }
+        kind = parent(k);
+    }
+    return false;
+}
+
+// the kind of a VM error, going by its message
+pub fn classify(message: &str) -> &'static str {
+    let kinds = [("assertion failed", "assertion-error"),
+                 ("wrong number of arguments", "arity-error"),
+                 ("keyword", "arity-error"),
+                 ("unbound", "unbound-variable"),
+                 ("division by zero", "arithmetic-error"),
+                 ("overflow", "arithmetic-error"),
+                 ("shift out of range", "arithmetic-error"),
+                 ("capability", "capability-error"),
+                 ("is frozen", "frozen-error"),
+                 ("expected", "type-error"),
+                 ("cannot compare", "type-error"),
+                 ("must return", "type-error")];
+    match kinds.iter().find(|k| message.contains(k.0)) {
+        Some(k) => return k.1,
+        None => return "vm-error",
+    }
+}
+
+pub fn from_error(e: &(Error + 'static)) -> Rc<Lisp> {
+    let (message, info) = match e.downcast_ref::<Diagnostic>() {
+        Some(d) => (d.message.clone(), d.span),
+        None => (format!("{}", e), None),
+    };
+    return Rc::new(Lisp::Condition(Rc::new(Condition {
+                                               kind: classify(&message).to_string(),
+                                               message,
+                                               payload: Rc::new(Lisp::Nil),
+                                               info,
+                                           })));
+}
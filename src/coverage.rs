@@ -0,0 +1,36 @@
+use data::{Code, Info};
+use sourcemap::{self, SourceMap};
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+
+// Line coverage of a run, for `--coverage`. The machine counts the times it runs each
+// source position; an expression ran as many times as the most run position of the
+// instructions in its ranges of the source map, and a line as many as the most run
+// expression starting on it. Code the JIT runs natively is not counted.
+
+// the times each line with code on it ran, by line
+pub fn lines(code: &Code, map: &SourceMap, hits: &HashMap<Info, usize>) -> BTreeMap<usize, usize> {
+    let mut lines = BTreeMap::new();
+    for r in &map.ranges {
+        let block = match sourcemap::block(code, &r.block) {
+            Some(block) if r.start < r.end && r.end <= block.len() => block,
+            _ => continue,
+        };
+        let n = block[r.start..r.end].iter().map(|c| hits.get(&c.info).map_or(0, |&n| n)).max();
+        let line = lines.entry(r.span[0]).or_insert(0);
+        *line = cmp::max(*line, n.unwrap_or(0));
+    }
+    return lines;
+}
+
+// an lcov tracefile of one source file
+pub fn lcov(file: &str, lines: &BTreeMap<usize, usize>) -> String {
+    let mut s = format!("TN:\nSF:{}\n", file);
+    for (line, n) in lines {
+        s.push_str(&format!("DA:{},{}\n", line, n));
+    }
+    let hit = lines.values().filter(|&&n| n > 0).count();
+    s.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit));
+    return s;
+}
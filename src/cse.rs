@@ -0,0 +1,250 @@
+use data::{AST, SExpr};
+use compiler;
+use types;
+
+use std::ops::Range;
+
+// Common subexpression elimination over the AST. In the body of every let, a pure
+// expression (arithmetic and car/cdr/cons over variables and constants) that is
+// evaluated more than once gets bound to a hidden name first and loaded after that.
+// Only places the body always evaluates are searched, never a branch of an if or the
+// body of a lambda or any other form, so nothing runs that wouldn't have before; what
+// can change is which of two errors is reported first. An expression mentioning a
+// name the body binds anywhere is left alone, as a let may rebind it in between.
+
+const PURE: &[(&str, usize)] = &[("+", 2), ("-", 2), ("car", 1), ("cdr", 1), ("cons", 2)];
+
+pub fn eliminate(ast: &AST) -> AST {
+    let ls = match ast.sexpr {
+        SExpr::List(ref ls) => ls,
+        _ => return ast.clone(),
+    };
+    let mut ls: Vec<AST> = ls.iter().map(eliminate).collect();
+    if ls.len() == 4 && is_atom(&ls[0], "let") {
+        let body = ls.pop().unwrap();
+        ls.push(eliminate_in(body));
+    }
+    return AST {
+               info: ast.info,
+               sexpr: SExpr::List(ls),
+           };
+}
+
+fn eliminate_in(body: AST) -> AST {
+    let mut body = body;
+    let mut lets = vec![];
+    let mut bound = vec![];
+    binders(&body, &mut bound);
+
+    loop {
+        let mut found = vec![];
+        occurrences(&body, &mut found);
+        let mut best: Option<(String, &AST)> = None;
+        for e in &found {
+            let key = format!("{}", e);
+            let repeated = found.iter().filter(|f| format!("{}", f) == key).count() > 1;
+            let longer = match best {
+                Some((ref k, _)) => key.len() > k.len(),
+                None => true,
+            };
+            if repeated && longer && !mentions(e, &bound) {
+                best = Some((key, e));
+            }
+        }
+        let (key, e) = match best {
+            Some((key, e)) => (key, e.clone()),
+            None => break,
+        };
+
+        let name = AST {
+            info: e.info,
+            sexpr: SExpr::Atom(format!(" cse {}:{}", e.info[0], e.info[1])),
+        };
+        body = replace(&body, &key, &name);
+        lets.push((name, e));
+    }
+
+    return lets.into_iter().rev().fold(body, |body, (name, e)| {
+        let head = AST {
+            info: e.info,
+            sexpr: SExpr::Atom("let".into()),
+        };
+        AST {
+            info: e.info,
+            sexpr: SExpr::List(vec![head, name, e, body]),
+        }
+    });
+}
+
+fn is_atom(ast: &AST, id: &str) -> bool {
+    match ast.sexpr {
+        SExpr::Atom(ref a) => return a == id,
+        _ => return false,
+    }
+}
+
+fn pure(ast: &AST) -> bool {
+    let ls = match ast.sexpr {
+        SExpr::List(ref ls) => ls,
+        _ => return false,
+    };
+    let arity = match PURE.iter().find(|&&(op, _)| !ls.is_empty() && is_atom(&ls[0], op)) {
+        Some(&(_, arity)) => arity,
+        None => return false,
+    };
+    return ls.len() == arity + 1 &&
+           ls[1..].iter().all(|a| match a.sexpr {
+                                  SExpr::List(_) => pure(a),
+                                  _ => true,
+                              });
+}
+
+// which elements of a list are evaluated whenever it is: the arguments of calls and
+// pure operations and the condition of an if
+fn evaluated(ls: &[AST]) -> Range<usize> {
+    match ls.first().map(|a| &a.sexpr) {
+        Some(&SExpr::Atom(ref id)) => {
+            match id.as_str() {
+                "if" if ls.len() == 4 => return 1..2,
+                "eq" => return 1..ls.len(),
+                _ if PURE.iter().any(|&(op, _)| op == id) => return 1..ls.len(),
+                _ if compiler::is_keyword(id) => return 0..0,
+                _ => return 1..ls.len(),
+            }
+        }
+        Some(&SExpr::List(_)) => return 0..ls.len(),
+        _ => return 0..0,
+    }
+}
+
+fn occurrences<'a>(ast: &'a AST, found: &mut Vec<&'a AST>) {
+    if pure(ast) {
+        found.push(ast);
+    }
+    if let SExpr::List(ref ls) = ast.sexpr {
+        for a in &ls[evaluated(ls)] {
+            occurrences(a, found);
+        }
+    }
+}
+
+fn replace(ast: &AST, key: &str, name: &AST) -> AST {
+    if pure(ast) && format!("{}", ast) == key {
+        return name.clone();
+    }
+    let ls = match ast.sexpr {
+        SExpr::List(ref ls) => ls,
+        _ => return ast.clone(),
+    };
+    let range = evaluated(ls);
+    let ls = ls.iter()
+        .enumerate()
+        .map(|(i, a)| if range.contains(&i) { replace(a, key, name) } else { a.clone() })
+        .collect();
+    return AST {
+               info: ast.info,
+               sexpr: SExpr::List(ls),
+           };
+}
+
+// every name in a destructuring pattern; for a parameter with a default, more than it
+// binds, which only leaves more alone
+fn pattern_ids(ast: &AST, bound: &mut Vec<String>) {
+    match ast.sexpr {
+        SExpr::Atom(ref id) => bound.push(id.clone()),
+        SExpr::List(ref ls) => ls.iter().for_each(|p| pattern_ids(p, bound)),
+        _ => {}
+    }
+}
+
+fn mentions(ast: &AST, names: &[String]) -> bool {
+    match ast.sexpr {
+        SExpr::Atom(ref id) => return names.contains(id),
+        SExpr::List(ref ls) => return ls.iter().any(|a| mentions(a, names)),
+        _ => return false,
+    }
+}
+
+// every name `ast` binds, wherever it binds it
+fn binders(ast: &AST, bound: &mut Vec<String>) {
+    let ls = match ast.sexpr {
+        SExpr::List(ref ls) => ls,
+        _ => return,
+    };
+    let head = match ls.first().map(|a| &a.sexpr) {
+        Some(&SExpr::Atom(ref head)) => head.as_str(),
+        _ => "",
+    };
+    match (head, ls.get(1).map(|a| &a.sexpr)) {
+        ("let", Some(&SExpr::Atom(ref id))) |
+        ("letrec", Some(&SExpr::Atom(ref id))) |
+        ("define/contract", Some(&SExpr::Atom(ref id))) => bound.push(id.clone()),
+        ("lambda", Some(&SExpr::Atom(ref id))) => bound.push(id.clone()),
+        ("lambda", Some(&SExpr::List(ref params))) => {
+            for p in params {
+                match types::param(p) {
+                    Some((id, _)) => bound.push(id.clone()),
+                    None => pattern_ids(p, bound),
+                }
+            }
+        }
+        ("let", Some(&SExpr::List(ref pattern))) if ls.len() == 4 => {
+            pattern.iter().for_each(|p| pattern_ids(p, bound))
+        }
+        ("let", Some(&SExpr::List(ref bindings))) => {
+            for b in bindings {
+                match b.sexpr {
+                    SExpr::List(ref b) if !b.is_empty() => pattern_ids(&b[0], bound),
+                    _ => {}
+                }
+            }
+        }
+        ("do", Some(&SExpr::List(ref vars))) => {
+            for v in vars {
+                if let SExpr::List(ref v) = v.sexpr {
+                    if let Some(&SExpr::Atom(ref id)) = v.first().map(|a| &a.sexpr) {
+                        bound.push(id.clone());
+                    }
+                }
+            }
+        }
+        ("define-record", Some(&SExpr::Atom(ref name))) if ls.len() > 2 => {
+            if let SExpr::List(ref fields) = ls[2].sexpr {
+                bound.extend(compiler::record_names(name, fields));
+            }
+        }
+        ("define-variant", Some(&SExpr::Atom(ref name))) => {
+            bound.push(format!("{}?", name));
+            for case in &ls[2..] {
+                if let SExpr::List(ref c) = case.sexpr {
+                    if let Some(&SExpr::Atom(ref id)) = c.first().map(|a| &a.sexpr) {
+                        bound.push(id.clone());
+                        bound.push(format!("{}?", id));
+                    }
+                }
+            }
+        }
+        ("match", _) => {
+            for clause in ls.iter().skip(2) {
+                if let SExpr::List(ref c) = clause.sexpr {
+                    if let Some(&SExpr::List(ref p)) = c.first().map(|a| &a.sexpr) {
+                        p.iter().skip(1).for_each(|p| pattern_ids(p, bound));
+                    }
+                }
+            }
+        }
+        ("try", _) => {
+            for clause in ls.iter().skip(2) {
+                if let SExpr::List(ref cl) = clause.sexpr {
+                    if let Some(&SExpr::Atom(ref id)) = cl.get(1).map(|a| &a.sexpr) {
+                        bound.push(id.clone());
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    for a in ls {
+        binders(a, bound);
+    }
+}
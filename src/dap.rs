@@ -0,0 +1,384 @@
+use data::{SECD, DumpOP, Env, Lisp, Info, RunResult};
+use diagnostic::{self, Diagnostic};
+use json::{Json, read_message, write_message};
+use parser::Parser;
+use compiler::Compiler;
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, Read, Write};
+use std::rc::Rc;
+
+// A Debug Adapter Protocol server over stdin/stdout (`secd dap`) driving one machine
+// through the step API. Frames are the machine's own: the next instruction, then
+// the return point of every application on the dump, innermost first.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Mode {
+    Continue,
+    StepIn,
+    Next,
+    StepOut,
+}
+
+// what a request sets off once it has been answered
+enum After {
+    Initialized,
+    Entry,
+    Run(Mode),
+}
+
+pub struct Debugger {
+    vm: Option<SECD>,
+    out: Rc<RefCell<String>>,
+    path: String,
+    breakpoints: HashSet<usize>,
+    stop_on_entry: bool,
+    seq: i64,
+}
+
+fn depth(vm: &SECD) -> usize {
+    return vm.dump.iter().filter(|d| matches!(**d, DumpOP::DumpAP(..))).count();
+}
+
+fn line(vm: &SECD) -> Option<usize> {
+    return vm.code.first().map(|c| c.info[0]);
+}
+
+// bindings the program wrote, leaving out the compiler's hidden ones
+fn env_variables(env: &Env) -> Json {
+    return Json::Arr(env.keys()
+                         .filter(|k| !k.contains(' '))
+                         .map(|k| variable(k, &format!("{}", env[k])))
+                         .collect());
+}
+
+fn stack_variables(stack: &[Rc<Lisp>]) -> Json {
+    return Json::Arr(stack.iter()
+                         .rev()
+                         .enumerate()
+                         .map(|(i, a)| variable(&format!("{}", i), &format!("{}", a)))
+                         .collect());
+}
+
+fn variable(name: &str, value: &str) -> Json {
+    return Json::obj(vec![("name", Json::str(name)),
+                          ("value", Json::str(value)),
+                          ("variablesReference", Json::Num(0.0))]);
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        return Debugger {
+                   vm: None,
+                   out: Rc::new(RefCell::new(String::new())),
+                   path: String::new(),
+                   breakpoints: HashSet::new(),
+                   stop_on_entry: false,
+                   seq: 0,
+               };
+    }
+
+    fn message(&mut self, kind: &str, mut fields: Vec<(&str, Json)>) -> Json {
+        self.seq += 1;
+        fields.insert(0, ("seq", Json::Num(self.seq as f64)));
+        fields.insert(1, ("type", Json::str(kind)));
+        return Json::obj(fields);
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> Json {
+        return self.message("event", vec![("event", Json::str(event)), ("body", body)]);
+    }
+
+    fn output(&mut self, category: &str, text: String) -> Json {
+        return self.event("output",
+                          Json::obj(vec![("category", Json::str(category)), ("output", Json::str(&text))]));
+    }
+
+    fn stopped(&mut self, reason: &str) -> Json {
+        return self.event("stopped",
+                          Json::obj(vec![("reason", Json::str(reason)),
+                                         ("threadId", Json::Num(1.0)),
+                                         ("allThreadsStopped", Json::Bool(true))]));
+    }
+
+    fn launch(&mut self, program: &str) -> Result<(), Box<Error>> {
+        let mut src = String::new();
+        try!(try!(File::open(program)).read_to_string(&mut src));
+        let code = try!(Compiler::new().compile(&try!(Parser::new(&src).parse())));
+        let mut vm = SECD::new(code);
+        vm.debugger = true;
+        self.out = vm.capture();
+        self.vm = Some(vm);
+        self.path = program.to_string();
+        return Ok(());
+    }
+
+    // runs until a stop; gives back the events that explain why it stopped
+    fn run(&mut self, mode: Mode) -> Vec<Json> {
+        let mut events = vec![];
+        let breakpoints = &self.breakpoints;
+        let r = match self.vm {
+            Some(ref mut vm) => {
+                let start_line = line(vm);
+                let start_depth = depth(vm);
+                // nothing has run before the first continue, so a breakpoint on the
+                // first line still counts as reached
+                let mut prev_line = if vm.steps == 0 { None } else { start_line };
+                let mut first = true;
+                loop {
+                    if vm.halted() {
+                        if vm.joining.is_some() || vm.receiving.is_some() {
+                            let msg = "threads need a scheduler, which the debugger does not run";
+                            break Err(From::from(Diagnostic::error("vm", None, msg.to_string())));
+                        }
+                        break Ok(None);
+                    }
+                    let l = line(vm);
+                    let d = depth(vm);
+                    let changed = l != prev_line;
+                    let stop = match mode {
+                        _ if changed && l.is_some_and(|l| breakpoints.contains(&l)) => {
+                            Some("breakpoint")
+                        }
+                        Mode::StepIn if !first && l != start_line => Some("step"),
+                        Mode::Next if !first && (l != start_line && d <= start_depth || d < start_depth) => {
+                            Some("step")
+                        }
+                        Mode::StepOut if d < start_depth => Some("step"),
+                        _ => None,
+                    };
+                    if stop.is_some() {
+                        break Ok(stop);
+                    }
+                    if let Err(e) = vm.step() {
+                        break Err(e);
+                    }
+                    // a break in the program stops at what follows it
+                    if vm.broke {
+                        vm.broke = false;
+                        break Ok(Some("breakpoint"));
+                    }
+                    prev_line = l;
+                    first = false;
+                }
+            }
+            None => Ok(None),
+        };
+
+        let printed = self.out.borrow_mut().split_off(0);
+        if !printed.is_empty() {
+            events.push(self.output("stdout", printed));
+        }
+
+        match r {
+            Ok(Some(reason)) => events.push(self.stopped(reason)),
+            Ok(None) => {
+                let code = match self.vm.as_mut().map(|vm| vm.result()) {
+                    Some(RunResult::Value(a)) => {
+                        events.push(self.output("console", format!("{}\n", a)));
+                        0
+                    }
+                    Some(RunResult::Exit(n)) => n,
+                    Some(RunResult::Yield(a)) => {
+                        events.push(self.output("console", format!("yield outside of a host: {}\n", a)));
+                        0
+                    }
+                    None => 0,
+                };
+                events.push(self.event("exited", Json::obj(vec![("exitCode", Json::Num(code as f64))])));
+                events.push(self.event("terminated", Json::obj(vec![])));
+            }
+            Err(e) => {
+                let d = diagnostic::from_error(&*e);
+                events.push(self.output("stderr", format!("{}\n", d)));
+                events.push(self.event("exited", Json::obj(vec![("exitCode", Json::Num(1.0))])));
+                events.push(self.event("terminated", Json::obj(vec![])));
+            }
+        }
+        return events;
+    }
+
+    fn frame(&self, id: usize, name: &str, info: Info) -> Json {
+        return Json::obj(vec![("id", Json::Num(id as f64)),
+                              ("name", Json::str(name)),
+                              ("source", Json::obj(vec![("path", Json::str(&self.path))])),
+                              ("line", Json::Num(info[0] as f64)),
+                              ("column", Json::Num(info[1] as f64))]);
+    }
+
+    // the environment and stack of every frame, frame i being the i-th return point
+    fn frames(&self) -> Vec<(Info, &Env, &[Rc<Lisp>])> {
+        let vm = match self.vm {
+            Some(ref vm) => vm,
+            None => return vec![],
+        };
+        let mut frames = vec![(vm.code.first().map_or([0, 0], |c| c.info), &vm.env, &vm.stack[vm.base..])];
+        let mut top = vm.base;
+        for d in vm.dump.iter().rev() {
+            if let DumpOP::DumpAP(base, ref env, ref code) = *d {
+                frames.push((code.first().map_or([0, 0], |c| c.info), env, &vm.stack[base..top]));
+                top = base;
+            }
+        }
+        return frames;
+    }
+
+    fn respond(&mut self, req: &Json, body: Result<Json, String>) -> Json {
+        let command = req.get("command").cloned().unwrap_or(Json::Null);
+        let request_seq = req.get("seq").cloned().unwrap_or(Json::Null);
+        match body {
+            Ok(body) => {
+                return self.message("response",
+                                    vec![("request_seq", request_seq),
+                                         ("success", Json::Bool(true)),
+                                         ("command", command),
+                                         ("body", body)])
+            }
+            Err(msg) => {
+                return self.message("response",
+                                    vec![("request_seq", request_seq),
+                                         ("success", Json::Bool(false)),
+                                         ("command", command),
+                                         ("message", Json::str(&msg))])
+            }
+        }
+    }
+
+    // answers one request; the response comes first, then any events it caused
+    pub fn handle(&mut self, req: &Json) -> Vec<Json> {
+        let command = req.get("command").and_then(|c| c.as_str()).unwrap_or("").to_string();
+        let args = req.get("arguments").cloned().unwrap_or(Json::Null);
+        let mut after = None;
+
+        let body = match command.as_str() {
+            "initialize" => {
+                after = Some(After::Initialized);
+                Ok(Json::obj(vec![("supportsConfigurationDoneRequest", Json::Bool(true))]))
+            }
+
+            "launch" => {
+                self.stop_on_entry = args.get("stopOnEntry").and_then(|b| b.as_bool()).unwrap_or(false);
+                match args.get("program").and_then(|p| p.as_str()) {
+                    Some(program) => {
+                        match self.launch(program) {
+                            Ok(()) => Ok(Json::obj(vec![])),
+                            Err(e) => Err(format!("{}", e)),
+                        }
+                    }
+                    None => Err("launch: program is required".to_string()),
+                }
+            }
+
+            "setBreakpoints" => {
+                let lines: Vec<usize> = args.get("breakpoints")
+                    .and_then(|b| b.as_array())
+                    .map(|bs| bs.iter().filter_map(|b| b.get("line").and_then(|l| l.as_i64())).collect::<Vec<_>>())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|l| l as usize)
+                    .collect();
+                self.breakpoints = lines.iter().cloned().collect();
+                let bs = lines.iter()
+                    .map(|&l| Json::obj(vec![("verified", Json::Bool(true)), ("line", Json::Num(l as f64))]))
+                    .collect();
+                Ok(Json::obj(vec![("breakpoints", Json::Arr(bs))]))
+            }
+
+            "configurationDone" => {
+                after = Some(if self.stop_on_entry { After::Entry } else { After::Run(Mode::Continue) });
+                Ok(Json::obj(vec![]))
+            }
+
+            "threads" => {
+                let main = Json::obj(vec![("id", Json::Num(1.0)), ("name", Json::str("main"))]);
+                Ok(Json::obj(vec![("threads", Json::Arr(vec![main]))]))
+            }
+
+            "stackTrace" => {
+                let frames: Vec<Json> = self.frames()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, f)| self.frame(i, if i == 0 { "current" } else { "return" }, f.0))
+                    .collect();
+                let n = frames.len();
+                Ok(Json::obj(vec![("stackFrames", Json::Arr(frames)), ("totalFrames", Json::Num(n as f64))]))
+            }
+
+            "scopes" => {
+                let id = args.get("frameId").and_then(|f| f.as_i64()).unwrap_or(0) as f64;
+                let scope = |name: &str, r: f64| {
+                    Json::obj(vec![("name", Json::str(name)),
+                                   ("variablesReference", Json::Num(r)),
+                                   ("expensive", Json::Bool(false))])
+                };
+                Ok(Json::obj(vec![("scopes",
+                                   Json::Arr(vec![scope("Environment", 2.0 * id + 1.0),
+                                                  scope("Stack", 2.0 * id + 2.0)]))]))
+            }
+
+            "variables" => {
+                let r = args.get("variablesReference").and_then(|r| r.as_i64()).unwrap_or(0);
+                let frames = self.frames();
+                let vars = match frames.get(((r - 1) / 2) as usize) {
+                    Some(&(_, env, _)) if r > 0 && r % 2 == 1 => env_variables(env),
+                    Some(&(_, _, stack)) if r > 0 => stack_variables(stack),
+                    _ => Json::Arr(vec![]),
+                };
+                Ok(Json::obj(vec![("variables", vars)]))
+            }
+
+            "continue" => {
+                after = Some(After::Run(Mode::Continue));
+                Ok(Json::obj(vec![("allThreadsContinued", Json::Bool(true))]))
+            }
+
+            "next" => {
+                after = Some(After::Run(Mode::Next));
+                Ok(Json::obj(vec![]))
+            }
+
+            "stepIn" => {
+                after = Some(After::Run(Mode::StepIn));
+                Ok(Json::obj(vec![]))
+            }
+
+            "stepOut" => {
+                after = Some(After::Run(Mode::StepOut));
+                Ok(Json::obj(vec![]))
+            }
+
+            "disconnect" | "terminate" => {
+                self.vm = None;
+                Ok(Json::obj(vec![]))
+            }
+
+            _ => Err(format!("unsupported request '{}'", command)),
+        };
+
+        let mut r = vec![self.respond(req, body)];
+        match after {
+            Some(After::Initialized) => r.push(self.event("initialized", Json::obj(vec![]))),
+            Some(After::Entry) => r.push(self.stopped("entry")),
+            Some(After::Run(mode)) => r.extend(self.run(mode)),
+            None => {}
+        }
+        return r;
+    }
+}
+
+// runs until the client disconnects or closes the stream
+pub fn serve<R: BufRead, W: Write>(mut input: R, mut output: W) -> Result<(), Box<Error>> {
+    let mut debugger = Debugger::new();
+    while let Some(req) = try!(read_message(&mut input)) {
+        for msg in debugger.handle(&req) {
+            try!(write_message(&mut output, &msg));
+        }
+        if req.get("command").and_then(|c| c.as_str()) == Some("disconnect") {
+            break;
+        }
+    }
+    return Ok(());
+}
@@ -1,32 +1,327 @@
 use std::fmt;
-use std::rc::Rc;
-use std::collections::HashMap;
+use std::mem;
+use std::ptr;
+use std::rc::{Rc, Weak};
+use std::hash::{Hash, Hasher};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::cell::RefCell;
+use std::ops::{Deref, Range};
+use std::time::Instant;
+use std::io::BufReader;
+use std::net::{TcpStream, TcpListener};
+use smallvec::SmallVec;
+use diagnostic::Diagnostic;
 
 #[derive(Debug, PartialEq)]
 pub struct SECD {
+    // one stack shared by all the calls in progress; the current one's starts at base
     pub stack: Stack,
+    pub base: usize,
+    pub consts: Pool,
+    // the pools of the values restore read back, which CONSTS leaves alone; their
+    // closures load from it at vm::RESTORED and up
+    pub restored: Pool,
     pub code: Code,
     pub env: Env,
     pub dump: Dump,
+    pub started: Instant,
+    pub exit: Option<i32>,
+    pub yielded: Option<Rc<Lisp>>,
+    // what the host gave for the native call the machine is about to make, see SECD::effects
+    pub answer: Option<Rc<Lisp>>,
+    pub spawned: Vec<(usize, SECD)>,
+    pub joining: Option<usize>,
+    pub receiving: Option<Queue>,
+    pub capabilities: Capabilities,
+    // shared with the threads the machine spawns, which record to and replay from the
+    // same log and draw from the same generator
+    pub replay: Rc<RefCell<Replay>>,
+    pub rng: Rc<::std::cell::Cell<u64>>,
+    pub output: Output,
+    pub steps: usize,
+    pub fuel: Option<usize>,
+    // the most values the machine may allocate, see SECD::limit_memory
+    pub memory: Option<usize>,
+    // what the program's test forms found, in the order they ran; spawned threads add
+    // theirs to their parent's
+    pub tests: Rc<RefCell<Vec<TestResult>>>,
+    pub coverage: Option<Coverage>,
+    // spans of the calls made, kept only when asked for with --trace
+    pub trace: Option<Trace>,
+    // the steps one call may take, kept only when asked for with SECD::limit_calls
+    pub budget: Option<Budget>,
+    // counted only when asked for with keep_stats
+    pub counters: Option<Rc<RefCell<Stats>>>,
+    // the closures trace has marked, by the lambda they were made from, and their names
+    pub traced: Vec<(Rc<ProcInfo>, String)>,
+    // whether a debugger drives the machine, which break then pauses
+    pub debugger: bool,
+    // set by a break that has paused the machine, for the debugger to take
+    pub broke: bool,
+    // what PRIM runs; the compiler that made the code must have used the same registry
+    pub primitives: Rc<::vm::Primitives>,
+    #[cfg(feature = "jit")]
+    pub jit: Option<Rc<RefCell<::jit::Jit>>>,
+    #[cfg(feature = "threaded")]
+    pub threaded: ::vm::Threaded,
+}
+
+// where puts and time write; spawned threads share their parent's sink
+#[derive(Debug, PartialEq, Clone)]
+pub enum Output {
+    Stdout,
+    Buffer(Rc<RefCell<String>>),
+    Null,
+    // queued for SECD::effects to hand to the host
+    Effects(Rc<RefCell<VecDeque<Effect>>>),
+}
+
+// what a machine driven by SECD::effects asks of its host
+#[derive(Debug, PartialEq, Clone)]
+pub enum Effect {
+    // the value puts printed, or the line time and help did as a string
+    Puts(Rc<Lisp>),
+    // the next instruction is about to consult the world, by its name in the language and
+    // with its arguments; answering it stands in for the world
+    NativeCall(String, Vec<Rc<Lisp>>),
+    Yield(Rc<Lisp>),
+}
+
+// one (test "name" expected actual); the values are printed so the results can leave
+// the machine's thread
+#[derive(Debug, PartialEq, Clone)]
+pub struct TestResult {
+    pub name: String,
+    pub passed: bool,
+    pub info: Info,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Replay {
+    Off,
+    Record(Vec<Rc<Lisp>>),
+    Replay(Vec<Rc<Lisp>>, usize),
+}
+
+// what effectful primitives a machine may use; everything else is pure
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Capabilities {
+    pub filesystem: bool,
+    pub process: bool,
+    pub network: bool,
+    pub clock: bool,
+    pub env_vars: bool,
+    // looking at the machine itself, secd-stack and the like
+    pub debug: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RunResult {
+    Value(Rc<Lisp>),
+    Exit(i32),
+    Yield(Rc<Lisp>),
 }
 
 pub type Stack = Vec<Rc<Lisp>>;
+// how many times each source position was run, counted while a machine and the threads
+// it spawns run with one; instructions of one position in a row run it once
+pub type Coverage = Rc<RefCell<Hits>>;
+
+// what a machine and the threads it spawns did, for SECD::stats
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Stats {
+    // values made by type; nil, the booleans and small ints are shared, never made
+    pub allocated: BTreeMap<&'static str, usize>,
+    pub peak_stack: usize,
+    pub peak_dump: usize,
+    // environments copied, by closures made and calls
+    pub env_clones: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let allocated: Vec<String> = self.allocated.iter().map(|(t, n)| format!("{} {}", t, n)).collect();
+        try!(writeln!(f, "allocated: {}", allocated.join(", ")));
+        try!(writeln!(f, "peak stack: {}", self.peak_stack));
+        try!(writeln!(f, "peak dump: {}", self.peak_dump));
+        return writeln!(f, "env clones: {}", self.env_clones);
+    }
+}
+
+// a machine's part of a trace: the events all the threads add to, the thread it is and
+// the calls it has in progress, innermost last
+#[derive(Debug, PartialEq, Clone)]
+pub struct Trace {
+    pub events: Rc<RefCell<Vec<TraceEvent>>>,
+    pub started: Instant,
+    pub tid: usize,
+    pub open: Vec<String>,
+}
+
+// a call beginning or ending, `micros` after the trace started
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceEvent {
+    pub name: String,
+    pub begin: bool,
+    pub tid: usize,
+    pub micros: u64,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        return Trace {
+                   events: Rc::new(RefCell::new(vec![])),
+                   started: Instant::now(),
+                   tid: 0,
+                   open: vec![],
+               };
+    }
+
+    pub fn begin(&mut self, name: String) {
+        self.push(name.clone(), true);
+        self.open.push(name);
+    }
+
+    pub fn end(&mut self) {
+        if let Some(name) = self.open.pop() {
+            self.push(name, false);
+        }
+    }
+
+    fn push(&self, name: String, begin: bool) {
+        self.events
+            .borrow_mut()
+            .push(TraceEvent {
+                      name,
+                      begin,
+                      tid: self.tid,
+                      micros: self.started.elapsed().as_micros() as u64,
+                  });
+    }
+}
+
+// how many steps a call may take before it is reported, as a warning or, when `fatal`,
+// an error that stops the machine; the steps count those of the calls it makes and of
+// the calls replacing it by tail calls
+#[derive(Debug, PartialEq, Clone)]
+pub struct Budget {
+    pub steps: usize,
+    pub fatal: bool,
+    // shared by the threads the machine spawns, as are the lambdas they are about, so
+    // each is reported once
+    pub warnings: Rc<RefCell<Vec<Diagnostic>>>,
+    pub reported: Rc<RefCell<Vec<Info>>>,
+    // the name each lambda, by where its body starts, was first bound to, noted as it is
+    // bound so that a call need not look for it
+    pub names: Rc<RefCell<HashMap<Info, String>>>,
+    // the calls in progress, innermost last: where the lambda's body starts, where it was
+    // called from and the step it began at
+    pub open: Vec<(Info, Info, usize)>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Hits {
+    pub last: Option<Info>,
+    pub counts: HashMap<Info, usize>,
+}
+// a program's constants; its code starts by installing them with CONSTS
+pub type Pool = Rc<Vec<Rc<Lisp>>>;
+
+// the constants `code` installs, for reading a program without running it
+pub fn pool(code: &Code) -> &[Rc<Lisp>] {
+    match code.iter().take(2).find(|c| !matches!(c.op, CodeOP::HEADER(_))).map(|c| &c.op) {
+        Some(&CodeOP::CONSTS(ref pool)) => return pool,
+        _ => return &[],
+    }
+}
+
+// what a program needs of the machine that runs it: the version of the instruction set
+// it was compiled for and the groups of instructions beyond the core it uses, as
+// vm::feature names them. A program's code starts with it as HEADER
+#[derive(Debug, PartialEq, Clone)]
+pub struct Header {
+    pub version: u32,
+    pub features: Vec<String>,
+}
+
+pub fn header(code: &Code) -> Option<&Header> {
+    match code.first().map(|c| &c.op) {
+        Some(&CodeOP::HEADER(ref header)) => return Some(header),
+        _ => return None,
+    }
+}
+// the stack's length and the base of the call it is in
+pub type Mark = [usize; 2];
+// argument lists; most calls pass few enough to stay off the heap
+pub type Args = SmallVec<[Rc<Lisp>; 4]>;
 pub type Code = Vec<CodeOPInfo>;
-pub type Env = HashMap<String, Rc<Lisp>>;
+pub type Env = BTreeMap<String, Rc<Lisp>>;
 pub type Dump = Vec<DumpOP>;
+// an index into a vm::Primitives, the builtins of vm::PRIMITIVES first
+pub type PrimId = usize;
+// channels only connect green threads, which all live on one OS thread, so Rc is enough
+pub type Queue = Rc<Mutable<VecDeque<Rc<Lisp>>>>;
+pub type PortRef = Rc<RefCell<Port>>;
+// the value a parameter made by make-parameter currently has, or the state of a promise
+pub type Cell = Rc<Mutable<Rc<Lisp>>>;
+
+// what a value that can change holds; once freeze has marked it, the instructions
+// that would change it raise instead. It borrows like the RefCell it wraps
+#[derive(Debug, PartialEq)]
+pub struct Mutable<T> {
+    value: RefCell<T>,
+    frozen: ::std::cell::Cell<bool>,
+}
+
+impl<T> Mutable<T> {
+    pub fn new(value: T) -> Mutable<T> {
+        return Mutable {
+                   value: RefCell::new(value),
+                   frozen: ::std::cell::Cell::new(false),
+               };
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        return self.frozen.get();
+    }
+
+    pub fn freeze(&self) {
+        self.frozen.set(true);
+    }
+}
+
+impl<T> Deref for Mutable<T> {
+    type Target = RefCell<T>;
+
+    fn deref(&self) -> &RefCell<T> {
+        return &self.value;
+    }
+}
+
+// an OS resource a program holds; closing one keeps the value, so a later use can say so
+#[derive(Debug)]
+pub enum Port {
+    Stream(BufReader<TcpStream>),
+    Listener(TcpListener),
+    Closed,
+}
 
 pub type Info = [usize; 2];
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct AST {
     pub info: Info,
     pub sexpr: SExpr,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum SExpr {
     Atom(String),
     Int(i32),
+    Str(String),
     List(Vec<AST>),
 }
 
@@ -40,38 +335,217 @@ pub struct CodeOPInfo {
 pub enum CodeOP {
     LET(String),
     LD(String),
-    LDC(Rc<Lisp>),
-    LDF(Vec<String>, Code),
+    // the constant at this index of the program's pool
+    LDC(usize),
+    LDF(Vec<String>, Code, Rc<ProcInfo>),
     SEL(Code, Code),
     JOIN,
     RET,
     AP,
     RAP,
+    TAP,
+    TRAP,
     ARGS(usize),
     PUTS,
+    POP,
     EQ,
     ADD,
     SUB,
+    CURTIME,
+    CLOCK,
+    TIME,
+    ASSERT(Info, String),
+    // compares the actual value on top of the stack with the expected one below it as
+    // the test of this name, leaving whether they are equal
+    TEST(String),
+    EXIT,
+    YIELD,
+    SPAWN,
+    TJOIN,
+    RANDOM,
+    CHAN,
+    SEND,
+    RECV,
+    GETENV,
+    SYSTEM,
+    PROCESS,
     CONS,
     CAR,
     CDR,
+    OUTSTR,
+    TCPCONNECT,
+    TCPLISTEN,
+    TCPACCEPT,
+    TCPREAD,
+    TCPWRITE,
+    TCPCLOSE,
+    HTTPGET,
+    DATENOW,
+    DATE2STR,
+    STR2DATE,
+    TRY(Code, Vec<Handler>),
+    ENDTRY,
+    MKCOND,
+    RAISE,
+    PROTECT(Code, Code),
+    ENDPROTECT,
+    RERAISE,
+    MKPARAM,
+    DEREF,
+    PARAMBIND(usize),
+    PARAMRESTORE,
+    HELP(String),
+    SECDSTACK,
+    SECDENV,
+    SECDWHERE,
+    TSEL(Code, Code),
+    PRIM(PrimId, usize),
+    CONSTS(Pool),
+    // hands the value a callback returned to the primitive waiting on the dump
+    RESUME,
+    // checks the machine can run the program before anything else does
+    HEADER(Header),
+}
+
+// one clause of a try: conditions of `kind` or below it run `code` with the
+// condition bound to `id`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Handler {
+    pub kind: String,
+    pub id: String,
+    pub code: Code,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum DumpOP {
-    DumpAP(Stack, Env, Code),
+    // the caller's base, environment and code
+    DumpAP(usize, Env, Code),
     DumpSEL(Code),
+    // the handlers of a try and the registers to restore when one of them runs
+    DumpTRY(Vec<Handler>, Mark, Env, Code),
+    // the cleanup of an unwind-protect and the same registers
+    DumpPROTECT(Code, Mark, Env, Code),
+    // a primitive in the middle of calling back into Lisp and the code after it
+    DumpCALLBACK(Callback, Code),
 }
 
+// where a primitive that calls closures is between calls, kept on the dump rather than
+// the Rust stack so that callbacks nest as deep as calls do and may suspend
 #[derive(Debug, PartialEq)]
+pub enum Callback {
+    // map and filter: the closure, the list, the index of the element after the one it
+    // was called on and what is kept so far
+    Map(Rc<Lisp>, Vec<Rc<Lisp>>, usize, Vec<Rc<Lisp>>),
+    Filter(Rc<Lisp>, Vec<Rc<Lisp>>, usize, Vec<Rc<Lisp>>),
+    // foldl and foldr: the closure, the elements in the order they are folded, the index
+    // of the next one and whether the accumulator comes second
+    Fold(Rc<Lisp>, Vec<Rc<Lisp>>, usize, bool),
+    SortBy(Rc<Lisp>, Merge),
+    // with-output-to-string: the output to go back to and the thunk's buffer
+    Output(Output, Rc<RefCell<String>>),
+    // force: the cell of the promise whose thunk was called
+    Force(Cell),
+    // a call of a traced closure: its name and how many traced calls it is inside
+    Trace(String, usize),
+}
+
+// a bottom-up merge sort paused at a comparison of left[i] with right[j]: each pass
+// merges the runs of `runs` in pairs into `next`
+#[derive(Debug, PartialEq, Default)]
+pub struct Merge {
+    pub runs: VecDeque<Vec<Rc<Lisp>>>,
+    pub next: Vec<Vec<Rc<Lisp>>>,
+    pub left: Vec<Rc<Lisp>>,
+    pub right: Vec<Rc<Lisp>>,
+    pub i: usize,
+    pub j: usize,
+    pub merged: Vec<Rc<Lisp>>,
+}
+
+// what a closure carries besides its code and environment; `doc` is the string a
+// lambda body may start with, `source` the lambda form when the compiler retains it
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ProcInfo {
+    pub doc: Option<String>,
+    pub source: Option<Rc<Lisp>>,
+}
+
+// what error and a failing instruction raise; `kind` places it in the hierarchy in
+// condition.rs and `info` is where it was raised
+#[derive(Debug, PartialEq, Hash)]
+pub struct Condition {
+    pub kind: String,
+    pub message: String,
+    pub payload: Rc<Lisp>,
+    pub info: Option<Info>,
+}
+
+// equality is structural except for closures, channels, ports, cells and weak references,
+// which are equal only to themselves; Hash agrees with it so values can key hash tables
+#[derive(Debug)]
 pub enum Lisp {
     Nil,
     False,
     True,
     Int(i32),
-    List(Vec<Rc<Lisp>>),
-    Closure(Vec<String>, Code, Env),
+    Str(String),
+    Symbol(String),
+    List(Args),
+    Closure(Vec<String>, Code, Env, Rc<ProcInfo>),
     Cons(Rc<Lisp>, Rc<Lisp>),
+    Thread(usize),
+    Chan(Queue),
+    Port(PortRef),
+    Condition(Rc<Condition>),
+    Cell(Cell),
+    // made by make-weak-ref; it doesn't keep what it refers to alive
+    Weak(Weak<Lisp>),
+}
+
+impl Capabilities {
+    pub fn none() -> Capabilities {
+        return Capabilities {
+                   filesystem: false,
+                   process: false,
+                   network: false,
+                   clock: false,
+                   env_vars: false,
+                   debug: false,
+               };
+    }
+
+    // the capabilities granted, by the names --allow takes, as cond-expand features
+    pub fn features(&self) -> Vec<&'static str> {
+        let granted = [("filesystem", self.filesystem),
+                       ("process", self.process),
+                       ("network", self.network),
+                       ("clock", self.clock),
+                       ("env-vars", self.env_vars),
+                       ("debug", self.debug)];
+        return granted.iter().filter(|c| c.1).map(|c| c.0).collect();
+    }
+
+    pub fn all() -> Capabilities {
+        return Capabilities {
+                   filesystem: true,
+                   process: true,
+                   network: true,
+                   clock: true,
+                   env_vars: true,
+                   debug: true,
+               };
+    }
+}
+
+// reading the clock and the environment is allowed unless taken away
+impl Default for Capabilities {
+    fn default() -> Capabilities {
+        return Capabilities {
+                   clock: true,
+                   env_vars: true,
+                   ..Capabilities::none()
+               };
+    }
 }
 
 impl fmt::Display for AST {
@@ -79,6 +553,7 @@ impl fmt::Display for AST {
         match self.sexpr {
             SExpr::Atom(ref id) => write!(f, "{}", id),
             SExpr::Int(ref n) => write!(f, "{}", n),
+            SExpr::Str(ref s) => write!(f, "{:?}", s),
             SExpr::List(ref list) => {
                 write!(f, "(").unwrap();
                 for i in 0..list.len() {
@@ -100,6 +575,273 @@ impl PartialEq for CodeOPInfo {
     }
 }
 
+impl PartialEq for Lisp {
+    fn eq(&self, a: &Lisp) -> bool {
+        match (self, a) {
+            (&Lisp::Nil, &Lisp::Nil) => return true,
+            (&Lisp::False, &Lisp::False) => return true,
+            (&Lisp::True, &Lisp::True) => return true,
+            (&Lisp::Int(n), &Lisp::Int(m)) => return n == m,
+            (&Lisp::Str(ref s), &Lisp::Str(ref t)) => return s == t,
+            (&Lisp::Symbol(ref s), &Lisp::Symbol(ref t)) => return s == t,
+            (&Lisp::List(ref l), &Lisp::List(ref m)) => return l == m,
+            (&Lisp::Cons(..), &Lisp::Cons(..)) => {
+                // with a stack of the pairs left to compare, so neither a long chain nor
+                // a deep nest of cars nests a call per cons
+                let mut pairs = vec![(self, a)];
+                while let Some(pair) = pairs.pop() {
+                    match pair {
+                        (&Lisp::Cons(ref car, ref cdr), &Lisp::Cons(ref car2, ref cdr2)) => {
+                            if !Rc::ptr_eq(cdr, cdr2) {
+                                pairs.push((cdr, cdr2));
+                            }
+                            if !Rc::ptr_eq(car, car2) {
+                                pairs.push((car, car2));
+                            }
+                        }
+                        (a, b) => {
+                            if a != b {
+                                return false;
+                            }
+                        }
+                    }
+                }
+                return true;
+            }
+            (&Lisp::Thread(n), &Lisp::Thread(m)) => return n == m,
+            (&Lisp::Chan(ref q), &Lisp::Chan(ref r)) => return Rc::ptr_eq(q, r),
+            (&Lisp::Port(ref p), &Lisp::Port(ref q)) => return Rc::ptr_eq(p, q),
+            (&Lisp::Condition(ref c), &Lisp::Condition(ref d)) => return c == d,
+            (&Lisp::Cell(ref c), &Lisp::Cell(ref d)) => return Rc::ptr_eq(c, d),
+            (&Lisp::Weak(ref w), &Lisp::Weak(ref v)) => return w.ptr_eq(v),
+            (&Lisp::Closure(..), &Lisp::Closure(..)) => return ptr::eq(self, a),
+            _ => return false,
+        }
+    }
+}
+
+impl Eq for Lisp {}
+
+// dropping a long cons chain, or a deep nest of them, would drop each part inside the
+// drop of the one holding it and run out of stack; instead the parts nothing else holds
+// are unlinked onto a stack and dropped in turn
+impl Drop for Lisp {
+    fn drop(&mut self) {
+        let mut parts = vec![];
+        unlink(self, &mut parts);
+        while let Some(a) = parts.pop() {
+            if let Ok(mut a) = Rc::try_unwrap(a) {
+                unlink(&mut a, &mut parts);
+            }
+        }
+    }
+}
+
+fn unlink(a: &mut Lisp, parts: &mut Vec<Rc<Lisp>>) {
+    match *a {
+        Lisp::Cons(ref mut car, ref mut cdr) => {
+            take(car, parts);
+            take(cdr, parts);
+        }
+        Lisp::List(ref mut ls) => ls.drain(..).for_each(|mut x| take(&mut x, parts)),
+        _ => {}
+    }
+}
+
+fn take(a: &mut Rc<Lisp>, parts: &mut Vec<Rc<Lisp>>) {
+    if Rc::strong_count(a) == 1 && matches!(**a, Lisp::Cons(..) | Lisp::List(_)) {
+        let nil = SHARED.try_with(|s| s.nil.clone()).unwrap_or_else(|_| Rc::new(Lisp::Nil));
+        parts.push(mem::replace(a, nil));
+    }
+}
+
+// the ints made once and shared, along with nil and the booleans, so arithmetic on
+// them clones an Rc instead of allocating one
+pub const SMALL_INTS: Range<i32> = -128..1024;
+
+struct Shared {
+    nil: Rc<Lisp>,
+    true_: Rc<Lisp>,
+    false_: Rc<Lisp>,
+    ints: Vec<Rc<Lisp>>,
+}
+
+thread_local! {
+    static SHARED: Shared = Shared {
+        nil: Rc::new(Lisp::Nil),
+        true_: Rc::new(Lisp::True),
+        false_: Rc::new(Lisp::False),
+        ints: SMALL_INTS.map(|n| Rc::new(Lisp::Int(n))).collect(),
+    };
+}
+
+impl Lisp {
+    pub fn nil() -> Rc<Lisp> {
+        return SHARED.with(|s| s.nil.clone());
+    }
+
+    pub fn bool(b: bool) -> Rc<Lisp> {
+        return SHARED.with(|s| if b { s.true_.clone() } else { s.false_.clone() });
+    }
+
+    pub fn int(n: i32) -> Rc<Lisp> {
+        if !SMALL_INTS.contains(&n) {
+            return Rc::new(Lisp::Int(n));
+        }
+        return SHARED.with(|s| s.ints[(n - SMALL_INTS.start) as usize].clone());
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Lisp::Nil => return "nil",
+            Lisp::False | Lisp::True => return "bool",
+            Lisp::Int(_) => return "int",
+            Lisp::Str(_) => return "string",
+            Lisp::Symbol(_) => return "symbol",
+            Lisp::List(_) => return "list",
+            Lisp::Closure(..) => return "closure",
+            Lisp::Cons(..) => return "cons",
+            Lisp::Thread(_) => return "thread",
+            Lisp::Chan(_) => return "chan",
+            Lisp::Port(_) => return "port",
+            Lisp::Condition(_) => return "condition",
+            Lisp::Cell(_) => return "cell",
+            Lisp::Weak(_) => return "weak",
+        }
+    }
+
+    // only these are ordered: ints before strings before symbols
+    fn rank(&self) -> Option<u8> {
+        match *self {
+            Lisp::Int(_) => return Some(0),
+            Lisp::Str(_) => return Some(1),
+            Lisp::Symbol(_) => return Some(2),
+            _ => return None,
+        }
+    }
+
+    // the value printed as Display does, cut off with ... after `limit` bytes; printing
+    // stops there, so a cell that holds itself is safe to show
+    pub fn show(&self, limit: usize) -> String {
+        let mut out = Bounded {
+            s: String::new(),
+            limit,
+        };
+        if fmt::write(&mut out, format_args!("{}", self)).is_err() {
+            out.s.push_str("...");
+        }
+        return out.s;
+    }
+}
+
+struct Bounded {
+    s: String,
+    limit: usize,
+}
+
+impl fmt::Write for Bounded {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.s.len() + s.len() <= self.limit {
+            self.s.push_str(s);
+            return Ok(());
+        }
+        let mut end = self.limit - self.s.len();
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.s.push_str(&s[..end]);
+        return Err(fmt::Error);
+    }
+}
+
+// a total order over ints, strings and symbols; any other value is only equal to
+// what it is eq to and has no order
+impl PartialOrd for Lisp {
+    // only ints, strings and symbols are ordered, a value of another type not even
+    // against itself
+    fn partial_cmp(&self, a: &Lisp) -> Option<Ordering> {
+        match (self, a) {
+            (&Lisp::Int(n), &Lisp::Int(m)) => return n.partial_cmp(&m),
+            (&Lisp::Str(ref s), &Lisp::Str(ref t)) => return s.partial_cmp(t),
+            (&Lisp::Symbol(ref s), &Lisp::Symbol(ref t)) => return s.partial_cmp(t),
+            _ => {
+                match (self.rank(), a.rank()) {
+                    (Some(r), Some(s)) => return r.partial_cmp(&s),
+                    _ => return None,
+                }
+            }
+        }
+    }
+}
+
+impl Hash for Lisp {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Lisp::Nil => 0.hash(state),
+            Lisp::False => 1.hash(state),
+            Lisp::True => 2.hash(state),
+            Lisp::Int(n) => {
+                3.hash(state);
+                n.hash(state);
+            }
+            Lisp::Str(ref s) => {
+                4.hash(state);
+                s.hash(state);
+            }
+            Lisp::Symbol(ref s) => {
+                5.hash(state);
+                s.hash(state);
+            }
+            Lisp::List(ref l) => {
+                6.hash(state);
+                l.hash(state);
+            }
+            Lisp::Closure(..) => {
+                7.hash(state);
+                (self as *const Lisp as usize).hash(state);
+            }
+            Lisp::Cons(..) => {
+                // in the order a call per part would, with a stack of those left
+                let mut todo = vec![self];
+                while let Some(a) = todo.pop() {
+                    match *a {
+                        Lisp::Cons(ref car, ref cdr) => {
+                            8.hash(state);
+                            todo.push(cdr);
+                            todo.push(car);
+                        }
+                        ref a => a.hash(state),
+                    }
+                }
+            }
+            Lisp::Thread(n) => {
+                9.hash(state);
+                n.hash(state);
+            }
+            Lisp::Chan(ref q) => {
+                10.hash(state);
+                (Rc::as_ptr(q) as usize).hash(state);
+            }
+            Lisp::Port(ref p) => {
+                11.hash(state);
+                (Rc::as_ptr(p) as usize).hash(state);
+            }
+            Lisp::Condition(ref c) => {
+                12.hash(state);
+                c.hash(state);
+            }
+            Lisp::Cell(ref c) => {
+                13.hash(state);
+                (Rc::as_ptr(c) as usize).hash(state);
+            }
+            Lisp::Weak(ref w) => {
+                14.hash(state);
+                (w.as_ptr() as usize).hash(state);
+            }
+        }
+    }
+}
+
 impl fmt::Display for Lisp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -107,9 +849,56 @@ impl fmt::Display for Lisp {
             &Lisp::True => write!(f, "true"),
             &Lisp::False => write!(f, "false"),
             &Lisp::Int(n) => write!(f, "{}", n),
-            &Lisp::Cons(ref car, ref cdr) => write!(f, "(cons {} {})", car, cdr),
+            &Lisp::Str(ref s) => write!(f, "{}", s),
+            &Lisp::Symbol(ref s) => write!(f, "{}", s),
+            &Lisp::Cons(..) => {
+                // with a stack of what is left to write, as a nest of cars is as deep as
+                // a chain of cdrs is long
+                let mut todo = vec![Ok(self)];
+                while let Some(next) = todo.pop() {
+                    match next {
+                        Ok(&Lisp::Cons(ref car, ref cdr)) => {
+                            try!(write!(f, "(cons "));
+                            todo.push(Err(")"));
+                            todo.push(Ok(cdr));
+                            todo.push(Err(" "));
+                            todo.push(Ok(car));
+                        }
+                        Ok(a) => try!(write!(f, "{}", a)),
+                        Err(s) => try!(write!(f, "{}", s)),
+                    }
+                }
+                Ok(())
+            }
             &Lisp::List(ref ls) => write!(f, "(list {:?})", ls),
-            &Lisp::Closure(ref args, _, _) => write!(f, "(lambda {:?} Code)", args),
+            &Lisp::Closure(ref args, _, _, _) => write!(f, "(lambda {:?} Code)", args),
+            &Lisp::Thread(id) => write!(f, "(thread {})", id),
+            &Lisp::Chan(ref q) => write!(f, "(chan {})", q.borrow().len()),
+            &Lisp::Port(ref p) => {
+                match *p.borrow() {
+                    Port::Stream(ref s) => {
+                        match s.get_ref().peer_addr() {
+                            Ok(addr) => write!(f, "(port {})", addr),
+                            Err(_) => write!(f, "(port)"),
+                        }
+                    }
+                    Port::Listener(ref l) => {
+                        match l.local_addr() {
+                            Ok(addr) => write!(f, "(listener {})", addr),
+                            Err(_) => write!(f, "(listener)"),
+                        }
+                    }
+                    Port::Closed => write!(f, "(port closed)"),
+                }
+            }
+            &Lisp::Condition(ref c) => write!(f, "(condition {} {})", c.kind, c.message),
+            &Lisp::Cell(ref c) => write!(f, "(cell {})", c.borrow()),
+            &Lisp::Weak(ref w) => {
+                match w.upgrade() {
+                    Some(a) => write!(f, "(weak {})", a),
+                    None => write!(f, "(weak)"),
+                }
+            }
         }
     }
 }
@@ -0,0 +1,159 @@
+use std::fmt::Write;
+
+// Calendar dates in UTC; programs see one as the list (year month day hour minute second).
+// The day arithmetic is Howard Hinnant's days_from_civil and civil_from_days.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+fn leap(year: i32) -> bool {
+    return year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if leap(year) => return 29,
+        2 => return 28,
+        4 | 6 | 9 | 11 => return 30,
+        _ => return 31,
+    }
+}
+
+impl Date {
+    pub fn from_unix(t: i64) -> Date {
+        let days = t.div_euclid(86400);
+        let secs = t.rem_euclid(86400) as u32;
+
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as i32;
+
+        return Date {
+                   year,
+                   month,
+                   day,
+                   hour: secs / 3600,
+                   minute: secs / 60 % 60,
+                   second: secs % 60,
+               };
+    }
+
+    pub fn to_unix(&self) -> i64 {
+        let y = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let m = self.month as i64;
+        let era = y.div_euclid(400);
+        let yoe = y.rem_euclid(400);
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        return days * 86400 + (self.hour * 3600 + self.minute * 60 + self.second) as i64;
+    }
+
+    pub fn is_valid(&self) -> bool {
+        return (1..=12).contains(&self.month) && self.day >= 1 &&
+               self.day <= days_in_month(self.year, self.month) && self.hour < 24 &&
+               self.minute < 60 && self.second < 60;
+    }
+
+    // %Y, %m, %d, %H, %M and %S as strftime has them, and %% for a percent sign
+    pub fn format(&self, fmt: &str) -> Result<String, String> {
+        let mut s = String::new();
+        let mut chars = fmt.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                s.push(c);
+                continue;
+            }
+            let _ = match chars.next() {
+                Some('Y') => write!(s, "{:04}", self.year),
+                Some('m') => write!(s, "{:02}", self.month),
+                Some('d') => write!(s, "{:02}", self.day),
+                Some('H') => write!(s, "{:02}", self.hour),
+                Some('M') => write!(s, "{:02}", self.minute),
+                Some('S') => write!(s, "{:02}", self.second),
+                Some('%') => write!(s, "%"),
+                Some(d) => return Err(format!("unknown directive %{}", d)),
+                None => return Err("format ends with %".to_string()),
+            };
+        }
+        return Ok(s);
+    }
+
+    // the inverse of format; fields the format leaves out are the start of their range
+    pub fn parse(fmt: &str, s: &str) -> Result<Option<Date>, String> {
+        let mut d = Date {
+            year: 1970,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        };
+        let s = s.as_bytes();
+        let mut pos = 0;
+        let mut chars = fmt.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                let mut buf = [0; 4];
+                let lit = c.encode_utf8(&mut buf).as_bytes();
+                if !s[pos..].starts_with(lit) {
+                    return Ok(None);
+                }
+                pos += lit.len();
+                continue;
+            }
+
+            let directive = match chars.next() {
+                Some(d) => d,
+                None => return Err("format ends with %".to_string()),
+            };
+            let width = match directive {
+                'Y' => 4,
+                'm' | 'd' | 'H' | 'M' | 'S' => 2,
+                '%' => {
+                    if s.get(pos) != Some(&b'%') {
+                        return Ok(None);
+                    }
+                    pos += 1;
+                    continue;
+                }
+                d => return Err(format!("unknown directive %{}", d)),
+            };
+
+            let start = pos;
+            while pos < s.len() && pos - start < width && s[pos].is_ascii_digit() {
+                pos += 1;
+            }
+            if pos == start {
+                return Ok(None);
+            }
+            let n: u32 = String::from_utf8_lossy(&s[start..pos]).parse().unwrap();
+            match directive {
+                'Y' => d.year = n as i32,
+                'm' => d.month = n,
+                'd' => d.day = n,
+                'H' => d.hour = n,
+                'M' => d.minute = n,
+                _ => d.second = n,
+            }
+        }
+
+        if pos != s.len() || !d.is_valid() {
+            return Ok(None);
+        }
+        return Ok(Some(d));
+    }
+}
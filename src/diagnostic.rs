@@ -0,0 +1,164 @@
+use data::Info;
+use json::Json;
+
+use std::fmt;
+use std::error::Error;
+
+// Errors from the parser, compiler and VM are Diagnostics behind Box<Error>, so a
+// tool can downcast one and get at the phase and location instead of parsing text.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    // the phase that reported it: "parse", "type", "compile", "link", "build", "vm" or
+    // "interp"
+    pub phase: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Info>,
+}
+
+impl Diagnostic {
+    pub fn error(phase: &'static str, span: Option<Info>, message: String) -> Diagnostic {
+        return Diagnostic {
+                   phase,
+                   severity: Severity::Error,
+                   message,
+                   span,
+               };
+    }
+
+    pub fn warning(phase: &'static str, span: Option<Info>, message: String) -> Diagnostic {
+        return Diagnostic {
+                   phase,
+                   severity: Severity::Warning,
+                   message,
+                   span,
+               };
+    }
+
+    // one line of JSON; `file` is whatever the caller read the program from
+    pub fn to_json(&self, file: Option<&str>) -> String {
+        let mut fields = vec![("phase", Json::str(self.phase)),
+                              ("severity", Json::str(&format!("{}", self.severity))),
+                              ("message", Json::str(&self.message))];
+        if let Some(file) = file {
+            fields.push(("file", Json::str(file)));
+        }
+        if let Some(span) = self.span {
+            fields.push(("span",
+                         Json::obj(vec![("line", Json::Num(span[0] as f64)),
+                                        ("column", Json::Num(span[1] as f64))])));
+        }
+        return format!("{}", Json::obj(fields));
+    }
+
+    // rustc style: the message, where it happened and the source line with a caret
+    // under the column; `color` adds ANSI escapes
+    pub fn render(&self, src: &str, file: Option<&str>, color: bool) -> String {
+        let paint = |code: &str, s: &str| if color {
+            format!("\x1b[{}m{}\x1b[0m", code, s)
+        } else {
+            s.to_string()
+        };
+        let level = match self.severity {
+            Severity::Error => "1;31",
+            Severity::Warning => "1;33",
+        };
+
+        let mut s = format!("{}{}\n",
+                            paint(level, &format!("{}[{}]", self.severity, self.phase)),
+                            paint("1", &format!(": {}", self.message)));
+
+        let span = match self.span {
+            Some(span) => span,
+            None => {
+                if let Some(file) = file {
+                    s.push_str(&format!(" {} {}\n", paint("1;34", "-->"), file));
+                }
+                return s;
+            }
+        };
+
+        let gutter = " ".repeat(format!("{}", span[0]).len());
+        s.push_str(&format!("{}{} {}:{}:{}\n",
+                            gutter,
+                            paint("1;34", "-->"),
+                            file.unwrap_or("<input>"),
+                            span[0],
+                            span[1]));
+
+        if let Some(line) = src.lines().nth(span[0].wrapping_sub(1)) {
+            let bar = paint("1;34", "|");
+            let caret = " ".repeat(span[1].saturating_sub(1)) + &paint(level, "^");
+            s.push_str(&format!("{} {}\n", gutter, bar));
+            s.push_str(&format!("{} {} {}\n", paint("1;34", &format!("{}", span[0])), bar, line));
+            s.push_str(&format!("{} {} {}\n", gutter, bar, caret));
+        }
+        return s;
+    }
+}
+
+// the errors of a phase that goes on after the first, like the compiler; in order of
+// where they are in the source
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+// wraps any error in a Diagnostic, keeping it as is when it already is one and taking
+// the first of several
+pub fn from_error(e: &(Error + 'static)) -> Diagnostic {
+    return all_from_error(e).remove(0);
+}
+
+// every Diagnostic in `e`
+pub fn all_from_error(e: &(Error + 'static)) -> Vec<Diagnostic> {
+    if let Some(ds) = e.downcast_ref::<Diagnostics>() {
+        return ds.0.clone();
+    }
+    match e.downcast_ref::<Diagnostic>() {
+        Some(d) => return vec![d.clone()],
+        None => return vec![Diagnostic::error("io", None, format!("{}", e))],
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Error => return write!(f, "error"),
+            Severity::Warning => return write!(f, "warning"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => {
+                return write!(f,
+                              "{}:{}:{} {}: {}",
+                              span[0],
+                              span[1],
+                              self.phase,
+                              self.severity,
+                              self.message)
+            }
+            None => return write!(f, "{} {}: {}", self.phase, self.severity, self.message),
+        }
+    }
+}
+
+impl Error for Diagnostic {}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines: Vec<String> = self.0.iter().map(|d| format!("{}", d)).collect();
+        return write!(f, "{}", lines.join("\n"));
+    }
+}
+
+impl Error for Diagnostics {}
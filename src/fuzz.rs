@@ -0,0 +1,61 @@
+use data::{SECD, Capabilities};
+use parser::Parser;
+use compiler::Compiler;
+
+use std::str;
+
+// Entry points for fuzzers. Whatever the bytes, these must return rather than
+// panic; a panic found through them is a bug in the parser, compiler or VM.
+
+// deeper nesting than this is rejected up front, the compiler recurses per level
+pub const MAX_DEPTH: usize = 64;
+
+// programs are cut off after this many instructions, loops are legal input
+pub const MAX_STEPS: usize = 100000;
+
+fn nesting(src: &str) -> usize {
+    let mut depth = 0;
+    let mut max = 0;
+    for c in src.bytes() {
+        if c == b'(' {
+            depth += 1;
+            if depth > max {
+                max = depth;
+            }
+        } else if c == b')' && depth > 0 {
+            depth -= 1;
+        }
+    }
+    return max;
+}
+
+pub fn fuzz_parse(bytes: &[u8]) {
+    if let Ok(src) = str::from_utf8(bytes) {
+        let _ = Parser::new(&src.to_string()).parse();
+    }
+}
+
+// runs with no capabilities at all, so fuzzed code cannot touch the host
+pub fn fuzz_compile_run(bytes: &[u8]) {
+    let src = match str::from_utf8(bytes) {
+        Ok(src) => src,
+        Err(_) => return,
+    };
+    if nesting(src) > MAX_DEPTH {
+        return;
+    }
+
+    let ast = match Parser::new(&src.to_string()).parse() {
+        Ok(ast) => ast,
+        Err(_) => return,
+    };
+    let code = match Compiler::new().compile(&ast) {
+        Ok(code) => code,
+        Err(_) => return,
+    };
+
+    let mut vm = SECD::new(code);
+    vm.capabilities = Capabilities::none();
+    vm.fuel = Some(MAX_STEPS);
+    let _ = vm.run_result();
+}
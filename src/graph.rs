@@ -0,0 +1,152 @@
+use data::{self, Code, CodeOP, Lisp};
+use teach;
+use vm::Primitives;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+// Graphviz DOT of compiled code and of values, for `secd graph`. Each block of code is a
+// box listing its instructions, with an edge to each block an instruction carries: the
+// branches of SEL, the body of a lambda, the handlers of try. A value is a node for
+// every distinct object, so shared structure has several edges into one node and a
+// cycle shows as a loop.
+
+const WIDTH: usize = 24;
+
+fn escape(s: &str) -> String {
+    return s.replace('\\', "\\\\").replace('"', "\\\"");
+}
+
+fn quote(s: &str) -> String {
+    return format!("\"{}\"", escape(s));
+}
+
+struct Blocks<'a> {
+    pool: &'a [Rc<Lisp>],
+    primitives: Primitives,
+    out: String,
+    count: usize,
+}
+
+impl<'a> Blocks<'a> {
+    // the node of `code`, after the nodes of the blocks it carries
+    fn block(&mut self, code: &Code) -> usize {
+        let id = self.count;
+        self.count += 1;
+        let mut label = String::new();
+        let mut edges = vec![];
+        for (i, c) in code.iter().enumerate() {
+            // \l ends a left-justified line
+            label.push_str(&escape(&teach::op(self.pool, &self.primitives, c)));
+            label.push_str("\\l");
+            let carried: Vec<(&str, &Code)> = match c.op {
+                CodeOP::SEL(ref t, ref f) |
+                CodeOP::TSEL(ref t, ref f) => vec![("then", t), ("else", f)],
+                CodeOP::LDF(_, ref body, _) => vec![("body", body)],
+                CodeOP::LDC(n) => {
+                    match self.pool.get(n).map(|a| &**a) {
+                        Some(&Lisp::Closure(_, ref body, _, _)) => vec![("body", body)],
+                        _ => vec![],
+                    }
+                }
+                CodeOP::TRY(ref body, ref handlers) => {
+                    let mut carried = vec![("body", body)];
+                    carried.extend(handlers.iter().map(|h| (h.kind.as_str(), &h.code)));
+                    carried
+                }
+                CodeOP::PROTECT(ref body, ref cleanup) => vec![("body", body), ("cleanup", cleanup)],
+                _ => vec![],
+            };
+            for (name, code) in carried {
+                edges.push((i, name, self.block(code)));
+            }
+        }
+        let _ = writeln!(self.out, "  n{} [shape=box, label=\"{}\"];", id, label);
+        for (i, name, to) in edges {
+            let _ = writeln!(self.out, "  n{} -> n{} [label={}];", id, to, quote(&format!("{} {}", i, name)));
+        }
+        return id;
+    }
+}
+
+pub fn code(code: &Code) -> String {
+    let mut blocks = Blocks {
+        pool: data::pool(code),
+        primitives: Primitives::standard(),
+        out: String::new(),
+        count: 0,
+    };
+    blocks.block(code);
+    return format!("digraph code {{\n{}}}\n", blocks.out);
+}
+
+struct Values {
+    seen: HashMap<*const Lisp, usize>,
+    out: String,
+}
+
+impl Values {
+    // the walk keeps its own stack, so a long list doesn't recurse: Visit writes a node
+    // the first time it is seen and leaves its id in `ids`, Edge writes the edge to the
+    // part just visited, and Done leaves the node's id for the edge to it
+    fn node(&mut self, a: &Rc<Lisp>) -> usize {
+        enum Walk {
+            Visit(Rc<Lisp>),
+            Edge(usize, String),
+            Done(usize),
+        }
+
+        let mut ids = vec![];
+        let mut work = vec![Walk::Visit(a.clone())];
+        while let Some(w) = work.pop() {
+            match w {
+                Walk::Visit(a) => {
+                    if let Some(&id) = self.seen.get(&Rc::as_ptr(&a)) {
+                        ids.push(id);
+                        continue;
+                    }
+                    let id = self.seen.len();
+                    self.seen.insert(Rc::as_ptr(&a), id);
+
+                    let (label, parts): (String, Vec<(String, Rc<Lisp>)>) = match *a {
+                        Lisp::Cons(ref car, ref cdr) => {
+                            ("cons".into(), vec![("car".into(), car.clone()), ("cdr".into(), cdr.clone())])
+                        }
+                        Lisp::List(ref ls) => {
+                            ("list".into(), ls.iter().enumerate().map(|(i, a)| (i.to_string(), a.clone())).collect())
+                        }
+                        Lisp::Cell(ref c) => ("cell".into(), vec![("value".into(), c.borrow().clone())]),
+                        Lisp::Weak(ref w) => {
+                            ("weak".into(), w.upgrade().map(|a| ("value".into(), a)).into_iter().collect())
+                        }
+                        Lisp::Closure(ref args, _, _, _) => (format!("lambda ({})", args.join(" ")), vec![]),
+                        _ => (a.show(WIDTH), vec![]),
+                    };
+                    let shape = if parts.is_empty() { "plaintext" } else { "ellipse" };
+                    let _ = writeln!(self.out, "  v{} [shape={}, label={}];", id, shape, quote(&label));
+                    work.push(Walk::Done(id));
+                    for (name, part) in parts.into_iter().rev() {
+                        work.push(Walk::Edge(id, name));
+                        work.push(Walk::Visit(part));
+                    }
+                }
+                Walk::Edge(id, name) => {
+                    let to = ids.pop().unwrap();
+                    let _ = writeln!(self.out, "  v{} -> v{} [label={}];", id, to, quote(&name));
+                }
+                Walk::Done(id) => ids.push(id),
+            }
+        }
+        return ids.pop().unwrap();
+    }
+}
+
+pub fn value(a: &Rc<Lisp>) -> String {
+    let mut values = Values {
+        seen: HashMap::new(),
+        out: String::new(),
+    };
+    values.node(a);
+    return format!("digraph value {{\n{}}}\n", values.out);
+}
@@ -0,0 +1,480 @@
+use data::{AST, SExpr, Info};
+use diagnostic::Diagnostic;
+use parser::{self, Piece};
+
+use std::fmt;
+use std::rc::Rc;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+// A direct evaluator over the AST, kept deliberately naive so it can serve as the
+// reference the compiler and VM are checked against. Only the pure part of the
+// language is supported; effects other than puts are an error here.
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Nil,
+    True,
+    False,
+    Int(i32),
+    Str(String),
+    Symbol(String),
+    Cons(Rc<Value>, Rc<Value>),
+    // the last field names the letrec binding a lambda must see itself under
+    Lambda(Vec<String>, Rc<AST>, Env, Option<String>),
+}
+
+pub type Env = BTreeMap<String, Rc<Value>>;
+
+type InterpResult = Result<Rc<Value>, Box<Error>>;
+
+pub struct Interp {
+    env: Env,
+}
+
+impl PartialEq for Value {
+    fn eq(&self, v: &Value) -> bool {
+        match (self, v) {
+            (&Value::Nil, &Value::Nil) => return true,
+            (&Value::True, &Value::True) => return true,
+            (&Value::False, &Value::False) => return true,
+            (&Value::Int(n), &Value::Int(m)) => return n == m,
+            (&Value::Str(ref a), &Value::Str(ref b)) => return a == b,
+            (&Value::Symbol(ref a), &Value::Symbol(ref b)) => return a == b,
+            (&Value::Cons(ref a, ref b), &Value::Cons(ref c, ref d)) => return a == c && b == d,
+            _ => return false,
+        }
+    }
+}
+
+// prints exactly like Lisp so results can be compared as text
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Value::Nil => write!(f, "nil"),
+            &Value::True => write!(f, "true"),
+            &Value::False => write!(f, "false"),
+            &Value::Int(n) => write!(f, "{}", n),
+            &Value::Str(ref s) => write!(f, "{}", s),
+            &Value::Symbol(ref s) => write!(f, "{}", s),
+            &Value::Cons(ref car, ref cdr) => write!(f, "(cons {} {})", car, cdr),
+            &Value::Lambda(ref args, _, _, _) => write!(f, "(lambda {:?} Code)", args),
+        }
+    }
+}
+
+fn bool_value(b: bool) -> Rc<Value> {
+    return Rc::new(if b { Value::True } else { Value::False });
+}
+
+impl Interp {
+    pub fn new() -> Self {
+        return Interp { env: BTreeMap::new() };
+    }
+
+    fn error<T>(&self, info: &Info, msg: &str) -> Result<T, Box<Error>> {
+        return Err(From::from(Diagnostic::error("interp", Some(*info), msg.to_string())));
+    }
+
+    pub fn eval(&mut self, ast: &AST) -> InterpResult {
+        match ast.sexpr {
+            SExpr::Int(n) => return Ok(Rc::new(Value::Int(n))),
+            SExpr::Str(ref s) => return self.eval_str(ast, s),
+            SExpr::Atom(ref id) => return self.eval_atom(ast, id),
+            SExpr::List(ref ls) => {
+                if ls.len() == 0 {
+                    return Ok(Rc::new(Value::Nil));
+                }
+
+                let id = match ls[0].sexpr {
+                    SExpr::Atom(ref id) => id.as_str(),
+                    SExpr::List(_) => "",
+                    _ => return self.error(&ls[0].info, "apply unexpect value"),
+                };
+
+                match id {
+                    "lambda" => return self.eval_lambda(ast, ls),
+                    "let" => return self.eval_let(ls, false),
+                    "letrec" => return self.eval_let(ls, true),
+                    "if" => return self.eval_if(ls),
+                    "begin" => return self.eval_begin(ls),
+                    "case" => return self.eval_case(ls),
+                    "do" => return self.eval_do(ls),
+                    "quote" => return self.eval_quote(ls),
+                    "puts" => {
+                        let a = try!(self.eval_args(ls, 1)).remove(0);
+                        println!("{}", a);
+                        return Ok(a);
+                    }
+                    "eq" => {
+                        let a = try!(self.eval_args(ls, 2));
+                        return Ok(bool_value(a[0] == a[1]));
+                    }
+                    "cons" => {
+                        let a = try!(self.eval_args(ls, 2));
+                        return Ok(Rc::new(Value::Cons(a[0].clone(), a[1].clone())));
+                    }
+                    "car" | "cdr" => {
+                        let a = try!(self.eval_args(ls, 1));
+                        match *a[0] {
+                            Value::Cons(ref car, ref cdr) => {
+                                return Ok(if id == "car" { car.clone() } else { cdr.clone() })
+                            }
+                            _ => return self.error(&ls[0].info, "expected Cons"),
+                        }
+                    }
+                    "+" | "-" => return self.eval_sum(ls, id),
+                    "min" | "max" | "quotient" | "remainder" | "bit-and" | "bit-or" |
+                    "bit-xor" | "shl" | "shr" => return self.eval_arith(ls, id),
+                    "abs" | "bit-not" => {
+                        let n = try!(self.eval_int(ls, 1)).remove(0);
+                        if id == "bit-not" {
+                            return Ok(Rc::new(Value::Int(!n)));
+                        }
+                        match n.checked_abs() {
+                            Some(a) => return Ok(Rc::new(Value::Int(a))),
+                            None => return self.error(&ls[0].info, "overflow"),
+                        }
+                    }
+                    "number->string" => {
+                        let n = try!(self.eval_int(ls, 1)).remove(0);
+                        return Ok(Rc::new(Value::Str(n.to_string())));
+                    }
+                    "string-append" => {
+                        let a = try!(self.eval_args(ls, 2));
+                        match (&*a[0], &*a[1]) {
+                            (&Value::Str(ref a), &Value::Str(ref b)) => {
+                                return Ok(Rc::new(Value::Str(format!("{}{}", a, b))))
+                            }
+                            _ => return self.error(&ls[0].info, "expected string"),
+                        }
+                    }
+                    "string->number" | "string->symbol" => {
+                        let a = try!(self.eval_args(ls, 1));
+                        let s = match *a[0] {
+                            Value::Str(ref s) => s.clone(),
+                            _ => return self.error(&ls[0].info, "expected string"),
+                        };
+                        if id == "string->symbol" && s.contains(' ') {
+                            return self.error(&ls[0].info, "a symbol cannot have a space");
+                        }
+                        if id == "string->symbol" {
+                            return Ok(Rc::new(Value::Symbol(s)));
+                        }
+                        match s.trim().parse() {
+                            Ok(n) => return Ok(Rc::new(Value::Int(n))),
+                            Err(_) => return Ok(Rc::new(Value::Nil)),
+                        }
+                    }
+                    "symbol->string" => {
+                        let a = try!(self.eval_args(ls, 1));
+                        match *a[0] {
+                            Value::Symbol(ref s) => return Ok(Rc::new(Value::Str(s.clone()))),
+                            _ => return self.error(&ls[0].info, "expected symbol"),
+                        }
+                    }
+                    "current-time" | "clock" | "time" | "assert" | "yield" | "spawn" | "join" |
+                    "chan" | "send" | "recv" | "random" | "exit" | "getenv" | "system" |
+                    "process" => return self.error(&ls[0].info, &format!("{} is not supported", id)),
+                    _ => return self.eval_apply(ls),
+                }
+            }
+        }
+    }
+
+    fn eval_atom(&mut self, ast: &AST, id: &String) -> InterpResult {
+        match id.as_str() {
+            "nil" => return Ok(Rc::new(Value::Nil)),
+            "true" => return Ok(Rc::new(Value::True)),
+            "false" => return Ok(Rc::new(Value::False)),
+            _ => {
+                match self.env.get(id) {
+                    Some(a) => return Ok(a.clone()),
+                    None => return self.error(&ast.info, &format!("unbound {}", id)),
+                }
+            }
+        }
+    }
+
+    // the pieces of an interpolated string joined, as the compiler joins them
+    fn eval_str(&mut self, ast: &AST, s: &str) -> InterpResult {
+        let mut out = String::new();
+        for piece in try!(parser::interpolation(s, ast.info)) {
+            match piece {
+                Piece::Text(text) => out.push_str(&text),
+                Piece::Hole(expr) => out.push_str(&format!("{}", try!(self.eval(&expr)))),
+            }
+        }
+        return Ok(Rc::new(Value::Str(out)));
+    }
+
+    fn eval_args(&mut self, ls: &Vec<AST>, n: usize) -> Result<Vec<Rc<Value>>, Box<Error>> {
+        if ls.len() != n + 1 {
+            return self.error(&ls[0].info, &format!("{} syntax", ls[0]));
+        }
+
+        let mut args = vec![];
+        for arg in &ls[1..] {
+            args.push(try!(self.eval(arg)));
+        }
+        return Ok(args);
+    }
+
+    fn eval_int(&mut self, ls: &Vec<AST>, n: usize) -> Result<Vec<i32>, Box<Error>> {
+        let mut ns = vec![];
+        for a in try!(self.eval_args(ls, n)) {
+            match *a {
+                Value::Int(n) => ns.push(n),
+                _ => return self.error(&ls[0].info, "expected int"),
+            }
+        }
+        return Ok(ns);
+    }
+
+    // (+ a b c) adds from the left, (+) is 0 and (- n) negates
+    fn eval_sum(&mut self, ls: &Vec<AST>, id: &str) -> InterpResult {
+        if ls.len() < 2 && id == "-" {
+            return self.error(&ls[0].info, "- syntax");
+        }
+
+        let mut ns = try!(self.eval_int(ls, ls.len() - 1));
+        if ns.len() < 2 {
+            ns.insert(0, 0);
+        }
+        let mut r = ns[0];
+        for &n in &ns[1..] {
+            r = match if id == "+" { r.checked_add(n) } else { r.checked_sub(n) } {
+                Some(r) => r,
+                None => return self.error(&ls[0].info, "overflow"),
+            };
+        }
+        return Ok(Rc::new(Value::Int(r)));
+    }
+
+    fn eval_arith(&mut self, ls: &Vec<AST>, id: &str) -> InterpResult {
+        let ns = try!(self.eval_int(ls, 2));
+        let (m, n) = (ns[0], ns[1]);
+        let r = match id {
+            "min" => Some(if m < n { m } else { n }),
+            "max" => Some(if m > n { m } else { n }),
+            "quotient" => m.checked_div(n),
+            "remainder" => m.checked_rem(n),
+            "bit-and" => Some(m & n),
+            "bit-or" => Some(m | n),
+            "bit-xor" => Some(m ^ n),
+            "shl" if (0..32).contains(&n) => Some(m << n),
+            "shr" if (0..32).contains(&n) => Some(m >> n),
+            _ => None,
+        };
+        match r {
+            Some(r) => return Ok(Rc::new(Value::Int(r))),
+            None => return self.error(&ls[0].info, &format!("{} out of range", id)),
+        }
+    }
+
+    fn eval_lambda(&mut self, ast: &AST, ls: &Vec<AST>) -> InterpResult {
+        if ls.len() != 3 {
+            return self.error(&ls[0].info, "lambda syntax");
+        }
+
+        let mut args = vec![];
+        match ls[1].sexpr {
+            SExpr::Atom(ref a) => args.push(a.clone()),
+            SExpr::List(ref aa) => {
+                for a in aa {
+                    match a.sexpr {
+                        SExpr::Atom(ref a) => args.push(a.clone()),
+                        _ => return self.error(&a.info, "lambda args"),
+                    }
+                }
+            }
+            _ => return self.error(&ls[1].info, "lambda args"),
+        }
+
+        return Ok(Rc::new(Value::Lambda(args, Rc::new(ast.clone()), self.env.clone(), None)));
+    }
+
+    fn eval_let(&mut self, ls: &Vec<AST>, rec: bool) -> InterpResult {
+        if ls.len() != 4 {
+            return self.error(&ls[0].info, "let syntax");
+        }
+
+        let id = match ls[1].sexpr {
+            SExpr::Atom(ref id) => id.clone(),
+            _ => return self.error(&ls[0].info, "let bind id sytax"),
+        };
+
+        let mut a = try!(self.eval(&ls[2]));
+        if rec {
+            if let Value::Lambda(ref args, ref body, ref env, _) = *a.clone() {
+                a = Rc::new(Value::Lambda(args.clone(), body.clone(), env.clone(), Some(id.clone())));
+            }
+        }
+
+        let saved = self.env.clone();
+        self.env.insert(id, a);
+        let r = self.eval(&ls[3]);
+        self.env = saved;
+        return r;
+    }
+
+    fn eval_if(&mut self, ls: &Vec<AST>) -> InterpResult {
+        if ls.len() != 4 {
+            return self.error(&ls[0].info, "if syntax");
+        }
+
+        match *try!(self.eval(&ls[1])) {
+            Value::True => return self.eval(&ls[2]),
+            Value::False => return self.eval(&ls[3]),
+            _ => return self.error(&ls[0].info, "if expected bool"),
+        }
+    }
+
+    fn eval_begin(&mut self, ls: &Vec<AST>) -> InterpResult {
+        if ls.len() < 2 {
+            return self.error(&ls[0].info, "begin syntax");
+        }
+
+        let mut a = Rc::new(Value::Nil);
+        for ast in &ls[1..] {
+            a = try!(self.eval(ast));
+        }
+        return Ok(a);
+    }
+
+    fn eval_quote(&mut self, ls: &Vec<AST>) -> InterpResult {
+        if ls.len() != 2 {
+            return self.error(&ls[0].info, "quote syntax");
+        }
+
+        match ls[1].sexpr {
+            SExpr::Atom(ref id) => return Ok(Rc::new(Value::Symbol(id.clone()))),
+            _ => return self.eval(&ls[1]),
+        }
+    }
+
+    fn eval_case(&mut self, ls: &Vec<AST>) -> InterpResult {
+        if ls.len() < 2 {
+            return self.error(&ls[0].info, "case syntax");
+        }
+
+        let key = try!(self.eval(&ls[1]));
+        for clause in &ls[2..] {
+            let cl = match clause.sexpr {
+                SExpr::List(ref cl) if cl.len() == 2 => cl,
+                _ => return self.error(&clause.info, "case clause syntax"),
+            };
+
+            match cl[0].sexpr {
+                SExpr::Atom(ref id) if id == "else" => return self.eval(&cl[1]),
+                SExpr::List(ref ds) => {
+                    for d in ds {
+                        if *try!(self.eval(d)) == *key {
+                            return self.eval(&cl[1]);
+                        }
+                    }
+                }
+                _ => return self.error(&cl[0].info, "case datum list"),
+            }
+        }
+        return Ok(Rc::new(Value::Nil));
+    }
+
+    // iterates in place instead of recursing, so long loops do not grow the host stack
+    fn eval_do(&mut self, ls: &Vec<AST>) -> InterpResult {
+        if ls.len() < 3 {
+            return self.error(&ls[0].info, "do syntax");
+        }
+
+        let mut vars = vec![];
+        match ls[1].sexpr {
+            SExpr::List(ref vs) => {
+                for v in vs {
+                    match v.sexpr {
+                        SExpr::List(ref v) if v.len() == 2 || v.len() == 3 => {
+                            match v[0].sexpr {
+                                SExpr::Atom(ref id) => vars.push((id.clone(), &v[1], v.get(2))),
+                                _ => return self.error(&v[0].info, "do variable"),
+                            }
+                        }
+                        _ => return self.error(&v.info, "do variable syntax"),
+                    }
+                }
+            }
+            _ => return self.error(&ls[1].info, "do variables syntax"),
+        }
+
+        let (test, result) = match ls[2].sexpr {
+            SExpr::List(ref t) if t.len() == 2 => (&t[0], &t[1]),
+            _ => return self.error(&ls[2].info, "do test syntax"),
+        };
+
+        let saved = self.env.clone();
+        let mut vals = vec![];
+        for &(_, init, _) in &vars {
+            vals.push(try!(self.eval(init)));
+        }
+
+        loop {
+            for (i, &(ref id, _, _)) in vars.iter().enumerate() {
+                self.env.insert(id.clone(), vals[i].clone());
+            }
+
+            match *try!(self.eval(test)) {
+                Value::True => break,
+                Value::False => {}
+                _ => return self.error(&test.info, "do expected bool"),
+            }
+
+            for ast in &ls[3..] {
+                try!(self.eval(ast));
+            }
+
+            vals = vec![];
+            for &(ref id, _, step) in &vars {
+                match step {
+                    Some(step) => vals.push(try!(self.eval(step))),
+                    None => vals.push(self.env[id].clone()),
+                }
+            }
+        }
+
+        let r = self.eval(result);
+        self.env = saved;
+        return r;
+    }
+
+    fn eval_apply(&mut self, ls: &Vec<AST>) -> InterpResult {
+        let f = try!(self.eval(&ls[0]));
+        let mut vals = vec![];
+        for arg in &ls[1..] {
+            vals.push(try!(self.eval(arg)));
+        }
+
+        match *f {
+            Value::Lambda(ref names, ref lambda, ref env, ref name) => {
+                if names.len() != vals.len() {
+                    return self.error(&ls[0].info, "wrong number of arguments");
+                }
+
+                let mut env = env.clone();
+                if let Some(ref name) = *name {
+                    env.insert(name.clone(), f.clone());
+                }
+                for (n, v) in names.iter().zip(vals) {
+                    env.insert(n.clone(), v);
+                }
+
+                let saved = ::std::mem::replace(&mut self.env, env);
+                let r = match lambda.sexpr {
+                    SExpr::List(ref l) => self.eval(&l[2]),
+                    _ => unreachable!(),
+                };
+                self.env = saved;
+                return r;
+            }
+
+            _ => return self.error(&ls[0].info, "apply expected lambda"),
+        }
+    }
+}
@@ -0,0 +1,537 @@
+use data::{Lisp, Code, CodeOP, CodeOPInfo, Env, ProcInfo};
+use vm;
+
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, UserFuncName, Value};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+
+use std::rc::Rc;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::collections::HashMap;
+
+// A JIT for the integer subset of the machine. Once a closure has been called
+// `threshold` times its body is translated with Cranelift, if every instruction in
+// it is one of: LD of a parameter, LDC of an int or bool, ADD, SUB, EQ, min, max,
+// abs, quotient, remainder, SEL, TSEL, and calls of the closure itself. Anything
+// else and the closure stays interpreted for good.
+//
+// Native code only runs when every argument is an int, and the name the body calls
+// itself by still means the closure. It gives up (BAIL) on overflow, division by zero
+// or recursing too deep, and since nothing in the subset has an effect the machine
+// then just runs the call again itself, raising the error the usual way. A closure
+// that gave up once is interpreted from then on, so deep recursion isn't redone.
+
+const BAIL: i64 = i64::MIN;
+
+// native frames before giving up; tail calls are jumps and don't count
+const MAX_DEPTH: i64 = 10000;
+
+type Entry = extern "C" fn(*const i64) -> i64;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Kind {
+    Int,
+    Bool,
+}
+
+struct Native {
+    entry: Entry,
+    kind: Kind,
+    // the name the body calls itself by, and whether it does so with AP or TAP,
+    // which look it up in the closure's own environment only
+    me: Option<String>,
+    plain: bool,
+}
+
+pub struct Jit {
+    pub threshold: usize,
+    module: JITModule,
+    // by ProcInfo, which every closure made by the same LDF shares; holding the Rc
+    // keeps its address from being reused
+    calls: HashMap<*const ProcInfo, (Rc<ProcInfo>, usize)>,
+    native: HashMap<*const ProcInfo, Option<Native>>,
+    funcs: usize,
+}
+
+impl fmt::Debug for Jit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "Jit {{ threshold: {}, compiled: {} }}", self.threshold, self.compiled());
+    }
+}
+
+impl PartialEq for Jit {
+    fn eq(&self, other: &Jit) -> bool {
+        return ptr::eq(self, other);
+    }
+}
+
+impl Jit {
+    pub fn new(threshold: usize) -> Result<Jit, String> {
+        let mut flags = settings::builder();
+        try!(flags.set("use_colocated_libcalls", "false").map_err(|e| e.to_string()));
+        try!(flags.set("is_pic", "false").map_err(|e| e.to_string()));
+        let isa = try!(cranelift_native::builder().map_err(|e| e.to_string()));
+        let isa = try!(isa.finish(settings::Flags::new(flags)).map_err(|e| e.to_string()));
+        return Ok(Jit {
+                      threshold,
+                      module: JITModule::new(JITBuilder::with_isa(isa, default_libcall_names())),
+                      calls: HashMap::new(),
+                      native: HashMap::new(),
+                      funcs: 0,
+                  });
+    }
+
+    // how many closure bodies run natively
+    pub fn compiled(&self) -> usize {
+        return self.native.values().filter(|n| n.is_some()).count();
+    }
+
+    // the result of calling `f` with `args`, if native code could compute it; `rec`
+    // says whether the call (RAP, TRAP) also sees the caller's environment `env`; the
+    // closure's LDCs load from `consts`
+    pub fn call(&mut self,
+                f: &Rc<Lisp>,
+                args: &Lisp,
+                env: &Env,
+                consts: &[Rc<Lisp>],
+                rec: bool)
+                -> Option<Rc<Lisp>> {
+        let (names, code, captured, info) = match **f {
+            Lisp::Closure(ref names, ref code, ref env, ref info) => (names, code, env, info),
+            _ => return None,
+        };
+        let vals = match *args {
+            Lisp::List(ref vals) if vals.len() == names.len() => vals,
+            _ => return None,
+        };
+        let mut ints = vec![];
+        for v in vals {
+            match **v {
+                Lisp::Int(n) => ints.push(n as i64),
+                _ => return None,
+            }
+        }
+
+        let key = &**info as *const ProcInfo;
+        if !self.native.contains_key(&key) {
+            let count = {
+                let entry = self.calls.entry(key).or_insert((info.clone(), 0));
+                entry.1 += 1;
+                entry.1
+            };
+            if count < self.threshold {
+                return None;
+            }
+            let native = self.compile(names, code, consts);
+            self.native.insert(key, native);
+        }
+
+        let native = match self.native.get(&key) {
+            Some(&Some(ref native)) => native,
+            _ => return None,
+        };
+        if let Some(ref me) = native.me {
+            let is_me = |v: Option<&Rc<Lisp>>| match v {
+                Some(v) => Rc::ptr_eq(v, f),
+                None => false,
+            };
+            // RAP and TRAP look in the closure's environment first, then the caller's
+            let own = captured.get(me);
+            let seen = if rec && own.is_none() { env.get(me) } else { own };
+            if !is_me(seen) || (own.is_some() || native.plain) && !is_me(own) {
+                return None;
+            }
+        }
+
+        let r = (native.entry)(ints.as_ptr());
+        let kind = native.kind;
+        if r == BAIL {
+            self.native.insert(key, None);
+            return None;
+        }
+        match kind {
+            Kind::Int => return Some(Lisp::int(r as i32)),
+            Kind::Bool => return Some(Lisp::bool(r != 0)),
+        }
+    }
+
+    // bodies returning a bool are found by failing to translate them as returning an int
+    fn compile(&mut self, names: &[String], code: &Code, consts: &[Rc<Lisp>]) -> Option<Native> {
+        for &kind in &[Kind::Int, Kind::Bool] {
+            match self.translate(names, code, consts, kind) {
+                Ok(native) => return Some(native),
+                Err(e) => debug!("jit: {}", e),
+            }
+        }
+        return None;
+    }
+
+    fn translate(&mut self,
+                 names: &[String],
+                 code: &Code,
+                 consts: &[Rc<Lisp>],
+                 kind: Kind)
+                 -> Result<Native, String> {
+        let n = self.funcs;
+        self.funcs += 1;
+        let ptr = self.module.target_config().pointer_type();
+
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(types::I64));
+        for _ in names {
+            sig.params.push(AbiParam::new(types::I64));
+        }
+        sig.returns.push(AbiParam::new(types::I64));
+        let body = try!(self.module
+                            .declare_function(&format!("body{}", n), Linkage::Local, &sig)
+                            .map_err(|e| e.to_string()));
+
+        let mut ctx = self.module.make_context();
+        let mut fctx = FunctionBuilderContext::new();
+        ctx.func.signature = sig.clone();
+        ctx.func.name = UserFuncName::user(0, body.as_u32());
+        let mut t = {
+            let b = FunctionBuilder::new(&mut ctx.func, &mut fctx);
+            Translator {
+                b,
+                names,
+                consts,
+                kind,
+                me: None,
+                plain: false,
+                body,
+                module: &mut self.module,
+                depth: None,
+                header: None,
+            }
+        };
+        if let Err(e) = t.function(code) {
+            drop(t);
+            self.module.clear_context(&mut ctx);
+            return Err(e);
+        }
+        let (me, plain) = (t.me.clone(), t.plain);
+        t.b.seal_all_blocks();
+        t.b.finalize();
+        try!(self.module.define_function(body, &mut ctx).map_err(|e| e.to_string()));
+        self.module.clear_context(&mut ctx);
+
+        // entry(args) = body(0, args[0], ...)
+        let mut esig = self.module.make_signature();
+        esig.params.push(AbiParam::new(ptr));
+        esig.returns.push(AbiParam::new(types::I64));
+        let entry = try!(self.module
+                             .declare_function(&format!("entry{}", n), Linkage::Local, &esig)
+                             .map_err(|e| e.to_string()));
+        ctx.func.signature = esig;
+        ctx.func.name = UserFuncName::user(0, entry.as_u32());
+        {
+            let mut b = FunctionBuilder::new(&mut ctx.func, &mut fctx);
+            let block = b.create_block();
+            b.append_block_params_for_function_params(block);
+            b.switch_to_block(block);
+            let p = b.block_params(block)[0];
+            let mut args = vec![b.ins().iconst(types::I64, 0)];
+            for i in 0..names.len() {
+                args.push(b.ins().load(types::I64, MemFlags::trusted(), p, (i * 8) as i32));
+            }
+            let callee = self.module.declare_func_in_func(body, b.func);
+            let call = b.ins().call(callee, &args);
+            let r = b.inst_results(call)[0];
+            b.ins().return_(&[r]);
+            b.seal_all_blocks();
+            b.finalize();
+        }
+        try!(self.module.define_function(entry, &mut ctx).map_err(|e| e.to_string()));
+        self.module.clear_context(&mut ctx);
+        try!(self.module.finalize_definitions().map_err(|e| e.to_string()));
+
+        let f = self.module.get_finalized_function(entry);
+        return Ok(Native {
+                      entry: unsafe { mem::transmute::<*const u8, Entry>(f) },
+                      kind,
+                      me,
+                      plain,
+                  });
+    }
+}
+
+// what the machine's stack holds at each point of the body, as Cranelift values
+#[derive(Clone)]
+enum Slot {
+    Int(Value),
+    Bool(Value),
+    Args(Vec<Slot>),
+    Me,
+}
+
+struct Translator<'a> {
+    b: FunctionBuilder<'a>,
+    names: &'a [String],
+    consts: &'a [Rc<Lisp>],
+    kind: Kind,
+    me: Option<String>,
+    plain: bool,
+    body: FuncId,
+    module: &'a mut JITModule,
+    depth: Option<Value>,
+    header: Option<Block>,
+}
+
+impl<'a> Translator<'a> {
+    // body(depth, args...) checks the depth and jumps to the header, which takes the
+    // arguments again so that tail calls can jump back to it
+    fn function(&mut self, code: &Code) -> Result<(), String> {
+        let start = self.b.create_block();
+        self.b.append_block_params_for_function_params(start);
+        let header = self.b.create_block();
+        for _ in self.names {
+            self.b.append_block_param(header, types::I64);
+        }
+        self.b.switch_to_block(start);
+        let params = self.b.block_params(start).to_vec();
+        let deep = self.b.ins().icmp_imm(IntCC::SignedGreaterThan, params[0], MAX_DEPTH);
+        self.bail_if(deep);
+        self.b.ins().jump(header, &params[1..]);
+
+        self.depth = Some(params[0]);
+        self.header = Some(header);
+        self.b.switch_to_block(header);
+        match try!(self.block(code, vec![])) {
+            None => return Ok(()),
+            Some(_) => return Err("JOIN outside of SEL".into()),
+        }
+    }
+
+    fn bail_if(&mut self, cond: Value) {
+        let bail = self.b.create_block();
+        let next = self.b.create_block();
+        self.b.ins().brif(cond, bail, &[], next, &[]);
+        self.b.switch_to_block(bail);
+        let r = self.b.ins().iconst(types::I64, BAIL);
+        self.b.ins().return_(&[r]);
+        self.b.switch_to_block(next);
+    }
+
+    // results out of the range of an int are an overflow
+    fn checked(&mut self, v: Value) -> Slot {
+        let narrow = self.b.ins().ireduce(types::I32, v);
+        let wide = self.b.ins().sextend(types::I64, narrow);
+        let overflow = self.b.ins().icmp(IntCC::NotEqual, v, wide);
+        self.bail_if(overflow);
+        return Slot::Int(v);
+    }
+
+    fn int(&self, s: Option<Slot>, op: &str) -> Result<Value, String> {
+        match s {
+            Some(Slot::Int(v)) => return Ok(v),
+            _ => return Err(format!("{} on something other than an int", op)),
+        }
+    }
+
+    fn cond(&self, s: Option<Slot>, op: &str) -> Result<Value, String> {
+        match s {
+            Some(Slot::Bool(v)) => return Ok(v),
+            _ => return Err(format!("{} on something other than a bool", op)),
+        }
+    }
+
+    fn slot(&self, kind: Kind, v: Value) -> Slot {
+        match kind {
+            Kind::Int => return Slot::Int(v),
+            Kind::Bool => return Slot::Bool(v),
+        }
+    }
+
+    // the value a JOIN leaves on top, or None once the block has returned
+    fn block(&mut self, code: &[CodeOPInfo], stack: Vec<Slot>) -> Result<Option<Slot>, String> {
+        let mut stack = stack;
+        for c in code {
+            match c.op {
+                CodeOP::LD(ref id) if self.names.contains(id) => {
+                    let i = self.names.iter().position(|n| n == id).unwrap();
+                    let header = self.header.unwrap();
+                    stack.push(Slot::Int(self.b.block_params(header)[i]));
+                }
+
+                CodeOP::LD(ref id) if self.me.is_none() || self.me.as_ref() == Some(id) => {
+                    self.me = Some(id.clone());
+                    stack.push(Slot::Me);
+                }
+
+                CodeOP::LDC(i) => {
+                    let slot = match self.consts.get(i).map(|lisp| &**lisp) {
+                        Some(&Lisp::Int(n)) => Slot::Int(self.b.ins().iconst(types::I64, n as i64)),
+                        Some(&Lisp::True) => Slot::Bool(self.b.ins().iconst(types::I64, 1)),
+                        Some(&Lisp::False) => Slot::Bool(self.b.ins().iconst(types::I64, 0)),
+                        Some(lisp) => return Err(format!("LDC of {}", lisp)),
+                        None => return Err(format!("LDC of missing constant {}", i)),
+                    };
+                    stack.push(slot);
+                }
+
+                CodeOP::ADD | CodeOP::SUB => {
+                    let n = try!(self.int(stack.pop(), "ADD"));
+                    let m = try!(self.int(stack.pop(), "ADD"));
+                    let v = if c.op == CodeOP::ADD {
+                        self.b.ins().iadd(m, n)
+                    } else {
+                        self.b.ins().isub(m, n)
+                    };
+                    stack.push(self.checked(v));
+                }
+
+                CodeOP::EQ => {
+                    let v = match (stack.pop(), stack.pop()) {
+                        (Some(Slot::Int(a)), Some(Slot::Int(b))) |
+                        (Some(Slot::Bool(a)), Some(Slot::Bool(b))) => {
+                            let eq = self.b.ins().icmp(IntCC::Equal, a, b);
+                            self.b.ins().uextend(types::I64, eq)
+                        }
+                        (Some(Slot::Int(_)), Some(Slot::Bool(_))) |
+                        (Some(Slot::Bool(_)), Some(Slot::Int(_))) => self.b.ins().iconst(types::I64, 0),
+                        _ => return Err("EQ of a call's arguments".into()),
+                    };
+                    stack.push(Slot::Bool(v));
+                }
+
+                CodeOP::PRIM(id, _) => {
+                    let name = match vm::builtin_name(id) {
+                        Some(name) => name,
+                        None => return Err("a registered primitive".into()),
+                    };
+                    let n = try!(self.int(stack.pop(), name));
+                    let v = match name {
+                        "abs" => {
+                            let v = self.b.ins().iabs(n);
+                            self.checked(v)
+                        }
+                        "min" | "max" => {
+                            let m = try!(self.int(stack.pop(), name));
+                            if name == "min" {
+                                Slot::Int(self.b.ins().smin(m, n))
+                            } else {
+                                Slot::Int(self.b.ins().smax(m, n))
+                            }
+                        }
+                        "quotient" | "remainder" => {
+                            let m = try!(self.int(stack.pop(), name));
+                            // the machine reports i32::MIN % -1 as an overflow too
+                            let zero = self.b.ins().icmp_imm(IntCC::Equal, n, 0);
+                            let minus = self.b.ins().icmp_imm(IntCC::Equal, n, -1);
+                            let bad = self.b.ins().bor(zero, minus);
+                            self.bail_if(bad);
+                            let v = if name == "quotient" {
+                                self.b.ins().sdiv(m, n)
+                            } else {
+                                self.b.ins().srem(m, n)
+                            };
+                            Slot::Int(v)
+                        }
+                        _ => return Err(format!("primitive {}", name)),
+                    };
+                    stack.push(v);
+                }
+
+                CodeOP::ARGS(n) if stack.len() >= n => {
+                    let at = stack.len() - n;
+                    let args = stack.split_off(at);
+                    stack.push(Slot::Args(args));
+                }
+
+                CodeOP::AP | CodeOP::RAP | CodeOP::TAP | CodeOP::TRAP => {
+                    let args = match (stack.pop(), stack.pop()) {
+                        (Some(Slot::Me), Some(Slot::Args(args))) => args,
+                        _ => return Err(format!("{:?} of another closure", c.op)),
+                    };
+                    if args.len() != self.names.len() {
+                        return Err("a call with the wrong number of arguments".into());
+                    }
+                    let mut vals = vec![];
+                    for a in args {
+                        vals.push(try!(self.int(Some(a), "a call")));
+                    }
+                    if c.op == CodeOP::AP || c.op == CodeOP::TAP {
+                        self.plain = true;
+                    }
+
+                    if c.op == CodeOP::TAP || c.op == CodeOP::TRAP {
+                        let header = self.header.unwrap();
+                        self.b.ins().jump(header, &vals);
+                        return Ok(None);
+                    }
+                    let depth = self.depth.unwrap();
+                    let deeper = self.b.ins().iadd_imm(depth, 1);
+                    vals.insert(0, deeper);
+                    let callee = self.module.declare_func_in_func(self.body, self.b.func);
+                    let call = self.b.ins().call(callee, &vals);
+                    let r = self.b.inst_results(call)[0];
+                    let bailed = self.b.ins().icmp_imm(IntCC::Equal, r, BAIL);
+                    self.bail_if(bailed);
+                    let kind = self.kind;
+                    stack.push(self.slot(kind, r));
+                }
+
+                CodeOP::SEL(ref t, ref f) => {
+                    let cond = try!(self.cond(stack.pop(), "SEL"));
+                    let (then, other, join) = (self.b.create_block(), self.b.create_block(),
+                                               self.b.create_block());
+                    let v = self.b.append_block_param(join, types::I64);
+                    self.b.ins().brif(cond, then, &[], other, &[]);
+                    let mut kinds = vec![];
+                    for &(block, code) in &[(then, t), (other, f)] {
+                        self.b.switch_to_block(block);
+                        match try!(self.block(code, stack.clone())) {
+                            Some(Slot::Int(r)) => {
+                                kinds.push(Kind::Int);
+                                self.b.ins().jump(join, &[r]);
+                            }
+                            Some(Slot::Bool(r)) => {
+                                kinds.push(Kind::Bool);
+                                self.b.ins().jump(join, &[r]);
+                            }
+                            _ => return Err("a SEL branch without a value".into()),
+                        }
+                    }
+                    if kinds[0] != kinds[1] {
+                        return Err("SEL branches of different kinds".into());
+                    }
+                    self.b.switch_to_block(join);
+                    stack.push(self.slot(kinds[0], v));
+                }
+
+                CodeOP::JOIN => return Ok(stack.pop()),
+
+                CodeOP::TSEL(ref t, ref f) => {
+                    let cond = try!(self.cond(stack.pop(), "TSEL"));
+                    let (then, other) = (self.b.create_block(), self.b.create_block());
+                    self.b.ins().brif(cond, then, &[], other, &[]);
+                    for &(block, code) in &[(then, t), (other, f)] {
+                        self.b.switch_to_block(block);
+                        if try!(self.block(code, stack.clone())).is_some() {
+                            return Err("a TSEL branch that doesn't return".into());
+                        }
+                    }
+                    return Ok(None);
+                }
+
+                CodeOP::RET => {
+                    let r = match (stack.pop(), self.kind) {
+                        (Some(Slot::Int(r)), Kind::Int) | (Some(Slot::Bool(r)), Kind::Bool) => r,
+                        _ => return Err(format!("returning something other than {:?}", self.kind)),
+                    };
+                    self.b.ins().return_(&[r]);
+                    return Ok(None);
+                }
+
+                ref op => return Err(format!("{:?}", op)),
+            }
+        }
+        return Err("a block that doesn't end".into());
+    }
+}
@@ -0,0 +1,322 @@
+use std::fmt;
+use std::error::Error;
+use std::io::{BufRead, Write};
+
+// Just enough JSON for the editor protocols; objects keep their key order.
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(s: &str) -> Result<Json, Box<Error>> {
+        let mut p = JsonParser {
+            src: s.as_bytes(),
+            pos: 0,
+        };
+        let v = try!(p.value());
+        p.ws();
+        if p.pos < p.src.len() {
+            return p.error("trailing characters");
+        }
+        return Ok(v);
+    }
+
+    pub fn obj(fields: Vec<(&str, Json)>) -> Json {
+        return Json::Obj(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+    }
+
+    pub fn str(s: &str) -> Json {
+        return Json::Str(s.to_string());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Obj(ref fields) => return fields.iter().find(|f| f.0 == key).map(|f| &f.1),
+            _ => return None,
+        }
+    }
+
+    // follows a path of object keys, `v.at(&["params", "textDocument", "uri"])`
+    pub fn at(&self, path: &[&str]) -> Option<&Json> {
+        return path.iter().try_fold(self, |v, key| v.get(key));
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::Str(ref s) => return Some(s),
+            _ => return None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Json::Num(n) => return Some(n as i64),
+            _ => return None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => return Some(b),
+            _ => return None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match *self {
+            Json::Arr(ref a) => return Some(a),
+            _ => return None,
+        }
+    }
+}
+
+// the Content-Length framing LSP and DAP share; None once the stream is closed
+pub fn read_message<R: BufRead>(input: &mut R) -> Result<Option<Json>, Box<Error>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if try!(input.read_line(&mut line)) == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(n) = line.strip_prefix("Content-Length:") {
+            len = Some(try!(n.trim().parse::<usize>()));
+        }
+    }
+
+    let len = match len {
+        Some(len) => len,
+        None => return Err(From::from("message without Content-Length")),
+    };
+    let mut body = vec![0; len];
+    try!(input.read_exact(&mut body));
+    return Ok(Some(try!(Json::parse(&String::from_utf8_lossy(&body)))));
+}
+
+pub fn write_message<W: Write>(output: &mut W, msg: &Json) -> Result<(), Box<Error>> {
+    let body = format!("{}", msg);
+    try!(write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body));
+    try!(output.flush());
+    return Ok(());
+}
+
+struct JsonParser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn error<T>(&self, msg: &str) -> Result<T, Box<Error>> {
+        return Err(From::from(format!("json error at {}: {}", self.pos, msg)));
+    }
+
+    fn ws(&mut self) {
+        while self.pos < self.src.len() && (self.src[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, lit: &str) -> bool {
+        if self.src[self.pos..].starts_with(lit.as_bytes()) {
+            self.pos += lit.len();
+            return true;
+        }
+        return false;
+    }
+
+    fn value(&mut self) -> Result<Json, Box<Error>> {
+        self.ws();
+        if self.pos >= self.src.len() {
+            return self.error("unexpected end");
+        }
+
+        if self.eat("null") {
+            return Ok(Json::Null);
+        }
+        if self.eat("true") {
+            return Ok(Json::Bool(true));
+        }
+        if self.eat("false") {
+            return Ok(Json::Bool(false));
+        }
+
+        match self.src[self.pos] {
+            b'"' => return Ok(Json::Str(try!(self.string()))),
+            b'[' => {
+                self.pos += 1;
+                let mut a = vec![];
+                self.ws();
+                if self.eat("]") {
+                    return Ok(Json::Arr(a));
+                }
+                loop {
+                    a.push(try!(self.value()));
+                    self.ws();
+                    if self.eat("]") {
+                        return Ok(Json::Arr(a));
+                    }
+                    if !self.eat(",") {
+                        return self.error("expected ',' or ']'");
+                    }
+                }
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut fields = vec![];
+                self.ws();
+                if self.eat("}") {
+                    return Ok(Json::Obj(fields));
+                }
+                loop {
+                    self.ws();
+                    if self.pos >= self.src.len() || self.src[self.pos] != b'"' {
+                        return self.error("expected key");
+                    }
+                    let key = try!(self.string());
+                    self.ws();
+                    if !self.eat(":") {
+                        return self.error("expected ':'");
+                    }
+                    fields.push((key, try!(self.value())));
+                    self.ws();
+                    if self.eat("}") {
+                        return Ok(Json::Obj(fields));
+                    }
+                    if !self.eat(",") {
+                        return self.error("expected ',' or '}'");
+                    }
+                }
+            }
+            b'-' | b'0'..=b'9' => {
+                let start = self.pos;
+                while self.pos < self.src.len() && b"+-.eE0123456789".contains(&self.src[self.pos]) {
+                    self.pos += 1;
+                }
+                let s = String::from_utf8_lossy(&self.src[start..self.pos]).into_owned();
+                match s.parse() {
+                    Ok(n) => return Ok(Json::Num(n)),
+                    Err(_) => return self.error("bad number"),
+                }
+            }
+            _ => return self.error("unexpected character"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Box<Error>> {
+        self.pos += 1;
+        let mut s: Vec<u8> = vec![];
+        loop {
+            if self.pos >= self.src.len() {
+                return self.error("unterminated string");
+            }
+            let c = self.src[self.pos];
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    if self.pos >= self.src.len() {
+                        return self.error("unterminated string");
+                    }
+                    let e = self.src[self.pos];
+                    self.pos += 1;
+                    match e {
+                        b'n' => s.push(b'\n'),
+                        b't' => s.push(b'\t'),
+                        b'r' => s.push(b'\r'),
+                        b'b' => s.push(8),
+                        b'f' => s.push(12),
+                        b'u' => {
+                            let c = try!(self.hex4());
+                            // a surrogate pair spells one character in two escapes
+                            let c = if (0xd800..0xdc00).contains(&c) && self.eat("\\u") {
+                                let lo = try!(self.hex4());
+                                0x10000 + ((c - 0xd800) << 10) + (lo.wrapping_sub(0xdc00) & 0x3ff)
+                            } else {
+                                c
+                            };
+                            let c = ::std::char::from_u32(c).unwrap_or('\u{fffd}');
+                            let mut buf = [0; 4];
+                            s.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        }
+                        e => s.push(e),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+        match String::from_utf8(s) {
+            Ok(s) => return Ok(s),
+            Err(_) => return self.error("invalid utf-8"),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, Box<Error>> {
+        if self.pos + 4 > self.src.len() {
+            return self.error("bad \\u escape");
+        }
+        let s = String::from_utf8_lossy(&self.src[self.pos..self.pos + 4]).into_owned();
+        self.pos += 4;
+        match u32::from_str_radix(&s, 16) {
+            Ok(n) => return Ok(n),
+            Err(_) => return self.error("bad \\u escape"),
+        }
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    try!(write!(f, "\""));
+    for c in s.chars() {
+        match c {
+            '"' => try!(write!(f, "\\\"")),
+            '\\' => try!(write!(f, "\\\\")),
+            '\n' => try!(write!(f, "\\n")),
+            '\t' => try!(write!(f, "\\t")),
+            '\r' => try!(write!(f, "\\r")),
+            c if (c as u32) < 0x20 => try!(write!(f, "\\u{:04x}", c as u32)),
+            c => try!(write!(f, "{}", c)),
+        }
+    }
+    return write!(f, "\"");
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => return write!(f, "null"),
+            Json::Bool(b) => return write!(f, "{}", b),
+            Json::Num(n) => return write!(f, "{}", n),
+            Json::Str(ref s) => return write_str(f, s),
+            Json::Arr(ref a) => {
+                try!(write!(f, "["));
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, ","));
+                    }
+                    try!(write!(f, "{}", v));
+                }
+                return write!(f, "]");
+            }
+            Json::Obj(ref fields) => {
+                try!(write!(f, "{{"));
+                for (i, &(ref k, ref v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, ","));
+                    }
+                    try!(write_str(f, k));
+                    try!(write!(f, ":{}", v));
+                }
+                return write!(f, "}}");
+            }
+        }
+    }
+}
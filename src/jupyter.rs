@@ -0,0 +1,337 @@
+use data::{SECD, Lisp};
+use compiler::Compiler;
+use parser::Parser;
+use json::Json;
+use date::Date;
+use zmtp;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A minimal Jupyter kernel (`secd jupyter <connection file>`). Cells run one after another
+// on one machine, each top-level form compiled incrementally like a REPL entry, so a
+// define stays bound for the cells after it. What a cell puts is sent as its stdout
+// stream and the value of its last form as its result. Only kernel_info, execute and
+// shutdown requests are answered.
+
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+
+pub struct Message {
+    pub header: Json,
+    pub parent: Json,
+    pub metadata: Json,
+    pub content: Json,
+}
+
+impl Message {
+    pub fn msg_type(&self) -> &str {
+        return self.header.get("msg_type").and_then(|t| t.as_str()).unwrap_or("");
+    }
+
+    // the frames from the delimiter on, signed with `key`
+    pub fn encode(&self, key: &[u8]) -> Vec<Vec<u8>> {
+        let parts: Vec<Vec<u8>> = [&self.header, &self.parent, &self.metadata, &self.content]
+            .iter()
+            .map(|j| format!("{}", j).into_bytes())
+            .collect();
+        let mut frames = vec![DELIMITER.to_vec(), sign(key, &parts).into_bytes()];
+        frames.extend(parts);
+        return frames;
+    }
+
+    // a message after whatever identities come before its delimiter; the signature must
+    // be the one `key` makes
+    pub fn decode(frames: &[Vec<u8>], key: &[u8]) -> Result<Message, Box<Error>> {
+        let at = match frames.iter().position(|f| f.as_slice() == DELIMITER) {
+            Some(at) if frames.len() >= at + 6 => at,
+            _ => return Err(From::from("jupyter: malformed message")),
+        };
+        let parts = &frames[at + 2..at + 6];
+        if !key.is_empty() && sign(key, parts).as_bytes() != frames[at + 1].as_slice() {
+            return Err(From::from("jupyter: bad signature"));
+        }
+        let mut json = vec![];
+        for part in parts {
+            json.push(try!(Json::parse(&String::from_utf8_lossy(part))));
+        }
+        let mut json = json.into_iter();
+        return Ok(Message {
+                      header: json.next().unwrap(),
+                      parent: json.next().unwrap(),
+                      metadata: json.next().unwrap(),
+                      content: json.next().unwrap(),
+                  });
+    }
+}
+
+// the hex HMAC-SHA256 of the parts, or nothing when there is no key
+pub fn sign(key: &[u8], parts: &[Vec<u8>]) -> String {
+    if key.is_empty() {
+        return String::new();
+    }
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes a key of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    return mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+// where a message the kernel sends goes
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Channel {
+    Reply,
+    IOPub,
+}
+
+pub struct Kernel {
+    compiler: Compiler,
+    vm: SECD,
+    out: Rc<RefCell<String>>,
+    session: String,
+    count: usize,
+    sent: usize,
+    pub shutdown: bool,
+}
+
+impl Kernel {
+    pub fn new() -> Kernel {
+        let mut vm = SECD::new(vec![]);
+        let out = vm.capture();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        return Kernel {
+                   compiler: Compiler::new(),
+                   vm,
+                   out,
+                   session: format!("secd-{:x}", now),
+                   count: 0,
+                   sent: 0,
+                   shutdown: false,
+               };
+    }
+
+    // runs a cell's forms in order, stopping at the first error; what they put and the
+    // value of the last
+    pub fn execute(&mut self, code: &str) -> (String, Result<Option<Rc<Lisp>>, Box<Error>>) {
+        self.out.borrow_mut().clear();
+        let r = self.run_cell(code);
+        return (self.out.borrow().clone(), r);
+    }
+
+    fn run_cell(&mut self, code: &str) -> Result<Option<Rc<Lisp>>, Box<Error>> {
+        let mut value = None;
+        for ast in try!(Parser::new(&code.to_string()).parse_all()) {
+            let code = try!(self.compiler.compile_incremental(&ast));
+            value = Some(try!(self.vm.run_code(code)));
+        }
+        return Ok(value);
+    }
+
+    fn message(&mut self, msg_type: &str, parent: &Message, content: Json) -> Message {
+        self.sent += 1;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let date = Date::from_unix(now as i64).format("%Y-%m-%dT%H:%M:%SZ").unwrap_or_default();
+        return Message {
+                   header: Json::obj(vec![("msg_id", Json::Str(format!("{}-{}", self.session, self.sent))),
+                                          ("session", Json::str(&self.session)),
+                                          ("username", Json::str("kernel")),
+                                          ("date", Json::Str(date)),
+                                          ("msg_type", Json::str(msg_type)),
+                                          ("version", Json::str("5.3"))]),
+                   parent: parent.header.clone(),
+                   metadata: Json::obj(vec![]),
+                   content,
+               };
+    }
+
+    fn status(&mut self, state: &str, parent: &Message) -> (Channel, Message) {
+        let msg = self.message("status", parent, Json::obj(vec![("execution_state", Json::str(state))]));
+        return (Channel::IOPub, msg);
+    }
+
+    // the messages answering a request, in the order they are to be sent; the status
+    // goes busy before them and idle after
+    pub fn handle(&mut self, req: &Message) -> Vec<(Channel, Message)> {
+        let mut sent = vec![self.status("busy", req)];
+        match req.msg_type() {
+            "kernel_info_request" => {
+                let info = self.kernel_info();
+                let reply = self.message("kernel_info_reply", req, info);
+                sent.push((Channel::Reply, reply));
+            }
+            "execute_request" => sent.extend(self.handle_execute(req)),
+            "shutdown_request" => {
+                self.shutdown = true;
+                let restart = req.content.get("restart").cloned().unwrap_or(Json::Bool(false));
+                let content = Json::obj(vec![("status", Json::str("ok")), ("restart", restart)]);
+                let reply = self.message("shutdown_reply", req, content);
+                sent.push((Channel::Reply, reply));
+            }
+            _ => {}
+        }
+        sent.push(self.status("idle", req));
+        return sent;
+    }
+
+    fn kernel_info(&self) -> Json {
+        let language = Json::obj(vec![("name", Json::str("lisp")),
+                                      ("version", Json::str(env!("CARGO_PKG_VERSION"))),
+                                      ("mimetype", Json::str("text/x-lisp")),
+                                      ("file_extension", Json::str(".lisp"))]);
+        return Json::obj(vec![("status", Json::str("ok")),
+                              ("protocol_version", Json::str("5.3")),
+                              ("implementation", Json::str("secd")),
+                              ("implementation_version", Json::str(env!("CARGO_PKG_VERSION"))),
+                              ("language_info", language),
+                              ("banner", Json::str("SECD machine"))]);
+    }
+
+    fn handle_execute(&mut self, req: &Message) -> Vec<(Channel, Message)> {
+        let code = req.content.get("code").and_then(|c| c.as_str()).unwrap_or("").to_string();
+        let silent = req.content.get("silent").and_then(|s| s.as_bool()).unwrap_or(false);
+        if !silent {
+            self.count += 1;
+        }
+        let count = Json::Num(self.count as f64);
+        let mut sent = vec![];
+
+        let input = Json::obj(vec![("code", Json::str(&code)), ("execution_count", count.clone())]);
+        sent.push((Channel::IOPub, self.message("execute_input", req, input)));
+
+        let (out, r) = self.execute(&code);
+        if !out.is_empty() && !silent {
+            let stream = Json::obj(vec![("name", Json::str("stdout")), ("text", Json::Str(out))]);
+            sent.push((Channel::IOPub, self.message("stream", req, stream)));
+        }
+        let reply = match r {
+            Ok(value) => {
+                if let (Some(value), false) = (value, silent) {
+                    let data = Json::obj(vec![("text/plain", Json::Str(format!("{}", value)))]);
+                    let result = Json::obj(vec![("execution_count", count.clone()),
+                                                ("data", data),
+                                                ("metadata", Json::obj(vec![]))]);
+                    sent.push((Channel::IOPub, self.message("execute_result", req, result)));
+                }
+                Json::obj(vec![("status", Json::str("ok")),
+                               ("execution_count", count),
+                               ("user_expressions", Json::obj(vec![]))])
+            }
+            Err(e) => {
+                let error = vec![("ename", Json::str("error")),
+                                 ("evalue", Json::Str(format!("{}", e))),
+                                 ("traceback", Json::Arr(vec![Json::Str(format!("{}", e))]))];
+                sent.push((Channel::IOPub, self.message("error", req, Json::obj(error.clone()))));
+                let mut reply = vec![("status", Json::str("error")), ("execution_count", count)];
+                reply.extend(error);
+                Json::obj(reply)
+            }
+        };
+        sent.push((Channel::Reply, self.message("execute_reply", req, reply)));
+        return sent;
+    }
+}
+
+// accepts connections on `listener` for good, shaking hands as `socket_type` and handing
+// each stream to `f` on a thread of its own
+fn accept<F>(listener: TcpListener, socket_type: &'static str, f: F)
+    where F: Fn(TcpStream) + Send + Sync + 'static
+{
+    let f = Arc::new(f);
+    thread::spawn(move || for stream in listener.incoming() {
+                      let mut stream = match stream {
+                          Ok(stream) => stream,
+                          Err(_) => continue,
+                      };
+                      let f = f.clone();
+                      thread::spawn(move || if zmtp::handshake(&mut stream, socket_type).is_ok() {
+                                        f(stream)
+                                    });
+                  });
+}
+
+fn port(conn: &Json, name: &str) -> Result<i64, Box<Error>> {
+    match conn.get(name).and_then(|p| p.as_i64()) {
+        Some(port) => return Ok(port),
+        None => return Err(From::from(format!("jupyter: connection file has no {}", name))),
+    }
+}
+
+// serves the kernel on the ports of a connection file until a shutdown request
+pub fn serve(connection_file: &str) -> Result<(), Box<Error>> {
+    let conn = try!(Json::parse(&try!(fs::read_to_string(connection_file))));
+    let ip = conn.get("ip").and_then(|i| i.as_str()).unwrap_or("127.0.0.1").to_string();
+    let key = conn.get("key").and_then(|k| k.as_str()).unwrap_or("").as_bytes().to_vec();
+    let bind = |name: &str| -> Result<TcpListener, Box<Error>> {
+        return Ok(try!(TcpListener::bind((ip.as_str(), try!(port(&conn, name)) as u16))));
+    };
+
+    // requests from every shell and control connection, with the stream to reply on
+    let (requests, incoming) = mpsc::channel::<(Vec<Vec<u8>>, TcpStream)>();
+    for name in &["shell_port", "control_port"] {
+        let requests = Mutex::new(requests.clone());
+        accept(try!(bind(name)), "ROUTER", move |mut stream| {
+            while let Ok(Some(frames)) = zmtp::read_message(&mut stream) {
+                let reply = match stream.try_clone() {
+                    Ok(reply) => reply,
+                    Err(_) => return,
+                };
+                if requests.lock().unwrap().send((frames, reply)).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    // input requests are not supported; clients still connect
+    accept(try!(bind("stdin_port")), "ROUTER", |mut stream| {
+        while let Ok(Some(_)) = zmtp::read_message(&mut stream) {}
+    });
+    accept(try!(bind("hb_port")), "REP", |mut stream| {
+        while let Ok(Some(frames)) = zmtp::read_message(&mut stream) {
+            if zmtp::write_message(&mut stream, &frames).is_err() {
+                return;
+            }
+        }
+    });
+    let subscribers = Arc::new(Mutex::new(vec![]));
+    let subscribe = subscribers.clone();
+    accept(try!(bind("iopub_port")), "PUB", move |mut stream| {
+        if let Ok(writer) = stream.try_clone() {
+            subscribe.lock().unwrap().push(writer);
+        }
+        // subscriptions come in as messages; everything goes to everyone anyway
+        while let Ok(Some(_)) = zmtp::read_message(&mut stream) {}
+    });
+
+    let mut kernel = Kernel::new();
+    while !kernel.shutdown {
+        let (frames, mut stream) = match incoming.recv() {
+            Ok(request) => request,
+            Err(_) => break,
+        };
+        let req = match Message::decode(&frames, &key) {
+            Ok(req) => req,
+            Err(_) => continue,
+        };
+        for (channel, msg) in kernel.handle(&req) {
+            match channel {
+                Channel::Reply => {
+                    let _ = zmtp::write_message(&mut stream, &msg.encode(&key));
+                }
+                Channel::IOPub => {
+                    let mut topic = vec![format!("kernel.{}", msg.msg_type()).into_bytes()];
+                    topic.extend(msg.encode(&key));
+                    subscribers.lock().unwrap().retain(|s| zmtp::write_message(&mut &*s, &topic).is_ok());
+                }
+            }
+        }
+    }
+    return Ok(());
+}
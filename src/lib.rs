@@ -1,19 +1,128 @@
+#[cfg(feature = "logging")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "http")]
+extern crate ureq;
+#[cfg(feature = "jit")]
+extern crate cranelift_codegen;
+#[cfg(feature = "jit")]
+extern crate cranelift_frontend;
+#[cfg(feature = "jit")]
+extern crate cranelift_jit;
+#[cfg(feature = "jit")]
+extern crate cranelift_module;
+#[cfg(feature = "jit")]
+extern crate cranelift_native;
+#[cfg(feature = "wasm")]
+extern crate wasm_encoder;
+#[cfg(feature = "jupyter")]
+extern crate hmac;
+#[cfg(feature = "jupyter")]
+extern crate sha2;
+extern crate smallvec;
+
+// without the logging feature the log macros compile to nothing; the arguments
+// are still type checked so both builds see the same variables used
+#[cfg(not(feature = "logging"))]
+macro_rules! debug {
+    ($($t:tt)*) => {
+        if false {
+            let _ = format_args!($($t)*);
+        }
+    };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! trace {
+    ($($t:tt)*) => {
+        if false {
+            let _ = format_args!($($t)*);
+        }
+    };
+}
+
 pub mod data;
+pub mod diagnostic;
+pub mod json;
+pub mod lsp;
+pub mod dap;
+#[cfg(feature = "jupyter")]
+pub mod zmtp;
+#[cfg(feature = "jupyter")]
+pub mod jupyter;
+pub mod sourcemap;
+pub mod prelude;
+pub mod date;
+pub mod condition;
+pub mod types;
+pub mod cse;
+pub mod coverage;
+pub mod trace;
+pub mod serialize;
+pub mod serve;
+pub mod teach;
+pub mod graph;
+pub mod transpile;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 pub mod parser;
 pub mod compiler;
+pub mod link;
 pub mod vm;
+pub mod scheduler;
+pub mod interp;
+pub mod fuzz;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "jit")]
+pub mod jit;
 
-pub use data::{SECD, Lisp};
+pub use data::{SECD, Lisp, RunResult, Capabilities, Output, Effect, TestResult, Hits, Stats, Trace};
+pub use diagnostic::Diagnostic;
 pub use parser::Parser;
-pub use compiler::Compiler;
+pub use compiler::{Compiler, CompiledProgram};
+pub use scheduler::Scheduler;
+pub use vm::Primitives;
 
 use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::panic;
+use std::sync::Mutex;
+use std::thread;
+#[cfg(feature = "logging")]
+use std::time::Instant;
+
+// logs how long one phase of running a program took
+#[cfg(feature = "logging")]
+fn phase<T, F: FnOnce() -> T>(name: &str, f: F) -> T {
+    let started = Instant::now();
+    let r = f();
+    debug!("{} took {:?}", name, started.elapsed());
+    return r;
+}
+
+#[cfg(not(feature = "logging"))]
+fn phase<T, F: FnOnce() -> T>(_: &str, f: F) -> T {
+    return f();
+}
+
+// a compiler whose features include the capabilities the program will run with
+fn compiler(caps: Capabilities) -> Compiler {
+    let mut c = Compiler::new();
+    c.features.extend(caps.features().into_iter().map(String::from));
+    return c;
+}
 
 pub fn run_lisp(s: &String) -> Result<Rc<Lisp>, Box<Error>> {
-    return SECD::new(try!(Compiler::new().compile(&try!(Parser::new(s).parse())))).run();
+    let ast = try!(phase("parse", || Parser::new(s).parse()));
+    let code = try!(phase("compile", || Compiler::new().compile(&ast)));
+    return phase("run", || SECD::new(code).run());
 }
 
 pub fn run_lisp_file(s: &String) -> Result<Rc<Lisp>, Box<Error>> {
@@ -22,3 +131,259 @@ pub fn run_lisp_file(s: &String) -> Result<Rc<Lisp>, Box<Error>> {
     try!(fh.read_to_string(&mut src));
     return run_lisp(&src);
 }
+
+// the program as Rust source for a standalone binary, for `secd build`
+pub fn build_lisp(s: &String) -> Result<String, Box<Error>> {
+    let ast = try!(phase("parse", || Parser::new(s).parse()));
+    let code = try!(phase("compile", || Compiler::new().compile(&ast)));
+    return phase("build", || transpile::rust(&code));
+}
+
+// the program as a WebAssembly module, for `secd build` with a .wasm output
+#[cfg(feature = "wasm")]
+pub fn build_wasm(s: &String) -> Result<Vec<u8>, Box<Error>> {
+    let ast = try!(phase("parse", || Parser::new(s).parse()));
+    let code = try!(phase("compile", || Compiler::new().compile(&ast)));
+    return phase("build", || wasm::module(&code));
+}
+
+// the program with its desugarings done, for `secd expand`; the names the compiler
+// makes up are printed without their spaces so the result reads as source
+pub fn expand_lisp(s: &String) -> Result<String, Box<Error>> {
+    let ast = try!(phase("parse", || Parser::new(s).parse()));
+    let expanded = try!(phase("expand", || Compiler::new().expand(&ast)));
+    return Ok(format!("{}", readable(expanded)));
+}
+
+fn readable(ast: data::AST) -> data::AST {
+    let sexpr = match ast.sexpr {
+        data::SExpr::Atom(id) => data::SExpr::Atom(id.trim_start().replace(' ', "-")),
+        data::SExpr::List(ls) => data::SExpr::List(ls.into_iter().map(readable).collect()),
+        sexpr => sexpr,
+    };
+    return data::AST { info: ast.info, sexpr };
+}
+
+pub fn eval_lisp(s: &String) -> Result<RunResult, Box<Error>> {
+    return eval_lisp_with(s, Capabilities::default());
+}
+
+// only parses and type checks, for --typecheck; the program is not run
+pub fn typecheck_lisp(s: &String) -> Result<types::Type, Box<Error>> {
+    let ast = try!(phase("parse", || Parser::new(s).parse()));
+    return phase("typecheck", || types::check(&ast));
+}
+
+pub fn eval_lisp_with(s: &String, caps: Capabilities) -> Result<RunResult, Box<Error>> {
+    let program = try!(compile_lisp(s, caps));
+    return eval_code_with(program.code, caps);
+}
+
+// the program compiled with its warnings, for a host that shows them before running it
+pub fn compile_lisp(s: &String, caps: Capabilities) -> Result<CompiledProgram, Box<Error>> {
+    let ast = try!(phase("parse", || Parser::new(s).parse()));
+    return phase("compile", || compiler(caps).compile_program(&ast));
+}
+
+pub fn eval_code_with(code: data::Code, caps: Capabilities) -> Result<RunResult, Box<Error>> {
+    let mut vm = SECD::new(code);
+    vm.capabilities = caps;
+    return phase("run", || Scheduler::new().run(vm));
+}
+
+// one file of a whole program, for link::link; `name` is the file, for its messages
+pub fn compile_unit(name: &str, s: &String, caps: Capabilities) -> Result<link::Unit, Box<Error>> {
+    let forms = try!(phase("parse", || Parser::new(s).parse_all()));
+    return phase("compile", || compiler(caps).compile_unit(name, &forms));
+}
+
+// links the units into one program and runs it
+pub fn eval_units_with(units: &[link::Unit], caps: Capabilities) -> Result<RunResult, Box<Error>> {
+    let code = try!(phase("link", || link::link(units)));
+    let mut vm = SECD::new(code);
+    vm.capabilities = caps;
+    return phase("run", || Scheduler::new().run(vm));
+}
+
+// compiles and runs a program under the scheduler for the runs below that report on
+// how it ran: `watch` sets the machine up and gives what to read once the program stops,
+// and `report` reads it. The result and the report, or `none` if the program never ran
+fn instrumented<W, T, S, R>(s: &String, caps: Capabilities, none: T, watch: S, report: R) -> (Result<RunResult, Box<Error>>, T)
+    where S: FnOnce(&mut SECD) -> W,
+          R: FnOnce(W, &CompiledProgram) -> T
+{
+    let ast = match Parser::new(s).parse() {
+        Ok(ast) => ast,
+        Err(e) => return (Err(e), none),
+    };
+    let program = match compiler(caps).compile_program(&ast) {
+        Ok(program) => program,
+        Err(e) => return (Err(e), none),
+    };
+    let mut vm = SECD::new(program.code.clone());
+    vm.capabilities = caps;
+    let watched = watch(&mut vm);
+    let r = Scheduler::new().run(vm);
+    return (r, report(watched, &program));
+}
+
+// runs a file of test forms, for `secd test`: the tests that ran, and the error that
+// stopped the file before its end if one did
+pub fn test_lisp(s: &String, caps: Capabilities) -> (Vec<TestResult>, Option<Box<Error>>) {
+    let report = |tests: Rc<RefCell<Vec<TestResult>>>, _: &CompiledProgram| tests.borrow_mut().drain(..).collect();
+    let (r, tests) = instrumented(s, caps, vec![], |vm| vm.tests.clone(), report);
+    return (tests, r.err());
+}
+
+// runs a program counting what ran, for `--coverage`: the result and the times each
+// line with code on it ran, as far as the program got
+pub fn cover_lisp(s: &String, caps: Capabilities) -> (Result<RunResult, Box<Error>>, BTreeMap<usize, usize>) {
+    let watch = |vm: &mut SECD| {
+        let hits = Rc::new(RefCell::new(Hits::default()));
+        vm.coverage = Some(hits.clone());
+        hits
+    };
+    let report = |hits: Rc<RefCell<Hits>>, program: &CompiledProgram| {
+        coverage::lines(&program.code, &program.source_map, &hits.borrow().counts)
+    };
+    return instrumented(s, caps, BTreeMap::new(), watch, report);
+}
+
+// runs a program timing its calls, for `--trace`: the result and the trace as far as the
+// program got, with the calls still in progress ended there
+pub fn trace_lisp(s: &String, caps: Capabilities) -> (Result<RunResult, Box<Error>>, String) {
+    let watch = |vm: &mut SECD| {
+        vm.trace = Some(Trace::new());
+        vm.trace.as_ref().unwrap().events.clone()
+    };
+    let report = |events: Rc<RefCell<Vec<data::TraceEvent>>>, _: &CompiledProgram| {
+        let mut events = events.borrow().clone();
+        let mut open = vec![];
+        for e in &events {
+            if e.begin {
+                open.push(e.clone());
+            } else if let Some(i) = open.iter().rposition(|o| o.tid == e.tid) {
+                open.remove(i);
+            }
+        }
+        let end = events.last().map_or(0, |e| e.micros);
+        for e in open.into_iter().rev() {
+            events.push(data::TraceEvent { begin: false, micros: end, ..e });
+        }
+        trace::chrome(&events)
+    };
+    return instrumented(s, caps, trace::chrome(&[]), watch, report);
+}
+
+// runs a program counting what it made, for `--stats`: the result and the counts as
+// far as the program got
+pub fn stats_lisp(s: &String, caps: Capabilities) -> (Result<RunResult, Box<Error>>, Stats) {
+    let watch = |vm: &mut SECD| {
+        vm.keep_stats();
+        vm.counters.clone()
+    };
+    let report = |stats: Option<Rc<RefCell<Stats>>>, _: &CompiledProgram| {
+        stats.map(|s| s.borrow().clone()).unwrap_or_default()
+    };
+    return instrumented(s, caps, Stats::default(), watch, report);
+}
+
+// runs a program reporting any call that takes more than `steps` steps, for
+// `--call-budget`: the result and the warnings, or an error in their place when `fatal`
+pub fn budget_lisp(s: &String, caps: Capabilities, steps: usize, fatal: bool) -> (Result<RunResult, Box<Error>>, Vec<Diagnostic>) {
+    let watch = |vm: &mut SECD| vm.limit_calls(steps, fatal);
+    let report = |warnings: Rc<RefCell<Vec<Diagnostic>>>, _: &CompiledProgram| warnings.borrow().clone();
+    return instrumented(s, caps, vec![], watch, report);
+}
+
+// the program's compiled code as a Graphviz graph, for `secd graph`
+pub fn graph_code_lisp(s: &String) -> Result<String, Box<Error>> {
+    let ast = try!(Parser::new(s).parse());
+    return Ok(graph::code(&try!(Compiler::new().compile(&ast))));
+}
+
+// the value the program ends with as a Graphviz graph, for `secd graph --value`
+pub fn graph_value_lisp(s: &String, caps: Capabilities) -> Result<String, Box<Error>> {
+    match try!(eval_lisp_with(s, caps)) {
+        RunResult::Value(a) | RunResult::Yield(a) => return Ok(graph::value(&a)),
+        RunResult::Exit(n) => return Err(From::from(format!("the program exited with {}", n))),
+    }
+}
+
+// runs a program printing the registers after every instruction to `out`, for `--teach`
+pub fn teach_lisp<W: Write>(s: &String, caps: Capabilities, out: &mut W) -> Result<RunResult, Box<Error>> {
+    let ast = try!(Parser::new(s).parse());
+    let code = try!(compiler(caps).compile(&ast));
+    let mut vm = SECD::new(code);
+    vm.capabilities = caps;
+    return teach::run(&mut vm, out);
+}
+
+// values hold Rc and cannot leave the thread that made them, so run_many's results are
+// printed
+#[derive(Debug, PartialEq, Clone)]
+pub enum Outcome {
+    Value(String),
+    Exit(i32),
+    Yield(String),
+}
+
+// runs independent compiled programs on a pool of OS threads, one machine per program;
+// results come back in the order of `programs`. Code holds its constants and procedure
+// info in Rc and so cannot be sent to another thread: each program crosses in the binary
+// form of serialize::write_code and is read back on the worker that runs it, so it can
+// only call the standard primitives
+pub fn run_many(programs: Vec<data::Code>, caps: Capabilities) -> Vec<Result<Outcome, String>> {
+    let primitives = Primitives::standard();
+    let programs: Vec<Result<Vec<u8>, String>> = programs.iter()
+        .map(|code| serialize::write_code(code, &primitives).map_err(|e| format!("{}", e)))
+        .collect();
+    let workers = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let jobs = Mutex::new(programs.into_iter().enumerate());
+    let results = Mutex::new(vec![]);
+
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| loop {
+                let job = jobs.lock().unwrap().next();
+                let (i, bytes) = match job {
+                    Some(job) => job,
+                    None => break,
+                };
+                let r = match panic::catch_unwind(|| bytes.and_then(|bytes| eval_outcome(&bytes, caps))) {
+                    Ok(r) => r,
+                    Err(_) => Err("vm panic".to_string()),
+                };
+                results.lock().unwrap().push((i, r));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|r| r.0);
+    return results.into_iter().map(|r| r.1).collect();
+}
+
+fn eval_outcome(bytes: &[u8], caps: Capabilities) -> Result<Outcome, String> {
+    let code = match serialize::read_code(bytes, &Primitives::standard()) {
+        Ok(code) => code,
+        Err(e) => return Err(format!("{}", e)),
+    };
+    match eval_code_with(code, caps) {
+        Ok(RunResult::Value(a)) => return Ok(Outcome::Value(format!("{}", a))),
+        Ok(RunResult::Exit(n)) => return Ok(Outcome::Exit(n)),
+        Ok(RunResult::Yield(a)) => return Ok(Outcome::Yield(format!("{}", a))),
+        Err(e) => return Err(format!("{}", e)),
+    }
+}
+
+pub fn eval_lisp_file(s: &String) -> Result<RunResult, Box<Error>> {
+    return eval_lisp_file_with(s, Capabilities::default());
+}
+
+pub fn eval_lisp_file_with(s: &String, caps: Capabilities) -> Result<RunResult, Box<Error>> {
+    let mut fh = try!(File::open(s));
+    let mut src = String::new();
+    try!(fh.read_to_string(&mut src));
+    return eval_lisp_with(&src, caps);
+}
@@ -0,0 +1,166 @@
+use data::{Code, CodeOP, CodeOPInfo, Handler, Header, Info, Lisp};
+use diagnostic::{Diagnostic, Diagnostics};
+use vm;
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::rc::Rc;
+
+// Whole-program mode: each file compiles on its own to a Unit (Compiler::compile_unit),
+// the defines it exports and the names it imports, and link puts the units together
+// into one program. The pools of the units become one pool, their code relocated to
+// match. The defines are bound unit by unit, a unit after those it imports from unless
+// they import from it too, and the program's one entry expression runs last, seeing
+// them all.
+
+pub struct Unit {
+    // the file, for messages
+    pub name: String,
+    pub pool: Vec<Rc<Lisp>>,
+    // the prelude definitions the unit uses, each with where it is and its value's code
+    pub prelude: Vec<(String, Info, Code)>,
+    // likewise for the unit's own defines, in order
+    pub defines: Vec<(String, Info, Code)>,
+    // the names the unit uses without binding, each where it's first used
+    pub imports: Vec<(String, Info)>,
+    pub entry: Option<Code>,
+}
+
+fn place(unit: &Unit, at: Info) -> String {
+    return format!("{}:{}:{}", unit.name, at[0], at[1]);
+}
+
+// `code` with its constants at `base` further into the pool
+fn relocate(code: &Code, base: usize) -> Code {
+    let op = |op: &CodeOP| match *op {
+        CodeOP::LDC(i) => CodeOP::LDC(i + base),
+        CodeOP::SEL(ref t, ref f) => CodeOP::SEL(relocate(t, base), relocate(f, base)),
+        CodeOP::TSEL(ref t, ref f) => CodeOP::TSEL(relocate(t, base), relocate(f, base)),
+        CodeOP::PROTECT(ref body, ref cleanup) => CodeOP::PROTECT(relocate(body, base), relocate(cleanup, base)),
+        CodeOP::LDF(ref args, ref body, ref info) => CodeOP::LDF(args.clone(), relocate(body, base), info.clone()),
+        CodeOP::TRY(ref body, ref handlers) => {
+            let handlers = handlers.iter()
+                .map(|h| {
+                         Handler {
+                             kind: h.kind.clone(),
+                             id: h.id.clone(),
+                             code: relocate(&h.code, base),
+                         }
+                     })
+                .collect();
+            CodeOP::TRY(relocate(body, base), handlers)
+        }
+        ref op => op.clone(),
+    };
+    return code.iter().map(|c| CodeOPInfo { info: c.info, op: op(&c.op) }).collect();
+}
+
+// a lambda lifted into the pool loads constants from it too
+fn relocate_value(a: &Rc<Lisp>, base: usize) -> Rc<Lisp> {
+    match **a {
+        Lisp::Closure(ref args, ref body, ref env, ref info) => {
+            return Rc::new(Lisp::Closure(args.clone(), relocate(body, base), env.clone(), info.clone()))
+        }
+        _ => return a.clone(),
+    }
+}
+
+// the units in the order their defines are bound
+fn order(units: &[Unit], defined: &HashMap<&str, (usize, Info)>) -> Vec<usize> {
+    fn visit(u: usize, units: &[Unit], defined: &HashMap<&str, (usize, Info)>, seen: &mut Vec<bool>, out: &mut Vec<usize>) {
+        if seen[u] {
+            return;
+        }
+        // marked before its imports, so a cycle stops here
+        seen[u] = true;
+        for &(ref name, _) in units[u].imports.iter() {
+            if let Some(&(v, _)) = defined.get(name.as_str()) {
+                visit(v, units, defined, seen, out);
+            }
+        }
+        out.push(u);
+    }
+    let mut seen = vec![false; units.len()];
+    let mut out = vec![];
+    for u in 0..units.len() {
+        visit(u, units, defined, &mut seen, &mut out);
+    }
+    return out;
+}
+
+// one program of the units: a name defined twice, one used but defined nowhere and
+// anything but one entry expression are errors
+pub fn link(units: &[Unit]) -> Result<Code, Box<Error>> {
+    let mut errors = vec![];
+    let mut defined: HashMap<&str, (usize, Info)> = HashMap::new();
+    for (u, unit) in units.iter().enumerate() {
+        for &(ref name, at, _) in unit.defines.iter() {
+            match defined.get(name.as_str()) {
+                Some(&(v, first)) => {
+                    errors.push(format!("{} is defined in both {} and {}",
+                                        name,
+                                        place(&units[v], first),
+                                        place(unit, at)))
+                }
+                None => {
+                    defined.insert(name, (u, at));
+                }
+            }
+        }
+    }
+    for unit in units {
+        for &(ref name, at) in unit.imports.iter() {
+            if !defined.contains_key(name.as_str()) {
+                errors.push(format!("{} is used at {} but no unit defines it", name, place(unit, at)));
+            }
+        }
+    }
+    let entries: Vec<&Unit> = units.iter().filter(|u| u.entry.is_some()).collect();
+    match entries.len() {
+        0 => errors.push("no unit has an entry expression".to_string()),
+        1 => {}
+        _ => errors.push(format!("{} and {} both have an entry expression", entries[0].name, entries[1].name)),
+    }
+    let mut errors: Vec<Diagnostic> = errors.into_iter().map(|e| Diagnostic::error("link", None, e)).collect();
+    match errors.len() {
+        0 => {}
+        1 => return Err(From::from(errors.remove(0))),
+        _ => return Err(From::from(Diagnostics(errors))),
+    }
+
+    let mut code = vec![];
+    let mut pool = vec![];
+    let mut entry = vec![];
+    let mut bound = HashSet::new();
+    for u in order(units, &defined) {
+        let unit = &units[u];
+        let base = pool.len();
+        pool.extend(unit.pool.iter().map(|a| relocate_value(a, base)));
+        // a unit's define of a prelude name is the whole program's
+        let prelude = unit.prelude.iter().filter(|p| !defined.contains_key(p.0.as_str()) && bound.insert(p.0.clone()));
+        for &(ref name, at, ref value) in prelude.chain(unit.defines.iter()) {
+            code.extend(relocate(value, base));
+            code.push(CodeOPInfo {
+                          info: at,
+                          op: CodeOP::LET(name.clone()),
+                      });
+        }
+        if let Some(ref value) = unit.entry {
+            entry = relocate(value, base);
+        }
+    }
+    code.extend(entry);
+
+    let info = code.first().map(|c| c.info).unwrap_or([0, 0]);
+    code.insert(0, CodeOPInfo { info, op: CodeOP::CONSTS(Rc::new(pool)) });
+    let features = vm::features(&code);
+    code.insert(0,
+                CodeOPInfo {
+                    info,
+                    op: CodeOP::HEADER(Header {
+                                           version: vm::VERSION,
+                                           features,
+                                       }),
+                });
+    return Ok(code);
+}
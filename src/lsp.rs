@@ -0,0 +1,365 @@
+use data::{self, AST, SExpr, CodeOP, Info};
+use diagnostic::{self, Diagnostic, Severity};
+use json::{Json, read_message, write_message};
+use parser::Parser;
+use compiler::{self, Compiler};
+use types;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, Write};
+
+// A language server over stdin/stdout (`secd lsp`). The analyses below work on
+// source text and 1-based [line, column] positions like the parser's Info; the
+// protocol side converts from and to the editor's 0-based positions.
+
+type Scope = Vec<(String, Info)>;
+
+// errors and warnings a compile finds, without running anything
+pub fn diagnostics(src: &str) -> Vec<Diagnostic> {
+    let ast = match Parser::new(&src.to_string()).parse() {
+        Ok(ast) => ast,
+        Err(e) => return vec![diagnostic::from_error(&*e)],
+    };
+    match Compiler::new().compile_program(&ast) {
+        Ok(p) => return p.warnings,
+        Err(e) => return diagnostic::all_from_error(&*e),
+    }
+}
+
+// the instructions the innermost expression at `pos` compiles to, with each LDC
+// showing the constant it loads rather than its index in the pool
+pub fn hover(src: &str, pos: Info) -> Option<String> {
+    let ast = match Parser::new(&src.to_string()).parse() {
+        Ok(ast) => ast,
+        Err(_) => return None,
+    };
+    let code = find(src, &ast, pos).and_then(|(node, _)| Compiler::new().compile(node).ok());
+    return code.map(|code| {
+        let pool = data::pool(&code);
+        let ops: Vec<String> = code.iter()
+            .filter_map(|c| match c.op {
+                CodeOP::CONSTS(_) | CodeOP::HEADER(_) => None,
+                CodeOP::LDC(i) => Some(format!("LDC({:?})", pool[i])),
+                ref op => Some(format!("{:?}", op)),
+            })
+            .collect();
+        format!("```\n{}\n```", ops.join("\n"))
+    });
+}
+
+// where the name at `pos` was bound by lambda, let, letrec or do
+pub fn definition(src: &str, pos: Info) -> Option<Info> {
+    let ast = match Parser::new(&src.to_string()).parse() {
+        Ok(ast) => ast,
+        Err(_) => return None,
+    };
+    return find(src, &ast, pos).and_then(|(node, scope)| match node.sexpr {
+        SExpr::Atom(ref id) => scope.iter().rev().find(|b| b.0 == *id).map(|b| b.1),
+        _ => None,
+    });
+}
+
+// names bound at `pos`, innermost first, then the special forms
+pub fn completion(src: &str, pos: Info) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    if let Ok(ast) = Parser::new(&src.to_string()).parse() {
+        if let Some((_, scope)) = find(src, &ast, pos) {
+            for &(ref id, _) in scope.iter().rev() {
+                if !names.contains(id) {
+                    names.push(id.clone());
+                }
+            }
+        }
+    }
+    for k in compiler::keywords() {
+        if !names.iter().any(|n| n == k) {
+            names.push(k.to_string());
+        }
+    }
+    return names;
+}
+
+fn offset(src: &str, pos: Info) -> usize {
+    let mut off = 0;
+    for (i, line) in src.split('\n').enumerate() {
+        if i + 1 == pos[0] {
+            return off + (pos[1] - 1).min(line.len());
+        }
+        off += line.len() + 1;
+    }
+    return src.len();
+}
+
+// the end of the expression starting at `start`, found from the text since the
+// AST only records where a node begins
+fn end_of(src: &[u8], start: usize) -> usize {
+    let mut i = start;
+    let mut depth = 0;
+    while i < src.len() {
+        match src[i] {
+            b'"' => {
+                i += 1;
+                while i < src.len() && src[i] != b'"' {
+                    i += if src[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+                if depth == 0 {
+                    return i.min(src.len());
+                }
+                continue;
+            }
+            b'(' => depth += 1,
+            b')' => {
+                if depth <= 1 {
+                    return i + 1;
+                }
+                depth -= 1;
+            }
+            b' ' | b'\n' if depth == 0 => return i,
+            _ => {}
+        }
+        i += 1;
+    }
+    return src.len();
+}
+
+fn atoms(ast: &AST) -> Scope {
+    match ast.sexpr {
+        SExpr::Atom(ref id) => return vec![(id.clone(), ast.info)],
+        SExpr::List(ref ls) => {
+            let mut r = vec![];
+            for a in ls {
+                // an annotated parameter, (x : int)
+                let a = match a.sexpr {
+                    SExpr::List(ref p) if types::param(a).is_some() => &p[0],
+                    _ => a,
+                };
+                if let SExpr::Atom(ref id) = a.sexpr {
+                    r.push((id.clone(), a.info));
+                }
+            }
+            return r;
+        }
+        _ => return vec![],
+    }
+}
+
+// what the binding form `ls` adds to the scope of its i-th element
+fn bound_in(ls: &Vec<AST>, i: usize) -> Scope {
+    let head = match ls.first().map(|a| &a.sexpr) {
+        Some(&SExpr::Atom(ref id)) => id.as_str(),
+        _ => return vec![],
+    };
+    match head {
+        "lambda" if (ls.len() == 3 || ls.len() == 4) && i >= 1 => return atoms(&ls[1]),
+        "let" if ls.len() == 4 && (i == 1 || i == 3) => return atoms(&ls[1]),
+        "letrec" if ls.len() == 4 && i >= 1 => return atoms(&ls[1]),
+        "define/contract" if ls.len() == 5 && i >= 1 => return atoms(&ls[1]),
+        "do" if ls.len() >= 3 && i >= 1 => {
+            let mut r = vec![];
+            if let SExpr::List(ref vars) = ls[1].sexpr {
+                for var in vars {
+                    if let SExpr::List(ref v) = var.sexpr {
+                        r.extend(v.first().map(atoms).unwrap_or_default());
+                    }
+                }
+            }
+            return r;
+        }
+        _ => return vec![],
+    }
+}
+
+// the innermost node around `pos` and the names visible there
+fn find<'a>(src: &str, ast: &'a AST, pos: Info) -> Option<(&'a AST, Scope)> {
+    return find_(src, ast, offset(src, pos), &mut vec![]);
+}
+
+fn find_<'a>(src: &str, ast: &'a AST, pos: usize, scope: &mut Scope) -> Option<(&'a AST, Scope)> {
+    let start = offset(src, ast.info);
+    if pos < start || pos > end_of(src.as_bytes(), start) {
+        return None;
+    }
+
+    if let SExpr::List(ref ls) = ast.sexpr {
+        for (i, child) in ls.iter().enumerate() {
+            let extra = bound_in(ls, i);
+            let n = extra.len();
+            scope.extend(extra);
+            let found = find_(src, child, pos, scope);
+            let len = scope.len();
+            scope.truncate(len - n);
+            if found.is_some() {
+                return found;
+            }
+        }
+    }
+    return Some((ast, scope.clone()));
+}
+
+fn lsp_position(src: &str, off: usize) -> Json {
+    let before = &src[..off.min(src.len())];
+    let line = before.matches('\n').count();
+    let col = before.len() - before.rfind('\n').map_or(0, |i| i + 1);
+    return Json::obj(vec![("line", Json::Num(line as f64)), ("character", Json::Num(col as f64))]);
+}
+
+// the whole expression starting at `info`, at least one character wide
+fn range(src: &str, info: Info) -> Json {
+    let start = offset(src, info);
+    let end = end_of(src.as_bytes(), start).max(start + 1);
+    return Json::obj(vec![("start", lsp_position(src, start)), ("end", lsp_position(src, end))]);
+}
+
+fn position(params: &Json) -> Option<Info> {
+    let line = params.at(&["position", "line"]).and_then(|l| l.as_i64());
+    let col = params.at(&["position", "character"]).and_then(|c| c.as_i64());
+    match (line, col) {
+        (Some(l), Some(c)) if l >= 0 && c >= 0 => return Some([l as usize + 1, c as usize + 1]),
+        _ => return None,
+    }
+}
+
+pub struct Server {
+    docs: HashMap<String, String>,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        return Server { docs: HashMap::new() };
+    }
+
+    fn publish(&self, uri: &str) -> Json {
+        let src = self.docs.get(uri).map(|s| s.as_str()).unwrap_or("");
+        let ds: Vec<Json> = diagnostics(src)
+            .into_iter()
+            .map(|d| {
+                let severity = match d.severity {
+                    Severity::Error => 1.0,
+                    Severity::Warning => 2.0,
+                };
+                Json::obj(vec![("range", range(src, d.span.unwrap_or([1, 1]))),
+                               ("severity", Json::Num(severity)),
+                               ("source", Json::str("secd")),
+                               ("code", Json::str(d.phase)),
+                               ("message", Json::str(&d.message))])
+            })
+            .collect();
+        return Json::obj(vec![("jsonrpc", Json::str("2.0")),
+                              ("method", Json::str("textDocument/publishDiagnostics")),
+                              ("params", Json::obj(vec![("uri", Json::str(uri)), ("diagnostics", Json::Arr(ds))]))]);
+    }
+
+    // answers one message; gives back what to send, responses and notifications alike
+    pub fn handle(&mut self, msg: &Json) -> Vec<Json> {
+        let method = msg.get("method").and_then(|m| m.as_str()).unwrap_or("");
+        let params = msg.get("params").cloned().unwrap_or(Json::Null);
+        let uri = params.at(&["textDocument", "uri"]).and_then(|u| u.as_str()).unwrap_or("").to_string();
+        let src = self.docs.get(&uri).cloned().unwrap_or_default();
+
+        let result = match method {
+            "initialize" => {
+                let sync = Json::obj(vec![("openClose", Json::Bool(true)),
+                                          ("change", Json::Num(1.0)),
+                                          ("save", Json::obj(vec![("includeText", Json::Bool(true))]))]);
+                Json::obj(vec![("capabilities",
+                                Json::obj(vec![("textDocumentSync", sync),
+                                               ("hoverProvider", Json::Bool(true)),
+                                               ("definitionProvider", Json::Bool(true)),
+                                               ("completionProvider", Json::obj(vec![]))])),
+                               ("serverInfo", Json::obj(vec![("name", Json::str("secd"))]))])
+            }
+
+            "textDocument/didOpen" => {
+                let text = params.at(&["textDocument", "text"]).and_then(|t| t.as_str()).unwrap_or("");
+                self.docs.insert(uri.clone(), text.to_string());
+                return vec![self.publish(&uri)];
+            }
+
+            "textDocument/didChange" => {
+                let changes = params.get("contentChanges").and_then(|c| c.as_array());
+                if let Some(text) = changes.and_then(|c| c.last()).and_then(|c| c.get("text")) {
+                    self.docs.insert(uri, text.as_str().unwrap_or("").to_string());
+                }
+                return vec![];
+            }
+
+            "textDocument/didSave" => {
+                if let Some(text) = params.get("text").and_then(|t| t.as_str()) {
+                    self.docs.insert(uri.clone(), text.to_string());
+                }
+                return vec![self.publish(&uri)];
+            }
+
+            "textDocument/didClose" => {
+                self.docs.remove(&uri);
+                return vec![];
+            }
+
+            "textDocument/hover" => {
+                match position(&params).and_then(|pos| hover(&src, pos)) {
+                    Some(text) => {
+                        Json::obj(vec![("contents",
+                                        Json::obj(vec![("kind", Json::str("markdown")),
+                                                       ("value", Json::str(&text))]))])
+                    }
+                    None => Json::Null,
+                }
+            }
+
+            "textDocument/definition" => {
+                match position(&params).and_then(|pos| definition(&src, pos)) {
+                    Some(info) => Json::obj(vec![("uri", Json::str(&uri)), ("range", range(&src, info))]),
+                    None => Json::Null,
+                }
+            }
+
+            "textDocument/completion" => {
+                let pos = position(&params).unwrap_or([1, 1]);
+                let items = completion(&src, pos)
+                    .into_iter()
+                    .map(|name| {
+                        let kind = if compiler::is_keyword(&name) { 14.0 } else { 6.0 };
+                        Json::obj(vec![("label", Json::str(&name)), ("kind", Json::Num(kind))])
+                    })
+                    .collect();
+                Json::Arr(items)
+            }
+
+            "shutdown" => Json::Null,
+
+            _ => {
+                if msg.get("id").is_none() {
+                    return vec![];
+                }
+                return vec![Json::obj(vec![("jsonrpc", Json::str("2.0")),
+                                           ("id", msg.get("id").cloned().unwrap_or(Json::Null)),
+                                           ("error",
+                                            Json::obj(vec![("code", Json::Num(-32601.0)),
+                                                           ("message", Json::str("method not found"))]))])];
+            }
+        };
+
+        match msg.get("id") {
+            Some(id) => {
+                return vec![Json::obj(vec![("jsonrpc", Json::str("2.0")), ("id", id.clone()), ("result", result)])]
+            }
+            None => return vec![],
+        }
+    }
+}
+
+// runs until the client sends exit or closes the stream
+pub fn serve<R: BufRead, W: Write>(mut input: R, mut output: W) -> Result<(), Box<Error>> {
+    let mut server = Server::new();
+    while let Some(msg) = try!(read_message(&mut input)) {
+        if msg.get("method").and_then(|m| m.as_str()) == Some("exit") {
+            break;
+        }
+        for reply in server.handle(&msg) {
+            try!(write_message(&mut output, &reply));
+        }
+    }
+    return Ok(());
+}
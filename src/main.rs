@@ -1,13 +1,454 @@
 extern crate secd;
+#[cfg(feature = "logging")]
+extern crate log;
 
 use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use secd::{RunResult, Capabilities, diagnostic, lsp, dap};
+
+// writes every record to stderr; the level comes from SECD_LOG (error .. trace)
+#[cfg(feature = "logging")]
+struct StderrLogger;
+
+#[cfg(feature = "logging")]
+impl log::Log for StderrLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        return true;
+    }
+
+    fn log(&self, record: &log::Record) {
+        eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(feature = "logging")]
+fn init_logger() {
+    let level = env::var("SECD_LOG")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(log::LevelFilter::Off);
+    let _ = log::set_logger(&StderrLogger);
+    log::set_max_level(level);
+}
+
+#[cfg(not(feature = "logging"))]
+fn init_logger() {}
+
+// NO_COLOR (https://no-color.org) turns colors off, as does a redirected stderr
+fn use_color() -> bool {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    return !no_color && io::stderr().is_terminal();
+}
+
+// errors go to stdout as JSON lines or to stderr for people
+fn report(e: &(Error + 'static), file: &str, json: bool) {
+    let src = fs::read_to_string(file).unwrap_or_default();
+    for d in diagnostic::all_from_error(e) {
+        if json {
+            println!("{}", d.to_json(Some(file)));
+        } else {
+            eprint!("{}", d.render(&src, Some(file), use_color()));
+        }
+    }
+}
+
+// the file's source; one that can't be read is reported like any other error
+fn read_source(path: &str, json: bool) -> String {
+    match fs::read_to_string(path) {
+        Ok(src) => return src,
+        Err(e) => {
+            report(&e, path, json);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+fn build_wasm(src: &String) -> Result<Vec<u8>, Box<Error>> {
+    return secd::build_wasm(src);
+}
+
+#[cfg(not(feature = "wasm"))]
+fn build_wasm(_: &String) -> Result<Vec<u8>, Box<Error>> {
+    return Err(From::from(secd::Diagnostic::error("build", None, "secd was built without the wasm feature".into())));
+}
+
+#[cfg(feature = "jupyter")]
+fn jupyter(connection_file: &str) -> Result<(), Box<Error>> {
+    return secd::jupyter::serve(connection_file);
+}
+
+#[cfg(not(feature = "jupyter"))]
+fn jupyter(_: &str) -> Result<(), Box<Error>> {
+    return Err(From::from("secd was built without the jupyter feature"));
+}
+
+// `path` if it is a file, else the .lisp files under it in order of their paths
+fn lisp_files(path: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(path) {
+        Ok(dir) => dir.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => return files.push(path.to_path_buf()),
+    };
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            lisp_files(&entry, files);
+        } else if entry.extension().is_some_and(|e| e == "lisp") {
+            files.push(entry);
+        }
+    }
+}
+
+// runs the test forms of every file, printing a line per test; exits 1 if any failed
+// or a file stopped with an error
+fn test(paths: &[String]) {
+    let mut files = vec![];
+    for path in paths {
+        lisp_files(Path::new(path), &mut files);
+    }
+
+    let (mut passed, mut failed) = (0, 0);
+    for file in files {
+        let name = file.display().to_string();
+        let src = match fs::read_to_string(&file) {
+            Ok(src) => src,
+            Err(e) => {
+                failed += 1;
+                println!("FAIL {}: {}", name, e);
+                continue;
+            }
+        };
+        let (tests, error) = secd::test_lisp(&src, Capabilities::default());
+        for t in tests {
+            if t.passed {
+                passed += 1;
+                println!("ok {}: {}", name, t.name);
+            } else {
+                failed += 1;
+                println!("FAIL {}:{}:{}: {}: expected {}, got {}",
+                         name,
+                         t.info[0],
+                         t.info[1],
+                         t.name,
+                         t.expected,
+                         t.actual);
+            }
+        }
+        if let Some(e) = error {
+            failed += 1;
+            println!("FAIL {}: stopped by an error", name);
+            report(&*e, &name, false);
+        }
+    }
+
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
 
 fn main() {
-    let mut args = env::args();
-    if args.len() == 2 {
-        println!("{}",
-                 secd::run_lisp_file(&args.nth(1).unwrap()).expect("main"));
+    init_logger();
+
+    if env::args().nth(1).as_deref() == Some("test") {
+        let paths: Vec<String> = env::args().skip(2).collect();
+        if paths.is_empty() {
+            println!("usage: secd test <dir or file>...");
+            process::exit(2);
+        }
+        test(&paths);
+        return;
+    }
+
+    if env::args().nth(1).as_deref() == Some("lsp") {
+        let stdin = io::stdin();
+        if let Err(e) = lsp::serve(stdin.lock(), io::stdout()) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if env::args().nth(1).as_deref() == Some("dap") {
+        let stdin = io::stdin();
+        if let Err(e) = dap::serve(stdin.lock(), io::stdout()) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if env::args().nth(1).as_deref() == Some("serve") {
+        let args: Vec<String> = env::args().skip(2).collect();
+        let addr = match args.as_slice() {
+            [l, addr] if l == "--listen" => addr,
+            _ => {
+                println!("usage: secd serve --listen <addr>");
+                process::exit(2);
+            }
+        };
+        if let Err(e) = secd::serve::serve(addr) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if env::args().nth(1).as_deref() == Some("jupyter") {
+        let connection_file = match env::args().nth(2) {
+            Some(file) => file,
+            None => {
+                println!("usage: secd jupyter <connection file>");
+                process::exit(2);
+            }
+        };
+        if let Err(e) = jupyter(&connection_file) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if env::args().nth(1).as_deref() == Some("graph") {
+        let args: Vec<String> = env::args().skip(2).collect();
+        let (input, value) = match args.as_slice() {
+            [input] => (input, false),
+            [v, input] if v == "--value" => (input, true),
+            _ => {
+                println!("usage: secd graph [--value] <file>");
+                process::exit(2);
+            }
+        };
+        let src = read_source(input, false);
+        let graph = if value {
+            secd::graph_value_lisp(&src, Capabilities::default())
+        } else {
+            secd::graph_code_lisp(&src)
+        };
+        match graph {
+            Ok(dot) => print!("{}", dot),
+            Err(e) => {
+                report(&*e, input, false);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if env::args().nth(1).as_deref() == Some("expand") {
+        let input = match env::args().nth(2) {
+            Some(input) => input,
+            None => {
+                println!("usage: secd expand <file>");
+                process::exit(2);
+            }
+        };
+        let src = read_source(&input, false);
+        match secd::expand_lisp(&src) {
+            Ok(expanded) => println!("{}", expanded),
+            Err(e) => {
+                report(&*e, &input, false);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if env::args().nth(1).as_deref() == Some("build") {
+        let args: Vec<String> = env::args().skip(2).collect();
+        let (input, out) = match args.as_slice() {
+            [input, o, out] if o == "-o" => (input, out),
+            _ => {
+                println!("usage: secd build <input.lisp> -o <out.rs|out.wasm>");
+                process::exit(2);
+            }
+        };
+        let src = read_source(input, false);
+        let built = if out.ends_with(".wasm") {
+            build_wasm(&src)
+        } else {
+            secd::build_lisp(&src).map(|rust| rust.into_bytes())
+        };
+        let built = match built {
+            Ok(built) => built,
+            Err(e) => {
+                report(&*e, input, false);
+                process::exit(1);
+            }
+        };
+        if let Err(e) = fs::write(out, built) {
+            eprintln!("{}: {}", out, e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let mut caps = Capabilities::default();
+    let mut json = false;
+    let mut typecheck = false;
+    let mut coverage = None;
+    let mut stats = false;
+    let mut teach = false;
+    let mut trace = None;
+    let mut budget = None;
+    let mut files = vec![];
+
+    for arg in env::args().skip(1) {
+        if arg == "--sandbox" {
+            caps = Capabilities::none();
+        } else if arg == "--typecheck" {
+            typecheck = true;
+        } else if arg.starts_with("--coverage=") {
+            coverage = Some(arg["--coverage=".len()..].to_string());
+        } else if arg == "--stats" {
+            stats = true;
+        } else if arg.starts_with("--trace=") {
+            trace = Some(arg["--trace=".len()..].to_string());
+        } else if arg.starts_with("--call-budget=") {
+            let (steps, fatal) = match arg["--call-budget=".len()..].split_once(',') {
+                Some((steps, "error")) => (steps, true),
+                Some(_) => ("", false),
+                None => (&arg["--call-budget=".len()..], false),
+            };
+            match steps.parse::<usize>() {
+                Ok(steps) => budget = Some((steps, fatal)),
+                Err(_) => {
+                    println!("usage: --call-budget=<steps>[,error]");
+                    process::exit(2);
+                }
+            }
+        } else if arg == "--teach" {
+            teach = true;
+        } else if arg == "--diagnostics=json" {
+            json = true;
+        } else if arg == "--diagnostics=human" {
+            json = false;
+        } else if arg.starts_with("--allow=") {
+            for cap in arg["--allow=".len()..].split(',') {
+                match cap {
+                    "filesystem" => caps.filesystem = true,
+                    "process" => caps.process = true,
+                    "network" => caps.network = true,
+                    "clock" => caps.clock = true,
+                    "env-vars" => caps.env_vars = true,
+                    "debug" => caps.debug = true,
+                    "all" => caps = Capabilities::all(),
+                    _ => {
+                        println!("unknown capability '{}'", cap);
+                        process::exit(2);
+                    }
+                }
+            }
+        } else {
+            files.push(arg);
+        }
+    }
+
+    if [stats, coverage.is_some(), teach, trace.is_some(), budget.is_some()].iter().filter(|&&b| b).count() > 1 {
+        println!("only one of --stats, --coverage, --teach, --trace and --call-budget can be used");
+        process::exit(2);
+    }
+
+    if files.len() == 1 {
+        let src = read_source(&files[0], json);
+        if typecheck {
+            if let Err(e) = secd::typecheck_lisp(&src) {
+                report(&*e, &files[0], json);
+                process::exit(1);
+            }
+        }
+
+        // the warnings are the same whichever way the program runs; the modes below other
+        // than the plain run compile it again their own way
+        let program = match secd::compile_lisp(&src, caps) {
+            Ok(program) => program,
+            Err(e) => {
+                report(&*e, &files[0], json);
+                process::exit(1);
+            }
+        };
+        for w in &program.warnings {
+            report(w, &files[0], json);
+        }
+
+        let r = match coverage {
+            Some(out) => {
+                let (r, lines) = secd::cover_lisp(&src, caps);
+                if let Err(e) = fs::write(&out, secd::coverage::lcov(&files[0], &lines)) {
+                    eprintln!("{}: {}", out, e);
+                    process::exit(1);
+                }
+                r
+            }
+            None if trace.is_some() => {
+                let out = trace.unwrap();
+                let (r, json) = secd::trace_lisp(&src, caps);
+                if let Err(e) = fs::write(&out, json) {
+                    eprintln!("{}: {}", out, e);
+                    process::exit(1);
+                }
+                r
+            }
+            None if teach => secd::teach_lisp(&src, caps, &mut io::stdout()),
+            None if stats => {
+                let (r, stats) = secd::stats_lisp(&src, caps);
+                eprint!("{}", stats);
+                r
+            }
+            None if budget.is_some() => {
+                let (steps, fatal) = budget.unwrap();
+                let (r, warnings) = secd::budget_lisp(&src, caps, steps, fatal);
+                for w in warnings {
+                    report(&w, &files[0], json);
+                }
+                r
+            }
+            None => secd::eval_code_with(program.code, caps),
+        };
+        match r {
+            Ok(RunResult::Value(a)) => println!("{}", a),
+            Ok(RunResult::Exit(n)) => process::exit(n),
+            Ok(RunResult::Yield(a)) => println!("yield outside of a host: {}", a),
+            Err(e) => {
+                report(&*e, &files[0], json);
+                process::exit(1);
+            }
+        }
+    } else if files.len() > 1 {
+        if typecheck || coverage.is_some() || stats || teach || trace.is_some() || budget.is_some() {
+            println!("--typecheck, --coverage, --stats, --teach, --trace and --call-budget take 1 file");
+            process::exit(2);
+        }
+        // whole-program mode: each file compiles to a unit and the units link into one
+        let mut units = vec![];
+        for file in files.iter() {
+            let src = read_source(file, json);
+            match secd::compile_unit(file, &src, caps) {
+                Ok(unit) => units.push(unit),
+                Err(e) => {
+                    report(&*e, file, json);
+                    process::exit(1);
+                }
+            }
+        }
+        match secd::eval_units_with(&units, caps) {
+            Ok(RunResult::Value(a)) => println!("{}", a),
+            Ok(RunResult::Exit(n)) => process::exit(n),
+            Ok(RunResult::Yield(a)) => println!("yield outside of a host: {}", a),
+            Err(e) => {
+                // the program's spans may be in any of the files, so none is shown
+                report(&*e, &files.join(", "), json);
+                process::exit(1);
+            }
+        }
     } else {
-        println!("expected 1 file");
+        println!("expected a file");
     }
 }
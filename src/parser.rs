@@ -1,7 +1,9 @@
 
 use data::{Info, AST, SExpr};
+use diagnostic::Diagnostic;
 
 use std::error::Error;
+use std::mem;
 
 pub struct Parser {
     src: String,
@@ -24,6 +26,10 @@ fn is_id(c: char) -> bool {
         .is_some()
 }
 
+fn error<T>(info: Option<Info>, msg: String) -> Result<T, Box<Error>> {
+    return Err(From::from(Diagnostic::error("parse", info, msg)));
+}
+
 impl Parser {
     pub fn new(s: &String) -> Parser {
         return Parser {
@@ -52,6 +58,8 @@ impl Parser {
         let mut t = Ok(None);
 
         while self.src.len() > self.pos {
+            // tokens are located by their first character
+            let start = self.info;
             match self.src.as_bytes()[self.pos] as char {
                 '(' => {
                     self.inc_width();
@@ -59,7 +67,7 @@ impl Parser {
                     t = Ok(Some(Token {
                                     token: String::from("("),
                                     kind: "(",
-                                    info: self.info,
+                                    info: start,
                                 }));
                     break;
                 }
@@ -70,7 +78,7 @@ impl Parser {
                     t = Ok(Some(Token {
                                     token: String::from(")"),
                                     kind: ")",
-                                    info: self.info,
+                                    info: start,
                                 }));
                     break;
                 }
@@ -85,6 +93,65 @@ impl Parser {
                     self.inc_pos();
                 }
 
+                '"' => {
+                    self.inc_width();
+                    self.inc_pos();
+
+                    let mut s = vec![];
+                    let mut closed = false;
+
+                    while self.src.len() > self.pos {
+                        let cc = self.src.as_bytes()[self.pos];
+                        self.inc_pos();
+
+                        match cc as char {
+                            '"' => {
+                                self.inc_width();
+                                closed = true;
+                                break;
+                            }
+
+                            '\\' if self.src.len() > self.pos => {
+                                self.inc_width();
+                                self.inc_width();
+                                let e = self.src.as_bytes()[self.pos];
+                                self.inc_pos();
+                                s.push(match e as char {
+                                           'n' => b'\n',
+                                           't' => b'\t',
+                                           _ => e,
+                                       });
+                            }
+
+                            '\n' => {
+                                self.inc_line();
+                                s.push(cc);
+                            }
+
+                            _ => {
+                                self.inc_width();
+                                s.push(cc);
+                            }
+                        }
+                    }
+
+                    if !closed {
+                        t = error(Some(start), "unterminated string".to_string());
+                    } else {
+                        t = match String::from_utf8(s) {
+                            Ok(s) => {
+                                Ok(Some(Token {
+                                            token: s,
+                                            kind: "str",
+                                            info: start,
+                                        }))
+                            }
+                            Err(_) => error(Some(start), "invalid utf-8 in string".to_string()),
+                        };
+                    }
+                    break;
+                }
+
                 c if c.is_numeric() => {
                     self.inc_width();
                     self.inc_pos();
@@ -100,15 +167,15 @@ impl Parser {
 
                             s.push(cc);
                         } else {
-                            t = Ok(Some(Token {
-                                            token: s,
-                                            kind: "int",
-                                            info: self.info,
-                                        }));
                             break;
                         }
                     }
 
+                    t = Ok(Some(Token {
+                                    token: s,
+                                    kind: "int",
+                                    info: start,
+                                }));
                     break;
                 }
 
@@ -127,20 +194,20 @@ impl Parser {
 
                             s.push(cc);
                         } else {
-                            t = Ok(Some(Token {
-                                            token: s,
-                                            kind: "id",
-                                            info: self.info,
-                                        }));
                             break;
                         }
                     }
 
+                    t = Ok(Some(Token {
+                                    token: s,
+                                    kind: "id",
+                                    info: start,
+                                }));
                     break;
                 }
 
                 c => {
-                    t = Err(From::from(format!("lex unexpect token '{}' in {:?}", c, self.info)));
+                    t = error(Some(self.info), format!("unexpected character '{}'", c));
                     break;
                 }
             }
@@ -162,8 +229,17 @@ impl Parser {
         return self.lex(true);
     }
 
+    // a program is its last top-level form
     pub fn parse(&mut self) -> ParserResult {
-        let mut ps = 0;
+        match try!(self.parse_all()).pop() {
+            Some(ast) => return Ok(ast),
+            None => return error(None, "empty program".to_string()),
+        }
+    }
+
+    // every top-level form, for a notebook cell that holds several
+    pub fn parse_all(&mut self) -> Result<Vec<AST>, Box<Error>> {
+        let mut opens: Vec<Info> = vec![];
         let mut list: Vec<Vec<AST>> = vec![vec![]];
 
         loop {
@@ -182,44 +258,112 @@ impl Parser {
                         }
 
                         "int" => {
+                            let n = match t.token.parse() {
+                                Ok(n) => n,
+                                Err(_) => {
+                                    return error(Some(t.info), format!("int out of range '{}'", t.token))
+                                }
+                            };
                             list.last_mut()
                                 .unwrap()
                                 .push(AST {
                                           info: t.info,
-                                          sexpr: SExpr::Int(t.token.parse().unwrap()),
+                                          sexpr: SExpr::Int(n),
+                                      })
+                        }
+
+                        "str" => {
+                            list.last_mut()
+                                .unwrap()
+                                .push(AST {
+                                          info: t.info,
+                                          sexpr: SExpr::Str(t.token),
                                       })
                         }
 
                         "(" => {
                             list.push(vec![]);
-                            ps += 1;
+                            opens.push(t.info);
                         }
 
                         ")" => {
+                            let info = match opens.pop() {
+                                Some(info) => info,
+                                None => return error(Some(t.info), "many ')'".to_string()),
+                            };
                             let node = list.pop().unwrap();
                             list.last_mut()
                                 .unwrap()
                                 .push(AST {
-                                          info: t.info,
+                                          info,
                                           sexpr: SExpr::List(node),
                                       });
-                            ps -= 1;
                         }
 
                         _ => unimplemented!(),
                     }
-
-                    if ps < 0 {
-                        return Err(From::from("many ')'".to_string()));
-                    }
                 }
             }
         }
 
-        if ps > 0 {
-            return Err(From::from("many '('".to_string()));
-        } else {
-            return Ok(list.pop().unwrap().pop().unwrap());
+        if let Some(info) = opens.pop() {
+            return error(Some(info), "many '('".to_string());
+        }
+        return Ok(list.pop().unwrap());
+    }
+}
+
+// a piece of a string literal: text, or the expression of a {<expr>} in it
+#[derive(Debug, PartialEq, Clone)]
+pub enum Piece {
+    Text(String),
+    Hole(AST),
+}
+
+// the pieces of the string literal at `info`, for the compiler to join with
+// string-append; {{ and }} stand for the braces themselves. The expression in a pair
+// of braces can't have braces of its own, and is located where the literal is
+pub fn interpolation(s: &str, info: Info) -> Result<Vec<Piece>, Box<Error>> {
+    let mut pieces = vec![];
+    let mut text = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '}' if chars.peek() == Some(&c) => {
+                chars.next();
+                text.push(c);
+            }
+            '{' => {
+                let mut src = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => src.push(c),
+                        None => return error(Some(info), "unclosed { in string; {{ is a brace".to_string()),
+                    }
+                }
+                let ast = match Parser::new(&src).parse_all() {
+                    Ok(ref mut forms) if forms.len() == 1 => forms.remove(0),
+                    _ => return error(Some(info), format!("{{{}}} in string is not one expression", src)),
+                };
+                if !text.is_empty() {
+                    pieces.push(Piece::Text(mem::replace(&mut text, String::new())));
+                }
+                pieces.push(Piece::Hole(locate(ast, info)));
+            }
+            _ => text.push(c),
         }
     }
+    if !text.is_empty() || pieces.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    return Ok(pieces);
+}
+
+fn locate(ast: AST, info: Info) -> AST {
+    let sexpr = match ast.sexpr {
+        SExpr::List(ls) => SExpr::List(ls.into_iter().map(|a| locate(a, info)).collect()),
+        sexpr => sexpr,
+    };
+    return AST { info, sexpr };
 }
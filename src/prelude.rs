@@ -0,0 +1,85 @@
+use data::{AST, SExpr};
+
+// Lisp definitions of the list primitives and of the stream functions. In call position
+// the compiler emits the native instructions for the primitives instead, so those are
+// only bound for programs that use one as a value, as in `(foldr map nil fs)`; the
+// others whenever a program uses them.
+
+// The recursion goes through an inner letrec: a closure called as a value only sees
+// the environment it was made in, which doesn't have its own name.
+pub const DEFINITIONS: &[(&str, &str)] =
+    &[("map",
+       "(lambda (f ls)
+          \"the list of f applied to each element of ls\"
+          (letrec loop (lambda (ls) (if (eq ls nil) nil (cons (f (car ls)) (loop (cdr ls)))))
+            (loop ls)))"),
+      ("filter",
+       "(lambda (f ls)
+          \"the elements of ls f is true for, in order\"
+          (letrec loop (lambda (ls)
+                         (if (eq ls nil) nil
+                           (if (f (car ls)) (cons (car ls) (loop (cdr ls))) (loop (cdr ls)))))
+            (loop ls)))"),
+      ("foldl",
+       "(lambda (f acc ls)
+          \"combines acc with each element of ls from the left, (f acc x)\"
+          (letrec loop (lambda (acc ls) (if (eq ls nil) acc (loop (f acc (car ls)) (cdr ls))))
+            (loop acc ls)))"),
+      ("foldr",
+       "(lambda (f acc ls)
+          \"combines each element of ls with acc from the right, (f x acc)\"
+          (letrec loop (lambda (ls) (if (eq ls nil) acc (f (car ls) (loop (cdr ls)))))
+            (loop ls)))"),
+      ("stream-car",
+       "(lambda (s)
+          \"the first element of the stream s\"
+          (car s))"),
+      ("stream-cdr",
+       "(lambda (s)
+          \"the stream after the first element of s, forcing it\"
+          (force (cdr s)))"),
+      ("stream-take",
+       "(lambda (s n)
+          \"a list of the first n elements of the stream s, or all of them if fewer\"
+          (letrec loop (lambda (s n)
+                         (if (eq n 0) nil
+                           (if (eq s nil) nil (cons (car s) (loop (force (cdr s)) (- n 1))))))
+            (loop s n)))"),
+      ("stream-map",
+       "(lambda (f s)
+          \"the stream of f applied to each element of s\"
+          (letrec loop (lambda (s) (if (eq s nil) nil (stream-cons (f (car s)) (loop (force (cdr s))))))
+            (loop s)))"),
+      ("stream-filter",
+       "(lambda (f s)
+          \"the stream of the elements of s f is true for\"
+          (letrec loop (lambda (s)
+                         (if (eq s nil) nil
+                           (if (f (car s)) (stream-cons (car s) (loop (force (cdr s)))) (loop (force (cdr s))))))
+            (loop s)))")];
+
+// the definitions the machine also has as primitives
+pub const NATIVE: &[&str] = &["map", "filter", "foldl", "foldr"];
+
+// the definitions `ast` mentions, in order of first use, those the machine has only
+// anywhere but at the head of a form; those it qualifies as prelude:<name>, which no
+// form takes over, are bound by that name apart from the others
+pub fn uses(ast: &AST, names: &mut Vec<String>) {
+    match ast.sexpr {
+        SExpr::Atom(ref id) => {
+            let name = id.trim_start_matches("prelude:");
+            if DEFINITIONS.iter().any(|d| d.0 == name) && !names.contains(id) {
+                names.push(id.clone());
+            }
+        }
+        SExpr::List(ref ls) => {
+            for (i, a) in ls.iter().enumerate() {
+                let head = i == 0 && matches!(a.sexpr, SExpr::Atom(ref id) if NATIVE.contains(&id.as_str()));
+                if !head {
+                    uses(a, names);
+                }
+            }
+        }
+        _ => {}
+    }
+}
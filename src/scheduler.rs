@@ -0,0 +1,116 @@
+use data::{SECD, Lisp, RunResult};
+use diagnostic::Diagnostic;
+
+use std::rc::Rc;
+use std::collections::HashMap;
+use std::error::Error;
+
+pub const QUANTUM: usize = 100;
+
+// round-robin green threads: every machine gets `quantum` instructions per turn. A
+// thread's value or error waits in `finished` for the one join that takes it; an error
+// stops only its thread and is raised in the joiner
+pub struct Scheduler {
+    pub quantum: usize,
+    threads: Vec<(usize, SECD)>,
+    finished: HashMap<usize, Result<Rc<Lisp>, Box<Error>>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        return Scheduler {
+                   quantum: QUANTUM,
+                   threads: vec![],
+                   finished: HashMap::new(),
+               };
+    }
+
+    // runs until the main machine finishes; threads still running at that point are dropped
+    pub fn run(&mut self, main: SECD) -> Result<RunResult, Box<Error>> {
+        self.threads.push((0, main));
+
+        loop {
+            let mut progress = false;
+            let mut i = 0;
+
+            while i < self.threads.len() {
+                let mut failed = None;
+                if let Some(id) = self.threads[i].1.joining {
+                    if let Some(r) = self.finished.remove(&id) {
+                        let vm = &mut self.threads[i].1;
+                        vm.joining = None;
+                        match r {
+                            Ok(a) => vm.stack.push(a),
+                            Err(e) => failed = vm.fail(e).err(),
+                        }
+                        progress = true;
+                    }
+                }
+
+                let ready = match self.threads[i].1.receiving {
+                    Some(ref q) => q.borrow().len() > 0,
+                    None => false,
+                };
+                if ready {
+                    self.threads[i].1.receiving = None;
+                }
+
+                for _ in 0..self.quantum {
+                    if failed.is_some() || self.threads[i].1.halted() {
+                        break;
+                    }
+                    failed = self.threads[i].1.step().err();
+                    progress = true;
+                }
+
+                let spawned: Vec<_> = self.threads[i].1.spawned.drain(..).collect();
+                for &(id, _) in &spawned {
+                    debug!("thread {} spawned", id);
+                }
+                self.threads.extend(spawned);
+
+                let (id, ref mut vm) = self.threads[i];
+                if let Some(e) = failed {
+                    if id == 0 {
+                        return Err(e);
+                    }
+                    debug!("thread {} failed after {} instructions", id, vm.steps);
+                    self.finished.insert(id, Err(e));
+                    self.threads.remove(i);
+                    continue;
+                }
+                if !vm.halted() || vm.joining.is_some() || vm.receiving.is_some() {
+                    i += 1;
+                    continue;
+                }
+
+                debug!("thread {} finished after {} instructions", id, vm.steps);
+                match vm.result() {
+                    RunResult::Value(a) => {
+                        if id == 0 {
+                            return Ok(RunResult::Value(a));
+                        }
+                        self.finished.insert(id, Ok(a));
+                    }
+
+                    RunResult::Exit(n) => return Ok(RunResult::Exit(n)),
+                    RunResult::Yield(a) => {
+                        if id == 0 {
+                            return Ok(RunResult::Yield(a));
+                        }
+                        return Err(From::from(Diagnostic::error("vm",
+                                                                None,
+                                                                "yield inside a thread".to_string())));
+                    }
+                }
+                self.threads.remove(i);
+            }
+
+            if !progress {
+                return Err(From::from(Diagnostic::error("vm",
+                                                        None,
+                                                        "deadlock, every thread is waiting".to_string())));
+            }
+        }
+    }
+}
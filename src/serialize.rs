@@ -0,0 +1,720 @@
+use data::*;
+use vm::{self, Primitives};
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::error::Error;
+use std::rc::{Rc, Weak};
+
+// A binary format for values, closures included, and for compiled code, so a
+// computation can be checkpointed to disk and picked up later. A value is saved with the
+// constant pool its closures load from; restoring it appends that pool to the machine's
+// restored pool, which CONSTS never replaces, and moves the closures' LDC indices to
+// match. Sharing is kept and cycles through
+// cells and channels survive, their contents being written after everything else.
+// Threads and ports cannot be saved, and a weak reference comes back empty. After the
+// magic comes the header of what is saved, checked before anything else is read, so
+// code from a later instruction set is refused rather than misread.
+
+const MAGIC: &[u8] = b"SECD\x02";
+
+// instructions without operands, saved by name
+const UNIT: &[CodeOP] = &[CodeOP::JOIN, CodeOP::RET, CodeOP::AP, CodeOP::RAP, CodeOP::TAP,
+                          CodeOP::TRAP, CodeOP::PUTS, CodeOP::POP, CodeOP::EQ, CodeOP::ADD,
+                          CodeOP::SUB, CodeOP::CURTIME, CodeOP::CLOCK, CodeOP::TIME, CodeOP::EXIT,
+                          CodeOP::YIELD, CodeOP::SPAWN, CodeOP::TJOIN, CodeOP::RANDOM,
+                          CodeOP::CHAN, CodeOP::SEND, CodeOP::RECV, CodeOP::GETENV,
+                          CodeOP::SYSTEM, CodeOP::PROCESS, CodeOP::CONS, CodeOP::CAR, CodeOP::CDR,
+                          CodeOP::OUTSTR, CodeOP::TCPCONNECT, CodeOP::TCPLISTEN,
+                          CodeOP::TCPACCEPT, CodeOP::TCPREAD, CodeOP::TCPWRITE,
+                          CodeOP::TCPCLOSE, CodeOP::HTTPGET, CodeOP::DATENOW, CodeOP::DATE2STR,
+                          CodeOP::STR2DATE, CodeOP::ENDTRY, CodeOP::MKCOND, CodeOP::RAISE,
+                          CodeOP::ENDPROTECT, CodeOP::RERAISE, CodeOP::MKPARAM, CodeOP::DEREF,
+                          CodeOP::PARAMRESTORE, CodeOP::SECDSTACK, CodeOP::SECDENV,
+                          CodeOP::SECDWHERE, CodeOP::RESUME];
+
+fn error<T>(msg: &str) -> Result<T, Box<Error>> {
+    return Err(From::from(msg.to_string()));
+}
+
+// what a value's cells and channels hold, written once the value itself is
+enum Deferred {
+    Cell(Rc<Lisp>),
+    Chan(Vec<Rc<Lisp>>),
+}
+
+// what is left to write of the values being written
+enum Part {
+    Value(Rc<Lisp>),
+    // a closure's name for the value after it
+    Key(String),
+    // where a condition was raised
+    Info(Option<Info>),
+    ProcInfo(Rc<ProcInfo>),
+}
+
+struct Writer<'a> {
+    out: Vec<u8>,
+    seen: HashMap<*const Lisp, u32>,
+    deferred: VecDeque<Deferred>,
+    primitives: &'a Primitives,
+    // the features of the instructions written
+    features: BTreeSet<&'static str>,
+    // where the machine's restored pool starts in the pool written
+    restored: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(primitives: &'a Primitives) -> Writer<'a> {
+        return Writer {
+                   out: vec![],
+                   seen: HashMap::new(),
+                   deferred: VecDeque::new(),
+                   primitives,
+                   features: BTreeSet::new(),
+                   restored: 0,
+               };
+    }
+
+    // the magic and the header before what was written
+    fn finish(self) -> Vec<u8> {
+        let mut w = Writer::new(self.primitives);
+        w.out.extend_from_slice(MAGIC);
+        w.u32(vm::VERSION as usize);
+        let features: Vec<String> = self.features.iter().map(|f| f.to_string()).collect();
+        w.strs(&features);
+        w.out.extend(self.out);
+        return w.out;
+    }
+
+    fn byte(&mut self, b: u8) {
+        self.out.push(b);
+    }
+
+    fn u32(&mut self, n: usize) {
+        self.out.extend_from_slice(&(n as u32).to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len());
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn strs(&mut self, ss: &[String]) {
+        self.u32(ss.len());
+        for s in ss {
+            self.str(s);
+        }
+    }
+
+    // the parts left to write go on a stack, so neither a long list nor a deep nest of
+    // them recurses
+    fn value(&mut self, a: &Rc<Lisp>) -> Result<(), Box<Error>> {
+        let mut todo = vec![Part::Value(a.clone())];
+        while let Some(part) = todo.pop() {
+            let a = match part {
+                Part::Value(a) => a,
+                Part::Key(k) => {
+                    self.str(&k);
+                    continue;
+                }
+                Part::Info(info) => {
+                    match info {
+                        Some(info) => {
+                            self.byte(1);
+                            self.u32(info[0]);
+                            self.u32(info[1]);
+                        }
+                        None => self.byte(0),
+                    }
+                    continue;
+                }
+                Part::ProcInfo(info) => {
+                    try!(self.proc_info(&info));
+                    continue;
+                }
+            };
+            if let Some(&i) = self.seen.get(&Rc::as_ptr(&a)) {
+                self.byte(b'@');
+                self.u32(i as usize);
+                continue;
+            }
+            match *a {
+                Lisp::Nil => self.byte(b'n'),
+                Lisp::False => self.byte(b'f'),
+                Lisp::True => self.byte(b't'),
+                Lisp::Int(n) => {
+                    self.byte(b'i');
+                    self.out.extend_from_slice(&n.to_le_bytes());
+                }
+                Lisp::Str(ref s) => {
+                    self.byte(b's');
+                    self.str(s);
+                }
+                Lisp::Symbol(ref s) => {
+                    self.byte(b'y');
+                    self.str(s);
+                }
+                Lisp::Weak(_) => self.byte(b'w'),
+                Lisp::Thread(_) => return error("cannot serialize thread"),
+                Lisp::Port(_) => return error("cannot serialize port"),
+                _ => {
+                    // everything else is numbered in the order it is first written
+                    let i = self.seen.len() as u32;
+                    self.seen.insert(Rc::as_ptr(&a), i);
+                    try!(self.object(&a, &mut todo));
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // writes what comes before the parts of `a` and pushes the parts, last first
+    fn object(&mut self, a: &Rc<Lisp>, todo: &mut Vec<Part>) -> Result<(), Box<Error>> {
+        match **a {
+            Lisp::Cons(ref car, ref cdr) => {
+                self.byte(b'c');
+                todo.push(Part::Value(cdr.clone()));
+                todo.push(Part::Value(car.clone()));
+            }
+            Lisp::List(ref ls) => {
+                self.byte(b'l');
+                self.u32(ls.len());
+                todo.extend(ls.iter().rev().map(|x| Part::Value(x.clone())));
+            }
+            Lisp::Closure(ref names, ref code, ref env, ref info) => {
+                self.byte(b'\\');
+                self.strs(names);
+                try!(self.code(code));
+                self.u32(env.len());
+                todo.push(Part::ProcInfo(info.clone()));
+                for (k, v) in env.iter().rev() {
+                    todo.push(Part::Value(v.clone()));
+                    todo.push(Part::Key(k.clone()));
+                }
+            }
+            Lisp::Condition(ref c) => {
+                self.byte(b'!');
+                self.str(&c.kind);
+                self.str(&c.message);
+                todo.push(Part::Info(c.info));
+                todo.push(Part::Value(c.payload.clone()));
+            }
+            Lisp::Cell(ref c) => {
+                self.byte(b'b');
+                self.byte(c.is_frozen() as u8);
+                self.deferred.push_back(Deferred::Cell(c.borrow().clone()));
+            }
+            Lisp::Chan(ref q) => {
+                self.byte(b'q');
+                self.byte(q.is_frozen() as u8);
+                self.deferred.push_back(Deferred::Chan(q.borrow().iter().cloned().collect()));
+            }
+            _ => unreachable!(),
+        }
+        return Ok(());
+    }
+
+    fn proc_info(&mut self, info: &ProcInfo) -> Result<(), Box<Error>> {
+        match info.doc {
+            Some(ref doc) => {
+                self.byte(1);
+                self.str(doc);
+            }
+            None => self.byte(0),
+        }
+        match info.source {
+            Some(ref source) => {
+                self.byte(1);
+                try!(self.value(source));
+            }
+            None => self.byte(0),
+        }
+        return Ok(());
+    }
+
+    // the contents of the cells and channels written so far, and of those they hold
+    fn deferred(&mut self) -> Result<(), Box<Error>> {
+        while let Some(d) = self.deferred.pop_front() {
+            match d {
+                Deferred::Cell(a) => try!(self.value(&a)),
+                Deferred::Chan(items) => {
+                    self.u32(items.len());
+                    for a in &items {
+                        try!(self.value(a));
+                    }
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn code(&mut self, code: &Code) -> Result<(), Box<Error>> {
+        self.u32(code.len());
+        for c in code {
+            self.u32(c.info[0]);
+            self.u32(c.info[1]);
+            try!(self.op(&c.op));
+        }
+        return Ok(());
+    }
+
+    fn op(&mut self, op: &CodeOP) -> Result<(), Box<Error>> {
+        // Debug would print the code an instruction carries too
+        let name = match *op {
+            CodeOP::LET(_) => "LET".to_string(),
+            CodeOP::LD(_) => "LD".to_string(),
+            CodeOP::TEST(_) => "TEST".to_string(),
+            CodeOP::HELP(_) => "HELP".to_string(),
+            CodeOP::LDC(_) => "LDC".to_string(),
+            CodeOP::ARGS(_) => "ARGS".to_string(),
+            CodeOP::PARAMBIND(_) => "PARAMBIND".to_string(),
+            CodeOP::LDF(..) => "LDF".to_string(),
+            CodeOP::SEL(..) => "SEL".to_string(),
+            CodeOP::TSEL(..) => "TSEL".to_string(),
+            CodeOP::PROTECT(..) => "PROTECT".to_string(),
+            CodeOP::ASSERT(..) => "ASSERT".to_string(),
+            CodeOP::TRY(..) => "TRY".to_string(),
+            CodeOP::PRIM(..) => "PRIM".to_string(),
+            CodeOP::CONSTS(_) => "CONSTS".to_string(),
+            CodeOP::HEADER(_) => "HEADER".to_string(),
+            ref op => format!("{:?}", op),
+        };
+        self.features.extend(vm::feature(op));
+        self.str(&name);
+        match *op {
+            CodeOP::LET(ref id) |
+            CodeOP::LD(ref id) |
+            CodeOP::TEST(ref id) |
+            CodeOP::HELP(ref id) => self.str(id),
+            CodeOP::LDC(n) if n >= vm::RESTORED => self.u32(self.restored + n - vm::RESTORED),
+            CodeOP::LDC(n) |
+            CodeOP::ARGS(n) |
+            CodeOP::PARAMBIND(n) => self.u32(n),
+            CodeOP::LDF(ref names, ref body, ref info) => {
+                self.strs(names);
+                try!(self.code(body));
+                try!(self.proc_info(info));
+            }
+            CodeOP::SEL(ref t, ref f) |
+            CodeOP::TSEL(ref t, ref f) |
+            CodeOP::PROTECT(ref t, ref f) => {
+                try!(self.code(t));
+                try!(self.code(f));
+            }
+            CodeOP::ASSERT(info, ref s) => {
+                self.u32(info[0]);
+                self.u32(info[1]);
+                self.str(s);
+            }
+            CodeOP::TRY(ref body, ref handlers) => {
+                try!(self.code(body));
+                self.u32(handlers.len());
+                for h in handlers {
+                    self.str(&h.kind);
+                    self.str(&h.id);
+                    try!(self.code(&h.code));
+                }
+            }
+            CodeOP::PRIM(id, n) => {
+                match self.primitives.name(id) {
+                    Some(name) => {
+                        let name = name.to_string();
+                        self.str(&name);
+                    }
+                    None => return error(&format!("cannot serialize primitive {}", id)),
+                }
+                self.u32(n);
+            }
+            CodeOP::CONSTS(ref pool) => {
+                self.u32(pool.len());
+                for a in pool.iter() {
+                    try!(self.value(a));
+                }
+            }
+            CodeOP::HEADER(ref header) => {
+                self.u32(header.version as usize);
+                self.strs(&header.features);
+            }
+            _ => {}
+        }
+        return Ok(());
+    }
+}
+
+// an object being read: its number, what it is, how many parts it has and those read
+struct Open {
+    i: usize,
+    what: Partial,
+    n: usize,
+    parts: Vec<Rc<Lisp>>,
+}
+
+enum Partial {
+    Cons,
+    List,
+    // the names, the code and the keys of the environment read so far
+    Closure(Vec<String>, Code, Vec<String>),
+    // the kind and message
+    Condition(String, String),
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    objects: Vec<Rc<Lisp>>,
+    // the cells and channels read so far, and whether to freeze them once filled
+    deferred: VecDeque<(Rc<Lisp>, bool)>,
+    // added to every LDC index, where the saved pool starts in the restoring machine's
+    base: usize,
+    primitives: &'a Primitives,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], base: usize, primitives: &'a Primitives) -> Result<Reader<'a>, Box<Error>> {
+        if !bytes.starts_with(MAGIC) {
+            if bytes.starts_with(&MAGIC[..4]) {
+                return error("serialized in another version of the format");
+            }
+            return error("not a serialized value");
+        }
+        let mut r = Reader {
+            bytes,
+            pos: MAGIC.len(),
+            objects: vec![],
+            deferred: VecDeque::new(),
+            base,
+            primitives,
+        };
+        let header = try!(r.header());
+        if let Err(e) = vm::check(&header) {
+            return error(&e);
+        }
+        return Ok(r);
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Box<Error>> {
+        if self.pos + n > self.bytes.len() {
+            return error("serialized value is cut short");
+        }
+        let bytes = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        return Ok(bytes);
+    }
+
+    fn byte(&mut self) -> Result<u8, Box<Error>> {
+        return Ok(try!(self.take(1))[0]);
+    }
+
+    fn u32(&mut self) -> Result<usize, Box<Error>> {
+        let mut b = [0; 4];
+        b.copy_from_slice(try!(self.take(4)));
+        return Ok(u32::from_le_bytes(b) as usize);
+    }
+
+    fn str(&mut self) -> Result<String, Box<Error>> {
+        let n = try!(self.u32());
+        match String::from_utf8(try!(self.take(n)).to_vec()) {
+            Ok(s) => return Ok(s),
+            Err(_) => return error("serialized string is not UTF-8"),
+        }
+    }
+
+    fn strs(&mut self) -> Result<Vec<String>, Box<Error>> {
+        let n = try!(self.u32());
+        let mut ss = vec![];
+        for _ in 0..n {
+            ss.push(try!(self.str()));
+        }
+        return Ok(ss);
+    }
+
+    // the objects whose parts are being read are kept on a stack, as the writer kept
+    // the parts it had left
+    fn value(&mut self) -> Result<Rc<Lisp>, Box<Error>> {
+        let mut open: Vec<Open> = vec![];
+        loop {
+            if let Some(&mut Open { what: Partial::Closure(_, _, ref mut keys), .. }) = open.last_mut() {
+                keys.push(try!(self.str()));
+            }
+            let mut a = match try!(self.begin()) {
+                Ok(a) => a,
+                Err(o) => {
+                    if o.n > 0 {
+                        open.push(o);
+                        continue;
+                    }
+                    try!(self.finish(o))
+                }
+            };
+            // `a` is a part of the innermost open object, which may now have them all
+            loop {
+                match open.last_mut() {
+                    None => return Ok(a),
+                    Some(o) => {
+                        o.parts.push(a);
+                        if o.parts.len() < o.n {
+                            break;
+                        }
+                    }
+                }
+                let o = open.pop().unwrap();
+                a = try!(self.finish(o));
+            }
+        }
+    }
+
+    // a value whole, or the object whose parts come next
+    fn begin(&mut self) -> Result<Result<Rc<Lisp>, Open>, Box<Error>> {
+        let tag = try!(self.byte());
+        match tag {
+            b'@' => {
+                let i = try!(self.u32());
+                match self.objects.get(i) {
+                    Some(a) => return Ok(Ok(a.clone())),
+                    None => return error("serialized value refers to nothing"),
+                }
+            }
+            b'n' => return Ok(Ok(Lisp::nil())),
+            b'f' => return Ok(Ok(Lisp::bool(false))),
+            b't' => return Ok(Ok(Lisp::bool(true))),
+            b'i' => {
+                let mut b = [0; 4];
+                b.copy_from_slice(try!(self.take(4)));
+                return Ok(Ok(Lisp::int(i32::from_le_bytes(b))));
+            }
+            b's' => return Ok(Ok(Rc::new(Lisp::Str(try!(self.str()))))),
+            b'y' => return Ok(Ok(Rc::new(Lisp::Symbol(try!(self.str()))))),
+            b'w' => return Ok(Ok(Rc::new(Lisp::Weak(Weak::new())))),
+            _ => {}
+        }
+
+        // numbered before what it holds is read, as the writer numbered it
+        let i = self.objects.len();
+        self.objects.push(Lisp::nil());
+        let (what, n) = match tag {
+            b'c' => (Partial::Cons, 2),
+            b'l' => (Partial::List, try!(self.u32())),
+            b'\\' => {
+                let names = try!(self.strs());
+                let code = try!(self.code());
+                (Partial::Closure(names, code, vec![]), try!(self.u32()))
+            }
+            b'!' => {
+                let kind = try!(self.str());
+                (Partial::Condition(kind, try!(self.str())), 1)
+            }
+            b'b' => {
+                let frozen = try!(self.byte()) != 0;
+                let a = Rc::new(Lisp::Cell(Rc::new(Mutable::new(Lisp::nil()))));
+                self.objects[i] = a.clone();
+                self.deferred.push_back((a.clone(), frozen));
+                return Ok(Ok(a));
+            }
+            b'q' => {
+                let frozen = try!(self.byte()) != 0;
+                let a = Rc::new(Lisp::Chan(Rc::new(Mutable::new(VecDeque::new()))));
+                self.objects[i] = a.clone();
+                self.deferred.push_back((a.clone(), frozen));
+                return Ok(Ok(a));
+            }
+            _ => return error("unknown tag in serialized value"),
+        };
+        return Ok(Err(Open {
+                          i,
+                          what,
+                          n,
+                          parts: vec![],
+                      }));
+    }
+
+    // the object made of its parts, once they are read, and of what comes after them
+    fn finish(&mut self, o: Open) -> Result<Rc<Lisp>, Box<Error>> {
+        let mut parts = o.parts.into_iter();
+        let a = match o.what {
+            Partial::Cons => Lisp::Cons(parts.next().unwrap(), parts.next().unwrap()),
+            Partial::List => Lisp::List(parts.collect()),
+            Partial::Closure(names, code, keys) => {
+                let env = keys.into_iter().zip(parts).collect();
+                Lisp::Closure(names, code, env, try!(self.proc_info()))
+            }
+            Partial::Condition(kind, message) => {
+                let payload = parts.next().unwrap();
+                let info = match try!(self.byte()) {
+                    0 => None,
+                    _ => Some([try!(self.u32()), try!(self.u32())]),
+                };
+                Lisp::Condition(Rc::new(Condition {
+                                            kind,
+                                            message,
+                                            payload,
+                                            info,
+                                        }))
+            }
+        };
+        let a = Rc::new(a);
+        self.objects[o.i] = a.clone();
+        return Ok(a);
+    }
+
+    fn proc_info(&mut self) -> Result<Rc<ProcInfo>, Box<Error>> {
+        let doc = match try!(self.byte()) {
+            0 => None,
+            _ => Some(try!(self.str())),
+        };
+        let source = match try!(self.byte()) {
+            0 => None,
+            _ => Some(try!(self.value())),
+        };
+        return Ok(Rc::new(ProcInfo { doc, source }));
+    }
+
+    // fills the cells and channels in the order they were read; freezing waits until
+    // then, as it would stop the filling
+    fn deferred(&mut self) -> Result<(), Box<Error>> {
+        while let Some((a, frozen)) = self.deferred.pop_front() {
+            match *a {
+                Lisp::Cell(ref c) => {
+                    let v = try!(self.value());
+                    *c.borrow_mut() = v;
+                    if frozen {
+                        c.freeze();
+                    }
+                }
+                Lisp::Chan(ref q) => {
+                    for _ in 0..try!(self.u32()) {
+                        let v = try!(self.value());
+                        q.borrow_mut().push_back(v);
+                    }
+                    if frozen {
+                        q.freeze();
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        return Ok(());
+    }
+
+    fn header(&mut self) -> Result<Header, Box<Error>> {
+        let version = try!(self.u32()) as u32;
+        return Ok(Header {
+                      version,
+                      features: try!(self.strs()),
+                  });
+    }
+
+    fn code(&mut self) -> Result<Code, Box<Error>> {
+        let n = try!(self.u32());
+        let mut code = vec![];
+        for _ in 0..n {
+            let info = [try!(self.u32()), try!(self.u32())];
+            let op = try!(self.op());
+            code.push(CodeOPInfo { info, op });
+        }
+        return Ok(code);
+    }
+
+    fn op(&mut self) -> Result<CodeOP, Box<Error>> {
+        let name = try!(self.str());
+        let op = match name.as_str() {
+            "LET" => CodeOP::LET(try!(self.str())),
+            "LD" => CodeOP::LD(try!(self.str())),
+            "TEST" => CodeOP::TEST(try!(self.str())),
+            "HELP" => CodeOP::HELP(try!(self.str())),
+            "LDC" => CodeOP::LDC(try!(self.u32()) + self.base),
+            "ARGS" => CodeOP::ARGS(try!(self.u32())),
+            "PARAMBIND" => CodeOP::PARAMBIND(try!(self.u32())),
+            "LDF" => {
+                let names = try!(self.strs());
+                let body = try!(self.code());
+                CodeOP::LDF(names, body, try!(self.proc_info()))
+            }
+            "SEL" => CodeOP::SEL(try!(self.code()), try!(self.code())),
+            "TSEL" => CodeOP::TSEL(try!(self.code()), try!(self.code())),
+            "PROTECT" => CodeOP::PROTECT(try!(self.code()), try!(self.code())),
+            "ASSERT" => {
+                let info = [try!(self.u32()), try!(self.u32())];
+                CodeOP::ASSERT(info, try!(self.str()))
+            }
+            "TRY" => {
+                let body = try!(self.code());
+                let mut handlers = vec![];
+                for _ in 0..try!(self.u32()) {
+                    let kind = try!(self.str());
+                    let id = try!(self.str());
+                    handlers.push(Handler {
+                                      kind,
+                                      id,
+                                      code: try!(self.code()),
+                                  });
+                }
+                CodeOP::TRY(body, handlers)
+            }
+            "PRIM" => {
+                let prim = try!(self.str());
+                let n = try!(self.u32());
+                match self.primitives.id(&prim) {
+                    Some(id) => CodeOP::PRIM(id, n),
+                    None => return error(&format!("no primitive {} to restore", prim)),
+                }
+            }
+            "HEADER" => CodeOP::HEADER(try!(self.header())),
+            "CONSTS" => {
+                let mut pool = vec![];
+                for _ in 0..try!(self.u32()) {
+                    pool.push(try!(self.value()));
+                }
+                CodeOP::CONSTS(Rc::new(pool))
+            }
+            _ => {
+                match UNIT.iter().find(|op| format!("{:?}", op) == name) {
+                    Some(op) => op.clone(),
+                    None => return error(&format!("unknown instruction {} in serialized code", name)),
+                }
+            }
+        };
+        return Ok(op);
+    }
+}
+
+// `a` with the pools its closures load constants from, the restored one written after
+// the other
+pub fn write_value(a: &Rc<Lisp>, pool: &[Rc<Lisp>], restored: &[Rc<Lisp>], primitives: &Primitives) -> Result<Vec<u8>, Box<Error>> {
+    let mut w = Writer::new(primitives);
+    w.restored = pool.len();
+    w.u32(pool.len() + restored.len());
+    for c in pool.iter().chain(restored) {
+        try!(w.value(c));
+    }
+    try!(w.value(a));
+    try!(w.deferred());
+    return Ok(w.finish());
+}
+
+// the saved pool and value, its LDC indices moved up by `base`, where the saved pool
+// goes in the machine's
+pub fn read_value(bytes: &[u8], base: usize, primitives: &Primitives) -> Result<(Vec<Rc<Lisp>>, Rc<Lisp>), Box<Error>> {
+    let mut r = try!(Reader::new(bytes, base, primitives));
+    let mut pool = vec![];
+    for _ in 0..try!(r.u32()) {
+        pool.push(try!(r.value()));
+    }
+    let a = try!(r.value());
+    try!(r.deferred());
+    return Ok((pool, a));
+}
+
+// a compiled program, its pool included
+pub fn write_code(code: &Code, primitives: &Primitives) -> Result<Vec<u8>, Box<Error>> {
+    let mut w = Writer::new(primitives);
+    try!(w.code(code));
+    try!(w.deferred());
+    return Ok(w.finish());
+}
+
+pub fn read_code(bytes: &[u8], primitives: &Primitives) -> Result<Code, Box<Error>> {
+    let mut r = try!(Reader::new(bytes, 0, primitives));
+    let code = try!(r.code());
+    try!(r.deferred());
+    return Ok(code);
+}
@@ -0,0 +1,144 @@
+use data::{SECD, Capabilities, RunResult};
+use compiler::Compiler;
+use diagnostic;
+use json::{Json, read_message, write_message};
+use parser::Parser;
+use scheduler::Scheduler;
+use serialize;
+use vm::Primitives;
+
+use std::error::Error;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+// An evaluation service (`secd serve --listen <addr>`): JSON-RPC 2.0 over TCP, each
+// message framed with a Content-Length header as LSP frames them. The one method, eval,
+// takes the program as `source` or as `bytecode`, the hex of serialize::write_code, and
+// runs it on a fresh sandboxed machine with the request's `fuel` and `memory` limits,
+// answering with the value and what the program put, or an error whose data is the
+// diagnostics. A request without limits gets the defaults below, and none gets more than
+// the maximums. Bytecode is the client's, so the machine checks each instruction has
+// the values it takes, and a panic all the same fails only its request. Each connection
+// is served on a thread of its own.
+
+const EVAL_FAILED: f64 = -32000.0;
+const METHOD_NOT_FOUND: f64 = -32601.0;
+const INVALID_PARAMS: f64 = -32602.0;
+
+// steps and values a run may use
+pub const DEFAULT_FUEL: usize = 10_000_000;
+pub const MAX_FUEL: usize = 100_000_000;
+pub const DEFAULT_MEMORY: usize = 1_000_000;
+pub const MAX_MEMORY: usize = 10_000_000;
+
+fn hex(s: &str) -> Result<Vec<u8>, Box<Error>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return Err(From::from("bytecode is not hex"));
+    }
+    let mut bytes = vec![];
+    for i in (0..s.len()).step_by(2) {
+        match u8::from_str_radix(&s[i..i + 2], 16) {
+            Ok(b) => bytes.push(b),
+            Err(_) => return Err(From::from("bytecode is not hex")),
+        }
+    }
+    return Ok(bytes);
+}
+
+fn error(id: &Json, code: f64, message: &str, data: Option<Json>) -> Json {
+    let mut error = vec![("code", Json::Num(code)), ("message", Json::str(message))];
+    if let Some(data) = data {
+        error.push(("data", data));
+    }
+    return Json::obj(vec![("jsonrpc", Json::str("2.0")), ("id", id.clone()), ("error", Json::obj(error))]);
+}
+
+// the program a request carries
+fn program(params: &Json) -> Result<Vec<::data::CodeOPInfo>, Box<Error>> {
+    if let Some(source) = params.get("source").and_then(|s| s.as_str()) {
+        let ast = try!(Parser::new(&source.to_string()).parse());
+        return Compiler::new().compile(&ast);
+    }
+    if let Some(bytecode) = params.get("bytecode").and_then(|s| s.as_str()) {
+        return serialize::read_code(&try!(hex(bytecode)), &Primitives::standard());
+    }
+    return Err(From::from("expected source or bytecode"));
+}
+
+// the answer to one request
+pub fn handle(req: &Json) -> Json {
+    let id = req.get("id").cloned().unwrap_or(Json::Null);
+    if req.get("method").and_then(|m| m.as_str()) != Some("eval") {
+        return error(&id, METHOD_NOT_FOUND, "unknown method", None);
+    }
+    let params = req.get("params").cloned().unwrap_or(Json::obj(vec![]));
+    match panic::catch_unwind(AssertUnwindSafe(|| eval(&id, &params))) {
+        Ok(r) => return r,
+        Err(_) => return error(&id, EVAL_FAILED, "vm panic", None),
+    }
+}
+
+fn eval(id: &Json, params: &Json) -> Json {
+    let id = id.clone();
+    let limit = |name: &str, default: usize, max: usize| {
+        let n = params.get(name).and_then(|n| n.as_i64()).map_or(default, |n| n.max(0) as usize);
+        return n.min(max);
+    };
+
+    let code = match program(params) {
+        Ok(code) => code,
+        Err(e) => {
+            if e.downcast_ref::<diagnostic::Diagnostic>().is_none() &&
+               e.downcast_ref::<diagnostic::Diagnostics>().is_none() {
+                return error(&id, INVALID_PARAMS, &format!("{}", e), None);
+            }
+            return failed(&id, &*e);
+        }
+    };
+    let mut vm = SECD::new(code);
+    vm.capabilities = Capabilities::none();
+    vm.fuel = Some(limit("fuel", DEFAULT_FUEL, MAX_FUEL));
+    vm.limit_memory(limit("memory", DEFAULT_MEMORY, MAX_MEMORY));
+    let out = vm.capture();
+    let r = Scheduler::new().run(vm);
+    let output = Json::Str(out.borrow().clone());
+    let result = match r {
+        Ok(RunResult::Value(a)) => Json::obj(vec![("value", Json::Str(format!("{}", a))), ("output", output)]),
+        Ok(RunResult::Exit(n)) => Json::obj(vec![("exit", Json::Num(n as f64)), ("output", output)]),
+        Ok(RunResult::Yield(a)) => Json::obj(vec![("yield", Json::Str(format!("{}", a))), ("output", output)]),
+        Err(e) => return failed(&id, &*e),
+    };
+    return Json::obj(vec![("jsonrpc", Json::str("2.0")), ("id", id), ("result", result)]);
+}
+
+fn failed(id: &Json, e: &(Error + 'static)) -> Json {
+    let diagnostics = diagnostic::all_from_error(e)
+        .iter()
+        .filter_map(|d| Json::parse(&d.to_json(None)).ok())
+        .collect();
+    return error(id, EVAL_FAILED, &format!("{}", e), Some(Json::Arr(diagnostics)));
+}
+
+fn connection(stream: TcpStream) -> Result<(), Box<Error>> {
+    let mut output = try!(stream.try_clone());
+    let mut input = BufReader::new(stream);
+    while let Some(req) = try!(read_message(&mut input)) {
+        try!(write_message(&mut output, &handle(&req)));
+    }
+    return Ok(());
+}
+
+// serves connections on `addr` until the listener fails
+pub fn serve(addr: &str) -> Result<(), Box<Error>> {
+    let listener = try!(TcpListener::bind(addr));
+    for stream in listener.incoming() {
+        let stream = try!(stream);
+        // a connection that breaks off ends only its own thread
+        thread::spawn(move || {
+            let _ = connection(stream);
+        });
+    }
+    return Ok(());
+}
@@ -0,0 +1,100 @@
+use data::{self, AST, SExpr, Lisp, Code, CodeOP, Info};
+use json::Json;
+
+use std::collections::HashMap;
+
+// Where each source expression ended up in the compiled code. Code is a tree:
+// SEL and TSEL branches, LDF bodies (and those of lambdas lifted to an LDC), a
+// TRY's body and handlers (branches 1 on) and the body and cleanup of PROTECT are
+// blocks of their own, so an instruction is named by the path to its block,
+// (instruction index, branch) per step down, plus its index in that block.
+// Expressions are numbered in preorder over the source AST.
+
+pub type BlockPath = Vec<(usize, usize)>;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Range {
+    pub expr: usize,
+    pub span: Info,
+    pub block: BlockPath,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SourceMap {
+    pub ranges: Vec<Range>,
+}
+
+impl SourceMap {
+    // the innermost expression instruction `pc` of `block` belongs to
+    pub fn expr_at(&self, block: &[(usize, usize)], pc: usize) -> Option<&Range> {
+        return self.ranges
+            .iter()
+            .filter(|r| r.block == block && r.start <= pc && pc < r.end)
+            .min_by_key(|r| (r.end - r.start, usize::MAX - r.expr));
+    }
+
+    // every range an expression compiled to, in the order they were emitted
+    pub fn ranges_of(&self, expr: usize) -> Vec<&Range> {
+        return self.ranges.iter().filter(|r| r.expr == expr).collect();
+    }
+
+    pub fn to_json(&self) -> Json {
+        let ranges = self.ranges
+            .iter()
+            .map(|r| {
+                let block = r.block
+                    .iter()
+                    .map(|&(i, b)| Json::Arr(vec![Json::Num(i as f64), Json::Num(b as f64)]))
+                    .collect();
+                Json::obj(vec![("expr", Json::Num(r.expr as f64)),
+                               ("span",
+                                Json::obj(vec![("line", Json::Num(r.span[0] as f64)),
+                                               ("column", Json::Num(r.span[1] as f64))])),
+                               ("block", Json::Arr(block)),
+                               ("start", Json::Num(r.start as f64)),
+                               ("end", Json::Num(r.end as f64))])
+            })
+            .collect();
+        return Json::obj(vec![("ranges", Json::Arr(ranges))]);
+    }
+}
+
+// the nested block `path` names, if there is one
+pub fn block<'a>(code: &'a Code, path: &[(usize, usize)]) -> Option<&'a Code> {
+    let pool = data::pool(code);
+    let mut code = code;
+    for &(i, branch) in path {
+        code = match code.get(i).map(|c| &c.op) {
+            Some(&CodeOP::SEL(ref t, _)) if branch == 0 => t,
+            Some(&CodeOP::SEL(_, ref f)) if branch == 1 => f,
+            Some(&CodeOP::TSEL(ref t, _)) if branch == 0 => t,
+            Some(&CodeOP::TSEL(_, ref f)) if branch == 1 => f,
+            Some(&CodeOP::LDF(_, ref body, _)) if branch == 0 => body,
+            Some(&CodeOP::LDC(i)) if branch == 0 => {
+                match pool.get(i).map(|lisp| &**lisp) {
+                    Some(&Lisp::Closure(_, ref body, _, _)) => body,
+                    _ => return None,
+                }
+            }
+            Some(&CodeOP::TRY(ref body, _)) if branch == 0 => body,
+            Some(&CodeOP::TRY(_, ref handlers)) if branch <= handlers.len() => &handlers[branch - 1].code,
+            Some(&CodeOP::PROTECT(ref body, _)) if branch == 0 => body,
+            Some(&CodeOP::PROTECT(_, ref cleanup)) if branch == 1 => cleanup,
+            _ => return None,
+        };
+    }
+    return Some(code);
+}
+
+// preorder ids by node address; the compiler only knows the nodes it was handed
+pub fn number(ast: &AST, ids: &mut HashMap<*const AST, (usize, Info)>) {
+    let id = ids.len();
+    ids.insert(ast as *const AST, (id, ast.info));
+    if let SExpr::List(ref ls) = ast.sexpr {
+        for a in ls {
+            number(a, ids);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use data::{SECD, Lisp, CodeOP, CodeOPInfo, DumpOP, RunResult};
+use vm::Primitives;
+
+use std::error::Error;
+use std::io::Write;
+use std::rc::Rc;
+
+// Runs a machine one instruction at a time for `--teach`, printing the four registers
+// after each: the top of S, the program's own names in E, the next few instructions of
+// C and the kinds of the frames on D. Long parts are cut short so every step stays a
+// few lines; spawned threads are not followed.
+
+// how much of each register is shown
+const VALUES: usize = 4;
+const NAMES: usize = 6;
+const OPS: usize = 4;
+const WIDTH: usize = 24;
+
+pub fn run<W: Write>(vm: &mut SECD, out: &mut W) -> Result<RunResult, Box<Error>> {
+    let mut n = 0;
+    try!(registers(vm, out));
+    while !vm.halted() {
+        n += 1;
+        let c = op(&vm.consts, &vm.primitives, &vm.code[0]);
+        let r = vm.step();
+        try!(writeln!(out, "step {}: {}", n, c));
+        try!(registers(vm, out));
+        try!(r);
+    }
+    return Ok(vm.result());
+}
+
+fn registers<W: Write>(vm: &SECD, out: &mut W) -> Result<(), Box<Error>> {
+    let stack: Vec<String> = vm.stack.iter().map(|a| a.show(WIDTH)).collect();
+    try!(writeln!(out, "  S: {}", elide(stack, VALUES, true)));
+
+    let env: Vec<String> = vm.env
+        .iter()
+        .filter(|&(k, _)| !k.contains(' '))
+        .map(|(k, v)| format!("{}={}", k, v.show(WIDTH)))
+        .collect();
+    try!(writeln!(out, "  E: {}", elide(env, NAMES, false)));
+
+    let code: Vec<String> = vm.code.iter().map(|c| op(&vm.consts, &vm.primitives, c)).collect();
+    try!(writeln!(out, "  C: {}", elide(code, OPS, false)));
+
+    let dump: Vec<String> = vm.dump.iter().map(|d| frame(d).to_string()).collect();
+    try!(writeln!(out, "  D: {}", elide(dump, OPS, true)));
+    return Ok(());
+}
+
+// at most `keep` items, the last ones if `last`, with how many were left out
+fn elide(items: Vec<String>, keep: usize, last: bool) -> String {
+    if items.len() <= keep {
+        return format!("[{}]", items.join(" "));
+    }
+    let more = format!("+{}", items.len() - keep);
+    if last {
+        return format!("[{} {}]", more, items[items.len() - keep..].join(" "));
+    }
+    return format!("[{} {}]", items[..keep].join(" "), more);
+}
+
+// an instruction on one line, with its constant looked up in `consts`; the code an
+// instruction carries is left out
+pub fn op(consts: &[Rc<Lisp>], primitives: &Primitives, c: &CodeOPInfo) -> String {
+    match c.op {
+        CodeOP::LET(ref id) => return format!("LET {}", id.trim_start()),
+        CodeOP::LD(ref id) => return format!("LD {}", id.trim_start()),
+        // before CONSTS has run there is only the index
+        CodeOP::LDC(i) => {
+            match consts.get(i) {
+                Some(a) => return format!("LDC {}", a.show(WIDTH)),
+                None => return format!("LDC #{}", i),
+            }
+        }
+        CodeOP::LDF(ref args, _, _) => return format!("LDF ({})", args.join(" ")),
+        CodeOP::SEL(..) => return "SEL".to_string(),
+        CodeOP::TSEL(..) => return "TSEL".to_string(),
+        CodeOP::TRY(..) => return "TRY".to_string(),
+        CodeOP::PROTECT(..) => return "PROTECT".to_string(),
+        CodeOP::CONSTS(_) => return "CONSTS".to_string(),
+        CodeOP::HEADER(ref header) => return format!("HEADER {}", header.version),
+        CodeOP::PRIM(id, n) => return format!("PRIM {} {}", primitives.name(id).unwrap_or("?"), n),
+        ref op => return format!("{:?}", op),
+    }
+}
+
+fn frame(d: &DumpOP) -> &'static str {
+    match *d {
+        DumpOP::DumpAP(..) => return "AP",
+        DumpOP::DumpSEL(..) => return "SEL",
+        DumpOP::DumpTRY(..) => return "TRY",
+        DumpOP::DumpPROTECT(..) => return "PROTECT",
+        DumpOP::DumpCALLBACK(..) => return "CALLBACK",
+    }
+}
@@ -0,0 +1,163 @@
+use data::{AST, SExpr, Info};
+
+// Random program generation for property tests. Programs are built as ASTs and
+// only use forms both the VM and the reference interpreter understand.
+
+pub struct Gen {
+    state: u64,
+}
+
+const NO_INFO: Info = [0; 2];
+
+fn node(sexpr: SExpr) -> AST {
+    return AST {
+               info: NO_INFO,
+               sexpr,
+           };
+}
+
+fn atom(id: &str) -> AST {
+    return node(SExpr::Atom(id.into()));
+}
+
+fn list(ls: Vec<AST>) -> AST {
+    return node(SExpr::List(ls));
+}
+
+impl Gen {
+    pub fn new(seed: u64) -> Self {
+        return Gen { state: if seed == 0 { 1 } else { seed } };
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        return self.state.wrapping_mul(0x2545F4914F6CDD1D);
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        return (self.next_u64() >> 33) as usize % n;
+    }
+
+    // a well-typed expression of int type; `vars` are the int variables in scope
+    pub fn int_expr(&mut self, depth: usize, vars: &mut Vec<String>) -> AST {
+        let leaf = depth == 0 || self.below(4) == 0;
+        if leaf {
+            if vars.len() > 0 && self.below(2) == 0 {
+                let i = self.below(vars.len());
+                return atom(&vars[i]);
+            }
+            return node(SExpr::Int(self.below(100) as i32));
+        }
+
+        match self.below(7) {
+            0 => {
+                let op = ["+", "-", "min", "max", "bit-and", "bit-or", "bit-xor"][self.below(7)];
+                let a = self.int_expr(depth - 1, vars);
+                let b = self.int_expr(depth - 1, vars);
+                return list(vec![atom(op), a, b]);
+            }
+
+            1 => {
+                let c = self.bool_expr(depth - 1, vars);
+                let t = self.int_expr(depth - 1, vars);
+                let f = self.int_expr(depth - 1, vars);
+                return list(vec![atom("if"), c, t, f]);
+            }
+
+            2 => {
+                let id = format!("v{}", vars.len());
+                let e = self.int_expr(depth - 1, vars);
+                vars.push(id.clone());
+                let body = self.int_expr(depth - 1, vars);
+                vars.pop();
+                return list(vec![atom("let"), atom(&id), e, body]);
+            }
+
+            3 => {
+                let id = format!("v{}", vars.len());
+                let arg = self.int_expr(depth - 1, vars);
+                vars.push(id.clone());
+                let body = self.int_expr(depth - 1, vars);
+                vars.pop();
+                return list(vec![list(vec![atom("lambda"), atom(&id), body]), arg]);
+            }
+
+            4 => {
+                let a = self.int_expr(depth - 1, vars);
+                let b = self.int_expr(depth - 1, vars);
+                let op = if self.below(2) == 0 { "car" } else { "cdr" };
+                return list(vec![atom(op), list(vec![atom("cons"), a, b])]);
+            }
+
+            5 => {
+                let key = self.int_expr(depth - 1, vars);
+                let n = node(SExpr::Int(self.below(100) as i32));
+                let a = self.int_expr(depth - 1, vars);
+                let b = self.int_expr(depth - 1, vars);
+                return list(vec![atom("case"),
+                                 key,
+                                 list(vec![list(vec![n]), a]),
+                                 list(vec![atom("else"), b])]);
+            }
+
+            _ => {
+                let a = self.int_expr(depth - 1, vars);
+                return list(vec![atom("abs"), a]);
+            }
+        }
+    }
+
+    pub fn bool_expr(&mut self, depth: usize, vars: &mut Vec<String>) -> AST {
+        if depth == 0 || self.below(3) == 0 {
+            return atom(if self.below(2) == 0 { "true" } else { "false" });
+        }
+
+        let a = self.int_expr(depth - 1, vars);
+        let b = self.int_expr(depth - 1, vars);
+        return list(vec![atom("eq"), a, b]);
+    }
+
+    // any shape at all, including ill-typed and unbound code; only the syntax is valid
+    pub fn any_expr(&mut self, depth: usize) -> AST {
+        if depth == 0 || self.below(3) == 0 {
+            match self.below(5) {
+                0 => return node(SExpr::Int(self.below(100) as i32)),
+                1 => return atom(["nil", "true", "false"][self.below(3)]),
+                2 => return node(SExpr::Str("s".into())),
+                3 => return atom(["x", "y"][self.below(2)]),
+                _ => return list(vec![]),
+            }
+        }
+
+        let ops = [("+", 2), ("-", 2), ("eq", 2), ("cons", 2), ("car", 1), ("cdr", 1), ("abs", 1),
+                   ("quotient", 2), ("number->string", 1), ("if", 3)];
+        match self.below(4) {
+            0 => {
+                let a = self.any_expr(depth - 1);
+                let b = self.any_expr(depth - 1);
+                return list(vec![atom("let"), atom(["x", "y"][self.below(2)]), a, b]);
+            }
+
+            1 => {
+                let body = self.any_expr(depth - 1);
+                let lambda = list(vec![atom("lambda"), atom("x"), body]);
+                let mut app = vec![lambda];
+                for _ in 0..self.below(3) {
+                    app.push(self.any_expr(depth - 1));
+                }
+                return list(app);
+            }
+
+            _ => {
+                let (op, n) = ops[self.below(ops.len())];
+                let mut app = vec![atom(op)];
+                for _ in 0..n {
+                    app.push(self.any_expr(depth - 1));
+                }
+                return list(app);
+            }
+        }
+    }
+}
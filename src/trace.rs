@@ -0,0 +1,20 @@
+use data::TraceEvent;
+use json::Json;
+
+// Traces of a run in the Chrome trace-event format, for `--trace`: a begin and an end
+// event around every call, on the thread that made it, which chrome://tracing and
+// Perfetto show as nested spans. A call is named by where its lambda's body starts.
+// Calls the JIT runs natively are not seen.
+
+pub fn chrome(events: &[TraceEvent]) -> String {
+    let events = events.iter()
+        .map(|e| {
+                 Json::obj(vec![("name", Json::str(&e.name)),
+                                ("ph", Json::str(if e.begin { "B" } else { "E" })),
+                                ("ts", Json::Num(e.micros as f64)),
+                                ("pid", Json::Num(1.0)),
+                                ("tid", Json::Num(e.tid as f64))])
+             })
+        .collect();
+    return format!("{}\n", Json::obj(vec![("traceEvents", Json::Arr(events))]));
+}
@@ -0,0 +1,429 @@
+use data::{self, Lisp, Code, CodeOP, CodeOPInfo};
+use diagnostic::Diagnostic;
+use vm;
+
+use std::rc::Rc;
+use std::error::Error;
+use std::fmt::Write;
+
+// Rust source for a standalone program that runs `code` the way the machine does.
+// Each block of code becomes a function over a small runtime with the machine's
+// stack and environment: SEL and TSEL become ifs, LDF refers to the function of its
+// body, and calls go through a loop in the runtime, so tail calls don't grow the
+// native stack. The program prints the final value, or the error the machine would
+// have raised, with its location, and exits 1.
+//
+// Only the pure core translates: variables, closures and calls, if, let, puts, eq,
+// +, -, cons, car, cdr and the integer primitives. Anything else is an error at the
+// instruction that uses it.
+
+const PRIMITIVES: &[&str] = &["min", "max", "abs", "quotient", "remainder", "bit-and", "bit-or",
+                              "bit-xor", "bit-not", "shl", "shr", "number->string"];
+
+pub fn rust(code: &Code) -> Result<String, Box<Error>> {
+    let mut t = Transpiler {
+        funcs: vec![],
+        pool: data::pool(code).to_vec(),
+    };
+    try!(t.function(code));
+
+    let mut out = String::new();
+    out.push_str(RUNTIME);
+    for (i, body) in t.funcs.iter().enumerate() {
+        write!(out, "\nfn f{}(rt: &mut Rt) -> Res {{\n{}    return Ok(Step::Ret);\n}}\n", i, body)
+            .unwrap();
+    }
+    let names: Vec<String> = (0..t.funcs.len()).map(|i| format!("f{}", i)).collect();
+    write!(out, "\nconst FUNCS: &[fn(&mut Rt) -> Res] = &[{}];\n", names.join(", ")).unwrap();
+    return Ok(out);
+}
+
+fn error<T>(c: &CodeOPInfo, msg: String) -> Result<T, Box<Error>> {
+    return Err(From::from(Diagnostic::error("build", Some(c.info), msg)));
+}
+
+struct Transpiler {
+    funcs: Vec<String>,
+    pool: Vec<Rc<Lisp>>,
+}
+
+impl Transpiler {
+    // the index of the function for `code`, which function 0 is the whole program of
+    fn function(&mut self, code: &Code) -> Result<usize, Box<Error>> {
+        let i = self.funcs.len();
+        self.funcs.push(String::new());
+        let mut body = String::new();
+        try!(self.block(code, 1, &mut body));
+        self.funcs[i] = body;
+        return Ok(i);
+    }
+
+    fn block(&mut self, code: &Code, depth: usize, out: &mut String) -> Result<(), Box<Error>> {
+        let pad = "    ".repeat(depth);
+        for c in code {
+            let at = format!("{}, {}", c.info[0], c.info[1]);
+            let line = match c.op {
+                CodeOP::LD(ref id) => format!("at!(rt.ld({:?}), {});", id, at),
+                CodeOP::LDC(i) => {
+                    let lisp = match self.pool.get(i) {
+                        Some(lisp) => lisp.clone(),
+                        None => return error(c, format!("no constant {}", i)),
+                    };
+                    format!("rt.stack.push({});", try!(self.constant(c, &lisp)))
+                }
+                CodeOP::CONSTS(_) | CodeOP::HEADER(_) => continue,
+                CodeOP::LDF(ref names, ref body, _) => {
+                    let f = try!(self.function(body));
+                    format!("rt.ldf(&{:?}, {});", names, f)
+                }
+                CodeOP::LET(ref id) => format!("rt.let_({:?});", id),
+                CodeOP::ARGS(n) => format!("rt.args({});", n),
+                CodeOP::AP => format!("rt.call(Step::Tail(\"AP\", false, [{}]))?;", at),
+                CodeOP::RAP => format!("rt.call(Step::Tail(\"RAP\", true, [{}]))?;", at),
+                CodeOP::TAP => format!("return rt.tail(Step::Tail(\"TAP\", false, [{}]));", at),
+                CodeOP::TRAP => format!("return rt.tail(Step::Tail(\"TRAP\", true, [{}]));", at),
+                CodeOP::RET => "return Ok(Step::Ret);".to_string(),
+                CodeOP::JOIN => continue,
+                CodeOP::SEL(ref t, ref f) |
+                CodeOP::TSEL(ref t, ref f) => {
+                    let name = if let CodeOP::SEL(..) = c.op { "SEL" } else { "TSEL" };
+                    let mut then = String::new();
+                    let mut other = String::new();
+                    try!(self.block(t, depth + 1, &mut then));
+                    try!(self.block(f, depth + 1, &mut other));
+                    format!("let b = at!(rt.cond({:?}), {});\n{}if b {{\n{}{}}} else {{\n{}{}}}",
+                            name,
+                            at,
+                            pad,
+                            then,
+                            pad,
+                            other,
+                            pad)
+                }
+                CodeOP::POP => "rt.stack.pop();".to_string(),
+                CodeOP::PUTS => "rt.puts();".to_string(),
+                CodeOP::EQ => "rt.eq();".to_string(),
+                CodeOP::ADD => format!("at!(rt.add(), {});", at),
+                CodeOP::SUB => format!("at!(rt.sub(), {});", at),
+                CodeOP::CONS => "rt.cons();".to_string(),
+                CodeOP::CAR => format!("at!(rt.car(), {});", at),
+                CodeOP::CDR => format!("at!(rt.cdr(), {});", at),
+                CodeOP::PRIM(id, _) => {
+                    match vm::builtin_name(id) {
+                        Some(name) if PRIMITIVES.contains(&name) => format!("at!(rt.prim({:?}), {});", name, at),
+                        Some(name) => return error(c, format!("cannot build {} into a program", name)),
+                        None => return error(c, "cannot build a registered primitive into a program".to_string()),
+                    }
+                }
+                ref op => return error(c, format!("cannot build {:?} into a program", op)),
+            };
+            out.push_str(&pad);
+            out.push_str(&line);
+            out.push('\n');
+        }
+        return Ok(());
+    }
+
+    // a Rust expression for the value; lifted lambdas are constant closures
+    fn constant(&mut self, c: &CodeOPInfo, lisp: &Lisp) -> Result<String, Box<Error>> {
+        match *lisp {
+            Lisp::Nil => return Ok("Rc::new(V::Nil)".into()),
+            Lisp::True => return Ok("Rc::new(V::True)".into()),
+            Lisp::False => return Ok("Rc::new(V::False)".into()),
+            Lisp::Int(n) => return Ok(format!("Rc::new(V::Int({}))", n)),
+            Lisp::Str(ref s) => return Ok(format!("Rc::new(V::Str({:?}.to_string()))", s)),
+            Lisp::Symbol(ref s) => return Ok(format!("Rc::new(V::Symbol({:?}.to_string()))", s)),
+            Lisp::Cons(ref car, ref cdr) => {
+                return Ok(format!("Rc::new(V::Cons({}, {}))",
+                                  try!(self.constant(c, car)),
+                                  try!(self.constant(c, cdr))))
+            }
+            Lisp::Closure(ref names, ref body, ref env, _) if env.is_empty() => {
+                let f = try!(self.function(body));
+                return Ok(format!("Rc::new(V::Closure(names(&{:?}), {}, Env::new()))", names, f));
+            }
+            _ => return error(c, format!("cannot build the constant {} into a program", lisp)),
+        }
+    }
+}
+
+const RUNTIME: &str = r#"// built by secd from compiled code; the runtime below does what the machine does
+#![allow(unreachable_code, unused_macros, dead_code)]
+
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::process;
+use std::rc::Rc;
+
+type Env = HashMap<String, Rc<V>>;
+
+#[derive(Debug, PartialEq)]
+enum V {
+    Nil,
+    False,
+    True,
+    Int(i32),
+    Str(String),
+    Symbol(String),
+    List(Vec<Rc<V>>),
+    Closure(Vec<String>, usize, Env),
+    Cons(Rc<V>, Rc<V>),
+}
+
+impl fmt::Display for V {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            V::Nil => write!(f, "nil"),
+            V::True => write!(f, "true"),
+            V::False => write!(f, "false"),
+            V::Int(n) => write!(f, "{}", n),
+            V::Str(ref s) => write!(f, "{}", s),
+            V::Symbol(ref s) => write!(f, "{}", s),
+            V::Cons(ref car, ref cdr) => write!(f, "(cons {} {})", car, cdr),
+            V::List(ref ls) => write!(f, "(list {:?})", ls),
+            V::Closure(ref args, _, _) => write!(f, "(lambda {:?} Code)", args),
+        }
+    }
+}
+
+enum Step {
+    Ret,
+    // a call of the closure on the stack, by the instruction at [line, column]
+    Tail(&'static str, bool, [usize; 2]),
+}
+
+type Res = Result<Step, String>;
+
+struct Rt {
+    stack: Vec<Rc<V>>,
+    env: Env,
+}
+
+macro_rules! at {
+    ($e:expr, $line:expr, $column:expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(e) => return Err(format!("{}:{}:vm error: {}", $line, $column, e)),
+        }
+    };
+}
+
+fn names(ns: &[&str]) -> Vec<String> {
+    return ns.iter().map(|n| n.to_string()).collect();
+}
+
+impl Rt {
+    fn pop(&mut self) -> Rc<V> {
+        return self.stack.pop().unwrap();
+    }
+
+    fn pop_int(&mut self, name: &str) -> Result<i32, String> {
+        match *self.pop() {
+            V::Int(n) => return Ok(n),
+            _ => return Err(format!("{}: expected int", name)),
+        }
+    }
+
+    fn push(&mut self, v: V) {
+        self.stack.push(Rc::new(v));
+    }
+
+    fn ld(&mut self, id: &str) -> Result<(), String> {
+        match self.env.get(id) {
+            Some(v) => self.stack.push(v.clone()),
+            None => return Err(format!("LD: unbound {}", id)),
+        }
+        return Ok(());
+    }
+
+    fn let_(&mut self, id: &str) {
+        let v = self.pop();
+        self.env.insert(id.to_string(), v);
+    }
+
+    fn ldf(&mut self, ns: &[&str], f: usize) {
+        let env = self.env.clone();
+        self.push(V::Closure(names(ns), f, env));
+    }
+
+    fn args(&mut self, n: usize) {
+        let at = self.stack.len() - n;
+        let ls = self.stack.split_off(at);
+        self.push(V::List(ls));
+    }
+
+    fn cond(&mut self, name: &str) -> Result<bool, String> {
+        match *self.pop() {
+            V::True => return Ok(true),
+            V::False => return Ok(false),
+            _ => return Err(format!("{}: expected bool", name)),
+        }
+    }
+
+    // sets up the callee's stack and environment like AP, RAP, TAP and TRAP do
+    fn enter(&mut self, name: &str, rec: bool) -> Result<usize, String> {
+        let f = self.pop();
+        let (ns, i, env) = match *f {
+            V::Closure(ref ns, i, ref env) => (ns, i, env),
+            _ => return Err(format!("{}: expected Closure", name)),
+        };
+        let vals = match *self.pop() {
+            V::List(ref vals) => vals.clone(),
+            _ => return Err(format!("{}: expected List", name)),
+        };
+        if ns.len() != vals.len() {
+            return Err(format!("{}: wrong number of arguments", name));
+        }
+        let mut env = env.clone();
+        for (n, v) in ns.iter().zip(vals) {
+            env.insert(n.clone(), v);
+        }
+        self.stack = vec![];
+        if rec {
+            self.env.extend(env);
+        } else {
+            self.env = env;
+        }
+        return Ok(i);
+    }
+
+    // runs the callee, and whatever it tail calls, in a frame of its own
+    fn call(&mut self, step: Step) -> Result<(), String> {
+        let n = self.stack.len();
+        let callee = self.stack.split_off(n - 2);
+        let stack = mem::replace(&mut self.stack, callee);
+        let env = self.env.clone();
+        self.run(step)?;
+        let r = self.pop();
+        self.stack = stack;
+        self.env = env;
+        self.stack.push(r);
+        return Ok(());
+    }
+
+    // follows tail calls until one returns
+    fn run(&mut self, step: Step) -> Result<(), String> {
+        let mut step = step;
+        while let Step::Tail(name, rec, [line, column]) = step {
+            let i = at!(self.enter(name, rec), line, column);
+            step = FUNCS[i](self)?;
+        }
+        return Ok(());
+    }
+
+    // the closure and arguments stay on the stack for the loop in run
+    fn tail(&mut self, step: Step) -> Res {
+        let n = self.stack.len();
+        let call = self.stack.split_off(n - 2);
+        self.stack = call;
+        return Ok(step);
+    }
+
+    fn puts(&mut self) {
+        println!("{}", self.stack.last().unwrap());
+    }
+
+    fn eq(&mut self) {
+        let a = self.pop();
+        let b = self.pop();
+        self.push(if a == b { V::True } else { V::False });
+    }
+
+    fn add(&mut self) -> Result<(), String> {
+        let n = self.pop_int("ADD")?;
+        let m = self.pop_int("ADD")?;
+        match m.checked_add(n) {
+            Some(a) => self.push(V::Int(a)),
+            None => return Err("ADD: overflow".to_string()),
+        }
+        return Ok(());
+    }
+
+    fn sub(&mut self) -> Result<(), String> {
+        let n = self.pop_int("SUB")?;
+        let m = self.pop_int("SUB")?;
+        match m.checked_sub(n) {
+            Some(a) => self.push(V::Int(a)),
+            None => return Err("SUB: overflow".to_string()),
+        }
+        return Ok(());
+    }
+
+    fn cons(&mut self) {
+        let a = self.pop();
+        let b = self.pop();
+        self.push(V::Cons(b, a));
+    }
+
+    fn car(&mut self) -> Result<(), String> {
+        match *self.pop() {
+            V::Cons(ref car, _) => self.stack.push(car.clone()),
+            _ => return Err("CAR: expected Cons".to_string()),
+        }
+        return Ok(());
+    }
+
+    fn cdr(&mut self) -> Result<(), String> {
+        match *self.pop() {
+            V::Cons(_, ref cdr) => self.stack.push(cdr.clone()),
+            _ => return Err("CDR: expected Cons".to_string()),
+        }
+        return Ok(());
+    }
+
+    fn prim(&mut self, name: &str) -> Result<(), String> {
+        let (op, unary) = match name {
+            "min" => ("MIN", false),
+            "max" => ("MAX", false),
+            "abs" => ("ABS", true),
+            "quotient" => ("QUOT", false),
+            "remainder" => ("REM", false),
+            "bit-and" => ("BAND", false),
+            "bit-or" => ("BOR", false),
+            "bit-xor" => ("BXOR", false),
+            "bit-not" => ("BNOT", true),
+            "shl" => ("SHL", false),
+            "shr" => ("SHR", false),
+            _ => ("NUM2STR", true),
+        };
+        let n = self.pop_int(op)?;
+        let m = if unary { 0 } else { self.pop_int(op)? };
+        let overflow = || format!("{}: overflow", op);
+        let v = match op {
+            "MIN" => V::Int(if m < n { m } else { n }),
+            "MAX" => V::Int(if m > n { m } else { n }),
+            "ABS" => V::Int(n.checked_abs().ok_or_else(overflow)?),
+            "QUOT" | "REM" if n == 0 => return Err(format!("{}: division by zero", op)),
+            "QUOT" => V::Int(m.checked_div(n).ok_or_else(overflow)?),
+            "REM" => V::Int(m.checked_rem(n).ok_or_else(overflow)?),
+            "BAND" => V::Int(m & n),
+            "BOR" => V::Int(m | n),
+            "BXOR" => V::Int(m ^ n),
+            "BNOT" => V::Int(!n),
+            "SHL" | "SHR" if !(0..32).contains(&n) => {
+                return Err(format!("{}: shift out of range", op))
+            }
+            "SHL" => V::Int(m << n),
+            "SHR" => V::Int(m >> n),
+            _ => V::Str(n.to_string()),
+        };
+        self.push(v);
+        return Ok(());
+    }
+}
+
+fn main() {
+    let mut rt = Rt {
+        stack: vec![],
+        env: Env::new(),
+    };
+    match f0(&mut rt).and_then(|step| rt.run(step)) {
+        Ok(_) => println!("{}", rt.stack.last().unwrap()),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+"#;
@@ -0,0 +1,967 @@
+use data::{AST, SExpr};
+use compiler::{self, Compiler};
+use diagnostic::Diagnostic;
+use prelude;
+
+use std::fmt;
+use std::error::Error;
+
+// An opt-in Hindley-Milner pass over the AST, run before compilation. Lists are
+// one type whatever they hold, and what the checker can't follow (list elements,
+// conditions, threads, ports) is Dyn, which agrees with every type, so it only
+// reports what is bound to go wrong at run time. Malformed forms are left for the
+// compiler to report.
+//
+// Lambda parameters written `(x : int)` and `(the int expr)` fix a type where
+// inference would have found a variable or Dyn; the compiler checks that they are
+// well formed and otherwise ignores them.
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Type {
+    Int,
+    Bool,
+    Str,
+    Sym,
+    // a cons cell or nil
+    List,
+    Dyn,
+    Fn(Vec<Type>, Box<Type>),
+    Var(usize),
+}
+
+// a let bound type; each use gets fresh copies of `vars`
+#[derive(Debug, Clone)]
+struct Scheme {
+    vars: Vec<usize>,
+    ty: Type,
+}
+
+// the form, the instruction it compiles to (named in errors) and its signature:
+// lower case letters are type variables and "(a -> b)" is a function
+const PRIMITIVES: &[(&str, &str, &str)] = &[("+", "ADD", "Int Int -> Int"),
+                                            ("-", "SUB", "Int Int -> Int"),
+                                            ("eq", "EQ", "Dyn Dyn -> Bool"),
+                                            ("puts", "PUTS", "a -> a"),
+                                            ("cons", "CONS", "Dyn Dyn -> List"),
+                                            ("car", "CAR", "List -> Dyn"),
+                                            ("cdr", "CDR", "List -> Dyn"),
+                                            ("min", "MIN", "Int Int -> Int"),
+                                            ("max", "MAX", "Int Int -> Int"),
+                                            ("abs", "ABS", "Int -> Int"),
+                                            ("quotient", "QUOT", "Int Int -> Int"),
+                                            ("remainder", "REM", "Int Int -> Int"),
+                                            ("bit-and", "BAND", "Int Int -> Int"),
+                                            ("bit-or", "BOR", "Int Int -> Int"),
+                                            ("bit-xor", "BXOR", "Int Int -> Int"),
+                                            ("bit-not", "BNOT", "Int -> Int"),
+                                            ("shl", "SHL", "Int Int -> Int"),
+                                            ("shr", "SHR", "Int Int -> Int"),
+                                            ("current-time", "CURTIME", "-> Int"),
+                                            ("clock", "CLOCK", "-> Int"),
+                                            ("time", "TIME", "a -> a"),
+                                            ("assert", "ASSERT", "Bool -> Bool"),
+                                            ("test", "TEST", "Str Dyn Dyn -> Bool"),
+                                            ("number->string", "NUM2STR", "Int -> Str"),
+                                            ("string-append", "STRAPPEND", "Str Str -> Str"),
+                                            ("value->string", "VALUE2STR", "Dyn -> Str"),
+                                            ("string->number", "STR2NUM", "Str -> Dyn"),
+                                            ("symbol->string", "SYM2STR", "Sym -> Str"),
+                                            ("string->symbol", "STR2SYM", "Str -> Sym"),
+                                            ("yield", "YIELD", "Dyn -> Dyn"),
+                                            ("spawn", "SPAWN", "(-> Dyn) -> Dyn"),
+                                            ("join", "TJOIN", "Dyn -> Dyn"),
+                                            ("chan", "CHAN", "-> Dyn"),
+                                            ("send", "SEND", "Dyn Dyn -> Dyn"),
+                                            ("recv", "RECV", "Dyn -> Dyn"),
+                                            ("random", "RANDOM", "Int -> Int"),
+                                            ("exit", "EXIT", "Int -> a"),
+                                            ("getenv", "GETENV", "Str -> Dyn"),
+                                            ("system", "SYSTEM", "Str -> List"),
+                                            ("process", "PROCESS", "Str List -> List"),
+                                            ("compare", "COMPARE", "Dyn Dyn -> Int"),
+                                            ("sort", "SORT", "List -> List"),
+                                            ("sort-by", "SORTBY", "(Dyn Dyn -> Int) List -> List"),
+                                            ("map", "MAP", "(Dyn -> Dyn) List -> List"),
+                                            ("filter", "FILTER", "(Dyn -> Bool) List -> List"),
+                                            ("foldl", "FOLDL", "(a Dyn -> a) a List -> a"),
+                                            ("foldr", "FOLDR", "(Dyn a -> a) a List -> a"),
+                                            ("range", "RANGE", "Int Int Int -> List"),
+                                            ("with-output-to-string", "OUTSTR", "(-> Dyn) -> Str"),
+                                            ("tcp-connect", "TCPCONNECT", "Str Int -> Dyn"),
+                                            ("tcp-listen", "TCPLISTEN", "Str Int -> Dyn"),
+                                            ("tcp-accept", "TCPACCEPT", "Dyn -> Dyn"),
+                                            ("tcp-read", "TCPREAD", "Dyn -> Dyn"),
+                                            ("tcp-write", "TCPWRITE", "Dyn Str -> Dyn"),
+                                            ("tcp-close", "TCPCLOSE", "Dyn -> List"),
+                                            ("http-get", "HTTPGET", "Str -> List"),
+                                            ("date-now", "DATENOW", "-> List"),
+                                            ("date->string", "DATE2STR", "Str List -> Str"),
+                                            ("string->date", "STR2DATE", "Str Str -> List"),
+                                            ("error", "MKCOND", "Sym Str Dyn -> a"),
+                                            ("raise", "RAISE", "Dyn -> a"),
+                                            ("dynamic-wind", "PROTECT", "(-> Dyn) (-> a) (-> Dyn) -> a"),
+                                            ("make-parameter", "MKPARAM", "a -> (-> a)"),
+                                            ("condition-type", "CONDTYPE", "Dyn -> Sym"),
+                                            ("condition-message", "CONDMSG", "Dyn -> Str"),
+                                            ("condition-payload", "CONDPAYLOAD", "Dyn -> Dyn"),
+                                            ("condition-location", "CONDLOC", "Dyn -> List"),
+                                            ("help", "HELP", "Dyn -> Dyn"),
+                                            ("procedure?", "PROCP", "Dyn -> Bool"),
+                                            ("procedure-arity", "PROCARITY", "Dyn -> Int"),
+                                            ("procedure-source", "PROCSOURCE", "Dyn -> List"),
+                                            ("copy", "COPY", "a -> a"),
+                                            ("freeze", "FREEZE", "a -> a"),
+                                            ("make-weak-ref", "MKWEAK", "Dyn -> Dyn"),
+                                            ("weak-deref", "WEAKDEREF", "Dyn -> Dyn"),
+                                            ("checkpoint", "CHECKPOINT", "Str a -> a"),
+                                            ("restore", "RESTORE", "Str -> Dyn"),
+                                            ("secd-stack", "SECDSTACK", "-> List"),
+                                            ("secd-env", "SECDENV", "-> List"),
+                                            ("secd-where", "SECDWHERE", "-> List"),
+                                            ("promise", "PROMISE", "(-> Dyn) -> Dyn"),
+                                            ("make-promise", "MKPROMISE", "Dyn -> Dyn"),
+                                            ("promise?", "PROMISEP", "Dyn -> Bool"),
+                                            ("force", "FORCE", "Dyn -> Dyn"),
+                                            ("untrace", "UNTRACE", "Dyn -> Dyn"),
+                                            ("break", "BREAK", "-> List"),
+                                            ("break-if", "BREAKIF", "Bool -> List")];
+
+type TypeResult = Result<Type, Box<Error>>;
+
+// a type as annotations spell it: int, bool, string, symbol, list, any, or
+// (<type>* -> <type>) for a function
+pub fn annotation(ast: &AST) -> Option<Type> {
+    match ast.sexpr {
+        SExpr::Atom(ref id) => {
+            match id.as_str() {
+                "int" => return Some(Type::Int),
+                "bool" => return Some(Type::Bool),
+                "string" => return Some(Type::Str),
+                "symbol" => return Some(Type::Sym),
+                "list" => return Some(Type::List),
+                "any" => return Some(Type::Dyn),
+                _ => return None,
+            }
+        }
+        SExpr::List(ref ls) => {
+            let arrow = ls.iter().position(|a| a.sexpr == SExpr::Atom("->".to_string()));
+            match arrow {
+                Some(i) if i + 2 == ls.len() => {
+                    let params: Option<Vec<Type>> = ls[..i].iter().map(annotation).collect();
+                    match (params, annotation(&ls[i + 1])) {
+                        (Some(params), Some(ret)) => return Some(Type::Fn(params, Box::new(ret))),
+                        _ => return None,
+                    }
+                }
+                _ => return None,
+            }
+        }
+        _ => return None,
+    }
+}
+
+// a lambda parameter, `x` or `(x : <type>)`, and its annotation if it has one
+pub fn param(ast: &AST) -> Option<(&String, Option<Type>)> {
+    match ast.sexpr {
+        SExpr::Atom(ref id) => return Some((id, None)),
+        SExpr::List(ref ls) if ls.len() == 3 && ls[1].sexpr == SExpr::Atom(":".to_string()) => {
+            match ls[0].sexpr {
+                SExpr::Atom(ref id) => return annotation(&ls[2]).map(|t| (id, Some(t))),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    }
+}
+
+// the type of a whole program, or the first type error in it
+pub fn check(ast: &AST) -> TypeResult {
+    let mut checker = Checker::new();
+    let ty = try!(checker.infer(ast));
+    return Ok(checker.zonk(&ty));
+}
+
+pub struct Checker {
+    // what each type variable has been solved to
+    subst: Vec<Option<Type>>,
+    env: Vec<(String, Scheme)>,
+}
+
+impl Checker {
+    pub fn new() -> Self {
+        return Checker {
+                   subst: vec![],
+                   env: vec![],
+               };
+    }
+
+    fn error<T>(&self, ast: &AST, msg: String) -> Result<T, Box<Error>> {
+        return Err(From::from(Diagnostic::error("type", Some(ast.info), msg)));
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.subst.push(None);
+        return Type::Var(self.subst.len() - 1);
+    }
+
+    // follows solved variables at the top of `t`
+    fn resolve(&self, t: &Type) -> Type {
+        if let Type::Var(v) = *t {
+            if let Some(ref t) = self.subst[v] {
+                return self.resolve(t);
+            }
+        }
+        return t.clone();
+    }
+
+    // follows solved variables all the way down
+    fn zonk(&self, t: &Type) -> Type {
+        match self.resolve(t) {
+            Type::Fn(params, ret) => {
+                return Type::Fn(params.iter().map(|p| self.zonk(p)).collect(),
+                                Box::new(self.zonk(&ret)))
+            }
+            t => return t,
+        }
+    }
+
+    fn free_vars(&self, t: &Type, vars: &mut Vec<usize>) {
+        match self.resolve(t) {
+            Type::Var(v) if !vars.contains(&v) => vars.push(v),
+            Type::Fn(params, ret) => {
+                for p in &params {
+                    self.free_vars(p, vars);
+                }
+                self.free_vars(&ret, vars);
+            }
+            _ => {}
+        }
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> bool {
+        match (self.resolve(a), self.resolve(b)) {
+            (Type::Dyn, _) |
+            (_, Type::Dyn) => return true,
+            (Type::Var(v), Type::Var(w)) if v == w => return true,
+            (Type::Var(v), t) |
+            (t, Type::Var(v)) => {
+                // a type that contains itself has no finite spelling; stop following it
+                let mut vars = vec![];
+                self.free_vars(&t, &mut vars);
+                self.subst[v] = Some(if vars.contains(&v) { Type::Dyn } else { t });
+                return true;
+            }
+            (Type::Fn(p, r), Type::Fn(q, s)) => {
+                return p.len() == q.len() &&
+                       p.iter().zip(q.iter()).all(|(p, q)| self.unify(p, q)) &&
+                       self.unify(&r, &s);
+            }
+            (a, b) => return a == b,
+        }
+    }
+
+    // the variables of `t` no binding in scope mentions
+    fn generalize(&self, t: &Type) -> Scheme {
+        let mut bound = vec![];
+        for &(_, ref s) in &self.env {
+            let mut vars = vec![];
+            self.free_vars(&s.ty, &mut vars);
+            bound.extend(vars.into_iter().filter(|v| !s.vars.contains(v)));
+        }
+        let mut vars = vec![];
+        self.free_vars(t, &mut vars);
+        vars.retain(|v| !bound.contains(v));
+        return Scheme {
+                   vars,
+                   ty: self.zonk(t),
+               };
+    }
+
+    fn instantiate(&mut self, s: &Scheme) -> Type {
+        let fresh: Vec<(usize, Type)> = s.vars.iter().map(|&v| (v, self.fresh())).collect();
+        return replace(&s.ty, &fresh);
+    }
+
+    fn bind(&mut self, id: &str, s: Scheme) {
+        self.env.push((id.to_string(), s));
+    }
+
+    fn bind_mono(&mut self, id: &str, t: Type) {
+        self.bind(id,
+                  Scheme {
+                      vars: vec![],
+                      ty: t,
+                  });
+    }
+
+    fn bound(&self, id: &str) -> bool {
+        return self.env.iter().any(|b| b.0 == id);
+    }
+
+    fn primitive(&self, id: &str) -> Option<(&'static str, &'static str)> {
+        // the prelude's closures of these can be passed around, and a letrec of the same
+        // name replaces them
+        if prelude::NATIVE.contains(&id) && self.bound(id) {
+            return None;
+        }
+        return PRIMITIVES.iter().find(|p| p.0 == id).map(|p| (p.1, p.2));
+    }
+
+    fn signature(&mut self, sig: &str) -> Type {
+        let sig = sig.replace('(', " ( ").replace(')', " ) ");
+        let tokens: Vec<&str> = sig.split_whitespace().collect();
+        let mut vars = vec![];
+        let mut pos = 0;
+        return self.signature_fn(&tokens, &mut pos, &mut vars);
+    }
+
+    fn signature_fn<'a>(&mut self,
+                        tokens: &[&'a str],
+                        pos: &mut usize,
+                        vars: &mut Vec<(&'a str, Type)>)
+                        -> Type {
+        let mut params = vec![];
+        while tokens[*pos] != "->" {
+            params.push(self.signature_type(tokens, pos, vars));
+        }
+        *pos += 1;
+        let ret = self.signature_type(tokens, pos, vars);
+        return Type::Fn(params, Box::new(ret));
+    }
+
+    fn signature_type<'a>(&mut self,
+                          tokens: &[&'a str],
+                          pos: &mut usize,
+                          vars: &mut Vec<(&'a str, Type)>)
+                          -> Type {
+        let token = tokens[*pos];
+        *pos += 1;
+        match token {
+            "Int" => return Type::Int,
+            "Bool" => return Type::Bool,
+            "Str" => return Type::Str,
+            "Sym" => return Type::Sym,
+            "List" => return Type::List,
+            "Dyn" => return Type::Dyn,
+            "(" => {
+                let f = self.signature_fn(tokens, pos, vars);
+                *pos += 1;
+                return f;
+            }
+            v => {
+                if let Some(&(_, ref t)) = vars.iter().find(|b| b.0 == v) {
+                    return t.clone();
+                }
+                let t = self.fresh();
+                vars.push((v, t.clone()));
+                return t;
+            }
+        }
+    }
+
+    pub fn infer(&mut self, ast: &AST) -> TypeResult {
+        match ast.sexpr {
+            SExpr::Int(_) => return Ok(Type::Int),
+            SExpr::Str(_) => return Ok(Type::Str),
+            SExpr::Atom(ref id) => return self.infer_atom(ast, id),
+            SExpr::List(ref ls) => {
+                let id = match ls.first().map(|a| &a.sexpr) {
+                    None => return Ok(Type::List),
+                    Some(&SExpr::Atom(ref id)) => id.as_str(),
+                    Some(_) => return self.infer_apply(ls),
+                };
+                match id {
+                    "lambda" => return self.infer_lambda(ls),
+                    "let" => return self.infer_let(ls, false),
+                    "letrec" => return self.infer_let(ls, true),
+                    "module" => return self.infer_module(ls),
+                    "if" => return self.infer_if(ls),
+                    "begin" => return self.infer_begin(ls),
+                    // which clause is compiled depends on the features, which aren't known here
+                    "cond-expand" => return Ok(Type::Dyn),
+                    // the name takes the place of begin
+                    "test-group" if ls.len() > 1 => return self.infer_begin(&ls[1..].to_vec()),
+                    "quote" => return Ok(infer_quote(ls)),
+                    "case" => return self.infer_case(ls),
+                    "do" => return self.infer_do(ls),
+                    "try" => return self.infer_try(ls),
+                    "unwind-protect" => return self.infer_protect(ls),
+                    "parameterize" => return self.infer_parameterize(ls),
+                    "the" => return self.infer_the(ls),
+                    "curry" => return self.infer_curry(ls),
+                    "define-record" => return self.infer_record(ls),
+                    "define-variant" => return self.infer_variant(ls),
+                    "match" => return self.infer_match(ls),
+                    // checked as the calls they thread into
+                    "->" | "->>" => {
+                        match Compiler::new().expand(ast) {
+                            Ok(call) => return self.infer(&call),
+                            Err(_) => return Ok(Type::Dyn),
+                        }
+                    }
+                    "define/contract" => return self.infer_contract(ls),
+                    // the expression is checked where it is, though it runs when forced
+                    "delay" if ls.len() == 2 => {
+                        try!(self.infer(&ls[1]));
+                        return Ok(Type::Dyn);
+                    }
+                    "trace" if ls.len() == 2 => {
+                        try!(self.infer(&ls[1]));
+                        return Ok(Type::Sym);
+                    }
+                    "stream-cons" if ls.len() == 3 => {
+                        try!(self.infer(&ls[1]));
+                        try!(self.infer(&ls[2]));
+                        return Ok(Type::List);
+                    }
+                    _ => {}
+                }
+                match self.primitive(id) {
+                    Some((op, sig)) => return self.infer_primitive(ls, op, sig),
+                    None => return self.infer_apply(ls),
+                }
+            }
+        }
+    }
+
+    fn infer_atom(&mut self, ast: &AST, id: &str) -> TypeResult {
+        match id {
+            "nil" => return Ok(Type::List),
+            "true" | "false" => return Ok(Type::Bool),
+            _ if id.starts_with("#:") => return Ok(Type::Sym),
+            _ => {}
+        }
+
+        if let Some(s) = self.env.iter().rev().find(|b| b.0 == id).map(|b| b.1.clone()) {
+            return Ok(self.instantiate(&s));
+        }
+        let id = id.trim_start_matches("prelude:");
+        if prelude::NATIVE.contains(&id) {
+            let sig = PRIMITIVES.iter().find(|p| p.0 == id).unwrap().2;
+            return Ok(self.signature(sig));
+        }
+        if prelude::DEFINITIONS.iter().any(|d| d.0 == id) {
+            return Ok(Type::Dyn);
+        }
+        return self.error(ast, format!("unbound variable {}", id));
+    }
+
+    fn infer_primitive(&mut self, ls: &Vec<AST>, op: &str, sig: &str) -> TypeResult {
+        let (params, ret) = match self.signature(sig) {
+            Type::Fn(params, ret) => (params, *ret),
+            _ => unreachable!(),
+        };
+        // + and - take any number of ints, - at least one
+        let params = match op {
+            "ADD" | "SUB" if ls.len() > 1 || op == "ADD" => vec![Type::Int; ls.len() - 1],
+            _ => params,
+        };
+        if params.len() != ls.len() - 1 {
+            return Ok(Type::Dyn);
+        }
+
+        for (arg, param) in ls[1..].iter().zip(params.iter()) {
+            let t = try!(self.infer(arg));
+            if !self.unify(param, &t) {
+                return self.error(arg, format!("{} on {}", op, self.zonk(&t)));
+            }
+        }
+        return Ok(ret);
+    }
+
+    fn infer_apply(&mut self, ls: &Vec<AST>) -> TypeResult {
+        let f = try!(self.infer(&ls[0]));
+        let mut args = vec![];
+        for arg in &ls[1..] {
+            args.push(try!(self.infer(arg)));
+        }
+
+        match self.resolve(&f) {
+            Type::Fn(params, ret) => {
+                if params.len() != args.len() {
+                    return self.error(&ls[0],
+                                      format!("applying a function of {} arguments to {}",
+                                              params.len(),
+                                              args.len()));
+                }
+                for (i, (p, a)) in params.iter().zip(args.iter()).enumerate() {
+                    let msg = format!("expected {}, found {}", self.zonk(p), self.zonk(a));
+                    if !self.unify(p, a) {
+                        return self.error(&ls[i + 1], msg);
+                    }
+                }
+                return Ok(*ret);
+            }
+            Type::Var(_) => {
+                let ret = self.fresh();
+                self.unify(&f, &Type::Fn(args, Box::new(ret.clone())));
+                return Ok(ret);
+            }
+            Type::Dyn => return Ok(Type::Dyn),
+            t => return self.error(&ls[0], format!("applying {} as a function", t)),
+        }
+    }
+
+    fn infer_lambda(&mut self, ls: &Vec<AST>) -> TypeResult {
+        match (ls.len(), ls.get(2).map(|a| &a.sexpr)) {
+            (3, _) |
+            (4, Some(&SExpr::Str(_))) => {}
+            _ => return Ok(Type::Dyn),
+        }
+        let ids: Vec<&AST> = match ls[1].sexpr {
+            SExpr::Atom(_) => vec![&ls[1]],
+            SExpr::List(ref ids) => ids.iter().collect(),
+            _ => return Ok(Type::Dyn),
+        };
+
+        let depth = self.env.len();
+        let mut params = vec![];
+        for id in ids {
+            match param(id) {
+                Some((id, t)) => {
+                    let t = t.unwrap_or_else(|| self.fresh());
+                    self.bind_mono(id, t.clone());
+                    params.push(t);
+                }
+                None => {
+                    self.env.truncate(depth);
+                    return Ok(Type::Dyn);
+                }
+            }
+        }
+        let body = self.infer(&ls[ls.len() - 1]);
+        self.env.truncate(depth);
+        return Ok(Type::Fn(params, Box::new(try!(body))));
+    }
+
+    // a letrec's own uses inside its definition see one type, later ones each a copy
+    fn infer_let(&mut self, ls: &Vec<AST>, rec: bool) -> TypeResult {
+        if ls.len() != 4 {
+            return Ok(Type::Dyn);
+        }
+        let id = match ls[1].sexpr {
+            SExpr::Atom(ref id) => id,
+            _ => return Ok(Type::Dyn),
+        };
+
+        let t = if rec {
+            let v = self.fresh();
+            self.bind_mono(id, v.clone());
+            let t = self.infer(&ls[2]);
+            self.env.pop();
+            let t = try!(t);
+            if !self.unify(&v, &t) {
+                return self.error(&ls[2],
+                                  format!("{} is used as {} but defined as {}",
+                                          id,
+                                          self.zonk(&v),
+                                          self.zonk(&t)));
+            }
+            t
+        } else {
+            try!(self.infer(&ls[2]))
+        };
+
+        let s = self.generalize(&t);
+        self.bind(id, s);
+        let body = self.infer(&ls[3]);
+        self.env.pop();
+        return body;
+    }
+
+    // each binding as a letrec under both its names, and the body with only the
+    // qualified ones
+    fn infer_module(&mut self, ls: &Vec<AST>) -> TypeResult {
+        let (name, bindings) = match (ls.len(), ls.get(1).map(|a| &a.sexpr), ls.get(2).map(|a| &a.sexpr)) {
+            (4, Some(&SExpr::Atom(ref name)), Some(&SExpr::List(ref bindings))) => (name, bindings),
+            _ => return Ok(Type::Dyn),
+        };
+
+        let mut pairs = vec![];
+        for b in bindings {
+            match b.sexpr {
+                SExpr::List(ref b) if b.len() == 2 => {
+                    match b[0].sexpr {
+                        SExpr::Atom(ref id) => pairs.push((id, &b[1])),
+                        _ => return Ok(Type::Dyn),
+                    }
+                }
+                _ => return Ok(Type::Dyn),
+            }
+        }
+
+        let depth = self.env.len();
+        let mut qualified = vec![];
+        for (id, expr) in pairs {
+            let q = format!("{}:{}", name, id);
+            let v = self.fresh();
+            self.bind_mono(&q, v.clone());
+            self.bind_mono(id, v.clone());
+            let t = self.infer(expr);
+            self.env.truncate(self.env.len() - 2);
+            let t = match t {
+                Ok(t) if self.unify(&v, &t) => t,
+                Ok(t) => {
+                    let msg = format!("{} is used as {} but defined as {}", q, self.zonk(&v), self.zonk(&t));
+                    self.env.truncate(depth);
+                    return self.error(expr, msg);
+                }
+                Err(e) => {
+                    self.env.truncate(depth);
+                    return Err(e);
+                }
+            };
+            let s = self.generalize(&t);
+            self.bind(&q, s.clone());
+            self.bind(id, s.clone());
+            qualified.push((q, s));
+        }
+        self.env.truncate(depth);
+        for (q, s) in qualified {
+            self.bind(&q, s);
+        }
+        let body = self.infer(&ls[3]);
+        self.env.truncate(depth);
+        return body;
+    }
+
+    // branches of different types make a Dyn, as lisp code often returns nil for nothing
+    fn infer_if(&mut self, ls: &Vec<AST>) -> TypeResult {
+        if ls.len() != 4 {
+            return Ok(Type::Dyn);
+        }
+        let test = try!(self.infer(&ls[1]));
+        if !self.unify(&Type::Bool, &test) {
+            return self.error(&ls[1], format!("SEL on {}", self.zonk(&test)));
+        }
+        let t = try!(self.infer(&ls[2]));
+        let f = try!(self.infer(&ls[3]));
+        return Ok(self.join(t, f));
+    }
+
+    fn join(&mut self, a: Type, b: Type) -> Type {
+        if self.unify(&a, &b) {
+            return a;
+        }
+        return Type::Dyn;
+    }
+
+    fn infer_begin(&mut self, ls: &Vec<AST>) -> TypeResult {
+        let mut t = Type::Dyn;
+        for e in &ls[1..] {
+            t = try!(self.infer(e));
+        }
+        return Ok(t);
+    }
+
+    fn infer_case(&mut self, ls: &Vec<AST>) -> TypeResult {
+        if ls.len() < 2 {
+            return Ok(Type::Dyn);
+        }
+        try!(self.infer(&ls[1]));
+
+        // without an else clause a key matching nothing makes nil
+        let mut t = None;
+        let mut exhaustive = false;
+        for clause in &ls[2..] {
+            let cl = match clause.sexpr {
+                SExpr::List(ref cl) if cl.len() == 2 => cl,
+                _ => return Ok(Type::Dyn),
+            };
+            let c = try!(self.infer(&cl[1]));
+            exhaustive |= cl[0].sexpr == SExpr::Atom("else".to_string());
+            t = Some(match t {
+                         Some(t) => self.join(t, c),
+                         None => c,
+                     });
+        }
+        match t {
+            Some(t) if exhaustive => return Ok(t),
+            Some(t) => return Ok(self.join(t, Type::List)),
+            None => return Ok(Type::List),
+        }
+    }
+
+    fn infer_do(&mut self, ls: &Vec<AST>) -> TypeResult {
+        if ls.len() < 3 {
+            return Ok(Type::Dyn);
+        }
+        let vars = match ls[1].sexpr {
+            SExpr::List(ref vars) => vars,
+            _ => return Ok(Type::Dyn),
+        };
+        let mut ids = vec![];
+        for var in vars {
+            match var.sexpr {
+                SExpr::List(ref v) if v.len() == 2 || v.len() == 3 => {
+                    match v[0].sexpr {
+                        SExpr::Atom(ref id) => ids.push((id, try!(self.infer(&v[1])), v.get(2))),
+                        _ => return Ok(Type::Dyn),
+                    }
+                }
+                _ => return Ok(Type::Dyn),
+            }
+        }
+        let (test, result) = match ls[2].sexpr {
+            SExpr::List(ref t) if t.len() == 2 => (&t[0], &t[1]),
+            _ => return Ok(Type::Dyn),
+        };
+
+        let depth = self.env.len();
+        for &(id, ref t, _) in &ids {
+            self.bind_mono(id, t.clone());
+        }
+        let r = self.infer_do_body(&ls[3..], &ids, test, result);
+        self.env.truncate(depth);
+        return r;
+    }
+
+    fn infer_do_body(&mut self,
+                     body: &[AST],
+                     ids: &[(&String, Type, Option<&AST>)],
+                     test: &AST,
+                     result: &AST)
+                     -> TypeResult {
+        let t = try!(self.infer(test));
+        if !self.unify(&Type::Bool, &t) {
+            return self.error(test, format!("SEL on {}", self.zonk(&t)));
+        }
+        for e in body {
+            try!(self.infer(e));
+        }
+        for &(_, ref t, step) in ids {
+            if let Some(step) = step {
+                let s = try!(self.infer(step));
+                let msg = format!("expected {}, found {}", self.zonk(t), self.zonk(&s));
+                if !self.unify(t, &s) {
+                    return self.error(step, msg);
+                }
+            }
+        }
+        return self.infer(result);
+    }
+
+    fn infer_try(&mut self, ls: &Vec<AST>) -> TypeResult {
+        if ls.len() < 3 {
+            return Ok(Type::Dyn);
+        }
+        let mut t = try!(self.infer(&ls[1]));
+        for clause in &ls[2..] {
+            let (id, expr) = match clause.sexpr {
+                SExpr::List(ref cl) if cl.len() == 3 => {
+                    match cl[1].sexpr {
+                        SExpr::Atom(ref id) => (id, &cl[2]),
+                        _ => return Ok(Type::Dyn),
+                    }
+                }
+                _ => return Ok(Type::Dyn),
+            };
+            self.bind_mono(id, Type::Dyn);
+            let h = self.infer(expr);
+            self.env.pop();
+            let h = try!(h);
+            t = self.join(t, h);
+        }
+        return Ok(t);
+    }
+
+    fn infer_protect(&mut self, ls: &Vec<AST>) -> TypeResult {
+        if ls.len() != 3 {
+            return Ok(Type::Dyn);
+        }
+        let t = try!(self.infer(&ls[1]));
+        try!(self.infer(&ls[2]));
+        return Ok(t);
+    }
+
+    // the record's functions take and give any values, the predicate giving a bool
+    fn infer_record(&mut self, ls: &Vec<AST>) -> TypeResult {
+        let (name, fields) = match (ls.len(), ls.get(1).map(|a| &a.sexpr), ls.get(2).map(|a| &a.sexpr)) {
+            (4, Some(&SExpr::Atom(ref name)), Some(&SExpr::List(ref fields))) => (name, fields),
+            _ => return Ok(Type::Dyn),
+        };
+        let depth = self.env.len();
+        let names = compiler::record_names(name, fields);
+        for (i, id) in names.iter().enumerate() {
+            let t = match i {
+                0 => Type::Fn(vec![Type::Dyn; fields.len()], Box::new(Type::Dyn)),
+                1 => Type::Fn(vec![Type::Dyn], Box::new(Type::Bool)),
+                _ => Type::Fn(vec![Type::Dyn], Box::new(Type::Dyn)),
+            };
+            self.bind_mono(id, t);
+        }
+        let body = self.infer(&ls[3]);
+        self.env.truncate(depth);
+        return body;
+    }
+
+    // likewise a variant's, <name>? and each case's predicate giving a bool
+    fn infer_variant(&mut self, ls: &Vec<AST>) -> TypeResult {
+        let name = match ls.get(1).map(|a| &a.sexpr) {
+            Some(&SExpr::Atom(ref name)) if ls.len() > 3 => name,
+            _ => return Ok(Type::Dyn),
+        };
+        let depth = self.env.len();
+        let predicate = Type::Fn(vec![Type::Dyn], Box::new(Type::Bool));
+        self.bind_mono(&format!("{}?", name), predicate.clone());
+        for case in &ls[2..ls.len() - 1] {
+            if let SExpr::List(ref c) = case.sexpr {
+                if let Some(&SExpr::Atom(ref id)) = c.first().map(|a| &a.sexpr) {
+                    self.bind_mono(id, Type::Fn(vec![Type::Dyn; c.len() - 1], Box::new(Type::Dyn)));
+                    self.bind_mono(&format!("{}?", id), predicate.clone());
+                }
+            }
+        }
+        let body = self.infer(&ls[ls.len() - 1]);
+        self.env.truncate(depth);
+        return body;
+    }
+
+    // the clauses' expressions are checked with the patterns' names of any type, the
+    // match being of any type itself
+    fn infer_match(&mut self, ls: &Vec<AST>) -> TypeResult {
+        if ls.len() < 3 {
+            return Ok(Type::Dyn);
+        }
+        try!(self.infer(&ls[1]));
+        for clause in &ls[2..] {
+            let c = match clause.sexpr {
+                SExpr::List(ref c) if c.len() == 2 => c,
+                _ => continue,
+            };
+            let depth = self.env.len();
+            if let SExpr::List(ref p) = c[0].sexpr {
+                for id in p.iter().skip(1).flat_map(pattern_ids) {
+                    self.bind_mono(&id, Type::Dyn);
+                }
+            }
+            let body = self.infer(&c[1]);
+            self.env.truncate(depth);
+            try!(body);
+        }
+        return Ok(Type::Dyn);
+    }
+
+    // the closure's type isn't followed past the arguments given
+    fn infer_curry(&mut self, ls: &Vec<AST>) -> TypeResult {
+        for a in &ls[1..] {
+            try!(self.infer(a));
+        }
+        return Ok(Type::Dyn);
+    }
+
+    fn infer_parameterize(&mut self, ls: &Vec<AST>) -> TypeResult {
+        if ls.len() != 3 {
+            return Ok(Type::Dyn);
+        }
+        let bindings = match ls[1].sexpr {
+            SExpr::List(ref bindings) => bindings,
+            _ => return Ok(Type::Dyn),
+        };
+        for b in bindings {
+            match b.sexpr {
+                SExpr::List(ref pv) if pv.len() == 2 => {
+                    let p = try!(self.infer(&pv[0]));
+                    let v = try!(self.infer(&pv[1]));
+                    if !self.unify(&p, &Type::Fn(vec![], Box::new(v))) {
+                        return self.error(&pv[0], format!("PARAMBIND on {}", self.zonk(&p)));
+                    }
+                }
+                _ => return Ok(Type::Dyn),
+            }
+        }
+        return self.infer(&ls[2]);
+    }
+    // the predicates are checked on their own; the definition is a letrec
+    fn infer_contract(&mut self, ls: &Vec<AST>) -> TypeResult {
+        if ls.len() != 5 {
+            return Ok(Type::Dyn);
+        }
+        let preds = match ls[2].sexpr {
+            SExpr::List(ref preds) => preds,
+            _ => return Ok(Type::Dyn),
+        };
+        for pred in preds.iter().filter(|p| p.sexpr != SExpr::Atom("->".to_string())) {
+            let t = try!(self.infer(pred));
+            let expected = Type::Fn(vec![self.fresh()], Box::new(Type::Bool));
+            let msg = format!("expected a predicate, found {}", self.zonk(&t));
+            if !self.unify(&expected, &t) {
+                return self.error(pred, msg);
+            }
+        }
+        let def = vec![ls[0].clone(), ls[1].clone(), ls[3].clone(), ls[4].clone()];
+        return self.infer_let(&def, true);
+    }
+
+    fn infer_the(&mut self, ls: &Vec<AST>) -> TypeResult {
+        if ls.len() != 3 {
+            return Ok(Type::Dyn);
+        }
+        let expected = match annotation(&ls[1]) {
+            Some(t) => t,
+            None => return Ok(Type::Dyn),
+        };
+        let t = try!(self.infer(&ls[2]));
+        let msg = format!("expected {}, found {}", expected, self.zonk(&t));
+        if !self.unify(&expected, &t) {
+            return self.error(&ls[2], msg);
+        }
+        return Ok(expected);
+    }
+}
+
+fn infer_quote(ls: &Vec<AST>) -> Type {
+    match ls.get(1).map(|a| &a.sexpr) {
+        Some(&SExpr::Atom(_)) => return Type::Sym,
+        Some(&SExpr::Int(_)) => return Type::Int,
+        Some(&SExpr::Str(_)) => return Type::Str,
+        Some(&SExpr::List(_)) => return Type::List,
+        None => return Type::Dyn,
+    }
+}
+
+// the names a let pattern binds
+fn pattern_ids(ast: &AST) -> Vec<String> {
+    match ast.sexpr {
+        SExpr::Atom(ref id) => return vec![id.clone()],
+        SExpr::List(ref ls) => return ls.iter().flat_map(pattern_ids).collect(),
+        _ => return vec![],
+    }
+}
+
+fn replace(t: &Type, vars: &[(usize, Type)]) -> Type {
+    match *t {
+        Type::Var(v) => {
+            match vars.iter().find(|b| b.0 == v) {
+                Some(&(_, ref t)) => return t.clone(),
+                None => return t.clone(),
+            }
+        }
+        Type::Fn(ref params, ref ret) => {
+            return Type::Fn(params.iter().map(|p| replace(p, vars)).collect(),
+                            Box::new(replace(ret, vars)))
+        }
+        _ => return t.clone(),
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Type::Int => return write!(f, "Int"),
+            Type::Bool => return write!(f, "Bool"),
+            Type::Str => return write!(f, "Str"),
+            Type::Sym => return write!(f, "Sym"),
+            Type::List => return write!(f, "List"),
+            Type::Dyn => return write!(f, "Dyn"),
+            Type::Fn(ref params, ref ret) => {
+                try!(write!(f, "("));
+                for p in params {
+                    try!(write!(f, "{} ", p));
+                }
+                return write!(f, "-> {})", ret);
+            }
+            Type::Var(v) => return write!(f, "t{}", v),
+        }
+    }
+}
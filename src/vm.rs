@@ -1,293 +1,3649 @@
 
 use data::*;
+use diagnostic::Diagnostic;
+use date::Date;
+use condition;
+use serialize;
 
 use std::rc::Rc;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::cell::RefCell;
+use std::env;
+use std::process::Command;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, TcpListener};
 use std::error::Error;
+use std::cmp;
+use std::mem;
+use std::fs;
+use std::fmt;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 type VMResult = Result<(), Box<Error>>;
 
+fn seed() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => return (d.as_nanos() as u64) | 1,
+        Err(_) => return 1,
+    }
+}
+
+pub const ASYNC_BUDGET: usize = 1000;
+// the stack a machine starts with room for, so short programs never grow it
+pub const STACK_CAPACITY: usize = 64;
+// where the LDC indices of restored closures start, past any pool the compiler makes
+pub const RESTORED: usize = usize::MAX / 2;
+
+pub type Primitive = fn(&mut SECD, &CodeOPInfo) -> Result<(), Box<Error>>;
+
+// builtins the compiler calls through PRIM, by name and arity. They take their
+// arguments straight off the stack, so a call needs no ARGS list and no dump
+pub const PRIMITIVES: &[(&str, usize, Primitive)] = &[
+    ("min", 2, SECD::run_min),
+    ("max", 2, SECD::run_max),
+    ("abs", 1, SECD::run_abs),
+    ("quotient", 2, SECD::run_quot),
+    ("remainder", 2, SECD::run_rem),
+    ("bit-and", 2, SECD::run_band),
+    ("bit-or", 2, SECD::run_bor),
+    ("bit-xor", 2, SECD::run_bxor),
+    ("bit-not", 1, SECD::run_bnot),
+    ("shl", 2, SECD::run_shl),
+    ("shr", 2, SECD::run_shr),
+    ("number->string", 1, SECD::run_num2str),
+    ("string->number", 1, SECD::run_str2num),
+    ("symbol->string", 1, SECD::run_sym2str),
+    ("string->symbol", 1, SECD::run_str2sym),
+    ("compare", 2, SECD::run_compare),
+    ("sort", 1, SECD::run_sort),
+    ("sort-by", 2, SECD::run_sort_by),
+    ("map", 2, SECD::run_map),
+    ("filter", 2, SECD::run_filter),
+    ("foldl", 3, SECD::run_foldl),
+    ("foldr", 3, SECD::run_foldr),
+    ("range", 3, SECD::run_range),
+    ("condition-type", 1, SECD::run_condtype),
+    ("condition-message", 1, SECD::run_condmsg),
+    ("condition-payload", 1, SECD::run_condpayload),
+    ("condition-location", 1, SECD::run_condloc),
+    ("procedure?", 1, SECD::run_procp),
+    ("procedure-arity", 1, SECD::run_procarity),
+    ("procedure-source", 1, SECD::run_procsource),
+    ("copy", 1, SECD::run_copy),
+    ("freeze", 1, SECD::run_freeze),
+    ("make-weak-ref", 1, SECD::run_mkweak),
+    ("weak-deref", 1, SECD::run_weakderef),
+    ("checkpoint", 2, SECD::run_checkpoint),
+    ("restore", 1, SECD::run_restore),
+    ("string-append", 2, SECD::run_strappend),
+    ("curry", 2, SECD::run_curry),
+    ("check-shape", 2, SECD::run_check_shape),
+    ("tagged?", 2, SECD::run_taggedp),
+    ("promise", 1, SECD::run_promise),
+    ("make-promise", 1, SECD::run_mkpromise),
+    ("promise?", 1, SECD::run_promisep),
+    ("force", 1, SECD::run_force),
+    ("trace", 2, SECD::run_trace),
+    ("untrace", 1, SECD::run_untrace),
+    ("break", 0, SECD::run_break),
+    ("break-if", 1, SECD::run_break_if),
+    ("value->string", 1, SECD::run_value2str),
+];
+
+// what a primitive calling closures does next: call `f` on the arguments and continue
+// as the callback says when it returns, or leave a value and carry on after it
+enum Next {
+    Call(Callback, Rc<Lisp>, Vec<Rc<Lisp>>),
+    Done(Rc<Lisp>),
+}
+
+// the symbol a promise's cell is consed onto, which no program can make (see MISSING),
+// so a cons of its own is never taken for a promise
+const PROMISE: &str = " promise";
+
+// the cell of a promise, PROMISE consed onto a cell holding (cons true <value>) once
+// forced and (cons false <thunk>) before
+fn promise(a: &Lisp) -> Option<Cell> {
+    match *a {
+        Lisp::Cons(ref tag, ref cell) => {
+            match (&**tag, &**cell) {
+                (&Lisp::Symbol(ref tag), &Lisp::Cell(ref cell)) if tag == PROMISE => return Some(cell.clone()),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    }
+}
+
+fn map(f: Rc<Lisp>, items: Vec<Rc<Lisp>>, i: usize, out: Vec<Rc<Lisp>>) -> Next {
+    match items.get(i).cloned() {
+        Some(x) => return Next::Call(Callback::Map(f.clone(), items, i + 1, out), f, vec![x]),
+        None => return Next::Done(list(out)),
+    }
+}
+
+fn filter(f: Rc<Lisp>, items: Vec<Rc<Lisp>>, i: usize, out: Vec<Rc<Lisp>>) -> Next {
+    match items.get(i).cloned() {
+        Some(x) => return Next::Call(Callback::Filter(f.clone(), items, i + 1, out), f, vec![x]),
+        None => return Next::Done(list(out)),
+    }
+}
+
+fn fold(f: Rc<Lisp>, items: Vec<Rc<Lisp>>, i: usize, right: bool, acc: Rc<Lisp>) -> Next {
+    match items.get(i).cloned() {
+        Some(x) => {
+            let args = if right { vec![x, acc] } else { vec![acc, x] };
+            return Next::Call(Callback::Fold(f.clone(), items, i + 1, right), f, args);
+        }
+        None => return Next::Done(acc),
+    }
+}
+
+// the next comparison of a merge sort, finishing merges and passes on the way
+fn merge(f: Rc<Lisp>, mut m: Merge) -> Next {
+    loop {
+        if m.i < m.left.len() && m.j < m.right.len() {
+            let args = vec![m.left[m.i].clone(), m.right[m.j].clone()];
+            return Next::Call(Callback::SortBy(f.clone(), m), f, args);
+        }
+        if !m.left.is_empty() {
+            let mut merged = mem::take(&mut m.merged);
+            merged.extend_from_slice(&m.left[m.i..]);
+            merged.extend_from_slice(&m.right[m.j..]);
+            m.next.push(merged);
+            m.left.clear();
+            m.right.clear();
+            m.i = 0;
+            m.j = 0;
+        }
+        if m.runs.len() >= 2 {
+            m.left = m.runs.pop_front().unwrap();
+            m.right = m.runs.pop_front().unwrap();
+            continue;
+        }
+        m.next.extend(m.runs.pop_front());
+        if m.next.len() <= 1 {
+            return Next::Done(list(m.next.pop().unwrap_or_default()));
+        }
+        m.runs = m.next.drain(..).collect();
+    }
+}
+
+// the version of the instruction set; code compiled for a later one may use instructions
+// this machine doesn't know, and is refused before it runs
+pub const VERSION: u32 = 1;
+
+// the groups of instructions beyond the core a machine of this version runs, which a
+// program's header lists the ones of it uses
+pub const FEATURES: &[&str] = &["conditions", "dates", "network", "parameters", "primitives", "processes",
+                                "reflection", "threads"];
+
+// the group an instruction belongs to, None for the core
+pub fn feature(op: &CodeOP) -> Option<&'static str> {
+    match *op {
+        CodeOP::TRY(..) | CodeOP::ENDTRY | CodeOP::MKCOND | CodeOP::RAISE | CodeOP::PROTECT(..) |
+        CodeOP::ENDPROTECT | CodeOP::RERAISE => return Some("conditions"),
+        CodeOP::DATENOW | CodeOP::DATE2STR | CodeOP::STR2DATE => return Some("dates"),
+        CodeOP::TCPCONNECT | CodeOP::TCPLISTEN | CodeOP::TCPACCEPT | CodeOP::TCPREAD | CodeOP::TCPWRITE |
+        CodeOP::TCPCLOSE | CodeOP::HTTPGET => return Some("network"),
+        CodeOP::MKPARAM | CodeOP::DEREF | CodeOP::PARAMBIND(_) | CodeOP::PARAMRESTORE => return Some("parameters"),
+        CodeOP::PRIM(..) => return Some("primitives"),
+        CodeOP::SYSTEM | CodeOP::PROCESS => return Some("processes"),
+        CodeOP::SECDSTACK | CodeOP::SECDENV | CodeOP::SECDWHERE => return Some("reflection"),
+        CodeOP::YIELD | CodeOP::SPAWN | CodeOP::TJOIN | CodeOP::CHAN | CodeOP::SEND | CodeOP::RECV => {
+            return Some("threads")
+        }
+        _ => return None,
+    }
+}
+
+// the features `code` uses, the code its instructions and constant closures carry included
+pub fn features(code: &Code) -> Vec<String> {
+    fn walk(code: &Code, pool: &[Rc<Lisp>], found: &mut BTreeSet<&'static str>) {
+        for c in code {
+            found.extend(feature(&c.op));
+            match c.op {
+                CodeOP::SEL(ref t, ref f) |
+                CodeOP::TSEL(ref t, ref f) |
+                CodeOP::PROTECT(ref t, ref f) => {
+                    walk(t, pool, found);
+                    walk(f, pool, found);
+                }
+                CodeOP::LDF(_, ref body, _) => walk(body, pool, found),
+                CodeOP::TRY(ref body, ref handlers) => {
+                    walk(body, pool, found);
+                    for h in handlers {
+                        walk(&h.code, pool, found);
+                    }
+                }
+                CodeOP::LDC(i) => {
+                    if let Some(&Lisp::Closure(_, ref body, _, _)) = pool.get(i).map(|a| &**a) {
+                        walk(body, pool, found);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    let mut found = BTreeSet::new();
+    walk(code, pool(code), &mut found);
+    return found.into_iter().map(|f| f.to_string()).collect();
+}
+
+// why a machine of this version can't run code with `header`, if it can't
+pub fn check(header: &Header) -> Result<(), String> {
+    if header.version > VERSION {
+        return Err(format!("code is for instruction set version {}, this machine runs version {}",
+                           header.version,
+                           VERSION));
+    }
+    match header.features.iter().find(|f| !FEATURES.contains(&f.as_str())) {
+        Some(f) => return Err(format!("code needs instruction feature {}, which this machine doesn't have", f)),
+        None => return Ok(()),
+    }
+}
+
+pub fn primitive(name: &str) -> Option<PrimId> {
+    return PRIMITIVES.iter().position(|&(n, _, _)| n == name);
+}
+
+// the name of a builtin by its id; primitives registered after them have none here
+pub fn builtin_name(id: PrimId) -> Option<&'static str> {
+    return PRIMITIVES.get(id).map(|p| p.0);
+}
+
+// the primitives a compiler calls and a machine runs, which must be the same registry
+// for the ids in PRIM to agree: the builtins first, in the order of PRIMITIVES, then
+// what an embedder registers, so code using only builtins runs on any machine
+#[derive(Clone)]
+pub struct Primitives {
+    entries: Vec<(String, usize, Primitive)>,
+}
+
+impl Primitives {
+    pub fn standard() -> Primitives {
+        let entries = PRIMITIVES.iter().map(|&(n, arity, run)| (n.to_string(), arity, run)).collect();
+        return Primitives { entries };
+    }
+
+    // a name already there keeps its id and gets the new arity and implementation
+    pub fn register(&mut self, name: &str, arity: usize, run: Primitive) -> PrimId {
+        match self.id(name) {
+            Some(id) => {
+                self.entries[id] = (name.to_string(), arity, run);
+                return id;
+            }
+            None => {
+                self.entries.push((name.to_string(), arity, run));
+                return self.entries.len() - 1;
+            }
+        }
+    }
+
+    pub fn id(&self, name: &str) -> Option<PrimId> {
+        return self.entries.iter().position(|e| e.0 == name);
+    }
+
+    pub fn name(&self, id: PrimId) -> Option<&str> {
+        return self.entries.get(id).map(|e| e.0.as_str());
+    }
+
+    pub fn arity(&self, id: PrimId) -> Option<usize> {
+        return self.entries.get(id).map(|e| e.1);
+    }
+
+    fn get(&self, id: PrimId) -> Option<(usize, Primitive)> {
+        return self.entries.get(id).map(|e| (e.1, e.2));
+    }
+}
+
+impl Default for Primitives {
+    fn default() -> Primitives {
+        return Primitives::standard();
+    }
+}
+
+// registries are told apart by what they name, not by their functions' addresses
+impl PartialEq for Primitives {
+    fn eq(&self, other: &Primitives) -> bool {
+        return self.entries.len() == other.entries.len() &&
+               self.entries.iter().zip(other.entries.iter()).all(|(a, b)| a.0 == b.0 && a.1 == b.1);
+    }
+}
+
+impl fmt::Debug for Primitives {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f.debug_list().entries(self.entries.iter().map(|e| (&e.0, e.1))).finish();
+    }
+}
+
+// a proper list of the values in order
+fn list(v: Vec<Rc<Lisp>>) -> Rc<Lisp> {
+    return v.into_iter().rev().fold(Lisp::nil(), |cdr, car| Rc::new(Lisp::Cons(car, cdr)));
+}
+
+// the symbol a keyword parameter the call leaves out is bound to, which the lambda's
+// own code replaces with the default; no symbol a program makes has a space, as neither
+// the reader nor string->symbol makes one
+pub const MISSING: &str = " missing";
+
+// each parameter of a closure with the argument it gets. A parameter [<id>] is an
+// optional one: the arguments after the required ones go to the optional ones in
+// order until one is a keyword. A parameter #:<id> is a keyword one: after the
+// positional arguments come pairs of the symbol #:<id> and the value <id> is bound to,
+// in any order. A parameter the call leaves out is MISSING
+fn arguments(names: &[String], vals: &[Rc<Lisp>]) -> Result<Vec<(String, Rc<Lisp>)>, String> {
+    let positional = names.iter().take_while(|n| !n.starts_with("#:")).count();
+    let required = names[..positional].iter().take_while(|n| !n.starts_with('[')).count();
+    if required == names.len() && names.len() == vals.len() {
+        return Ok(names.iter().cloned().zip(vals.iter().cloned()).collect());
+    }
+    if vals.len() < required {
+        return Err("wrong number of arguments".to_string());
+    }
+    let keyword = |a: &Rc<Lisp>| match **a {
+        Lisp::Symbol(ref s) if s.starts_with("#:") => Some(s.clone()),
+        _ => None,
+    };
+    let mut given = required;
+    while given < positional.min(vals.len()) && keyword(&vals[given]).is_none() {
+        given += 1;
+    }
+    let missing = || Rc::new(Lisp::Symbol(MISSING.to_string()));
+    let mut args = vec![];
+    for (i, name) in names[..positional].iter().enumerate() {
+        let name = name.trim_start_matches('[').trim_end_matches(']').to_string();
+        args.push((name, if i < given { vals[i].clone() } else { missing() }));
+    }
+
+    let mut keys = vec![];
+    let mut rest = vals[given..].iter();
+    while let Some(key) = rest.next() {
+        let key = match keyword(key) {
+            Some(key) => key,
+            None => return Err("wrong number of arguments".to_string()),
+        };
+        if !names[positional..].contains(&key) {
+            return Err(format!("unknown keyword {}", key));
+        }
+        if keys.contains(&key) {
+            return Err(format!("keyword {} given twice", key));
+        }
+        match rest.next() {
+            Some(a) => args.push((key[2..].to_string(), a.clone())),
+            None => return Err(format!("keyword {} without a value", key)),
+        }
+        keys.push(key);
+    }
+    for name in names[positional..].iter().filter(|n| !keys.contains(n)) {
+        args.push((name[2..].to_string(), missing()));
+    }
+    return Ok(args);
+}
+
+// whether `a` has the shape of a let or lambda pattern, quoted: a list of patterns fits
+// a list of as many values, and one with a . before the last pattern the values after
+// the others as the last; an id fits anything
+fn fits(pattern: &Lisp, a: &Lisp) -> bool {
+    match (pattern, a) {
+        (&Lisp::Nil, &Lisp::Nil) => return true,
+        (&Lisp::Nil, _) => return false,
+        (&Lisp::Cons(ref p, ref ps), _) => {
+            match (&**p, &**ps) {
+                (&Lisp::Symbol(ref dot), &Lisp::Cons(ref rest, _)) if dot == "." => return fits(rest, a),
+                _ => {}
+            }
+            match *a {
+                Lisp::Cons(ref car, ref cdr) => return fits(p, car) && fits(ps, cdr),
+                _ => return false,
+            }
+        }
+        _ => return true,
+    }
+}
+
+// a quoted pattern as it was written
+fn pattern_text(pattern: &Lisp) -> String {
+    let mut parts = vec![];
+    let mut p = pattern;
+    while let Lisp::Cons(ref car, ref cdr) = *p {
+        parts.push(pattern_text(car));
+        p = cdr;
+    }
+    match *p {
+        Lisp::Nil => return format!("({})", parts.join(" ")),
+        _ => return format!("{}", p),
+    }
+}
+
+// a cell or channel already frozen has had what it holds frozen too, which ends cycles
+fn freeze(a: &Rc<Lisp>) {
+    // with a stack of what is left to visit, as a long list would nest a call per element
+    let mut todo = vec![a.clone()];
+    while let Some(a) = todo.pop() {
+        match *a {
+            Lisp::List(ref ls) => todo.extend(ls.iter().cloned()),
+            Lisp::Cons(ref car, ref cdr) => {
+                todo.push(cdr.clone());
+                todo.push(car.clone());
+            }
+            Lisp::Closure(_, _, ref env, _) => todo.extend(env.values().cloned()),
+            Lisp::Condition(ref cond) => todo.push(cond.payload.clone()),
+            Lisp::Chan(ref q) if !q.is_frozen() => {
+                q.freeze();
+                todo.extend(q.borrow().iter().cloned());
+            }
+            Lisp::Cell(ref cell) if !cell.is_frozen() => {
+                cell.freeze();
+                todo.push(cell.borrow().clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+fn date(d: Date) -> Rc<Lisp> {
+    let fields = [d.year, d.month as i32, d.day as i32, d.hour as i32, d.minute as i32, d.second as i32];
+    return list(fields.iter().map(|&n| Lisp::int(n)).collect());
+}
+
+// status, headers and body
+type Response = (i32, Vec<(String, String)>, String);
+
+// an error status is still a response; only failing to get one is an error
+#[cfg(feature = "http")]
+fn http_get(url: &str) -> Result<Response, String> {
+    let response = match ureq::get(url).call() {
+        Ok(r) => r,
+        Err(ureq::Error::Status(_, r)) => r,
+        Err(e) => return Err(format!("{}", e)),
+    };
+    let status = response.status() as i32;
+    let headers = response.headers_names()
+        .into_iter()
+        .filter_map(|name| response.header(&name).map(|v| (name.clone(), v.to_string())))
+        .collect();
+    match response.into_string() {
+        Ok(body) => return Ok((status, headers, body)),
+        Err(e) => return Err(format!("{}", e)),
+    }
+}
+
+#[cfg(not(feature = "http"))]
+fn http_get(_: &str) -> Result<Response, String> {
+    return Err("built without the http feature".to_string());
+}
+
+// the instructions that go through nondet, by the name of their form and how many of
+// the values on the stack are its arguments; time only asks for the clock
+fn native(op: &CodeOP) -> Option<(&'static str, usize)> {
+    match *op {
+        CodeOP::CURTIME => return Some(("current-time", 0)),
+        CodeOP::CLOCK => return Some(("clock", 0)),
+        CodeOP::TIME => return Some(("clock", 0)),
+        CodeOP::RANDOM => return Some(("random", 1)),
+        CodeOP::GETENV => return Some(("getenv", 1)),
+        CodeOP::SYSTEM => return Some(("system", 1)),
+        CodeOP::PROCESS => return Some(("process", 2)),
+        CodeOP::DATENOW => return Some(("date-now", 0)),
+        CodeOP::TCPACCEPT => return Some(("tcp-accept", 1)),
+        CodeOP::TCPREAD => return Some(("tcp-read", 1)),
+        CodeOP::HTTPGET => return Some(("http-get", 1)),
+        _ => return None,
+    }
+}
+
+// runs a machine for its host, stopping at each effect instead of performing it
+pub struct Effects<'a> {
+    vm: &'a mut SECD,
+    queue: Rc<RefCell<VecDeque<Effect>>>,
+    // the NativeCall for the next instruction has been handed out
+    asked: bool,
+    done: bool,
+}
+
+impl<'a> Effects<'a> {
+    // the value the native call just handed out gives the program; without one the
+    // machine makes the call itself
+    pub fn answer(&mut self, a: Rc<Lisp>) {
+        self.vm.answer = Some(a);
+    }
+
+    // the value the yield just handed out evaluates to, nil unless resumed
+    pub fn resume(&mut self, a: Rc<Lisp>) {
+        self.vm.stack.pop();
+        self.vm.stack.push(a);
+    }
+}
+
+impl<'a> Iterator for Effects<'a> {
+    type Item = Result<Effect, Box<Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(e) = self.queue.borrow_mut().pop_front() {
+                return Some(Ok(e));
+            }
+            if self.done {
+                return None;
+            }
+            if let Some(a) = self.vm.yielded.take() {
+                self.vm.stack.push(Lisp::nil());
+                return Some(Ok(Effect::Yield(a)));
+            }
+            if self.vm.halted() {
+                self.done = true;
+                if self.vm.joining.is_some() || self.vm.receiving.is_some() {
+                    return Some(Err(From::from(Diagnostic::error("vm",
+                                                                 None,
+                                                                 "effects: threads need a scheduler"
+                                                                     .to_string()))));
+                }
+                return None;
+            }
+            if !self.asked {
+                if let Some((name, n)) = native(&self.vm.code[0].op) {
+                    if self.vm.stack.len() >= n {
+                        self.asked = true;
+                        let args = self.vm.stack[self.vm.stack.len() - n..].to_vec();
+                        return Some(Ok(Effect::NativeCall(name.to_string(), args)));
+                    }
+                }
+            }
+            self.asked = false;
+            let r = self.vm.step();
+            self.vm.answer = None;
+            if let Err(e) = r {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+// thread 0 is whichever machine a scheduler was started with
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(1);
+
+pub struct RunFuture<'a> {
+    vm: &'a mut SECD,
+    budget: usize,
+}
+
+impl<'a> RunFuture<'a> {
+    pub fn budget(mut self, n: usize) -> Self {
+        self.budget = n;
+        return self;
+    }
+}
+
+impl<'a> Future for RunFuture<'a> {
+    type Output = Result<RunResult, Box<Error>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        for _ in 0..self.budget {
+            if self.vm.halted() {
+                break;
+            }
+            if let Err(e) = self.vm.step() {
+                return Poll::Ready(Err(e));
+            }
+        }
+
+        if self.vm.halted() {
+            return Poll::Ready(Ok(self.vm.result()));
+        } else {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+    }
+}
+
+// how many values the instruction takes off the stack, checked before it runs so that
+// code the compiler didn't make, read back with serialize::read_code, raises rather than
+// panics; a primitive checks its own arguments
+fn takes(op: &CodeOP) -> usize {
+    match *op {
+        CodeOP::ARGS(n) => return n,
+        CodeOP::PARAMBIND(n) => return 2 * n,
+        CodeOP::MKCOND => return 3,
+        CodeOP::AP | CodeOP::RAP | CodeOP::TAP | CodeOP::TRAP | CodeOP::EQ | CodeOP::ADD | CodeOP::SUB |
+        CodeOP::TIME | CodeOP::TEST(_) | CodeOP::SEND | CodeOP::PROCESS | CodeOP::CONS | CodeOP::TCPCONNECT |
+        CodeOP::TCPLISTEN | CodeOP::TCPWRITE | CodeOP::DATE2STR | CodeOP::STR2DATE => return 2,
+        CodeOP::LET(_) | CodeOP::SEL(..) | CodeOP::TSEL(..) | CodeOP::RET | CodeOP::PUTS | CodeOP::ASSERT(..) |
+        CodeOP::EXIT | CodeOP::YIELD | CodeOP::SPAWN | CodeOP::TJOIN | CodeOP::RANDOM | CodeOP::RECV |
+        CodeOP::GETENV | CodeOP::SYSTEM | CodeOP::CAR | CodeOP::CDR | CodeOP::OUTSTR | CodeOP::TCPACCEPT |
+        CodeOP::TCPREAD | CodeOP::TCPCLOSE | CodeOP::HTTPGET | CodeOP::RAISE | CodeOP::RERAISE | CodeOP::MKPARAM |
+        CodeOP::DEREF | CodeOP::PARAMRESTORE | CodeOP::HELP(_) | CodeOP::RESUME => return 1,
+        CodeOP::LD(_) | CodeOP::LDC(_) | CodeOP::LDF(..) | CodeOP::JOIN | CodeOP::POP | CodeOP::CURTIME |
+        CodeOP::CLOCK | CodeOP::CHAN | CodeOP::DATENOW | CodeOP::TRY(..) | CodeOP::ENDTRY | CodeOP::PROTECT(..) |
+        CodeOP::ENDPROTECT | CodeOP::SECDSTACK | CodeOP::SECDENV | CodeOP::SECDWHERE | CodeOP::PRIM(..) |
+        CodeOP::CONSTS(_) | CodeOP::HEADER(_) => return 0,
+    }
+}
+
+// threaded dispatch: the code is paired with a function per instruction rather than
+// matched at every step. A block is threaded once, the first time the machine moves to
+// it, and kept in `blocks` under its place in the program, in the LDF, SEL or TRY that
+// holds it. The copy the machine runs keeps its address as instructions come off the
+// front and as it goes to the dump and back, so `live` finds its functions again when a
+// call returns; code reached any other way, the program the machine starts with among
+// it, is threaded when the machine first steps into it
+#[cfg(feature = "threaded")]
+pub type Dispatch = fn(&mut SECD, &CodeOPInfo) -> VMResult;
+
+// how many copies `live` follows before it starts again; a caller forgotten then is
+// threaded again when it is returned to
+#[cfg(feature = "threaded")]
+const LIVE: usize = 1 << 16;
+
+#[cfg(feature = "threaded")]
+#[derive(Clone, Default)]
+pub struct Threaded {
+    pub blocks: HashMap<(usize, usize), Rc<Vec<Dispatch>>>,
+    pub live: HashMap<usize, Rc<Vec<Dispatch>>>,
+    // the address of the code running and its functions
+    at: usize,
+    current: Rc<Vec<Dispatch>>,
+}
+
+#[cfg(feature = "threaded")]
+impl Threaded {
+    // the functions for `block`, about to run as the copy at `at`
+    fn enter(&mut self, block: &Code, at: usize) {
+        let key = (block.as_ptr() as usize, block.len());
+        let fns = self.blocks.entry(key).or_insert_with(|| Rc::new(thread(block))).clone();
+        self.follow(at, fns);
+    }
+
+    fn follow(&mut self, at: usize, fns: Rc<Vec<Dispatch>>) {
+        if self.live.len() >= LIVE {
+            self.live.clear();
+        }
+        self.live.insert(at, fns.clone());
+        self.at = at;
+        self.current = fns;
+    }
+
+    // the function for the first instruction of `code`; an address reused by other
+    // code can only hand it a function for another kind, which leaves it to exec
+    fn next(&mut self, code: &Code) -> Dispatch {
+        let at = code.as_ptr() as usize;
+        if at != self.at || code.len() > self.current.len() {
+            match self.live.get(&at) {
+                Some(fns) if fns.len() >= code.len() => {
+                    self.at = at;
+                    self.current = fns.clone();
+                }
+                _ => self.follow(at, Rc::new(thread(code))),
+            }
+        }
+        return self.current[self.current.len() - code.len()];
+    }
+}
+
+#[cfg(feature = "threaded")]
+impl PartialEq for Threaded {
+    fn eq(&self, _: &Threaded) -> bool {
+        return true;
+    }
+}
+
+#[cfg(feature = "threaded")]
+impl fmt::Debug for Threaded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "Threaded({} blocks)", self.blocks.len());
+    }
+}
+
+// a dispatch runs one kind of instruction; handed another, as it may be when the code
+// was changed without being threaded again, it leaves it to exec
+#[cfg(feature = "threaded")]
+macro_rules! dispatch {
+    ($p:pat => $run:ident($($arg:ident),*)) => {
+        |vm: &mut SECD, c: &CodeOPInfo| {
+            if vm.stack.len() < takes(&c.op) {
+                return vm.error(c, "stack underflow");
+            }
+            match c.op {
+                $p => vm.$run(c $(, $arg)*),
+                _ => vm.exec(c.clone()),
+            }
+        }
+    };
+}
+
+#[cfg(feature = "threaded")]
+fn dispatch(op: &CodeOP) -> Dispatch {
+    match *op {
+        CodeOP::LET(..) => dispatch!(CodeOP::LET(ref id) => run_let(id)),
+        CodeOP::LD(..) => dispatch!(CodeOP::LD(ref id) => run_ld(id)),
+        CodeOP::LDC(..) => dispatch!(CodeOP::LDC(i) => run_ldc(i)),
+        CodeOP::LDF(..) => {
+            dispatch!(CodeOP::LDF(ref names, ref code, ref proc_info) => run_ldf(names, code, proc_info))
+        }
+        CodeOP::RET => dispatch!(CodeOP::RET => run_ret()),
+        CodeOP::AP => dispatch!(CodeOP::AP => run_ap()),
+        CodeOP::RAP => dispatch!(CodeOP::RAP => run_rap()),
+        CodeOP::TAP => dispatch!(CodeOP::TAP => run_tap()),
+        CodeOP::TRAP => dispatch!(CodeOP::TRAP => run_trap()),
+        CodeOP::ARGS(..) => dispatch!(CodeOP::ARGS(n) => run_args(n)),
+        CodeOP::PUTS => dispatch!(CodeOP::PUTS => run_puts()),
+        CodeOP::POP => dispatch!(CodeOP::POP => run_pop()),
+        CodeOP::SEL(..) => dispatch!(CodeOP::SEL(ref t, ref f) => run_sel(t, f)),
+        CodeOP::JOIN => dispatch!(CodeOP::JOIN => run_join()),
+        CodeOP::EQ => dispatch!(CodeOP::EQ => run_eq()),
+        CodeOP::ADD => dispatch!(CodeOP::ADD => run_add()),
+        CodeOP::SUB => dispatch!(CodeOP::SUB => run_sub()),
+        CodeOP::CURTIME => dispatch!(CodeOP::CURTIME => run_curtime()),
+        CodeOP::CLOCK => dispatch!(CodeOP::CLOCK => run_clock()),
+        CodeOP::TIME => dispatch!(CodeOP::TIME => run_time()),
+        CodeOP::ASSERT(..) => dispatch!(CodeOP::ASSERT(ref info, ref expr) => run_assert(info, expr)),
+        CodeOP::TEST(..) => dispatch!(CodeOP::TEST(ref name) => run_test(name)),
+        CodeOP::YIELD => dispatch!(CodeOP::YIELD => run_yield()),
+        CodeOP::SPAWN => dispatch!(CodeOP::SPAWN => run_spawn()),
+        CodeOP::TJOIN => dispatch!(CodeOP::TJOIN => run_tjoin()),
+        CodeOP::CHAN => dispatch!(CodeOP::CHAN => run_chan()),
+        CodeOP::SEND => dispatch!(CodeOP::SEND => run_send()),
+        CodeOP::RECV => dispatch!(CodeOP::RECV => run_recv()),
+        CodeOP::RANDOM => dispatch!(CodeOP::RANDOM => run_random()),
+        CodeOP::EXIT => dispatch!(CodeOP::EXIT => run_exit()),
+        CodeOP::GETENV => dispatch!(CodeOP::GETENV => run_getenv()),
+        CodeOP::SYSTEM => dispatch!(CodeOP::SYSTEM => run_system()),
+        CodeOP::PROCESS => dispatch!(CodeOP::PROCESS => run_process()),
+        CodeOP::CONS => dispatch!(CodeOP::CONS => run_cons()),
+        CodeOP::CAR => dispatch!(CodeOP::CAR => run_car()),
+        CodeOP::CDR => dispatch!(CodeOP::CDR => run_cdr()),
+        CodeOP::OUTSTR => dispatch!(CodeOP::OUTSTR => run_outstr()),
+        CodeOP::TCPCONNECT => dispatch!(CodeOP::TCPCONNECT => run_tcp_connect()),
+        CodeOP::TCPLISTEN => dispatch!(CodeOP::TCPLISTEN => run_tcp_listen()),
+        CodeOP::TCPACCEPT => dispatch!(CodeOP::TCPACCEPT => run_tcp_accept()),
+        CodeOP::TCPREAD => dispatch!(CodeOP::TCPREAD => run_tcp_read()),
+        CodeOP::TCPWRITE => dispatch!(CodeOP::TCPWRITE => run_tcp_write()),
+        CodeOP::TCPCLOSE => dispatch!(CodeOP::TCPCLOSE => run_tcp_close()),
+        CodeOP::HTTPGET => dispatch!(CodeOP::HTTPGET => run_http_get()),
+        CodeOP::DATENOW => dispatch!(CodeOP::DATENOW => run_date_now()),
+        CodeOP::DATE2STR => dispatch!(CodeOP::DATE2STR => run_date2str()),
+        CodeOP::STR2DATE => dispatch!(CodeOP::STR2DATE => run_str2date()),
+        CodeOP::TRY(..) => dispatch!(CodeOP::TRY(ref body, ref handlers) => run_try(body, handlers)),
+        CodeOP::ENDTRY => dispatch!(CodeOP::ENDTRY => run_endtry()),
+        CodeOP::MKCOND => dispatch!(CodeOP::MKCOND => run_mkcond()),
+        CodeOP::RAISE => dispatch!(CodeOP::RAISE => run_raise()),
+        CodeOP::PROTECT(..) => dispatch!(CodeOP::PROTECT(ref body, ref cleanup) => run_protect(body, cleanup)),
+        CodeOP::ENDPROTECT => dispatch!(CodeOP::ENDPROTECT => run_endprotect()),
+        CodeOP::RERAISE => dispatch!(CodeOP::RERAISE => run_reraise()),
+        CodeOP::MKPARAM => dispatch!(CodeOP::MKPARAM => run_mkparam()),
+        CodeOP::DEREF => dispatch!(CodeOP::DEREF => run_deref()),
+        CodeOP::PARAMBIND(..) => dispatch!(CodeOP::PARAMBIND(n) => run_parambind(n)),
+        CodeOP::PARAMRESTORE => dispatch!(CodeOP::PARAMRESTORE => run_paramrestore()),
+        CodeOP::HELP(..) => dispatch!(CodeOP::HELP(ref name) => run_help(name)),
+        CodeOP::SECDSTACK => dispatch!(CodeOP::SECDSTACK => run_secdstack()),
+        CodeOP::SECDENV => dispatch!(CodeOP::SECDENV => run_secdenv()),
+        CodeOP::SECDWHERE => dispatch!(CodeOP::SECDWHERE => run_secdwhere()),
+        CodeOP::TSEL(..) => dispatch!(CodeOP::TSEL(ref t, ref f) => run_tsel(t, f)),
+        CodeOP::PRIM(..) => dispatch!(CodeOP::PRIM(id, n) => run_prim(id, n)),
+        CodeOP::CONSTS(..) => dispatch!(CodeOP::CONSTS(ref pool) => run_consts(pool)),
+        CodeOP::RESUME => dispatch!(CodeOP::RESUME => run_resume()),
+        CodeOP::HEADER(..) => dispatch!(CodeOP::HEADER(ref header) => run_header(header)),
+    }
+}
+
+#[cfg(feature = "threaded")]
+pub fn thread(code: &Code) -> Vec<Dispatch> {
+    return code.iter().map(|c| dispatch(&c.op)).collect();
+}
+
 impl SECD {
     pub fn new(c: Code) -> SECD {
         return SECD {
-                   stack: vec![],
-                   env: HashMap::new(),
+                   stack: Vec::with_capacity(STACK_CAPACITY),
+                   base: 0,
+                   consts: Rc::new(vec![]),
+                   restored: Rc::new(vec![]),
+                   env: Env::new(),
                    code: c,
                    dump: vec![],
+                   started: Instant::now(),
+                   exit: None,
+                   yielded: None,
+                   answer: None,
+                   spawned: vec![],
+                   joining: None,
+                   receiving: None,
+                   capabilities: Capabilities::default(),
+                   replay: Rc::new(RefCell::new(Replay::Off)),
+                   rng: Rc::new(::std::cell::Cell::new(seed())),
+                   output: Output::Stdout,
+                   steps: 0,
+                   fuel: None,
+                   memory: None,
+                   tests: Rc::new(RefCell::new(vec![])),
+                   coverage: None,
+                   trace: None,
+                   budget: None,
+                   counters: None,
+                   traced: vec![],
+                   debugger: false,
+                   broke: false,
+                   primitives: Rc::new(Primitives::standard()),
+                   #[cfg(feature = "jit")]
+                   jit: None,
+                   #[cfg(feature = "threaded")]
+                   threaded: Threaded::default(),
                };
     }
 
-    fn error(&self, c: &CodeOPInfo, msg: &str) -> VMResult {
-        return Err(From::from(format!("{}:{}:vm error: {}", c.info[0], c.info[1], msg)));
+    // moves to a copy of a block of the program, whose functions, when dispatch is
+    // threaded, were worked out the first time
+    fn set_code(&mut self, block: &Code) {
+        self.code = block.clone();
+        #[cfg(feature = "threaded")]
+        {
+            let at = self.code.as_ptr() as usize;
+            self.threaded.enter(block, at);
+        }
+    }
+
+    // a fixed seed makes random reproducible without recording a log
+    pub fn seed(&mut self, seed: u64) {
+        self.rng.set(if seed == 0 { 1 } else { seed });
+    }
+
+    // sends puts and time output to a buffer instead of stdout and returns it
+    pub fn capture(&mut self) -> Rc<RefCell<String>> {
+        let buf = Rc::new(RefCell::new(String::new()));
+        self.output = Output::Buffer(buf.clone());
+        return buf;
+    }
+
+    fn write_line(&self, s: &str) {
+        match self.output {
+            Output::Stdout => println!("{}", s),
+            Output::Buffer(ref buf) => {
+                let mut buf = buf.borrow_mut();
+                buf.push_str(s);
+                buf.push('\n');
+            }
+            Output::Null => {}
+            Output::Effects(ref effects) => {
+                effects.borrow_mut().push_back(Effect::Puts(self.alloc(Lisp::Str(s.to_string()))))
+            }
+        }
+    }
+
+    fn error<T>(&self, c: &CodeOPInfo, msg: &str) -> Result<T, Box<Error>> {
+        return Err(From::from(Diagnostic::error("vm", Some(c.info), msg.to_string())));
+    }
+
+    fn require(&self, c: &CodeOPInfo, name: &str, cap: &str, allowed: bool) -> VMResult {
+        if allowed {
+            return Ok(());
+        } else {
+            return self.error(c, &format!("{}: capability '{}' is not granted", name, cap));
+        }
+    }
+
+    pub fn run(&mut self) -> Result<Rc<Lisp>, Box<Error>> {
+        match try!(self.run_result()) {
+            RunResult::Yield(_) => {
+                return Err(From::from(Diagnostic::error("vm",
+                                                        None,
+                                                        "yield outside of run_result".to_string())))
+            }
+            _ => return Ok(self.stack.last().unwrap().clone()),
+        }
+    }
+
+    // like run, but tells a program that called exit or yielded apart from one that returned
+    pub fn run_result(&mut self) -> Result<RunResult, Box<Error>> {
+        try!(self.run_());
+        debug!("halted after {} instructions, env size {}", self.steps, self.env.len());
+        if self.joining.is_some() {
+            return Err(From::from(Diagnostic::error("vm",
+                                                    None,
+                                                    "JOIN: threads need a scheduler".to_string())));
+        }
+        if self.receiving.is_some() {
+            return Err(From::from(Diagnostic::error("vm", None, "RECV: channel is empty".to_string())));
+        }
+        return Ok(self.result());
+    }
+
+    // runs `code` after what this machine ran before, with the environment it left: the
+    // next entry of a REPL, from Compiler::compile_incremental. An entry that fails
+    // leaves the environment as it was
+    pub fn run_code(&mut self, code: Code) -> Result<Rc<Lisp>, Box<Error>> {
+        self.stack.clear();
+        self.dump.clear();
+        self.base = 0;
+        self.code = code;
+        let env = self.env.clone();
+        let r = self.run();
+        if r.is_err() {
+            self.env = env;
+        }
+        return r;
+    }
+
+    // counts what this machine and the threads it spawns from now on do, for stats
+    pub fn keep_stats(&mut self) {
+        self.counters = Some(Rc::new(RefCell::new(Stats::default())));
+    }
+
+    // stops the machine and the threads it spawns once they have allocated more than
+    // `values` values between them, as keep_stats counts them
+    pub fn limit_memory(&mut self, values: usize) {
+        if self.counters.is_none() {
+            self.keep_stats();
+        }
+        self.memory = Some(values);
+    }
+
+    // reports a call of this machine or the threads it spawns that takes more than
+    // `steps` steps, with an error stopping the machine when `fatal` and otherwise with
+    // a warning in what this gives back
+    pub fn limit_calls(&mut self, steps: usize, fatal: bool) -> Rc<RefCell<Vec<Diagnostic>>> {
+        let warnings = Rc::new(RefCell::new(vec![]));
+        self.budget = Some(Budget {
+                               steps,
+                               fatal,
+                               warnings: warnings.clone(),
+                               reported: Rc::new(RefCell::new(vec![])),
+                               names: Rc::new(RefCell::new(HashMap::new())),
+                               open: vec![],
+                           });
+        return warnings;
+    }
+
+    pub fn stats(&self) -> Option<Stats> {
+        return self.counters.as_ref().map(|s| s.borrow().clone());
+    }
+
+    // a new value, counted when keeping stats
+    fn alloc(&self, lisp: Lisp) -> Rc<Lisp> {
+        if let Some(ref stats) = self.counters {
+            *stats.borrow_mut().allocated.entry(lisp.type_name()).or_insert(0) += 1;
+        }
+        return Rc::new(lisp);
+    }
+
+    fn int(&self, n: i32) -> Rc<Lisp> {
+        if !SMALL_INTS.contains(&n) {
+            return self.alloc(Lisp::Int(n));
+        }
+        return Lisp::int(n);
+    }
+
+    fn clone_env(&self, env: &Env) -> Env {
+        if let Some(ref stats) = self.counters {
+            stats.borrow_mut().env_clones += 1;
+        }
+        return env.clone();
+    }
+
+    pub fn result(&mut self) -> RunResult {
+        if let Some(a) = self.yielded.take() {
+            return RunResult::Yield(a);
+        }
+        match self.exit {
+            Some(n) => return RunResult::Exit(n),
+            None => return RunResult::Value(self.stack.last().unwrap().clone()),
+        }
+    }
+
+    // continue a machine suspended by yield; `a` becomes the value of the yield expression
+    pub fn resume(&mut self, a: Rc<Lisp>) -> Result<RunResult, Box<Error>> {
+        self.stack.push(a);
+        return self.run_result();
+    }
+
+    // the machine stops when it runs out of code, is suspended by yield or waits for a thread or channel
+    pub fn halted(&self) -> bool {
+        return self.code.len() == 0 || self.yielded.is_some() || self.joining.is_some() ||
+               self.receiving.is_some();
+    }
+
+    fn run_(&mut self) -> VMResult {
+        while !self.halted() {
+            try!(self.step());
+        }
+
+        return Ok(());
+    }
+
+    // execute a single instruction
+    pub fn step(&mut self) -> VMResult {
+        if self.halted() {
+            return Ok(());
+        }
+        if let Some(fuel) = self.fuel {
+            if self.steps >= fuel {
+                return Err(From::from(Diagnostic::error("vm",
+                                                        None,
+                                                        format!("out of fuel after {} steps", self.steps))));
+            }
+        }
+        if let (Some(limit), Some(ref stats)) = (self.memory, &self.counters) {
+            let allocated: usize = stats.borrow().allocated.values().sum();
+            if allocated > limit {
+                return Err(From::from(Diagnostic::error("vm",
+                                                        None,
+                                                        format!("out of memory after {} values", allocated))));
+            }
+        }
+        self.steps += 1;
+
+        #[cfg(feature = "threaded")]
+        let dispatch = self.threaded.next(&self.code);
+        let c = self.code.remove(0);
+        if let Some(ref coverage) = self.coverage {
+            let mut hits = coverage.borrow_mut();
+            if hits.last != Some(c.info) {
+                hits.last = Some(c.info);
+                *hits.counts.entry(c.info).or_insert(0) += 1;
+            }
+        }
+        trace!("{:?} stack={} env={} dump={}",
+               c.op,
+               self.stack.len(),
+               self.env.len(),
+               self.dump.len());
+        // raise already looked for a handler itself
+        let raise = c.op == CodeOP::RAISE || c.op == CodeOP::RERAISE;
+        let call = match self.trace {
+            Some(_) => self.callee(&c),
+            None => None,
+        };
+        let budgeted = match self.budget {
+            Some(_) => self.budgeted(&c),
+            None => None,
+        };
+        #[cfg(feature = "threaded")]
+        let r = dispatch(self, &c);
+        #[cfg(not(feature = "threaded"))]
+        let r = self.exec(c);
+        if let Some(ref stats) = self.counters {
+            let mut stats = stats.borrow_mut();
+            stats.peak_stack = cmp::max(stats.peak_stack, self.stack.len());
+            stats.peak_dump = cmp::max(stats.peak_dump, self.dump.len());
+        }
+        let r = match r {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                if raise || !self.unwinding() {
+                    Err(e)
+                } else {
+                    let cond = condition::from_error(&*e);
+                    self.throw(cond, e)
+                }
+            }
+        };
+        if self.trace.is_some() {
+            self.trace_calls(call, r.is_ok());
+        }
+        let r = match r {
+            Ok(()) if self.budget.is_some() => self.budget_calls(budgeted),
+            r => r,
+        };
+        if r.is_err() {
+            self.abandon(0);
+        }
+        return r;
+    }
+
+    // where the lambda of the closure an application is about to call starts, as a trace
+    // names it, where the call is and whether it replaces the current one
+    fn budgeted(&self, c: &CodeOPInfo) -> Option<(Info, Info, bool)> {
+        let tail = match c.op {
+            CodeOP::AP | CodeOP::RAP => false,
+            CodeOP::TAP | CodeOP::TRAP => true,
+            _ => return None,
+        };
+        match self.stack.last().map(|f| &**f) {
+            Some(&Lisp::Closure(_, ref body, _, _)) => {
+                return Some((body.first().map_or([0, 0], |b| b.info), c.info, tail));
+            }
+            _ => return None,
+        }
+    }
+
+    // notes the name a closure is bound to for a budget to report its lambda by, if the
+    // lambda has none yet
+    fn name_closure(&self, id: &str, a: &Lisp) {
+        if let (&Some(ref budget), &Lisp::Closure(_, ref body, _, _)) = (&self.budget, a) {
+            if !id.contains(' ') {
+                let at = body.first().map_or([0, 0], |b| b.info);
+                budget.names.borrow_mut().entry(at).or_insert_with(|| id.to_string());
+            }
+        }
+    }
+
+    // follows the applications on the dump as trace_calls does, reporting a call once it
+    // has taken more steps than the budget, the first time for its lambda
+    fn budget_calls(&mut self, call: Option<(Info, Info, bool)>) -> VMResult {
+        let depth = self.dump.iter().filter(|d| matches!(**d, DumpOP::DumpAP(..))).count();
+        let steps = self.steps;
+        let budget = self.budget.as_mut().unwrap();
+        budget.open.truncate(depth);
+        match call {
+            Some((at, from, false)) if budget.open.len() < depth => budget.open.push((at, from, steps)),
+            Some((at, from, true)) if budget.open.len() == depth && depth > 0 => {
+                let started = budget.open.pop().unwrap().2;
+                budget.open.push((at, from, started));
+            }
+            _ => {}
+        }
+        while budget.open.len() < depth {
+            budget.open.push(([0, 0], [0, 0], steps));
+        }
+
+        let over = budget.open.iter().find(|o| steps - o.2 > budget.steps && !budget.reported.borrow().contains(&o.0));
+        let (at, from, _) = match over {
+            Some(o) => *o,
+            None => return Ok(()),
+        };
+        budget.reported.borrow_mut().push(at);
+        let name = budget.names.borrow().get(&at).cloned();
+        let function = match name {
+            Some(name) => format!("{} (lambda {}:{})", name, at[0], at[1]),
+            None => format!("lambda {}:{}", at[0], at[1]),
+        };
+        let msg = format!("{} has taken more than {} steps in one call", function, budget.steps);
+        if budget.fatal {
+            return Err(From::from(Diagnostic::error("vm", Some(from), msg)));
+        }
+        budget.warnings.borrow_mut().push(Diagnostic::warning("vm", Some(from), msg));
+        return Ok(());
+    }
+
+    // what a trace calls the closure an application is about to call, and whether the
+    // call replaces the current one
+    fn callee(&self, c: &CodeOPInfo) -> Option<(String, bool)> {
+        let tail = match c.op {
+            CodeOP::AP | CodeOP::RAP => false,
+            CodeOP::TAP | CodeOP::TRAP => true,
+            _ => return None,
+        };
+        match self.stack.last().map(|f| &**f) {
+            Some(&Lisp::Closure(_, ref body, _, _)) => {
+                let at = body.first().map_or([0, 0], |b| b.info);
+                return Some((format!("lambda {}:{}", at[0], at[1]), tail));
+            }
+            _ => return None,
+        }
+    }
+
+    // begins and ends spans as the applications on the dump come and go
+    fn trace_calls(&mut self, call: Option<(String, bool)>, ok: bool) {
+        let depth = self.dump.iter().filter(|d| matches!(**d, DumpOP::DumpAP(..))).count();
+        let trace = self.trace.as_mut().unwrap();
+        while trace.open.len() > depth {
+            trace.end();
+        }
+        match call {
+            Some((name, false)) if trace.open.len() < depth => trace.begin(name),
+            Some((name, true)) if ok && trace.open.len() == depth && depth > 0 => {
+                trace.end();
+                trace.begin(name);
+            }
+            _ => {}
+        }
+        while trace.open.len() < depth {
+            trace.begin("?".to_string());
+        }
+    }
+
+    fn exec(&mut self, c: CodeOPInfo) -> VMResult {
+        if self.stack.len() < takes(&c.op) {
+            return self.error(&c, "stack underflow");
+        }
+        match c.op {
+            CodeOP::LET(ref id) => {
+                try!(self.run_let(&c, id));
+            }
+
+            CodeOP::LD(ref id) => {
+                try!(self.run_ld(&c, id));
+            }
+
+            CodeOP::LDC(i) => {
+                try!(self.run_ldc(&c, i));
+            }
+
+            CodeOP::LDF(ref names, ref code, ref proc_info) => {
+                try!(self.run_ldf(&c, names, code, proc_info));
+            }
+
+            CodeOP::RET => {
+                try!(self.run_ret(&c));
+            }
+
+            CodeOP::AP => {
+                try!(self.run_ap(&c));
+            }
+
+            CodeOP::RAP => {
+                try!(self.run_rap(&c));
+            }
+
+            CodeOP::TAP => {
+                try!(self.run_tap(&c));
+            }
+
+            CodeOP::TRAP => {
+                try!(self.run_trap(&c));
+            }
+
+            CodeOP::ARGS(n) => {
+                try!(self.run_args(&c, n));
+            }
+
+            CodeOP::PUTS => {
+                try!(self.run_puts(&c));
+            }
+
+            CodeOP::POP => {
+                try!(self.run_pop(&c));
+            }
+
+            CodeOP::SEL(ref t, ref f) => {
+                try!(self.run_sel(&c, t, f));
+            }
+
+            CodeOP::JOIN => {
+                try!(self.run_join(&c));
+            }
+
+            CodeOP::EQ => {
+                try!(self.run_eq(&c));
+            }
+
+            CodeOP::ADD => {
+                try!(self.run_add(&c));
+            }
+
+            CodeOP::SUB => {
+                try!(self.run_sub(&c));
+            }
+
+            CodeOP::CURTIME => {
+                try!(self.run_curtime(&c));
+            }
+
+            CodeOP::CLOCK => {
+                try!(self.run_clock(&c));
+            }
+
+            CodeOP::TIME => {
+                try!(self.run_time(&c));
+            }
+
+            CodeOP::ASSERT(ref info, ref expr) => {
+                try!(self.run_assert(&c, info, expr));
+            }
+
+            CodeOP::TEST(ref name) => {
+                try!(self.run_test(&c, name));
+            }
+
+            CodeOP::YIELD => {
+                try!(self.run_yield(&c));
+            }
+
+            CodeOP::SPAWN => {
+                try!(self.run_spawn(&c));
+            }
+
+            CodeOP::TJOIN => {
+                try!(self.run_tjoin(&c));
+            }
+
+            CodeOP::CHAN => {
+                try!(self.run_chan(&c));
+            }
+
+            CodeOP::SEND => {
+                try!(self.run_send(&c));
+            }
+
+            CodeOP::RECV => {
+                try!(self.run_recv(&c));
+            }
+
+            CodeOP::RANDOM => {
+                try!(self.run_random(&c));
+            }
+
+            CodeOP::EXIT => {
+                try!(self.run_exit(&c));
+            }
+
+            CodeOP::GETENV => {
+                try!(self.run_getenv(&c));
+            }
+
+            CodeOP::SYSTEM => {
+                try!(self.run_system(&c));
+            }
+
+            CodeOP::PROCESS => {
+                try!(self.run_process(&c));
+            }
+
+            CodeOP::CONS => {
+                try!(self.run_cons(&c));
+            }
+
+            CodeOP::CAR => {
+                try!(self.run_car(&c));
+            }
+
+            CodeOP::CDR => {
+                try!(self.run_cdr(&c));
+            }
+
+            CodeOP::OUTSTR => {
+                try!(self.run_outstr(&c));
+            }
+
+            CodeOP::TCPCONNECT => {
+                try!(self.run_tcp_connect(&c));
+            }
+
+            CodeOP::TCPLISTEN => {
+                try!(self.run_tcp_listen(&c));
+            }
+
+            CodeOP::TCPACCEPT => {
+                try!(self.run_tcp_accept(&c));
+            }
+
+            CodeOP::TCPREAD => {
+                try!(self.run_tcp_read(&c));
+            }
+
+            CodeOP::TCPWRITE => {
+                try!(self.run_tcp_write(&c));
+            }
+
+            CodeOP::TCPCLOSE => {
+                try!(self.run_tcp_close(&c));
+            }
+
+            CodeOP::HTTPGET => {
+                try!(self.run_http_get(&c));
+            }
+
+            CodeOP::DATENOW => {
+                try!(self.run_date_now(&c));
+            }
+
+            CodeOP::DATE2STR => {
+                try!(self.run_date2str(&c));
+            }
+
+            CodeOP::STR2DATE => {
+                try!(self.run_str2date(&c));
+            }
+
+            CodeOP::TRY(ref body, ref handlers) => {
+                try!(self.run_try(&c, body, handlers));
+            }
+
+            CodeOP::ENDTRY => {
+                try!(self.run_endtry(&c));
+            }
+
+            CodeOP::MKCOND => {
+                try!(self.run_mkcond(&c));
+            }
+
+            CodeOP::RAISE => {
+                try!(self.run_raise(&c));
+            }
+
+            CodeOP::PROTECT(ref body, ref cleanup) => {
+                try!(self.run_protect(&c, body, cleanup));
+            }
+
+            CodeOP::ENDPROTECT => {
+                try!(self.run_endprotect(&c));
+            }
+
+            CodeOP::RERAISE => {
+                try!(self.run_reraise(&c));
+            }
+
+            CodeOP::MKPARAM => {
+                try!(self.run_mkparam(&c));
+            }
+
+            CodeOP::DEREF => {
+                try!(self.run_deref(&c));
+            }
+
+            CodeOP::PARAMBIND(n) => {
+                try!(self.run_parambind(&c, n));
+            }
+
+            CodeOP::PARAMRESTORE => {
+                try!(self.run_paramrestore(&c));
+            }
+
+            CodeOP::HELP(ref name) => {
+                try!(self.run_help(&c, name));
+            }
+
+            CodeOP::SECDSTACK => {
+                try!(self.run_secdstack(&c));
+            }
+
+            CodeOP::SECDENV => {
+                try!(self.run_secdenv(&c));
+            }
+
+            CodeOP::SECDWHERE => {
+                try!(self.run_secdwhere(&c));
+            }
+
+            CodeOP::TSEL(ref t, ref f) => {
+                try!(self.run_tsel(&c, t, f));
+            }
+
+            CodeOP::PRIM(id, n) => {
+                try!(self.run_prim(&c, id, n));
+            }
+
+            CodeOP::CONSTS(ref pool) => {
+                try!(self.run_consts(&c, pool));
+            }
+
+            CodeOP::RESUME => {
+                try!(self.run_resume(&c));
+            }
+
+            CodeOP::HEADER(ref header) => {
+                try!(self.run_header(&c, header));
+            }
+        }
+
+        return Ok(());
+    }
+
+    // drives the machine one effect at a time: what it prints, the native calls it is
+    // about to make, which the host may answer for it, and what it yields. Once it is
+    // over, result gives what the machine ended with
+    pub fn effects(&mut self) -> Effects<'_> {
+        let queue = Rc::new(RefCell::new(VecDeque::new()));
+        self.output = Output::Effects(queue.clone());
+        return Effects {
+                   vm: self,
+                   queue,
+                   asked: false,
+                   done: false,
+               };
+    }
+
+    // a future that runs `ASYNC_BUDGET` instructions per poll before yielding to the executor
+    pub fn run_async(&mut self) -> RunFuture<'_> {
+        return RunFuture {
+                   vm: self,
+                   budget: ASYNC_BUDGET,
+               };
+    }
+
+
+    fn run_let(&mut self, _: &CodeOPInfo, id: &String) -> VMResult {
+        let expr = self.stack.pop().unwrap();
+        self.name_closure(id, &expr);
+        self.env.insert(id.clone(), expr);
+        return Ok(());
+    }
+
+    fn run_ld(&mut self, c: &CodeOPInfo, id: &String) -> VMResult {
+        let expr = match self.env.get(id) {
+            Some(expr) => expr.clone(),
+            None => return self.error(c, &format!("LD: unbound {}", id)),
+        };
+        self.stack.push(expr);
+        return Ok(());
+    }
+
+    // code built by hand may load a constant without a pool to find it in
+    fn run_ldc(&mut self, c: &CodeOPInfo, i: usize) -> VMResult {
+        let lisp = if i >= RESTORED {
+            self.restored.get(i - RESTORED)
+        } else {
+            self.consts.get(i)
+        };
+        match lisp {
+            Some(lisp) => self.stack.push(lisp.clone()),
+            None => return self.error(c, &format!("LDC: no constant {}", i)),
+        }
+        return Ok(());
+    }
+
+    fn run_header(&mut self, c: &CodeOPInfo, header: &Header) -> VMResult {
+        match check(header) {
+            Ok(()) => return Ok(()),
+            Err(e) => return self.error(c, &format!("HEADER: {}", e)),
+        }
+    }
+
+    fn run_consts(&mut self, _: &CodeOPInfo, pool: &Pool) -> VMResult {
+        self.consts = pool.clone();
+        return Ok(());
+    }
+
+    fn run_ldf(&mut self,
+               _: &CodeOPInfo,
+               names: &Vec<String>,
+               code: &Code,
+               proc_info: &Rc<ProcInfo>)
+               -> VMResult {
+        self.stack
+            .push(self.alloc(Lisp::Closure(names.clone(),
+                                        code.clone(),
+                                        self.clone_env(&self.env),
+                                        proc_info.clone())));
+        return Ok(());
+    }
+
+    fn run_ap(&mut self, c: &CodeOPInfo) -> VMResult {
+        if try!(self.trace_call(c)) {
+            return Ok(());
+        }
+        return self.ap(c);
+    }
+
+    fn ap(&mut self, c: &CodeOPInfo) -> VMResult {
+        if self.jit_call(false) {
+            return Ok(());
+        }
+        match *self.stack.pop().unwrap() {
+            Lisp::Closure(ref names, ref code, ref env, _) => {
+                match *self.stack.pop().unwrap() {
+                    Lisp::List(ref vals) => {
+                        let args = match arguments(names, vals) {
+                            Ok(args) => args,
+                            Err(e) => return self.error(c, &format!("AP: {}", e)),
+                        };
+
+                        let mut env = self.clone_env(env);
+                        for (name, a) in args {
+                            self.name_closure(&name, &a);
+                            env.insert(name, a);
+                        }
+
+                        self.dump
+                            .push(DumpOP::DumpAP(self.base,
+                                                 self.clone_env(&self.env),
+                                                 self.code.clone()));
+
+                        self.base = self.stack.len();
+                        self.env = env;
+                        self.set_code(code);
+
+                        return Ok(());
+                    }
+                    _ => return self.error(c, "AP: expected List"),
+                }
+            }
+
+            _ => return self.error(c, "AP: expected Closure"),
+        }
+    }
+
+    fn run_rap(&mut self, c: &CodeOPInfo) -> VMResult {
+        if try!(self.trace_call(c)) {
+            return Ok(());
+        }
+        return self.rap(c);
+    }
+
+    fn rap(&mut self, c: &CodeOPInfo) -> VMResult {
+        if self.jit_call(true) {
+            return Ok(());
+        }
+        match *self.stack.pop().unwrap() {
+            Lisp::Closure(ref names, ref code, ref env, _) => {
+                match *self.stack.pop().unwrap() {
+                    Lisp::List(ref vals) => {
+                        let args = match arguments(names, vals) {
+                            Ok(args) => args,
+                            Err(e) => return self.error(c, &format!("RAP: {}", e)),
+                        };
+
+                        let mut env = self.clone_env(env);
+                        for (name, a) in args {
+                            self.name_closure(&name, &a);
+                            env.insert(name, a);
+                        }
+
+                        self.dump
+                            .push(DumpOP::DumpAP(self.base,
+                                                 self.clone_env(&self.env),
+                                                 self.code.clone()));
+
+                        self.base = self.stack.len();
+                        self.env.extend(env);
+                        self.set_code(code);
+
+                        return Ok(());
+                    }
+
+                    _ => return self.error(c, "RAP: expected List"),
+                }
+            }
+
+            _ => return self.error(c, "RAP: expected Closure"),
+        }
+    }
+
+    // replaces the closure and arguments on top of the stack with the call's result if
+    // the JIT could compute it; with fuel set every step has to be counted, and with a
+    // closure traced every call it makes printed, so never
+    #[cfg(feature = "jit")]
+    fn jit_call(&mut self, rec: bool) -> bool {
+        let jit = match self.jit {
+            Some(ref jit) if self.fuel.is_none() && self.traced.is_empty() && self.stack.len() >= 2 => jit.clone(),
+            _ => return false,
+        };
+        let n = self.stack.len();
+        let r = jit.borrow_mut().call(&self.stack[n - 1], &self.stack[n - 2], &self.env, &self.consts, rec);
+        match r {
+            Some(r) => {
+                self.stack.truncate(n - 2);
+                self.stack.push(r);
+                return true;
+            }
+            None => return false,
+        }
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn jit_call(&mut self, _: bool) -> bool {
+        return false;
+    }
+
+    // prints a call of a traced closure, indented by the traced calls it is inside, and
+    // makes it with a callback frame to print what it returns; a tail call is made as
+    // any other, returning after that. False for a closure that isn't traced
+    fn trace_call(&mut self, c: &CodeOPInfo) -> Result<bool, Box<Error>> {
+        if self.traced.is_empty() || self.stack.len() < 2 {
+            return Ok(false);
+        }
+        let n = self.stack.len();
+        let name = match *self.stack[n - 1] {
+            Lisp::Closure(_, _, _, ref proc_info) => {
+                match self.traced.iter().find(|t| Rc::ptr_eq(&t.0, proc_info)) {
+                    Some(t) => t.1.clone(),
+                    None => return Ok(false),
+                }
+            }
+            _ => return Ok(false),
+        };
+        let mut call = format!("({}", name);
+        if let Lisp::List(ref args) = *self.stack[n - 2] {
+            for a in args.iter() {
+                call.push_str(&format!(" {}", a));
+            }
+        }
+        call.push(')');
+        let depth = self.dump
+            .iter()
+            .filter(|d| match **d {
+                        DumpOP::DumpCALLBACK(Callback::Trace(..), _) => true,
+                        _ => false,
+                    })
+            .count();
+        self.write_line(&format!("{}{}", "  ".repeat(depth), call));
+
+        let (tail, rec) = match c.op {
+            CodeOP::AP => (false, false),
+            CodeOP::RAP => (false, true),
+            CodeOP::TAP => (true, false),
+            _ => (true, true),
+        };
+        let code = if tail {
+            vec![CodeOPInfo {
+                     info: c.info,
+                     op: CodeOP::RET,
+                 }]
+        } else {
+            mem::take(&mut self.code)
+        };
+        self.dump.push(DumpOP::DumpCALLBACK(Callback::Trace(name, depth), code));
+        self.code = vec![CodeOPInfo {
+                             info: c.info,
+                             op: CodeOP::RESUME,
+                         }];
+        try!(if rec { self.rap(c) } else { self.ap(c) });
+        return Ok(true);
+    }
+
+    // a tail call reuses the caller's DumpAP, so the frames the callee would return
+    // through are dropped instead of saved; pending DumpSELs can only lead to a RET
+    fn drop_tail_frames(&mut self) {
+        while let Some(&DumpOP::DumpSEL(_)) = self.dump.last() {
+            self.dump.pop();
+        }
+    }
+
+    fn run_tap(&mut self, c: &CodeOPInfo) -> VMResult {
+        if try!(self.trace_call(c)) {
+            return Ok(());
+        }
+        if self.jit_call(false) {
+            self.drop_tail_frames();
+            return self.run_ret(c);
+        }
+        match *self.stack.pop().unwrap() {
+            Lisp::Closure(ref names, ref code, ref env, _) => {
+                match *self.stack.pop().unwrap() {
+                    Lisp::List(ref vals) => {
+                        let args = match arguments(names, vals) {
+                            Ok(args) => args,
+                            Err(e) => return self.error(c, &format!("TAP: {}", e)),
+                        };
+
+                        let mut env = self.clone_env(env);
+                        for (name, a) in args {
+                            self.name_closure(&name, &a);
+                            env.insert(name, a);
+                        }
+
+                        self.drop_tail_frames();
+
+                        self.stack.truncate(self.base);
+                        self.env = env;
+                        self.set_code(code);
+
+                        return Ok(());
+                    }
+                    _ => return self.error(c, "TAP: expected List"),
+                }
+            }
+
+            _ => return self.error(c, "TAP: expected Closure"),
+        }
+    }
+
+    fn run_trap(&mut self, c: &CodeOPInfo) -> VMResult {
+        if try!(self.trace_call(c)) {
+            return Ok(());
+        }
+        if self.jit_call(true) {
+            self.drop_tail_frames();
+            return self.run_ret(c);
+        }
+        match *self.stack.pop().unwrap() {
+            Lisp::Closure(ref names, ref code, ref env, _) => {
+                match *self.stack.pop().unwrap() {
+                    Lisp::List(ref vals) => {
+                        let args = match arguments(names, vals) {
+                            Ok(args) => args,
+                            Err(e) => return self.error(c, &format!("TRAP: {}", e)),
+                        };
+
+                        let mut env = self.clone_env(env);
+                        for (name, a) in args {
+                            self.name_closure(&name, &a);
+                            env.insert(name, a);
+                        }
+
+                        self.drop_tail_frames();
+
+                        self.stack.truncate(self.base);
+                        self.env.extend(env);
+                        self.set_code(code);
+
+                        return Ok(());
+                    }
+
+                    _ => return self.error(c, "TRAP: expected List"),
+                }
+            }
+
+            _ => return self.error(c, "TRAP: expected Closure"),
+        }
+    }
+
+    fn run_ret(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        match self.dump.pop() {
+            Some(DumpOP::DumpAP(base, env, code)) => {
+                self.stack.truncate(self.base);
+                self.base = base;
+                self.env = env;
+                self.code = code;
+
+                self.stack.push(a.clone());
+
+                return Ok(());
+            }
+
+            _ => return self.error(c, "RET: expected DumpAP"),
+        }
+    }
+
+    fn run_args(&mut self, _: &CodeOPInfo, n: usize) -> VMResult {
+        let at = self.stack.len() - n;
+        let ls = self.stack.drain(at..).collect();
+
+        self.stack.push(self.alloc(Lisp::List(ls)));
+        return Ok(());
+    }
+
+    fn run_puts(&mut self, _: &CodeOPInfo) -> VMResult {
+        let a = self.stack.last().unwrap().clone();
+        match self.output {
+            Output::Effects(ref effects) => effects.borrow_mut().push_back(Effect::Puts(a)),
+            _ => self.write_line(&format!("{}", a)),
+        }
+        return Ok(());
+    }
+
+    fn run_pop(&mut self, _: &CodeOPInfo) -> VMResult {
+        self.stack.pop();
+        return Ok(());
+    }
+
+    fn run_sel(&mut self, c: &CodeOPInfo, t: &Code, f: &Code) -> VMResult {
+        let b = self.stack.pop().unwrap();
+        let code = match *b {
+            Lisp::True => t,
+            Lisp::False => f,
+            _ => return self.error(c, "SEL: expected bool"),
+        };
+
+        self.dump.push(DumpOP::DumpSEL(self.code.clone()));
+
+        self.set_code(code);
+
+        return Ok(());
+    }
+
+    // both branches end in RET, so there is nothing to come back to
+    fn run_tsel(&mut self, c: &CodeOPInfo, t: &Code, f: &Code) -> VMResult {
+        let b = self.stack.pop().unwrap();
+        match *b {
+            Lisp::True => self.set_code(t),
+            Lisp::False => self.set_code(f),
+            _ => return self.error(c, "TSEL: expected bool"),
+        }
+        return Ok(());
+    }
+
+    // code built by hand, or for another registry, may name a primitive that isn't there
+    fn run_prim(&mut self, c: &CodeOPInfo, id: PrimId, n: usize) -> VMResult {
+        match self.primitives.get(id) {
+            Some((arity, run)) if arity == n => {
+                if self.stack.len() < n {
+                    return self.error(c, "PRIM: stack underflow");
+                }
+                return run(self, c);
+            }
+            _ => return self.error(c, &format!("PRIM: no primitive {} of {} arguments", id, n)),
+        }
+    }
+
+    fn run_join(&mut self, c: &CodeOPInfo) -> VMResult {
+        if let Some(DumpOP::DumpSEL(code)) = self.dump.pop() {
+            self.code = code;
+
+            return Ok(());
+        } else {
+            return self.error(c, "JOIN: expected DumpSEL");
+        }
+    }
+
+    fn run_eq(&mut self, _: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let b = self.stack.pop().unwrap();
+        self.stack
+            .push(Lisp::bool(a == b));
+
+        return Ok(());
+    }
+
+    fn run_add(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        if let Lisp::Int(n) = *a {
+            let b = self.stack.pop().unwrap();
+            if let Lisp::Int(m) = *b {
+                match m.checked_add(n) {
+                    Some(a) => self.stack.push(self.int(a)),
+                    None => return self.error(c, "ADD: overflow"),
+                }
+
+                return Ok(());
+            } else {
+                return self.error(c, "ADD: expected int");
+            }
+        } else {
+            return self.error(c, "ADD: expected int");
+        }
+    }
+
+    fn run_sub(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        if let Lisp::Int(n) = *a {
+            let b = self.stack.pop().unwrap();
+            if let Lisp::Int(o) = *b {
+                match o.checked_sub(n) {
+                    Some(a) => self.stack.push(self.int(a)),
+                    None => return self.error(c, "SUB: overflow"),
+                }
+
+                return Ok(());
+            } else {
+                return self.error(c, "SUB: expected int");
+            }
+        } else {
+            return self.error(c, "SUB: expected int");
+        }
+    }
+
+    fn pop_int(&mut self, c: &CodeOPInfo, name: &str) -> Result<i32, Box<Error>> {
+        match *self.stack.pop().unwrap() {
+            Lisp::Int(n) => return Ok(n),
+            _ => return self.error(c, &format!("{}: expected int", name)),
+        }
+    }
+
+    fn run_min(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "MIN"));
+        let m = try!(self.pop_int(c, "MIN"));
+        self.stack.push(self.int(if m < n { m } else { n }));
+
+        return Ok(());
+    }
+
+    fn run_max(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "MAX"));
+        let m = try!(self.pop_int(c, "MAX"));
+        self.stack.push(self.int(if m > n { m } else { n }));
+
+        return Ok(());
+    }
+
+    fn run_abs(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "ABS"));
+        match n.checked_abs() {
+            Some(a) => self.stack.push(self.int(a)),
+            None => return self.error(c, "ABS: overflow"),
+        }
+
+        return Ok(());
+    }
+
+    fn run_quot(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "QUOT"));
+        let m = try!(self.pop_int(c, "QUOT"));
+        if n == 0 {
+            return self.error(c, "QUOT: division by zero");
+        }
+        match m.checked_div(n) {
+            Some(q) => self.stack.push(self.int(q)),
+            None => return self.error(c, "QUOT: overflow"),
+        }
+
+        return Ok(());
+    }
+
+    fn run_rem(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "REM"));
+        let m = try!(self.pop_int(c, "REM"));
+        if n == 0 {
+            return self.error(c, "REM: division by zero");
+        }
+        match m.checked_rem(n) {
+            Some(r) => self.stack.push(self.int(r)),
+            None => return self.error(c, "REM: overflow"),
+        }
+
+        return Ok(());
+    }
+
+    fn run_band(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "BAND"));
+        let m = try!(self.pop_int(c, "BAND"));
+        self.stack.push(self.int(m & n));
+
+        return Ok(());
+    }
+
+    fn run_bor(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "BOR"));
+        let m = try!(self.pop_int(c, "BOR"));
+        self.stack.push(self.int(m | n));
+
+        return Ok(());
+    }
+
+    fn run_bxor(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "BXOR"));
+        let m = try!(self.pop_int(c, "BXOR"));
+        self.stack.push(self.int(m ^ n));
+
+        return Ok(());
+    }
+
+    fn run_bnot(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "BNOT"));
+        self.stack.push(self.int(!n));
+
+        return Ok(());
+    }
+
+    fn run_shl(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "SHL"));
+        let m = try!(self.pop_int(c, "SHL"));
+        if !(0..32).contains(&n) {
+            return self.error(c, "SHL: shift out of range");
+        }
+        self.stack.push(self.int(m << n));
+
+        return Ok(());
+    }
+
+    // arithmetic shift, so negative numbers keep their sign
+    fn run_shr(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "SHR"));
+        let m = try!(self.pop_int(c, "SHR"));
+        if !(0..32).contains(&n) {
+            return self.error(c, "SHR: shift out of range");
+        }
+        self.stack.push(self.int(m >> n));
+
+        return Ok(());
+    }
+
+    fn run_curtime(&mut self, c: &CodeOPInfo) -> VMResult {
+        try!(self.require(c, "CURTIME", "clock", self.capabilities.clock));
+        let a = try!(self.nondet(c, "CURTIME", |vm| match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) if d.as_secs() > i32::MAX as u64 => return vm.error(c, "CURTIME: the time is past what an int holds"),
+            Ok(d) => return Ok(vm.int(d.as_secs() as i32)),
+            Err(_) => return vm.error(c, "CURTIME: clock is before unix epoch"),
+        }));
+        self.stack.push(a);
+
+        return Ok(());
+    }
+
+    fn pop_date(&mut self, c: &CodeOPInfo, name: &str) -> Result<Date, Box<Error>> {
+        let a = self.stack.pop().unwrap();
+        let mut fields = vec![];
+        for x in try!(self.list_to_vec(c, name, &a)) {
+            match *x {
+                Lisp::Int(n) => fields.push(n),
+                _ => return self.error(c, &format!("{}: expected date", name)),
+            }
+        }
+        if fields.len() != 6 || fields[1..].iter().any(|&n| n < 0) {
+            return self.error(c, &format!("{}: expected date", name));
+        }
+        let d = Date {
+            year: fields[0],
+            month: fields[1] as u32,
+            day: fields[2] as u32,
+            hour: fields[3] as u32,
+            minute: fields[4] as u32,
+            second: fields[5] as u32,
+        };
+        if !d.is_valid() {
+            return self.error(c, &format!("{}: invalid date", name));
+        }
+        return Ok(d);
+    }
+
+    fn run_date_now(&mut self, c: &CodeOPInfo) -> VMResult {
+        try!(self.require(c, "DATE-NOW", "clock", self.capabilities.clock));
+        let a = try!(self.nondet(c, "DATE-NOW", |vm| match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => return Ok(date(Date::from_unix(d.as_secs() as i64))),
+            Err(_) => return vm.error(c, "DATE-NOW: clock is before unix epoch"),
+        }));
+        self.stack.push(a);
+
+        return Ok(());
+    }
+
+    fn run_date2str(&mut self, c: &CodeOPInfo) -> VMResult {
+        let d = try!(self.pop_date(c, "DATE->STRING"));
+        let fmt = try!(self.pop_str(c, "DATE->STRING"));
+        match d.format(&fmt) {
+            Ok(s) => self.stack.push(self.alloc(Lisp::Str(s))),
+            Err(e) => return self.error(c, &format!("DATE->STRING: {}", e)),
+        }
+
+        return Ok(());
+    }
+
+    // nil when the string doesn't match the format, like string->number
+    fn run_str2date(&mut self, c: &CodeOPInfo) -> VMResult {
+        let s = try!(self.pop_str(c, "STRING->DATE"));
+        let fmt = try!(self.pop_str(c, "STRING->DATE"));
+        match Date::parse(&fmt, &s) {
+            Ok(Some(d)) => self.stack.push(date(d)),
+            Ok(None) => self.stack.push(Lisp::nil()),
+            Err(e) => return self.error(c, &format!("STRING->DATE: {}", e)),
+        }
+
+        return Ok(());
+    }
+
+    // milliseconds since the machine was created
+    fn run_clock(&mut self, c: &CodeOPInfo) -> VMResult {
+        try!(self.require(c, "CLOCK", "clock", self.capabilities.clock));
+        let ms = try!(self.clock_ms(c, "CLOCK"));
+        self.stack.push(self.int(ms));
+
+        return Ok(());
+    }
+
+    fn clock_ms(&mut self, c: &CodeOPInfo, name: &str) -> Result<i32, Box<Error>> {
+        let a = try!(self.nondet(c, name, |vm| {
+            let ms = vm.started.elapsed().as_millis();
+            if ms > i32::MAX as u128 {
+                return vm.error(c, &format!("{}: the milliseconds since start are past what an int holds", name));
+            }
+            return Ok(vm.int(ms as i32));
+        }));
+        match *a {
+            Lisp::Int(ms) => return Ok(ms),
+            _ => return self.error(c, &format!("{}: replay log is out of step", name)),
+        }
+    }
+
+    // xorshift64*; under replay the drawn numbers come from the log like any other input
+    fn run_random(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "RANDOM"));
+        if n <= 0 {
+            return self.error(c, "RANDOM: expected positive int");
+        }
+        let a = try!(self.nondet(c, "RANDOM", |vm| {
+            let mut x = vm.rng.get();
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+            vm.rng.set(x);
+            let r = x.wrapping_mul(0x2545F4914F6CDD1D) >> 33;
+            return Ok(vm.int((r % n as u64) as i32));
+        }));
+        self.stack.push(a);
+
+        return Ok(());
+    }
+
+    // every value that depends on the world outside the program goes through here,
+    // so a recorded run can be replayed to exactly the same result
+    fn nondet<F>(&mut self, c: &CodeOPInfo, name: &str, f: F) -> Result<Rc<Lisp>, Box<Error>>
+        where F: FnOnce(&mut SECD) -> Result<Rc<Lisp>, Box<Error>>
+    {
+        if let Some(a) = self.answer.take() {
+            return Ok(a);
+        }
+        if let Replay::Replay(ref log, ref mut i) = *self.replay.borrow_mut() {
+            if *i < log.len() {
+                *i += 1;
+                return Ok(log[*i - 1].clone());
+            }
+            return self.error(c, &format!("{}: replay log is exhausted", name));
+        }
+        let a = try!(f(self));
+        if let Replay::Record(ref mut log) = *self.replay.borrow_mut() {
+            log.push(a.clone());
+        }
+        return Ok(a);
+    }
+
+    // start recording the nondeterministic inputs of the next run
+    pub fn record(&mut self) {
+        *self.replay.borrow_mut() = Replay::Record(vec![]);
+    }
+
+    // feed a log taken from a recorded run back in instead of consulting the world
+    pub fn replay(&mut self, log: Vec<Rc<Lisp>>) {
+        *self.replay.borrow_mut() = Replay::Replay(log, 0);
+    }
+
+    pub fn replay_log(&self) -> Vec<Rc<Lisp>> {
+        match *self.replay.borrow() {
+            Replay::Record(ref log) | Replay::Replay(ref log, _) => return log.clone(),
+            Replay::Off => return vec![],
+        }
+    }
+
+    fn run_time(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let start = try!(self.pop_int(c, "TIME"));
+        try!(self.require(c, "TIME", "clock", self.capabilities.clock));
+        let ms = try!(self.clock_ms(c, "TIME"));
+        self.write_line(&format!("time: {} ms", ms.wrapping_sub(start)));
+        self.stack.push(a);
+
+        return Ok(());
+    }
+
+    fn run_assert(&mut self, c: &CodeOPInfo, info: &Info, expr: &String) -> VMResult {
+        match **self.stack.last().unwrap() {
+            Lisp::True => return Ok(()),
+            Lisp::False => {
+                return self.error(c,
+                                  &format!("ASSERT: assertion failed: {} at {}:{}",
+                                           expr,
+                                           info[0],
+                                           info[1]))
+            }
+            _ => return self.error(c, "ASSERT: expected bool"),
+        }
+    }
+
+    fn run_test(&mut self, c: &CodeOPInfo, name: &String) -> VMResult {
+        let actual = self.stack.pop().unwrap();
+        let expected = self.stack.pop().unwrap();
+        let passed = expected == actual;
+        self.tests.borrow_mut().push(TestResult {
+                            name: name.clone(),
+                            passed,
+                            info: c.info,
+                            expected: format!("{}", expected),
+                            actual: format!("{}", actual),
+                        });
+        self.stack.push(Lisp::bool(passed));
+        return Ok(());
+    }
+
+    fn pop_str(&mut self, c: &CodeOPInfo, name: &str) -> Result<String, Box<Error>> {
+        match *self.stack.pop().unwrap() {
+            Lisp::Str(ref s) => return Ok(s.clone()),
+            _ => return self.error(c, &format!("{}: expected string", name)),
+        }
+    }
+
+    fn run_num2str(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "NUM2STR"));
+        self.stack.push(self.alloc(Lisp::Str(n.to_string())));
+
+        return Ok(());
+    }
+
+    // any value as puts prints it, a string as it is; what an interpolated expression
+    // goes through
+    fn run_value2str(&mut self, _: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        match *a {
+            Lisp::Str(_) => self.stack.push(a.clone()),
+            _ => self.stack.push(self.alloc(Lisp::Str(format!("{}", a)))),
+        }
+
+        return Ok(());
+    }
+
+    fn run_strappend(&mut self, c: &CodeOPInfo) -> VMResult {
+        let b = try!(self.pop_str(c, "STRAPPEND"));
+        let a = try!(self.pop_str(c, "STRAPPEND"));
+        self.stack.push(self.alloc(Lisp::Str(a + &b)));
+
+        return Ok(());
+    }
+
+    // strings that are not a number give nil rather than an error
+    fn run_str2num(&mut self, c: &CodeOPInfo) -> VMResult {
+        let s = try!(self.pop_str(c, "STR2NUM"));
+        match s.trim().parse() {
+            Ok(n) => self.stack.push(self.int(n)),
+            Err(_) => self.stack.push(Lisp::nil()),
+        }
+
+        return Ok(());
+    }
+
+    fn run_sym2str(&mut self, c: &CodeOPInfo) -> VMResult {
+        match *self.stack.pop().unwrap() {
+            Lisp::Symbol(ref s) => self.stack.push(self.alloc(Lisp::Str(s.clone()))),
+            _ => return self.error(c, "SYM2STR: expected symbol"),
+        }
+
+        return Ok(());
+    }
+
+    // a name with a space is one of the compiler's and the machine's own, like MISSING
+    // and the tags of records, which a program must not be able to make
+    fn run_str2sym(&mut self, c: &CodeOPInfo) -> VMResult {
+        let s = try!(self.pop_str(c, "STR2SYM"));
+        if s.contains(' ') {
+            return self.error(c, "STR2SYM: a symbol cannot have a space");
+        }
+        self.stack.push(self.alloc(Lisp::Symbol(s)));
+
+        return Ok(());
+    }
+
+    fn run_yield(&mut self, _: &CodeOPInfo) -> VMResult {
+        self.yielded = self.stack.pop();
+        return Ok(());
+    }
+
+    // the new machine starts by applying the thunk, so its body's RET has a frame to return to
+    fn run_spawn(&mut self, c: &CodeOPInfo) -> VMResult {
+        let f = self.stack.pop().unwrap();
+        match *f {
+            Lisp::Closure(ref names, _, _, _) if names.len() == 0 => {}
+            _ => return self.error(c, "SPAWN: expected Closure without arguments"),
+        }
+
+        let mut vm = SECD::new(vec![CodeOPInfo {
+                                        info: c.info,
+                                        op: CodeOP::AP,
+                                    }]);
+        vm.stack.push(self.alloc(Lisp::List(Args::new())));
+        vm.stack.push(f);
+        vm.capabilities = self.capabilities;
+        vm.replay = self.replay.clone();
+        vm.rng = self.rng.clone();
+        vm.output = self.output.clone();
+        vm.fuel = self.fuel;
+        vm.memory = self.memory;
+        vm.consts = self.consts.clone();
+        vm.restored = self.restored.clone();
+        vm.coverage = self.coverage.clone();
+        vm.tests = self.tests.clone();
+        vm.counters = self.counters.clone();
+        vm.traced = self.traced.clone();
+        vm.primitives = self.primitives.clone();
+        #[cfg(feature = "jit")]
+        {
+            vm.jit = self.jit.clone();
+        }
+
+        let id = NEXT_THREAD.fetch_add(1, Ordering::SeqCst);
+        vm.trace = self.trace.as_ref().map(|t| {
+                                               Trace {
+                                                   tid: id,
+                                                   open: vec![],
+                                                   ..t.clone()
+                                               }
+                                           });
+        vm.budget = self.budget.as_ref().map(|b| Budget { open: vec![], ..b.clone() });
+        self.spawned.push((id, vm));
+        self.stack.push(self.alloc(Lisp::Thread(id)));
+
+        return Ok(());
+    }
+
+    // the scheduler pushes the thread's result once it has finished
+    fn run_tjoin(&mut self, c: &CodeOPInfo) -> VMResult {
+        match *self.stack.pop().unwrap() {
+            Lisp::Thread(id) => self.joining = Some(id),
+            _ => return self.error(c, "TJOIN: expected thread"),
+        }
+
+        return Ok(());
+    }
+
+    fn run_chan(&mut self, _: &CodeOPInfo) -> VMResult {
+        self.stack.push(self.alloc(Lisp::Chan(Rc::new(Mutable::new(VecDeque::new())))));
+        return Ok(());
+    }
+
+    // send gives back the value it sent
+    fn run_send(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        match *self.stack.pop().unwrap() {
+            Lisp::Chan(ref q) if q.is_frozen() => return self.error(c, "SEND: chan is frozen"),
+            Lisp::Chan(ref q) => q.borrow_mut().push_back(a.clone()),
+            _ => return self.error(c, "SEND: expected chan"),
+        }
+        self.stack.push(a);
+
+        return Ok(());
+    }
+
+    // on an empty channel the instruction is put back and the machine waits until
+    // the scheduler sees something arrive
+    fn run_recv(&mut self, c: &CodeOPInfo) -> VMResult {
+        let ch = self.stack.pop().unwrap();
+        let a = match *ch {
+            Lisp::Chan(ref q) if q.is_frozen() => return self.error(c, "RECV: chan is frozen"),
+            Lisp::Chan(ref q) => {
+                match q.borrow_mut().pop_front() {
+                    Some(a) => a,
+                    None => {
+                        self.receiving = Some(q.clone());
+                        self.stack.push(ch.clone());
+                        self.code.insert(0, c.clone());
+                        return Ok(());
+                    }
+                }
+            }
+            _ => return self.error(c, "RECV: expected chan"),
+        };
+        self.stack.push(a);
+
+        return Ok(());
+    }
+
+    // the status stays on the stack so run still has a value to return
+    fn run_exit(&mut self, c: &CodeOPInfo) -> VMResult {
+        let n = try!(self.pop_int(c, "EXIT"));
+        self.stack.push(self.int(n));
+        self.exit = Some(n);
+        self.abandon(0);
+        self.code.clear();
+        self.dump.clear();
+
+        return Ok(());
+    }
+
+    fn run_getenv(&mut self, c: &CodeOPInfo) -> VMResult {
+        let name = try!(self.pop_str(c, "GETENV"));
+        try!(self.require(c, "GETENV", "env-vars", self.capabilities.env_vars));
+        let a = try!(self.nondet(c, "GETENV", |vm| match env::var(name) {
+            Ok(v) => return Ok(vm.alloc(Lisp::Str(v))),
+            Err(_) => return Ok(Lisp::nil()),
+        }));
+        self.stack.push(a);
+
+        return Ok(());
+    }
+
+    fn list_to_vec(&self, c: &CodeOPInfo, name: &str, a: &Rc<Lisp>) -> Result<Vec<Rc<Lisp>>, Box<Error>> {
+        let mut v = vec![];
+        let mut a = a.clone();
+        loop {
+            let next = match *a {
+                Lisp::Nil => return Ok(v),
+                Lisp::Cons(ref car, ref cdr) => {
+                    v.push(car.clone());
+                    cdr.clone()
+                }
+                _ => return self.error(c, &format!("{}: expected list", name)),
+            };
+            a = next;
+        }
+    }
+
+    // both commands give (cons <exit code> <stdout>); a signal kills with code -1
+    fn run_command(&mut self, c: &CodeOPInfo, name: &str, mut cmd: Command) -> VMResult {
+        try!(self.require(c, name, "process", self.capabilities.process));
+        let a = try!(self.nondet(c, name, |vm| match cmd.output() {
+            Ok(out) => {
+                let code = out.status.code().unwrap_or(-1);
+                let stdout = String::from_utf8_lossy(&out.stdout).into_owned();
+                return Ok(vm.alloc(Lisp::Cons(vm.int(code), vm.alloc(Lisp::Str(stdout)))));
+            }
+            Err(e) => return vm.error(c, &format!("{}: {}", name, e)),
+        }));
+        self.stack.push(a);
+
+        return Ok(());
+    }
+
+    fn run_system(&mut self, c: &CodeOPInfo) -> VMResult {
+        let line = try!(self.pop_str(c, "SYSTEM"));
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(line);
+        return self.run_command(c, "SYSTEM", cmd);
+    }
+
+    fn run_process(&mut self, c: &CodeOPInfo) -> VMResult {
+        let args = self.stack.pop().unwrap();
+        let prog = try!(self.pop_str(c, "PROCESS"));
+        let mut cmd = Command::new(prog);
+        for arg in try!(self.list_to_vec(c, "PROCESS", &args)) {
+            match *arg {
+                Lisp::Str(ref s) => cmd.arg(s),
+                _ => return self.error(c, "PROCESS: expected string argument"),
+            };
+        }
+        return self.run_command(c, "PROCESS", cmd);
+    }
+
+    // -1, 0 or 1 as the first argument is less than, equal to or greater than the second
+    fn run_compare(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let b = self.stack.pop().unwrap();
+        match b.partial_cmp(&a) {
+            Some(o) => self.stack.push(self.int(o as i32)),
+            None => {
+                return self.error(c,
+                                  &format!("COMPARE: cannot compare {} with {}",
+                                           b.type_name(),
+                                           a.type_name()))
+            }
+        }
+        return Ok(());
+    }
+
+    // where a primitive calling closures goes from here: `next` calls one more, with
+    // the primitive's frame on the dump under the call's own so that RESUME continues it,
+    // or gives the value the primitive leaves; `code` is what runs after the primitive
+    fn callback(&mut self, c: &CodeOPInfo, next: Next, code: Code) {
+        match next {
+            Next::Call(cb, f, args) => {
+                self.dump.push(DumpOP::DumpCALLBACK(cb, code));
+                let args = self.alloc(Lisp::List(Args::from_vec(args)));
+                self.stack.push(args);
+                self.stack.push(f);
+                self.code = vec![CodeOPInfo {
+                                     info: c.info,
+                                     op: CodeOP::AP,
+                                 },
+                                 CodeOPInfo {
+                                     info: c.info,
+                                     op: CodeOP::RESUME,
+                                 }];
+            }
+            Next::Done(a) => {
+                self.stack.push(a);
+                self.code = code;
+            }
+        }
+    }
+
+    fn run_resume(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let (cb, code) = match self.dump.pop() {
+            Some(DumpOP::DumpCALLBACK(cb, code)) => (cb, code),
+            _ => return self.error(c, "RESUME: expected DumpCALLBACK"),
+        };
+        let next = try!(self.next_callback(c, cb, a));
+        self.callback(c, next, code);
+        return Ok(());
+    }
+
+    // the step after the callback of `cb` returned `a`
+    fn next_callback(&mut self, c: &CodeOPInfo, cb: Callback, a: Rc<Lisp>) -> Result<Next, Box<Error>> {
+        match cb {
+            Callback::Map(f, items, i, mut out) => {
+                out.push(a);
+                return Ok(map(f, items, i, out));
+            }
+            Callback::Filter(f, items, i, mut out) => {
+                match *a {
+                    Lisp::True => out.push(items[i - 1].clone()),
+                    Lisp::False => {}
+                    _ => return self.error(c, "FILTER: predicate must return a bool"),
+                }
+                return Ok(filter(f, items, i, out));
+            }
+            Callback::Fold(f, items, i, right) => return Ok(fold(f, items, i, right, a)),
+            Callback::SortBy(f, mut m) => {
+                let greater = match *a {
+                    Lisp::Int(n) => n > 0,
+                    _ => return self.error(c, "SORT-BY: comparison must return an int"),
+                };
+                if greater {
+                    m.merged.push(m.right[m.j].clone());
+                    m.j += 1;
+                } else {
+                    m.merged.push(m.left[m.i].clone());
+                    m.i += 1;
+                }
+                return Ok(merge(f, m));
+            }
+            Callback::Output(output, buf) => {
+                self.output = output;
+                let s = buf.borrow().clone();
+                return Ok(Next::Done(self.alloc(Lisp::Str(s))));
+            }
+            Callback::Trace(_, depth) => {
+                self.write_line(&format!("{}=> {}", "  ".repeat(depth), a));
+                return Ok(Next::Done(a));
+            }
+            Callback::Force(cell) => {
+                // a force inside the thunk may have got there first, and its value stays
+                if let Lisp::Cons(ref done, ref value) = **cell.borrow() {
+                    if **done == Lisp::True {
+                        return Ok(Next::Done(value.clone()));
+                    }
+                }
+                // a frozen promise runs its thunk on every force instead
+                if !cell.is_frozen() {
+                    *cell.borrow_mut() = self.alloc(Lisp::Cons(Lisp::bool(true), a.clone()));
+                }
+                return Ok(Next::Done(a));
+            }
+        }
+    }
+
+    // the frames from `from` up are being abandoned: an output with-output-to-string
+    // replaced goes back to what it was
+    fn abandon(&mut self, from: usize) {
+        let outer = self.dump.iter().skip(from).find_map(|d| match *d {
+            DumpOP::DumpCALLBACK(Callback::Output(ref output, _), _) => Some(output.clone()),
+            _ => None,
+        });
+        if let Some(output) = outer {
+            self.output = output;
+        }
+    }
+
+    // a stable merge sort
+    fn merge_sort<F>(&mut self, mut v: Vec<Rc<Lisp>>, cmp: &mut F) -> Result<Vec<Rc<Lisp>>, Box<Error>>
+        where F: FnMut(&mut SECD, &Rc<Lisp>, &Rc<Lisp>) -> Result<cmp::Ordering, Box<Error>>
+    {
+        if v.len() <= 1 {
+            return Ok(v);
+        }
+
+        let right = v.split_off(v.len() / 2);
+        let left = try!(self.merge_sort(v, cmp));
+        let right = try!(self.merge_sort(right, cmp));
+
+        let mut merged = Vec::with_capacity(left.len() + right.len());
+        let (mut i, mut j) = (0, 0);
+        while i < left.len() && j < right.len() {
+            if try!(cmp(self, &left[i], &right[j])) == cmp::Ordering::Greater {
+                merged.push(right[j].clone());
+                j += 1;
+            } else {
+                merged.push(left[i].clone());
+                i += 1;
+            }
+        }
+        merged.extend_from_slice(&left[i..]);
+        merged.extend_from_slice(&right[j..]);
+        return Ok(merged);
+    }
+
+    fn push_list(&mut self, v: Vec<Rc<Lisp>>) {
+        self.stack.push(list(v));
+    }
+
+    fn run_sort(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let v = try!(self.list_to_vec(c, "SORT", &a));
+        let sorted = try!(self.merge_sort(v, &mut |vm, a, b| match a.partial_cmp(b) {
+            Some(o) => return Ok(o),
+            None => {
+                return vm.error(c,
+                                &format!("SORT: cannot compare {} with {}",
+                                         a.type_name(),
+                                         b.type_name()))
+            }
+        }));
+        self.push_list(sorted);
+
+        return Ok(());
+    }
+
+    // (sort-by lst f) orders by (f a b), negative, zero or positive like compare
+    fn run_sort_by(&mut self, c: &CodeOPInfo) -> VMResult {
+        let f = self.stack.pop().unwrap();
+        let a = self.stack.pop().unwrap();
+        try!(self.expect_closure(c, "SORT-BY", &f, 2));
+        let v = try!(self.list_to_vec(c, "SORT-BY", &a));
+        let m = Merge {
+            runs: v.into_iter().map(|a| vec![a]).collect(),
+            ..Merge::default()
+        };
+        let code = mem::take(&mut self.code);
+        self.callback(c, merge(f, m), code);
+
+        return Ok(());
+    }
+
+    fn expect_closure(&self, c: &CodeOPInfo, name: &str, f: &Rc<Lisp>, arity: usize) -> VMResult {
+        match **f {
+            // parameters with defaults may be left out
+            Lisp::Closure(ref names, _, _, _) if names.iter().filter(|n| !n.starts_with("#:") && !n.starts_with('[')).count() <= arity &&
+                                                 names.iter().filter(|n| !n.starts_with("#:")).count() >= arity => {
+                return Ok(())
+            }
+            _ => {
+                return self.error(c,
+                                  &format!("{}: expected Closure of {} argument{}",
+                                           name,
+                                           arity,
+                                           if arity == 1 { "" } else { "s" }))
+            }
+        }
+    }
+
+    fn run_map(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let f = self.stack.pop().unwrap();
+        try!(self.expect_closure(c, "MAP", &f, 1));
+        let items = try!(self.list_to_vec(c, "MAP", &a));
+        let code = mem::take(&mut self.code);
+        self.callback(c, map(f, items, 0, vec![]), code);
+
+        return Ok(());
+    }
+
+    fn run_filter(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let f = self.stack.pop().unwrap();
+        try!(self.expect_closure(c, "FILTER", &f, 1));
+        let items = try!(self.list_to_vec(c, "FILTER", &a));
+        let code = mem::take(&mut self.code);
+        self.callback(c, filter(f, items, 0, vec![]), code);
+
+        return Ok(());
+    }
+
+    fn run_foldl(&mut self, c: &CodeOPInfo) -> VMResult {
+        return self.run_fold(c, "FOLDL", false);
+    }
+
+    fn run_foldr(&mut self, c: &CodeOPInfo) -> VMResult {
+        return self.run_fold(c, "FOLDR", true);
+    }
+
+    // foldl calls (f acc x) from the first element on, foldr (f x acc) from the last
+    fn run_fold(&mut self, c: &CodeOPInfo, name: &str, right: bool) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let acc = self.stack.pop().unwrap();
+        let f = self.stack.pop().unwrap();
+        try!(self.expect_closure(c, name, &f, 2));
+        let mut items = try!(self.list_to_vec(c, name, &a));
+        if right {
+            items.reverse();
+        }
+        let code = mem::take(&mut self.code);
+        self.callback(c, fold(f, items, 0, right, acc), code);
+
+        return Ok(());
+    }
+
+    // (range start end step) counts from start towards end, which is left out
+    fn run_range(&mut self, c: &CodeOPInfo) -> VMResult {
+        let step = try!(self.pop_int(c, "RANGE"));
+        let end = try!(self.pop_int(c, "RANGE"));
+        let start = try!(self.pop_int(c, "RANGE"));
+        if step == 0 {
+            return self.error(c, "RANGE: step must not be 0");
+        }
+
+        let mut v = vec![];
+        let mut i = start;
+        while (step > 0 && i < end) || (step < 0 && i > end) {
+            v.push(self.int(i));
+            i = match i.checked_add(step) {
+                Some(i) => i,
+                None => break,
+            };
+        }
+        self.push_list(v);
+
+        return Ok(());
+    }
+
+    // runs the thunk with its own buffer as the output, threads it spawns included
+    fn run_outstr(&mut self, c: &CodeOPInfo) -> VMResult {
+        let f = self.stack.pop().unwrap();
+        try!(self.expect_closure(c, "WITH-OUTPUT-TO-STRING", &f, 0));
+
+        let buf = Rc::new(RefCell::new(String::new()));
+        let output = mem::replace(&mut self.output, Output::Buffer(buf.clone()));
+        let code = mem::take(&mut self.code);
+        self.callback(c, Next::Call(Callback::Output(output, buf), f, vec![]), code);
+
+        return Ok(());
+    }
+
+    fn pop_port(&mut self, c: &CodeOPInfo, name: &str) -> Result<PortRef, Box<Error>> {
+        match *self.stack.pop().unwrap() {
+            Lisp::Port(ref p) => return Ok(p.clone()),
+            _ => return self.error(c, &format!("{}: expected port", name)),
+        }
+    }
+
+    fn push_port(&mut self, p: Port) {
+        self.stack.push(self.alloc(Lisp::Port(Rc::new(RefCell::new(p)))));
+    }
+
+    fn pop_addr(&mut self, c: &CodeOPInfo, name: &str) -> Result<(String, u16), Box<Error>> {
+        let port = try!(self.pop_int(c, name));
+        let host = try!(self.pop_str(c, name));
+        if !(0..=65535).contains(&port) {
+            return self.error(c, &format!("{}: port out of range", name));
+        }
+        try!(self.require(c, name, "network", self.capabilities.network));
+        return Ok((host, port as u16));
+    }
+
+    // sockets block the OS thread, and with it every green thread on this machine
+    fn run_tcp_connect(&mut self, c: &CodeOPInfo) -> VMResult {
+        let addr = try!(self.pop_addr(c, "TCP-CONNECT"));
+        match TcpStream::connect(addr) {
+            Ok(s) => self.push_port(Port::Stream(BufReader::new(s))),
+            Err(e) => return self.error(c, &format!("TCP-CONNECT: {}", e)),
+        }
+
+        return Ok(());
+    }
+
+    fn run_tcp_listen(&mut self, c: &CodeOPInfo) -> VMResult {
+        let addr = try!(self.pop_addr(c, "TCP-LISTEN"));
+        match TcpListener::bind(addr) {
+            Ok(l) => self.push_port(Port::Listener(l)),
+            Err(e) => return self.error(c, &format!("TCP-LISTEN: {}", e)),
+        }
+
+        return Ok(());
+    }
+
+    // what accept and read give depends on the peer, so they are recorded and replayed
+    // like any other input
+    fn run_tcp_accept(&mut self, c: &CodeOPInfo) -> VMResult {
+        let p = try!(self.pop_port(c, "TCP-ACCEPT"));
+        let a = try!(self.nondet(c, "TCP-ACCEPT", |vm| {
+            let accepted = match *p.borrow() {
+                Port::Listener(ref l) => l.accept(),
+                _ => return vm.error(c, "TCP-ACCEPT: expected listener"),
+            };
+            match accepted {
+                Ok((s, _)) => return Ok(vm.alloc(Lisp::Port(Rc::new(RefCell::new(Port::Stream(BufReader::new(s))))))),
+                Err(e) => return vm.error(c, &format!("TCP-ACCEPT: {}", e)),
+            }
+        }));
+        self.stack.push(a);
+
+        return Ok(());
+    }
+
+    // one line without its line break, or nil once the peer has closed
+    fn run_tcp_read(&mut self, c: &CodeOPInfo) -> VMResult {
+        let p = try!(self.pop_port(c, "TCP-READ"));
+        let a = try!(self.nondet(c, "TCP-READ", |vm| {
+            let mut line = String::new();
+            let read = match *p.borrow_mut() {
+                Port::Stream(ref mut s) => s.read_line(&mut line),
+                _ => return vm.error(c, "TCP-READ: expected open stream"),
+            };
+            match read {
+                Ok(0) => return Ok(Lisp::nil()),
+                Ok(_) => {
+                    let n = line.trim_end_matches(&['\r', '\n'][..]).len();
+                    line.truncate(n);
+                    return Ok(vm.alloc(Lisp::Str(line)));
+                }
+                Err(e) => return vm.error(c, &format!("TCP-READ: {}", e)),
+            }
+        }));
+        self.stack.push(a);
+
+        return Ok(());
+    }
+
+    // writes the string as is and gives the port back
+    fn run_tcp_write(&mut self, c: &CodeOPInfo) -> VMResult {
+        let s = try!(self.pop_str(c, "TCP-WRITE"));
+        let p = try!(self.pop_port(c, "TCP-WRITE"));
+        let written = match *p.borrow_mut() {
+            Port::Stream(ref mut stream) => stream.get_mut().write_all(s.as_bytes()),
+            _ => return self.error(c, "TCP-WRITE: expected open stream"),
+        };
+        if let Err(e) = written {
+            return self.error(c, &format!("TCP-WRITE: {}", e));
+        }
+        self.stack.push(self.alloc(Lisp::Port(p)));
+
+        return Ok(());
     }
 
-    pub fn run(&mut self) -> Result<Rc<Lisp>, Box<Error>> {
-        try!(self.run_());
-        return Ok(self.stack.last().unwrap().clone());
+    fn run_tcp_close(&mut self, c: &CodeOPInfo) -> VMResult {
+        let p = try!(self.pop_port(c, "TCP-CLOSE"));
+        *p.borrow_mut() = Port::Closed;
+        self.stack.push(Lisp::nil());
+
+        return Ok(());
     }
 
-    fn run_(&mut self) -> VMResult {
-        while self.code.len() > 0 {
-            let c = self.code.remove(0);
-            match c.op { 
-                CodeOP::LET(ref id) => {
-                    try!(self.run_let(&c, id));
-                }
+    // gives (status headers body), the headers as a list of (cons name value); recorded
+    // and replayed like any other input
+    fn run_http_get(&mut self, c: &CodeOPInfo) -> VMResult {
+        let url = try!(self.pop_str(c, "HTTP-GET"));
+        try!(self.require(c, "HTTP-GET", "network", self.capabilities.network));
+        let a = try!(self.nondet(c, "HTTP-GET", |vm| {
+            let (status, headers, body) = match http_get(&url) {
+                Ok(r) => r,
+                Err(e) => return vm.error(c, &format!("HTTP-GET: {}", e)),
+            };
+            let headers = headers.into_iter()
+                .rev()
+                .fold(Lisp::nil(), |cdr, (name, value)| {
+                    let h = vm.alloc(Lisp::Cons(vm.alloc(Lisp::Str(name)), vm.alloc(Lisp::Str(value))));
+                    vm.alloc(Lisp::Cons(h, cdr))
+                });
+            return Ok(list(vec![vm.int(status), headers, vm.alloc(Lisp::Str(body))]));
+        }));
+        self.stack.push(a);
 
-                CodeOP::LD(ref id) => {
-                    try!(self.run_ld(&c, id));
-                }
+        return Ok(());
+    }
 
-                CodeOP::LDC(ref lisp) => {
-                    try!(self.run_ldc(&c, lisp));
-                }
+    fn run_try(&mut self, _: &CodeOPInfo, body: &Code, handlers: &Vec<Handler>) -> VMResult {
+        self.dump
+            .push(DumpOP::DumpTRY(handlers.clone(),
+                                  self.mark(),
+                                  self.clone_env(&self.env),
+                                  self.code.clone()));
+        self.set_code(body);
 
-                CodeOP::LDF(ref names, ref code) => {
-                    try!(self.run_ldf(&c, names, code));
-                }
+        return Ok(());
+    }
 
-                CodeOP::RET => {
-                    try!(self.run_ret(&c));
-                }
+    fn run_endtry(&mut self, c: &CodeOPInfo) -> VMResult {
+        if let Some(DumpOP::DumpTRY(_, _, _, code)) = self.dump.pop() {
+            self.code = code;
 
-                CodeOP::AP => {
-                    try!(self.run_ap(&c));
-                }
+            return Ok(());
+        } else {
+            return self.error(c, "ENDTRY: expected DumpTRY");
+        }
+    }
 
-                CodeOP::RAP => {
-                    try!(self.run_rap(&c));
-                }
+    fn mark(&self) -> Mark {
+        return [self.stack.len(), self.base];
+    }
 
-                CodeOP::ARGS(n) => {
-                    try!(self.run_args(&c, n));
-                }
+    // the calls made since `mark` are abandoned along with what they left on the stack
+    fn unwind_to(&mut self, mark: Mark) {
+        self.stack.truncate(mark[0]);
+        self.base = mark[1];
+    }
 
-                CodeOP::PUTS => {
-                    try!(self.run_puts(&c));
-                }
+    // raises `e` where the machine is, as an instruction's error is: a try around it
+    // handles it, otherwise it is what the machine stops with
+    pub fn fail(&mut self, e: Box<Error>) -> VMResult {
+        if !self.unwinding() {
+            return Err(e);
+        }
+        let cond = condition::from_error(&*e);
+        return self.throw(cond, e);
+    }
 
-                CodeOP::SEL(ref t, ref f) => {
-                    try!(self.run_sel(&c, t, f));
-                }
+    // whether an error has any frame to stop at on its way out
+    fn unwinding(&self) -> bool {
+        return self.dump.iter().any(|d| matches!(*d, DumpOP::DumpTRY(..) | DumpOP::DumpPROTECT(..)));
+    }
 
-                CodeOP::JOIN => {
-                    try!(self.run_join(&c));
-                }
+    // unwinds to the innermost try with a handler for `cond` and runs it, or to an
+    // unwind-protect on the way there, whose cleanup raises `cond` again when done;
+    // `e` is the error the machine stops with when there is neither
+    fn throw(&mut self, cond: Rc<Lisp>, e: Box<Error>) -> VMResult {
+        let kind = match *cond {
+            Lisp::Condition(ref c) => c.kind.clone(),
+            _ => unreachable!(),
+        };
+        let found = self.dump.iter().enumerate().rev().find_map(|(i, d)| match *d {
+            DumpOP::DumpTRY(ref handlers, _, _, _) => {
+                handlers.iter().position(|h| condition::is_a(&kind, &h.kind)).map(|h| (i, h))
+            }
+            DumpOP::DumpPROTECT(..) => Some((i, 0)),
+            _ => None,
+        });
+        let (i, h) = match found {
+            Some(found) => found,
+            None => return Err(e),
+        };
 
-                CodeOP::EQ => {
-                    try!(self.run_eq(&c));
-                }
+        self.abandon(i + 1);
+        self.dump.truncate(i + 1);
+        match self.dump.pop() {
+            Some(DumpOP::DumpTRY(mut handlers, mark, env, code)) => {
+                let handler = handlers.swap_remove(h);
+                self.unwind_to(mark);
+                self.env = env;
+                self.env.insert(handler.id, cond);
+                self.dump.push(DumpOP::DumpSEL(code));
+                self.code = handler.code;
+            }
+            Some(DumpOP::DumpPROTECT(cleanup, mark, env, _)) => {
+                self.unwind_to(mark);
+                self.stack.push(cond);
+                self.env = env;
+                self.code = cleanup;
+                self.code.push(CodeOPInfo {
+                                   info: self.code.last().map_or([0, 0], |c| c.info),
+                                   op: CodeOP::RERAISE,
+                               });
+            }
+            _ => unreachable!(),
+        }
 
-                CodeOP::ADD => {
-                    try!(self.run_add(&c));
-                }
+        return Ok(());
+    }
 
-                CodeOP::SUB => {
-                    try!(self.run_sub(&c));
-                }
+    // what a condition stops the machine with when nothing catches it; the VM's own
+    // messages already say where they come from
+    fn uncaught(&self, c: &CodeOPInfo, cond: &Rc<Lisp>) -> Box<Error> {
+        match **cond {
+            Lisp::Condition(ref cond) => {
+                let message = if condition::is_a(&cond.kind, "vm-error") {
+                    cond.message.clone()
+                } else {
+                    format!("{}: {}", cond.kind, cond.message)
+                };
+                return From::from(Diagnostic::error("vm", cond.info.or(Some(c.info)), message));
+            }
+            _ => unreachable!(),
+        }
+    }
 
-                CodeOP::CONS => {
-                    try!(self.run_cons(&c));
-                }
+    fn run_protect(&mut self, _: &CodeOPInfo, body: &Code, cleanup: &Code) -> VMResult {
+        self.dump
+            .push(DumpOP::DumpPROTECT(cleanup.clone(),
+                                      self.mark(),
+                                      self.clone_env(&self.env),
+                                      self.code.clone()));
+        self.set_code(body);
 
-                CodeOP::CAR => {
-                    try!(self.run_car(&c));
-                }
+        return Ok(());
+    }
 
-                CodeOP::CDR => {
-                    try!(self.run_cdr(&c));
-                }
-            }
+    // the body returned: the cleanup runs and the body's value stays on the stack
+    fn run_endprotect(&mut self, c: &CodeOPInfo) -> VMResult {
+        if let Some(DumpOP::DumpPROTECT(cleanup, _, env, code)) = self.dump.pop() {
+            self.dump.push(DumpOP::DumpSEL(code));
+            self.env = env;
+            self.code = cleanup;
+            self.code.push(CodeOPInfo {
+                               info: c.info,
+                               op: CodeOP::JOIN,
+                           });
+
+            return Ok(());
+        } else {
+            return self.error(c, "ENDPROTECT: expected DumpPROTECT");
         }
+    }
+
+    fn run_reraise(&mut self, c: &CodeOPInfo) -> VMResult {
+        let cond = self.stack.pop().unwrap();
+        let e = self.uncaught(c, &cond);
+        return self.throw(cond, e);
+    }
+
+    // (error kind message payload) builds the condition and raise throws it
+    fn run_mkcond(&mut self, c: &CodeOPInfo) -> VMResult {
+        let payload = self.stack.pop().unwrap();
+        let message = try!(self.pop_str(c, "ERROR"));
+        let kind = match *self.stack.pop().unwrap() {
+            Lisp::Symbol(ref s) => s.clone(),
+            _ => return self.error(c, "ERROR: expected symbol"),
+        };
+        self.stack.push(self.alloc(Lisp::Condition(Rc::new(Condition {
+                                                            kind,
+                                                            message,
+                                                            payload,
+                                                            info: Some(c.info),
+                                                        }))));
 
         return Ok(());
     }
 
+    // any other value is raised as an error carrying it
+    fn run_raise(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let cond = match *a {
+            Lisp::Condition(_) => a.clone(),
+            _ => {
+                self.alloc(Lisp::Condition(Rc::new(Condition {
+                                                    kind: "error".to_string(),
+                                                    message: format!("{}", a),
+                                                    payload: a.clone(),
+                                                    info: Some(c.info),
+                                                })))
+            }
+        };
+        let e = self.uncaught(c, &cond);
+        return self.throw(cond, e);
+    }
 
-    fn run_let(&mut self, _: &CodeOPInfo, id: &String) -> VMResult {
-        let expr = self.stack.pop().unwrap();
-        self.env.insert(id.clone(), expr);
+    fn pop_condition(&mut self, c: &CodeOPInfo, name: &str) -> Result<Rc<Condition>, Box<Error>> {
+        match *self.stack.pop().unwrap() {
+            Lisp::Condition(ref cond) => return Ok(cond.clone()),
+            _ => return self.error(c, &format!("{}: expected condition", name)),
+        }
+    }
+
+    fn run_condtype(&mut self, c: &CodeOPInfo) -> VMResult {
+        let cond = try!(self.pop_condition(c, "CONDITION-TYPE"));
+        self.stack.push(self.alloc(Lisp::Symbol(cond.kind.clone())));
         return Ok(());
     }
 
-    fn run_ld(&mut self, _: &CodeOPInfo, id: &String) -> VMResult {
-        let expr = self.env.get(id).unwrap();
-        self.stack.push(expr.clone());
+    fn run_condmsg(&mut self, c: &CodeOPInfo) -> VMResult {
+        let cond = try!(self.pop_condition(c, "CONDITION-MESSAGE"));
+        self.stack.push(self.alloc(Lisp::Str(cond.message.clone())));
         return Ok(());
     }
 
-    fn run_ldc(&mut self, _: &CodeOPInfo, lisp: &Rc<Lisp>) -> VMResult {
-        self.stack.push(lisp.clone());
+    fn run_condpayload(&mut self, c: &CodeOPInfo) -> VMResult {
+        let cond = try!(self.pop_condition(c, "CONDITION-PAYLOAD"));
+        self.stack.push(cond.payload.clone());
+        return Ok(());
+    }
+
+    // (cons line column), or nil for a condition from outside the program
+    fn run_condloc(&mut self, c: &CodeOPInfo) -> VMResult {
+        let cond = try!(self.pop_condition(c, "CONDITION-LOCATION"));
+        match cond.info {
+            Some(info) => {
+                self.stack.push(self.alloc(Lisp::Cons(self.int(info[0] as i32),
+                                                   self.int(info[1] as i32))))
+            }
+            None => self.stack.push(Lisp::nil()),
+        }
         return Ok(());
     }
 
-    fn run_ldf(&mut self, _: &CodeOPInfo, names: &Vec<String>, code: &Code) -> VMResult {
+    // a parameter is a closure of no arguments reading the cell it closes over
+    fn run_mkparam(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let mut env = Env::new();
+        env.insert(" parameter".to_string(), self.alloc(Lisp::Cell(Rc::new(Mutable::new(a)))));
+        let code = vec![CodeOPInfo {
+                            info: c.info,
+                            op: CodeOP::LD(" parameter".to_string()),
+                        },
+                        CodeOPInfo {
+                            info: c.info,
+                            op: CodeOP::DEREF,
+                        },
+                        CodeOPInfo {
+                            info: c.info,
+                            op: CodeOP::RET,
+                        }];
         self.stack
-            .push(Rc::new(Lisp::Closure(names.clone(), code.clone(), self.env.clone())));
+            .push(self.alloc(Lisp::Closure(vec![], code, env, Rc::new(ProcInfo::default()))));
+
         return Ok(());
     }
 
-    fn run_ap(&mut self, c: &CodeOPInfo) -> VMResult {
+    fn run_deref(&mut self, c: &CodeOPInfo) -> VMResult {
         match *self.stack.pop().unwrap() {
-            Lisp::Closure(ref names, ref code, ref env) => {
-                match *self.stack.pop().unwrap() {
-                    Lisp::List(ref vals) => {
-                        let mut env = env.clone();
-                        for i in 0..names.len() {
-                            env.insert(names[i].clone(), vals[i].clone());
+            Lisp::Cell(ref cell) => self.stack.push(cell.borrow().clone()),
+            _ => return self.error(c, "DEREF: expected cell"),
+        }
+        return Ok(());
+    }
+
+    // sets `n` (parameter value) pairs at once and leaves a list of (cons cell old-value)
+    fn run_parambind(&mut self, c: &CodeOPInfo, n: usize) -> VMResult {
+        let mut pairs = vec![];
+        for _ in 0..n {
+            let a = self.stack.pop().unwrap();
+            let p = self.stack.pop().unwrap();
+            let cell = match *p {
+                Lisp::Closure(_, _, ref env, _) => {
+                    match env.get(" parameter").map(|c| &**c) {
+                        Some(&Lisp::Cell(ref cell)) if cell.is_frozen() => {
+                            return self.error(c, "PARAMETERIZE: parameter is frozen")
                         }
+                        Some(&Lisp::Cell(ref cell)) => cell.clone(),
+                        _ => return self.error(c, "PARAMETERIZE: expected parameter"),
+                    }
+                }
+                _ => return self.error(c, "PARAMETERIZE: expected parameter"),
+            };
+            pairs.push((cell, a));
+        }
 
-                        self.dump
-                            .push(DumpOP::DumpAP(self.stack.clone(),
-                                                 self.env.clone(),
-                                                 self.code.clone()));
+        let mut saved = Args::new();
+        for (cell, a) in pairs.into_iter().rev() {
+            let old = mem::replace(&mut *cell.borrow_mut(), a);
+            saved.push(self.alloc(Lisp::Cons(self.alloc(Lisp::Cell(cell)), old)));
+        }
+        self.stack.push(self.alloc(Lisp::List(saved)));
 
-                        self.stack = vec![];
-                        self.env = env;
-                        self.code = code.clone();
+        return Ok(());
+    }
 
-                        return Ok(());
-                    }
-                    _ => return self.error(c, "AP: expected List"),
+    fn run_paramrestore(&mut self, c: &CodeOPInfo) -> VMResult {
+        let saved = self.stack.pop().unwrap();
+        let saved = match *saved {
+            Lisp::List(ref saved) => saved,
+            _ => return self.error(c, "PARAMRESTORE: expected List"),
+        };
+        // frozen or not since, a cell gets back the value parameterize found in it;
+        // in reverse, so a parameter bound twice ends up with its value from before both
+        for s in saved.iter().rev() {
+            if let Lisp::Cons(ref cell, ref old) = **s {
+                if let Lisp::Cell(ref cell) = **cell {
+                    *cell.borrow_mut() = old.clone();
                 }
             }
-
-            _ => return self.error(c, "AP: expected Closure"),
         }
+        self.stack.push(Lisp::nil());
+
+        return Ok(());
     }
 
-    fn run_rap(&mut self, c: &CodeOPInfo) -> VMResult {
-        match *self.stack.pop().unwrap() {
-            Lisp::Closure(ref names, ref code, ref env) => {
-                match *self.stack.pop().unwrap() {
-                    Lisp::List(ref vals) => {
-                        let mut env = env.clone();
-                        for i in 0..names.len() {
-                            env.insert(names[i].clone(), vals[i].clone());
-                        }
+    // prints the call with its parameters and the doc string under it, and leaves the
+    // doc string, or nil when there is none
+    fn run_help(&mut self, c: &CodeOPInfo, name: &str) -> VMResult {
+        let f = self.stack.pop().unwrap();
+        let (names, proc_info) = match *f {
+            Lisp::Closure(ref names, _, _, ref proc_info) => (names, proc_info),
+            _ => return self.error(c, "HELP: expected Closure"),
+        };
 
-                        self.dump
-                            .push(DumpOP::DumpAP(self.stack.clone(),
-                                                 self.env.clone(),
-                                                 self.code.clone()));
+        let mut call = format!("({}", name);
+        for n in names {
+            call.push(' ');
+            call.push_str(n);
+        }
+        call.push(')');
+        self.write_line(&call);
 
-                        self.stack = vec![];
-                        self.env.extend(env);
-                        self.code = code.clone();
+        match proc_info.doc {
+            Some(ref doc) => {
+                for line in doc.lines() {
+                    self.write_line(&format!("  {}", line.trim()));
+                }
+                self.stack.push(self.alloc(Lisp::Str(doc.clone())));
+            }
+            None => self.stack.push(Lisp::nil()),
+        }
 
-                        return Ok(());
-                    }
+        return Ok(());
+    }
 
-                    _ => return self.error(c, "RAP: expected List"),
+    fn run_procp(&mut self, _: &CodeOPInfo) -> VMResult {
+        let f = self.stack.pop().unwrap();
+        let b = matches!(*f, Lisp::Closure(..));
+        self.stack.push(Lisp::bool(b));
+        return Ok(());
+    }
+
+    fn run_procarity(&mut self, c: &CodeOPInfo) -> VMResult {
+        let f = self.stack.pop().unwrap();
+        match *f {
+            // the positional parameters; keyword ones may be left out
+            Lisp::Closure(ref names, _, _, _) => {
+                self.stack.push(self.int(names.iter().filter(|n| !n.starts_with("#:")).count() as i32))
+            }
+            _ => return self.error(c, "PROCARITY: expected Closure"),
+        }
+        return Ok(());
+    }
+
+    // a closure taking the rest of the closure's positional arguments, those in the list
+    // bound in its environment; its ProcInfo is a copy, so the JIT keeps them apart
+    fn run_curry(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let f = self.stack.pop().unwrap();
+        let given = try!(self.list_to_vec(c, "CURRY", &a));
+        match *f {
+            Lisp::Closure(ref names, ref code, ref env, ref proc_info) => {
+                let positional = names.iter().take_while(|n| !n.starts_with("#:")).count();
+                if given.len() > positional {
+                    return self.error(c,
+                                      &format!("CURRY: the closure takes {} arguments, not {}", positional, given.len()));
+                }
+                let mut env = self.clone_env(env);
+                for (name, a) in names.iter().zip(given.iter()) {
+                    env.insert(name.trim_start_matches('[').trim_end_matches(']').to_string(), a.clone());
                 }
+                let rest = names[given.len()..].to_vec();
+                let curried = Lisp::Closure(rest, code.clone(), env, Rc::new((**proc_info).clone()));
+                self.stack.push(self.alloc(curried));
             }
+            _ => return self.error(c, "CURRY: expected Closure"),
+        }
+        return Ok(());
+    }
 
-            _ => return self.error(c, "RAP: expected Closure"),
+    // the value back when it fits the pattern, which the compiler placed this at
+    fn run_check_shape(&mut self, c: &CodeOPInfo) -> VMResult {
+        let pattern = self.stack.pop().unwrap();
+        let fitted = fits(&pattern, self.stack.last().unwrap());
+        if !fitted {
+            let a = self.stack.pop().unwrap();
+            return self.error(c, &format!("SHAPE: expected {}, got {}", pattern_text(&pattern), a));
         }
+        return Ok(());
     }
 
-    fn run_ret(&mut self, c: &CodeOPInfo) -> VMResult {
+    // whether the value is a cons whose car is eq to the tag, as a record is
+    fn run_taggedp(&mut self, _: &CodeOPInfo) -> VMResult {
+        let tag = self.stack.pop().unwrap();
         let a = self.stack.pop().unwrap();
-        match self.dump.pop().unwrap() {
-            DumpOP::DumpAP(stack, env, code) => {
-                self.stack = stack;
-                self.env = env;
-                self.code = code.clone();
+        let tagged = match *a {
+            Lisp::Cons(ref car, _) => **car == *tag,
+            _ => false,
+        };
+        self.stack.push(Lisp::bool(tagged));
+        return Ok(());
+    }
 
-                self.stack.push(a.clone());
+    // marks the closure, and every other made from the same lambda, to print its calls
+    // and what they return under the name
+    fn run_trace(&mut self, c: &CodeOPInfo) -> VMResult {
+        let name = self.stack.pop().unwrap();
+        let f = self.stack.pop().unwrap();
+        let proc_info = match *f {
+            Lisp::Closure(_, _, _, ref proc_info) => proc_info.clone(),
+            _ => return self.error(c, "TRACE: expected Closure"),
+        };
+        self.traced.retain(|t| !Rc::ptr_eq(&t.0, &proc_info));
+        self.traced.push((proc_info, format!("{}", name)));
+        self.stack.push(name);
+        return Ok(());
+    }
 
-                return Ok(());
+    // gives the name the closure was traced under, or nil if it wasn't
+    fn run_untrace(&mut self, c: &CodeOPInfo) -> VMResult {
+        let f = self.stack.pop().unwrap();
+        let proc_info = match *f {
+            Lisp::Closure(_, _, _, ref proc_info) => proc_info.clone(),
+            _ => return self.error(c, "UNTRACE: expected Closure"),
+        };
+        let name = match self.traced.iter().position(|t| Rc::ptr_eq(&t.0, &proc_info)) {
+            Some(i) => {
+                let (_, name) = self.traced.remove(i);
+                self.alloc(Lisp::Symbol(name))
             }
+            None => Lisp::nil(),
+        };
+        self.stack.push(name);
+        return Ok(());
+    }
 
-            _ => return self.error(c, "RET: expected DumpAP"),
-        }
+    // pauses the machine where it is when a debugger drives it; nil either way
+    fn run_break(&mut self, _: &CodeOPInfo) -> VMResult {
+        self.broke = self.debugger;
+        self.stack.push(Lisp::nil());
+        return Ok(());
     }
 
-    fn run_args(&mut self, _: &CodeOPInfo, n: usize) -> VMResult {
-        let mut ls = vec![];
-        for _ in 0..n {
-            ls.insert(0, self.stack.pop().unwrap());
+    fn run_break_if(&mut self, c: &CodeOPInfo) -> VMResult {
+        match *self.stack.pop().unwrap() {
+            Lisp::True => self.broke = self.debugger,
+            Lisp::False => {}
+            _ => return self.error(c, "BREAK-IF: expected Bool"),
         }
-
-        self.stack.push(Rc::new(Lisp::List(ls)));
+        self.stack.push(Lisp::nil());
         return Ok(());
     }
 
-    fn run_puts(&mut self, _: &CodeOPInfo) -> VMResult {
-        println!("{}", *self.stack.last().unwrap());
+    // a promise of what the thunk gives, which force calls it for at most once
+    fn run_promise(&mut self, c: &CodeOPInfo) -> VMResult {
+        let f = self.stack.pop().unwrap();
+        try!(self.expect_closure(c, "PROMISE", &f, 0));
+        let state = self.alloc(Lisp::Cons(Lisp::bool(false), f));
+        self.push_promise(state);
         return Ok(());
     }
 
-    fn run_sel(&mut self, c: &CodeOPInfo, t: &Code, f: &Code) -> VMResult {
-        let b = self.stack.pop().unwrap();
-        let code = match *b {
-            Lisp::True => t,
-            Lisp::False => f,
-            _ => return self.error(c, "SEL: expected bool"),
-        };
-
-        self.dump.push(DumpOP::DumpSEL(self.code.clone()));
+    // a promise already forced to the value
+    fn run_mkpromise(&mut self, _: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let state = self.alloc(Lisp::Cons(Lisp::bool(true), a));
+        self.push_promise(state);
+        return Ok(());
+    }
 
-        self.code = code.clone();
+    fn push_promise(&mut self, state: Rc<Lisp>) {
+        let cell = self.alloc(Lisp::Cell(Rc::new(Mutable::new(state))));
+        let tag = self.alloc(Lisp::Symbol(PROMISE.to_string()));
+        let promise = self.alloc(Lisp::Cons(tag, cell));
+        self.stack.push(promise);
+    }
 
+    fn run_promisep(&mut self, _: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        self.stack.push(Lisp::bool(promise(&a).is_some()));
         return Ok(());
     }
 
-    fn run_join(&mut self, c: &CodeOPInfo) -> VMResult {
-        if let DumpOP::DumpSEL(ref code) = self.dump.pop().unwrap() {
-            self.code = code.clone();
+    // the promise's value, calling its thunk the first time; anything else is its own
+    // value
+    fn run_force(&mut self, c: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        let cell = match promise(&a) {
+            Some(cell) => cell,
+            None => {
+                self.stack.push(a);
+                return Ok(());
+            }
+        };
+        let state = cell.borrow().clone();
+        match *state {
+            Lisp::Cons(ref done, ref value) if **done == Lisp::True => self.stack.push(value.clone()),
+            Lisp::Cons(_, ref thunk) => {
+                let code = mem::take(&mut self.code);
+                self.callback(c, Next::Call(Callback::Force(cell.clone()), thunk.clone(), vec![]), code);
+            }
+            _ => return self.error(c, "FORCE: expected promise"),
+        }
+        return Ok(());
+    }
 
-            return Ok(());
-        } else {
-            return self.error(c, "JOIN: expected DumpSEL");
+    // nil unless the compiler was retaining source
+    fn run_procsource(&mut self, c: &CodeOPInfo) -> VMResult {
+        let f = self.stack.pop().unwrap();
+        match *f {
+            Lisp::Closure(_, _, _, ref proc_info) => {
+                let source = proc_info.source.clone().unwrap_or_else(Lisp::nil);
+                self.stack.push(source);
+            }
+            _ => return self.error(c, "PROCSOURCE: expected Closure"),
         }
+        return Ok(());
     }
 
-    fn run_eq(&mut self, _: &CodeOPInfo) -> VMResult {
+    // saves the value, closures and all, with the pool they load constants from; the
+    // value is left as checkpoint's result
+    fn run_checkpoint(&mut self, c: &CodeOPInfo) -> VMResult {
         let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        self.stack
-            .push(Rc::new(if a == b { Lisp::True } else { Lisp::False }));
+        let path = try!(self.pop_str(c, "CHECKPOINT"));
+        try!(self.require(c, "CHECKPOINT", "filesystem", self.capabilities.filesystem));
+        let bytes = match serialize::write_value(&a, &self.consts, &self.restored, &self.primitives) {
+            Ok(bytes) => bytes,
+            Err(e) => return self.error(c, &format!("CHECKPOINT: {}", e)),
+        };
+        if let Err(e) = fs::write(&path, bytes) {
+            return self.error(c, &format!("CHECKPOINT: {}: {}", path, e));
+        }
+        self.stack.push(a);
+        return Ok(());
+    }
 
+    // a value saved by checkpoint; its pool goes on the end of the restored one, where the
+    // next CONSTS, run by the next entry of an incremental session, doesn't replace it
+    fn run_restore(&mut self, c: &CodeOPInfo) -> VMResult {
+        let path = try!(self.pop_str(c, "RESTORE"));
+        try!(self.require(c, "RESTORE", "filesystem", self.capabilities.filesystem));
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) => return self.error(c, &format!("RESTORE: {}: {}", path, e)),
+        };
+        let (pool, a) = match serialize::read_value(&bytes, RESTORED + self.restored.len(), &self.primitives) {
+            Ok(restored) => restored,
+            Err(e) => return self.error(c, &format!("RESTORE: {}", e)),
+        };
+        if !pool.is_empty() {
+            let mut restored = (*self.restored).clone();
+            restored.extend(pool);
+            self.restored = Rc::new(restored);
+        }
+        self.stack.push(a);
         return Ok(());
     }
 
-    fn run_add(&mut self, c: &CodeOPInfo) -> VMResult {
+    fn run_copy(&mut self, c: &CodeOPInfo) -> VMResult {
         let a = self.stack.pop().unwrap();
-        if let Lisp::Int(n) = *a {
-            let b = self.stack.pop().unwrap();
-            if let Lisp::Int(m) = *b {
-                self.stack.push(Rc::new(Lisp::Int(m + n)));
+        let a = try!(self.copy(c, &a));
+        self.stack.push(a);
+        return Ok(());
+    }
 
-                return Ok(());
-            } else {
-                return self.error(c, "ADD: expected int");
+    // a copy shares nothing that can change with `a`, so it starts out unfrozen; `seen`
+    // maps the channels and cells copied so far to their copies, keeping sharing and
+    // cycles through them as they were. The walk keeps its own stack, so neither a long
+    // list nor a deep nest of them recurses: Visit pushes the copies of the leaves to
+    // `done` and Build makes a value of the copies of its parts there
+    fn copy(&self, c: &CodeOPInfo, a: &Rc<Lisp>) -> Result<Rc<Lisp>, Box<Error>> {
+        enum Walk {
+            Visit(Rc<Lisp>),
+            Build(Rc<Lisp>),
+        }
+        let mut seen: HashMap<*const Lisp, Rc<Lisp>> = HashMap::new();
+        let mut todo = vec![Walk::Visit(a.clone())];
+        let mut done: Vec<Rc<Lisp>> = vec![];
+        while let Some(walk) = todo.pop() {
+            let a = match walk {
+                Walk::Visit(a) => a,
+                Walk::Build(a) => {
+                    let copied = match *a {
+                        Lisp::List(ref ls) => {
+                            let at = done.len() - ls.len();
+                            self.alloc(Lisp::List(done.drain(at..).collect()))
+                        }
+                        Lisp::Cons(..) => {
+                            let cdr = done.pop().unwrap();
+                            let car = done.pop().unwrap();
+                            self.alloc(Lisp::Cons(car, cdr))
+                        }
+                        Lisp::Closure(ref names, ref code, ref env, ref proc_info) => {
+                            let at = done.len() - env.len();
+                            let copied = env.keys().cloned().zip(done.drain(at..)).collect();
+                            self.alloc(Lisp::Closure(names.clone(), code.clone(), copied, proc_info.clone()))
+                        }
+                        Lisp::Condition(ref cond) => {
+                            let payload = done.pop().unwrap();
+                            self.alloc(Lisp::Condition(Rc::new(Condition {
+                                                                   kind: cond.kind.clone(),
+                                                                   message: cond.message.clone(),
+                                                                   payload,
+                                                                   info: cond.info,
+                                                               })))
+                        }
+                        Lisp::Chan(ref q) => {
+                            let copied = seen[&Rc::as_ptr(&a)].clone();
+                            if let Lisp::Chan(ref copied) = *copied {
+                                let at = done.len() - q.borrow().len();
+                                copied.borrow_mut().extend(done.drain(at..));
+                            }
+                            copied
+                        }
+                        Lisp::Cell(_) => {
+                            let copied = seen[&Rc::as_ptr(&a)].clone();
+                            if let Lisp::Cell(ref copied) = *copied {
+                                *copied.borrow_mut() = done.pop().unwrap();
+                            }
+                            copied
+                        }
+                        _ => unreachable!(),
+                    };
+                    done.push(copied);
+                    continue;
+                }
+            };
+
+            if let Some(copied) = seen.get(&Rc::as_ptr(&a)) {
+                done.push(copied.clone());
+                continue;
+            }
+            match *a {
+                Lisp::Nil | Lisp::False | Lisp::True | Lisp::Int(_) | Lisp::Thread(_) | Lisp::Weak(_) => {
+                    done.push(a.clone())
+                }
+                Lisp::Str(ref s) => done.push(self.alloc(Lisp::Str(s.clone()))),
+                Lisp::Symbol(ref s) => done.push(self.alloc(Lisp::Symbol(s.clone()))),
+                Lisp::List(ref ls) => {
+                    todo.push(Walk::Build(a.clone()));
+                    todo.extend(ls.iter().rev().map(|x| Walk::Visit(x.clone())));
+                }
+                Lisp::Cons(ref car, ref cdr) => {
+                    todo.push(Walk::Build(a.clone()));
+                    todo.push(Walk::Visit(cdr.clone()));
+                    todo.push(Walk::Visit(car.clone()));
+                }
+                Lisp::Closure(_, _, ref env, _) => {
+                    todo.push(Walk::Build(a.clone()));
+                    todo.extend(env.values().rev().map(|x| Walk::Visit(x.clone())));
+                }
+                Lisp::Condition(ref cond) => {
+                    todo.push(Walk::Build(a.clone()));
+                    todo.push(Walk::Visit(cond.payload.clone()));
+                }
+                // in `seen` before what they hold is copied, for the cycles through them
+                Lisp::Chan(ref q) => {
+                    seen.insert(Rc::as_ptr(&a), self.alloc(Lisp::Chan(Rc::new(Mutable::new(VecDeque::new())))));
+                    todo.push(Walk::Build(a.clone()));
+                    todo.extend(q.borrow().iter().rev().map(|x| Walk::Visit(x.clone())));
+                }
+                Lisp::Cell(ref cell) => {
+                    seen.insert(Rc::as_ptr(&a), self.alloc(Lisp::Cell(Rc::new(Mutable::new(Lisp::nil())))));
+                    todo.push(Walk::Build(a.clone()));
+                    todo.push(Walk::Visit(cell.borrow().clone()));
+                }
+                Lisp::Port(_) => return self.error(c, "COPY: cannot copy port"),
             }
-        } else {
-            return self.error(c, "ADD: expected int");
         }
+        return Ok(done.pop().unwrap());
     }
 
-    fn run_sub(&mut self, c: &CodeOPInfo) -> VMResult {
+    // marks every channel and cell reachable from the value frozen and gives the value
+    // back; ports are left as they are
+    fn run_freeze(&mut self, _: &CodeOPInfo) -> VMResult {
         let a = self.stack.pop().unwrap();
-        if let Lisp::Int(n) = *a {
-            let b = self.stack.pop().unwrap();
-            if let Lisp::Int(o) = *b {
-                self.stack.push(Rc::new(Lisp::Int(o - n)));
+        freeze(&a);
+        self.stack.push(a);
+        return Ok(());
+    }
 
-                return Ok(());
-            } else {
-                return self.error(c, "SUB: expected int");
-            }
-        } else {
-            return self.error(c, "SUB: expected int");
+    fn run_mkweak(&mut self, _: &CodeOPInfo) -> VMResult {
+        let a = self.stack.pop().unwrap();
+        self.stack.push(self.alloc(Lisp::Weak(Rc::downgrade(&a))));
+        return Ok(());
+    }
+
+    // nil once nothing else holds the value; nil, the booleans and small ints are
+    // shared by the machine, so a reference to one of them never goes
+    fn run_weakderef(&mut self, c: &CodeOPInfo) -> VMResult {
+        match *self.stack.pop().unwrap() {
+            Lisp::Weak(ref w) => self.stack.push(w.upgrade().unwrap_or_else(Lisp::nil)),
+            _ => return self.error(c, "WEAK-DEREF: expected weak reference"),
         }
+        return Ok(());
+    }
+
+    // the stack as it is under the result, bottom first
+    fn run_secdstack(&mut self, c: &CodeOPInfo) -> VMResult {
+        try!(self.require(c, "SECDSTACK", "debug", self.capabilities.debug));
+        let stack = list(self.stack[self.base..].to_vec());
+        self.stack.push(stack);
+        return Ok(());
+    }
+
+    // (name . value) pairs sorted by name; the compiler's hidden bindings are left out
+    fn run_secdenv(&mut self, c: &CodeOPInfo) -> VMResult {
+        try!(self.require(c, "SECDENV", "debug", self.capabilities.debug));
+        let pairs = self.env
+            .keys()
+            .filter(|k| !k.contains(' '))
+            .map(|k| self.alloc(Lisp::Cons(self.alloc(Lisp::Symbol(k.clone())), self.env[k].clone())))
+            .collect();
+        self.stack.push(list(pairs));
+        return Ok(());
+    }
+
+    // (line column depth), where depth counts the calls the machine will return from
+    fn run_secdwhere(&mut self, c: &CodeOPInfo) -> VMResult {
+        try!(self.require(c, "SECDWHERE", "debug", self.capabilities.debug));
+        let depth = self.dump.iter().filter(|d| matches!(**d, DumpOP::DumpAP(..))).count();
+        let fields = [c.info[0] as i32, c.info[1] as i32, depth as i32];
+        self.stack.push(list(fields.iter().map(|&n| self.int(n)).collect()));
+        return Ok(());
     }
 
     fn run_cons(&mut self, _: &CodeOPInfo) -> VMResult {
         let a = self.stack.pop().unwrap();
         let b = self.stack.pop().unwrap();
-        self.stack.push(Rc::new(Lisp::Cons(b, a)));
+        self.stack.push(self.alloc(Lisp::Cons(b, a)));
 
         return Ok(());
     }
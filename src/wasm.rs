@@ -0,0 +1,850 @@
+use data::{self, Lisp, Code, CodeOP, CodeOPInfo};
+use diagnostic::Diagnostic;
+use vm;
+
+use wasm_encoder::{BlockType, CodeSection, ConstExpr, DataSection, ElementSection, Elements,
+                   EntityType, ExportKind, ExportSection, Function, FunctionSection, GlobalSection,
+                   GlobalType, ImportSection, Instruction, MemArg, MemorySection, MemoryType,
+                   Module, RefType, TableSection, TableType, TypeSection, ValType};
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::Rc;
+
+// A WebAssembly module that runs `code` the way the machine does, for the same pure
+// core the Rust backend translates (see transpile). Values live in the module's memory
+// as 16 byte cells of four little endian i32s, a tag and up to three fields:
+//
+//   0 nil, 1 true, 2 false, 3 int (n), 4 string and 5 symbol (address, byte length),
+//   6 cons (car, cdr), 7 closure (table index, environment, parameter names),
+//   8 argument list (value, rest), 9 environment entry (name, value, rest)
+//
+// An environment is a list of entries with at most one per name, names being
+// addresses of interned strings; binding a name copies the entries in front of its old
+// one, if any, and shares the rest. Every block of code becomes a function of its
+// environment; TAP and TRAP are tail calls
+// (the tail call proposal). Memory is taken from a bump allocator and never freed.
+//
+// The module imports `secd.puts(address, length)`, given the UTF-8 text of a value,
+// and `secd.error(address, length, line, column)` with the message of the error the
+// machine would have raised; the module traps after calling it. It exports `memory`,
+// `main`, which runs the program and returns the address of its value, and `show`,
+// which gives the address of a string cell with a value's text.
+
+const CELL: u32 = 16;
+
+const NIL: u32 = 0;
+const TRUE: u32 = 1;
+const FALSE: u32 = 2;
+const INT: u32 = 3;
+const STR: u32 = 4;
+const SYMBOL: u32 = 5;
+const CONS: u32 = 6;
+const CLOSURE: u32 = 7;
+const ARG: u32 = 8;
+const ENTRY: u32 = 9;
+
+// the heap pointer, the only global
+const HP: u32 = 0;
+
+// locals of every compiled block: its environment and a scratch value
+const ENV: u32 = 0;
+const TMP: u32 = 1;
+
+// functions, the imports first
+const F_PUTS: u32 = 0;
+const F_ERROR: u32 = 1;
+const F_FAIL: u32 = 2;
+const F_RESERVE: u32 = 3;
+const F_ALLOC: u32 = 4;
+const F_CELL: u32 = 5;
+const F_INT: u32 = 6;
+const F_INT_OF: u32 = 7;
+const F_COND: u32 = 8;
+const F_BYTES_EQ: u32 = 9;
+const F_EQUAL: u32 = 10;
+const F_EQ: u32 = 11;
+const F_CONS: u32 = 12;
+const F_CAR: u32 = 13;
+const F_CDR: u32 = 14;
+const F_ARG: u32 = 15;
+const F_LD: u32 = 16;
+const F_REMOVE: u32 = 17;
+const F_PUT: u32 = 18;
+const F_BIND: u32 = 19;
+const F_APPEND: u32 = 20;
+const F_APPLY: u32 = 21;
+const F_BYTE: u32 = 22;
+const F_WRITES: u32 = 23;
+const F_ITOA: u32 = 24;
+const F_WRITE: u32 = 25;
+const F_SHOW: u32 = 26;
+const F_PRINT: u32 = 27;
+const F_NUM2STR: u32 = 28;
+const F_ARITH: u32 = 29;
+
+// integer operations by primitive name, the name the machine reports them by and
+// whether they take one argument; their functions follow F_ARITH in this order
+const ARITH: &[(&str, &str, bool)] = &[("+", "ADD", false),
+                                       ("-", "SUB", false),
+                                       ("min", "MIN", false),
+                                       ("max", "MAX", false),
+                                       ("quotient", "QUOT", false),
+                                       ("remainder", "REM", false),
+                                       ("bit-and", "BAND", false),
+                                       ("bit-or", "BOR", false),
+                                       ("bit-xor", "BXOR", false),
+                                       ("shl", "SHL", false),
+                                       ("shr", "SHR", false),
+                                       ("abs", "ABS", true),
+                                       ("bit-not", "BNOT", true)];
+
+type I = Instruction<'static>;
+
+fn load(offset: u64) -> I {
+    return Instruction::I32Load(MemArg {
+                                    offset,
+                                    align: 2,
+                                    memory_index: 0,
+                                });
+}
+
+fn store(offset: u64) -> I {
+    return Instruction::I32Store(MemArg {
+                                     offset,
+                                     align: 2,
+                                     memory_index: 0,
+                                 });
+}
+
+fn byte() -> MemArg {
+    return MemArg {
+               offset: 0,
+               align: 0,
+               memory_index: 0,
+           };
+}
+
+fn get(local: u32) -> I {
+    return Instruction::LocalGet(local);
+}
+
+fn int(n: i32) -> I {
+    return Instruction::I32Const(n);
+}
+
+fn addr(a: u32) -> I {
+    return Instruction::I32Const(a as i32);
+}
+
+pub fn module(code: &Code) -> Result<Vec<u8>, Box<Error>> {
+    let mut b = Builder {
+        types: vec![],
+        funcs: vec![],
+        data: vec![0; CELL as usize],
+        strings: HashMap::new(),
+        names: HashMap::new(),
+        table: vec![],
+        constants: [0; 3],
+        pool: data::pool(code).to_vec(),
+        pooled: HashMap::new(),
+    };
+    b.constants = [b.cell(NIL, 0, 0, 0), b.cell(TRUE, 0, 0, 0), b.cell(FALSE, 0, 0, 0)];
+    let puts = b.ty(&[ValType::I32, ValType::I32], &[]);
+    let error = b.ty(&[ValType::I32; 4], &[]);
+    b.runtime();
+
+    let mut body = vec![];
+    try!(b.block(code, &mut body));
+    let main = b.define(&[], &[ValType::I32], &[ValType::I32, ValType::I32], &body);
+    return Ok(b.finish(puts, error, main));
+}
+
+fn error<T>(c: &CodeOPInfo, msg: String) -> Result<T, Box<Error>> {
+    return Err(From::from(Diagnostic::error("build", Some(c.info), msg)));
+}
+
+struct Builder {
+    types: Vec<(Vec<ValType>, Vec<ValType>)>,
+    funcs: Vec<(u32, Function)>,
+    data: Vec<u8>,
+    strings: HashMap<String, u32>,
+    names: HashMap<Vec<String>, u32>,
+    // the functions closures can call, by table index
+    table: Vec<u32>,
+    // nil, true and false
+    constants: [u32; 3],
+    // the program's constants, and the cells of those already loaded
+    pool: Vec<Rc<Lisp>>,
+    pooled: HashMap<usize, u32>,
+}
+
+impl Builder {
+    fn ty(&mut self, params: &[ValType], results: &[ValType]) -> u32 {
+        let ty = (params.to_vec(), results.to_vec());
+        match self.types.iter().position(|t| *t == ty) {
+            Some(i) => return i as u32,
+            None => {
+                self.types.push(ty);
+                return self.types.len() as u32 - 1;
+            }
+        }
+    }
+
+    // adds a function and gives its index
+    fn define(&mut self, params: &[ValType], results: &[ValType], locals: &[ValType], body: &[I]) -> u32 {
+        let ty = self.ty(params, results);
+        let mut f = Function::new(locals.iter().map(|&t| (1, t)));
+        for i in body {
+            f.instruction(i);
+        }
+        f.instruction(&Instruction::End);
+        self.funcs.push((ty, f));
+        return F_FAIL + self.funcs.len() as u32 - 1;
+    }
+
+    fn runtime_fn(&mut self, index: u32, params: usize, result: bool, locals: &[ValType], body: &[I]) {
+        let params = vec![ValType::I32; params];
+        let results = if result { vec![ValType::I32] } else { vec![] };
+        let f = self.define(&params, &results, locals, body);
+        assert_eq!(f, index);
+    }
+
+    fn align(&mut self) -> u32 {
+        let at = self.data.len().next_multiple_of(CELL as usize);
+        self.data.resize(at, 0);
+        return at as u32;
+    }
+
+    // a static cell in the data segment
+    fn cell(&mut self, tag: u32, a: u32, b: u32, c: u32) -> u32 {
+        let at = self.align();
+        for n in &[tag, a, b, c] {
+            self.data.extend_from_slice(&n.to_le_bytes());
+        }
+        return at;
+    }
+
+    fn text(&mut self, tag: u32, s: &str) -> u32 {
+        let at = self.data.len() as u32;
+        self.data.extend_from_slice(s.as_bytes());
+        return self.cell(tag, at, s.len() as u32, 0);
+    }
+
+    // interned, so the address of a name is the name
+    fn string(&mut self, s: &str) -> u32 {
+        if let Some(&at) = self.strings.get(s) {
+            return at;
+        }
+        let at = self.text(STR, s);
+        self.strings.insert(s.to_string(), at);
+        return at;
+    }
+
+    fn message(&mut self, s: &str) -> I {
+        return addr(self.string(s));
+    }
+
+    // a closure's parameters, their count and then their names
+    fn names(&mut self, names: &[String]) -> u32 {
+        if let Some(&at) = self.names.get(names) {
+            return at;
+        }
+        let names_at: Vec<u32> = names.iter().map(|n| self.string(n)).collect();
+        let at = self.align();
+        self.data.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        for n in names_at {
+            self.data.extend_from_slice(&n.to_le_bytes());
+        }
+        self.names.insert(names.to_vec(), at);
+        return at;
+    }
+
+    fn fail(&mut self, msg: &str, line: u32, column: u32) -> Vec<I> {
+        return vec![self.message(msg), get(line), get(column), Instruction::Call(F_FAIL)];
+    }
+
+    // the functions compiled code calls; their indices are the F_ constants
+    fn runtime(&mut self) {
+        use wasm_encoder::Instruction::*;
+        let i32 = ValType::I32;
+        let empty = BlockType::Empty;
+        let lambda = self.ty(&[i32], &[i32]);
+
+        // msg, line, column
+        self.runtime_fn(F_FAIL, 3, false, &[], &[get(0), load(4), get(0), load(8), get(1), get(2), Call(F_ERROR), Unreachable]);
+
+        // end: grows memory to hold everything below end
+        let oom = self.message("out of memory");
+        self.runtime_fn(F_RESERVE,
+                        1,
+                        false,
+                        &[],
+                        &[Block(empty),
+                          get(0), MemorySize(0), int(16), I32Shl, I32LeU, BrIf(0),
+                          get(0), MemorySize(0), int(16), I32Shl, I32Sub, int(65535), I32Add, int(16), I32ShrU,
+                          MemoryGrow(0), int(-1), I32Ne, BrIf(0),
+                          oom, int(0), int(0), Call(F_FAIL),
+                          End]);
+
+        // size
+        self.runtime_fn(F_ALLOC,
+                        1,
+                        true,
+                        &[i32],
+                        &[GlobalGet(HP), LocalSet(1),
+                          GlobalGet(HP), get(0), I32Add, int(15), I32Add, int(-16), I32And, GlobalSet(HP),
+                          GlobalGet(HP), Call(F_RESERVE),
+                          get(1)]);
+
+        // tag, a, b, c
+        self.runtime_fn(F_CELL,
+                        4,
+                        true,
+                        &[i32],
+                        &[addr(CELL), Call(F_ALLOC), LocalSet(4),
+                          get(4), get(0), store(0),
+                          get(4), get(1), store(4),
+                          get(4), get(2), store(8),
+                          get(4), get(3), store(12),
+                          get(4)]);
+
+        // n
+        self.runtime_fn(F_INT, 1, true, &[], &[addr(INT), get(0), int(0), int(0), Call(F_CELL)]);
+
+        // value, msg, line, column
+        self.runtime_fn(F_INT_OF,
+                        4,
+                        true,
+                        &[],
+                        &[get(0), load(0), addr(INT), I32Ne, If(empty),
+                          get(1), get(2), get(3), Call(F_FAIL),
+                          End,
+                          get(0), load(4)]);
+
+        // value, msg, line, column; 1 for true and 0 for false
+        self.runtime_fn(F_COND,
+                        4,
+                        true,
+                        &[],
+                        &[get(0), load(0), addr(TRUE), I32Eq, If(empty), int(1), Return, End,
+                          get(0), load(0), addr(FALSE), I32Eq, If(empty), int(0), Return, End,
+                          get(1), get(2), get(3), Call(F_FAIL), Unreachable]);
+
+        // p, q, length
+        self.runtime_fn(F_BYTES_EQ,
+                        3,
+                        true,
+                        &[],
+                        &[Block(empty), Loop(empty),
+                          get(2), I32Eqz, BrIf(1),
+                          get(0), I32Load8U(byte()), get(1), I32Load8U(byte()), I32Ne, If(empty), int(0), Return, End,
+                          get(0), int(1), I32Add, LocalSet(0),
+                          get(1), int(1), I32Add, LocalSet(1),
+                          get(2), int(1), I32Sub, LocalSet(2),
+                          Br(0),
+                          End, End,
+                          int(1)]);
+
+        // a, b; the machine's ==, with closures equal when made by the same LDF in
+        // the same environment
+        self.runtime_fn(F_EQUAL,
+                        2,
+                        true,
+                        &[i32],
+                        &[get(0), get(1), I32Eq, If(empty), int(1), Return, End,
+                          get(0), load(0), get(1), load(0), I32Ne, If(empty), int(0), Return, End,
+                          get(0), load(0), LocalSet(2),
+                          get(2), addr(INT), I32LtU, If(empty), int(1), Return, End,
+                          get(2), addr(INT), I32Eq, If(empty), get(0), load(4), get(1), load(4), I32Eq, Return, End,
+                          get(2), addr(STR), I32Eq, get(2), addr(SYMBOL), I32Eq, I32Or, If(empty),
+                          get(0), load(8), get(1), load(8), I32Ne, If(empty), int(0), Return, End,
+                          get(0), load(4), get(1), load(4), get(0), load(8), Call(F_BYTES_EQ), Return,
+                          End,
+                          get(2), addr(CONS), I32Eq, If(empty),
+                          get(0), load(4), get(1), load(4), Call(F_EQUAL), If(BlockType::Result(i32)),
+                          get(0), load(8), get(1), load(8), Call(F_EQUAL),
+                          Else, int(0), End,
+                          Return,
+                          End,
+                          get(2), addr(CLOSURE), I32Eq, If(empty),
+                          get(0), load(4), get(1), load(4), I32Eq,
+                          get(0), load(8), get(1), load(8), I32Eq, I32And,
+                          get(0), load(12), get(1), load(12), I32Eq, I32And,
+                          Return,
+                          End,
+                          int(0)]);
+
+        let (t, f) = (self.constants[1], self.constants[2]);
+        self.runtime_fn(F_EQ, 2, true, &[], &[addr(t), addr(f), get(0), get(1), Call(F_EQUAL), Select]);
+
+        self.runtime_fn(F_CONS, 2, true, &[], &[addr(CONS), get(0), get(1), int(0), Call(F_CELL)]);
+
+        // value, line, column
+        let mut car = vec![get(0), load(0), addr(CONS), I32Ne, If(empty)];
+        car.extend(self.fail("CAR: expected Cons", 1, 2));
+        car.extend(vec![End, get(0), load(4)]);
+        self.runtime_fn(F_CAR, 3, true, &[], &car);
+        let mut cdr = vec![get(0), load(0), addr(CONS), I32Ne, If(empty)];
+        cdr.extend(self.fail("CDR: expected Cons", 1, 2));
+        cdr.extend(vec![End, get(0), load(8)]);
+        self.runtime_fn(F_CDR, 3, true, &[], &cdr);
+
+        // value, rest
+        self.runtime_fn(F_ARG, 2, true, &[], &[addr(ARG), get(0), get(1), int(0), Call(F_CELL)]);
+
+        // env, name, msg, line, column
+        self.runtime_fn(F_LD,
+                        5,
+                        true,
+                        &[],
+                        &[Loop(empty),
+                          get(0), I32Eqz, If(empty), get(2), get(3), get(4), Call(F_FAIL), End,
+                          get(0), load(4), get(1), I32Eq, If(empty), get(0), load(8), Return, End,
+                          get(0), load(12), LocalSet(0),
+                          Br(0),
+                          End,
+                          Unreachable]);
+
+        // env, name; env without name
+        self.runtime_fn(F_REMOVE,
+                        2,
+                        true,
+                        &[i32],
+                        &[get(0), LocalSet(2),
+                          Block(empty), Loop(empty),
+                          get(2), I32Eqz, If(empty), get(0), Return, End,
+                          get(2), load(4), get(1), I32Eq, BrIf(1),
+                          get(2), load(12), LocalSet(2),
+                          Br(0),
+                          End, End,
+                          get(0), load(4), get(1), I32Eq, If(empty), get(0), load(12), Return, End,
+                          addr(ENTRY), get(0), load(4), get(0), load(8), get(0), load(12), get(1), Call(F_REMOVE),
+                          Call(F_CELL)]);
+
+        // env, name, value
+        self.runtime_fn(F_PUT,
+                        3,
+                        true,
+                        &[],
+                        &[addr(ENTRY), get(1), get(2), get(0), get(1), Call(F_REMOVE), Call(F_CELL)]);
+
+        // names, args, env, msg, line, column; env with the arguments bound
+        self.runtime_fn(F_BIND,
+                        6,
+                        true,
+                        &[i32],
+                        &[Block(empty), Loop(empty),
+                          get(6), get(0), load(0), I32Eq, BrIf(1),
+                          get(1), I32Eqz, If(empty), get(3), get(4), get(5), Call(F_FAIL), End,
+                          get(2), get(0), get(6), int(2), I32Shl, I32Add, load(4), get(1), load(4),
+                          Call(F_PUT), LocalSet(2),
+                          get(1), load(8), LocalSet(1),
+                          get(6), int(1), I32Add, LocalSet(6),
+                          Br(0),
+                          End, End,
+                          get(1), If(empty), get(3), get(4), get(5), Call(F_FAIL), End,
+                          get(2)]);
+
+        // front, back; back with the entries of front put in it
+        self.runtime_fn(F_APPEND,
+                        2,
+                        true,
+                        &[],
+                        &[get(0), I32Eqz, If(empty), get(1), Return, End,
+                          get(0), load(12), get(1), Call(F_APPEND), get(0), load(4), get(0), load(8), Call(F_PUT)]);
+
+        // args, closure, env, rec, not-a-closure msg, arity msg, line, column; a call
+        // by RAP or TRAP sees the caller's env under the closure's
+        self.runtime_fn(F_APPLY,
+                        8,
+                        true,
+                        &[],
+                        &[get(1), load(0), addr(CLOSURE), I32Ne, If(empty), get(4), get(6), get(7), Call(F_FAIL), End,
+                          get(1), load(12), get(0),
+                          get(3), If(BlockType::Result(i32)),
+                          get(1), load(8), get(2), Call(F_APPEND),
+                          Else, get(1), load(8), End,
+                          get(5), get(6), get(7), Call(F_BIND),
+                          get(1), load(4),
+                          ReturnCallIndirect {
+                              type_index: lambda,
+                              table_index: 0,
+                          }]);
+
+        // b; writing text is the only thing that moves the heap pointer by less than
+        // a cell, so the bytes written in a row are contiguous
+        self.runtime_fn(F_BYTE,
+                        1,
+                        false,
+                        &[],
+                        &[GlobalGet(HP), int(1), I32Add, Call(F_RESERVE),
+                          GlobalGet(HP), get(0), I32Store8(byte()),
+                          GlobalGet(HP), int(1), I32Add, GlobalSet(HP)]);
+
+        // string
+        self.runtime_fn(F_WRITES,
+                        1,
+                        false,
+                        &[i32],
+                        &[Block(empty), Loop(empty),
+                          get(1), get(0), load(8), I32GeU, BrIf(1),
+                          get(0), load(4), get(1), I32Add, I32Load8U(byte()), Call(F_BYTE),
+                          get(1), int(1), I32Add, LocalSet(1),
+                          Br(0),
+                          End, End]);
+
+        // n, an i64 so that negating i32::MIN is fine
+        let f_itoa = self.define(&[ValType::I64],
+                                 &[],
+                                 &[],
+                                 &[get(0), I64Const(0), I64LtS, If(empty),
+                                   int(b'-' as i32), Call(F_BYTE),
+                                   I64Const(0), get(0), I64Sub, LocalSet(0),
+                                   End,
+                                   get(0), I64Const(10), I64GeS, If(empty),
+                                   get(0), I64Const(10), I64DivS, Call(F_ITOA),
+                                   End,
+                                   get(0), I64Const(10), I64RemS, I32WrapI64, int(b'0' as i32), I32Add, Call(F_BYTE)]);
+        assert_eq!(f_itoa, F_ITOA);
+
+        // value; what Display of the value would
+        let texts: Vec<I> = ["nil", "true", "false", "(cons ", "(lambda [", ", ", "] Code)", "(list)"]
+            .iter()
+            .map(|s| self.message(s))
+            .collect();
+        let mut write = vec![get(0), load(0), LocalSet(1)];
+        for (tag, text) in [NIL, TRUE, FALSE].iter().zip(&texts) {
+            write.extend(vec![get(1), addr(*tag), I32Eq, If(empty), text.clone(), Call(F_WRITES), Return, End]);
+        }
+        write.extend(vec![get(1), addr(INT), I32Eq, If(empty), get(0), load(4), I64ExtendI32S, Call(F_ITOA), Return, End,
+                          get(1), addr(STR), I32Eq, get(1), addr(SYMBOL), I32Eq, I32Or, If(empty),
+                          get(0), Call(F_WRITES), Return,
+                          End,
+                          get(1), addr(CONS), I32Eq, If(empty),
+                          texts[3].clone(), Call(F_WRITES),
+                          get(0), load(4), Call(F_WRITE),
+                          int(b' ' as i32), Call(F_BYTE),
+                          get(0), load(8), Call(F_WRITE),
+                          int(b')' as i32), Call(F_BYTE),
+                          Return,
+                          End,
+                          get(1), addr(CLOSURE), I32Eq, If(empty),
+                          texts[4].clone(), Call(F_WRITES),
+                          Block(empty), Loop(empty),
+                          get(2), get(0), load(12), load(0), I32GeU, BrIf(1),
+                          get(2), If(empty), texts[5].clone(), Call(F_WRITES), End,
+                          int(b'"' as i32), Call(F_BYTE),
+                          get(0), load(12), get(2), int(2), I32Shl, I32Add, load(4), Call(F_WRITES),
+                          int(b'"' as i32), Call(F_BYTE),
+                          get(2), int(1), I32Add, LocalSet(2),
+                          Br(0),
+                          End, End,
+                          texts[6].clone(), Call(F_WRITES),
+                          Return,
+                          End,
+                          texts[7].clone(), Call(F_WRITES)]);
+        self.runtime_fn(F_WRITE, 1, false, &[i32, i32], &write);
+
+        // value; a new string
+        self.runtime_fn(F_SHOW,
+                        1,
+                        true,
+                        &[i32, i32],
+                        &[GlobalGet(HP), LocalSet(1),
+                          get(0), Call(F_WRITE),
+                          GlobalGet(HP), get(1), I32Sub, LocalSet(2),
+                          GlobalGet(HP), int(15), I32Add, int(-16), I32And, GlobalSet(HP),
+                          addr(STR), get(1), get(2), int(0), Call(F_CELL)]);
+
+        // value
+        self.runtime_fn(F_PRINT,
+                        1,
+                        false,
+                        &[i32],
+                        &[get(0), Call(F_SHOW), LocalTee(1), load(4), get(1), load(8), Call(F_PUTS)]);
+
+        // n, line, column
+        let m = self.message("NUM2STR: expected int");
+        self.runtime_fn(F_NUM2STR, 3, true, &[], &[get(0), m, get(1), get(2), Call(F_INT_OF), Drop, get(0), Call(F_SHOW)]);
+
+        // m, n, line, column or n, line, column; n goes in x and m in y, checked in
+        // the order the machine pops them
+        for (i, &(_, op, unary)) in ARITH.iter().enumerate() {
+            let expected = self.message(&format!("{}: expected int", op));
+            let (n, line, column) = if unary { (0, 1, 2) } else { (1, 2, 3) };
+            let (x, y, z) = (column + 1, column + 2, column + 3);
+            let mut body = vec![get(n), expected.clone(), get(line), get(column), Call(F_INT_OF), LocalSet(x)];
+            if !unary {
+                body.extend(vec![get(0), expected, get(line), get(column), Call(F_INT_OF), LocalSet(y)]);
+            }
+            let overflow = self.fail(&format!("{}: overflow", op), line, column);
+            let zero = self.fail(&format!("{}: division by zero", op), line, column);
+            let range = self.fail(&format!("{}: shift out of range", op), line, column);
+            let checked = |wide: I| {
+                let mut r = vec![get(y), I64ExtendI32S, get(x), I64ExtendI32S, wide, LocalSet(z),
+                                 get(z), get(z), I32WrapI64, I64ExtendI32S, I64Ne, If(empty)];
+                r.extend(overflow.clone());
+                r.extend(vec![End, get(z), I32WrapI64]);
+                r
+            };
+            let divide = |op: I| {
+                let mut r = vec![get(x), I32Eqz, If(empty)];
+                r.extend(zero.clone());
+                r.extend(vec![End, get(y), int(i32::MIN), I32Eq, get(x), int(-1), I32Eq, I32And, If(empty)]);
+                r.extend(overflow.clone());
+                r.extend(vec![End, get(y), get(x), op]);
+                r
+            };
+            let shift = |op: I| {
+                let mut r = vec![get(x), int(32), I32GeU, If(empty)];
+                r.extend(range.clone());
+                r.extend(vec![End, get(y), get(x), op]);
+                r
+            };
+            body.extend(match op {
+                            "ADD" => checked(I64Add),
+                            "SUB" => checked(I64Sub),
+                            "MIN" => vec![get(y), get(x), get(y), get(x), I32LtS, Select],
+                            "MAX" => vec![get(y), get(x), get(y), get(x), I32GtS, Select],
+                            "QUOT" => divide(I32DivS),
+                            "REM" => divide(I32RemS),
+                            "BAND" => vec![get(y), get(x), I32And],
+                            "BOR" => vec![get(y), get(x), I32Or],
+                            "BXOR" => vec![get(y), get(x), I32Xor],
+                            "SHL" => shift(I32Shl),
+                            "SHR" => shift(I32ShrS),
+                            "ABS" => {
+                                let mut r = vec![get(x), int(i32::MIN), I32Eq, If(empty)];
+                                r.extend(overflow.clone());
+                                r.extend(vec![End, int(0), get(x), I32Sub, get(x), get(x), int(0), I32LtS, Select]);
+                                r
+                            }
+                            _ => vec![get(x), int(-1), I32Xor],
+                        });
+            body.push(Call(F_INT));
+            let params = if unary { 3 } else { 4 };
+            self.runtime_fn(F_ARITH + i as u32, params, true, &[i32, i32, ValType::I64], &body);
+        }
+    }
+
+    // a closure's body, giving its table index
+    fn lambda(&mut self, body: &Code) -> Result<u32, Box<Error>> {
+        let mut out = vec![];
+        try!(self.block(body, &mut out));
+        out.push(Instruction::Unreachable);
+        let f = self.define(&[ValType::I32], &[ValType::I32], &[ValType::I32], &out);
+        self.table.push(f);
+        return Ok(self.table.len() as u32 - 1);
+    }
+
+    // constant i, made once however many times it is loaded
+    fn pooled(&mut self, c: &CodeOPInfo, i: usize) -> Result<u32, Box<Error>> {
+        if let Some(&v) = self.pooled.get(&i) {
+            return Ok(v);
+        }
+        let lisp = match self.pool.get(i) {
+            Some(lisp) => lisp.clone(),
+            None => return error(c, format!("no constant {}", i)),
+        };
+        let v = try!(self.constant(c, &lisp));
+        self.pooled.insert(i, v);
+        return Ok(v);
+    }
+
+    fn constant(&mut self, c: &CodeOPInfo, lisp: &Lisp) -> Result<u32, Box<Error>> {
+        match *lisp {
+            Lisp::Nil => return Ok(self.constants[0]),
+            Lisp::True => return Ok(self.constants[1]),
+            Lisp::False => return Ok(self.constants[2]),
+            Lisp::Int(n) => return Ok(self.cell(INT, n as u32, 0, 0)),
+            Lisp::Str(ref s) => return Ok(self.string(s)),
+            Lisp::Symbol(ref s) => return Ok(self.text(SYMBOL, s)),
+            Lisp::Cons(ref car, ref cdr) => {
+                let car = try!(self.constant(c, car));
+                let cdr = try!(self.constant(c, cdr));
+                return Ok(self.cell(CONS, car, cdr, 0));
+            }
+            Lisp::Closure(ref names, ref body, ref env, _) if env.is_empty() => {
+                let f = try!(self.lambda(body));
+                let names = self.names(names);
+                return Ok(self.cell(CLOSURE, f, 0, names));
+            }
+            _ => return error(c, format!("cannot build the constant {} into a module", lisp)),
+        }
+    }
+
+    fn call(&mut self, c: &CodeOPInfo, name: &str, rec: bool, out: &mut Vec<I>) {
+        let not_closure = self.message(&format!("{}: expected Closure", name));
+        let arity = self.message(&format!("{}: wrong number of arguments", name));
+        out.extend(vec![get(ENV),
+                        int(rec as i32),
+                        not_closure,
+                        arity,
+                        addr(c.info[0] as u32),
+                        addr(c.info[1] as u32)]);
+    }
+
+    fn block(&mut self, code: &Code, out: &mut Vec<I>) -> Result<(), Box<Error>> {
+        use wasm_encoder::Instruction::*;
+        for c in code {
+            let at = [addr(c.info[0] as u32), addr(c.info[1] as u32)];
+            match c.op {
+                CodeOP::LD(ref id) => {
+                    let name = self.string(id);
+                    let unbound = self.message(&format!("LD: unbound {}", id));
+                    out.extend(vec![get(ENV), addr(name), unbound]);
+                    out.extend_from_slice(&at);
+                    out.push(Call(F_LD));
+                }
+                CodeOP::LDC(i) => {
+                    let v = try!(self.pooled(c, i));
+                    out.push(addr(v));
+                }
+                CodeOP::CONSTS(_) | CodeOP::HEADER(_) => {}
+                CodeOP::LDF(ref names, ref body, _) => {
+                    let f = try!(self.lambda(body));
+                    let names = self.names(names);
+                    out.extend(vec![addr(CLOSURE), addr(f), get(ENV), addr(names), Call(F_CELL)]);
+                }
+                CodeOP::LET(ref id) => {
+                    let name = self.string(id);
+                    out.extend(vec![LocalSet(TMP), get(ENV), addr(name), get(TMP), Call(F_PUT), LocalSet(ENV)]);
+                }
+                CodeOP::ARGS(n) => {
+                    out.push(int(0));
+                    for _ in 0..n {
+                        out.push(Call(F_ARG));
+                    }
+                }
+                CodeOP::AP | CodeOP::RAP => {
+                    let name = if let CodeOP::AP = c.op { "AP" } else { "RAP" };
+                    self.call(c, name, name == "RAP", out);
+                    out.push(Call(F_APPLY));
+                }
+                CodeOP::TAP | CodeOP::TRAP => {
+                    let name = if let CodeOP::TAP = c.op { "TAP" } else { "TRAP" };
+                    self.call(c, name, name == "TRAP", out);
+                    out.push(ReturnCall(F_APPLY));
+                }
+                CodeOP::RET => out.push(Return),
+                CodeOP::JOIN => {}
+                CodeOP::SEL(ref t, ref f) |
+                CodeOP::TSEL(ref t, ref f) => {
+                    let tail = matches!(c.op, CodeOP::TSEL(..));
+                    let expected = self.message(if tail { "TSEL: expected bool" } else { "SEL: expected bool" });
+                    out.push(expected);
+                    out.extend_from_slice(&at);
+                    out.push(Call(F_COND));
+                    out.push(If(if tail { BlockType::Empty } else { BlockType::Result(ValType::I32) }));
+                    try!(self.block(t, out));
+                    out.push(Else);
+                    try!(self.block(f, out));
+                    out.push(End);
+                    // both branches returned
+                    if tail {
+                        out.push(Unreachable);
+                    }
+                }
+                CodeOP::POP => out.push(Drop),
+                CodeOP::PUTS => out.extend(vec![LocalTee(TMP), get(TMP), Call(F_PRINT)]),
+                CodeOP::EQ => out.push(Call(F_EQ)),
+                CodeOP::CONS => out.push(Call(F_CONS)),
+                CodeOP::CAR | CodeOP::CDR => {
+                    out.extend_from_slice(&at);
+                    out.push(Call(if let CodeOP::CAR = c.op { F_CAR } else { F_CDR }));
+                }
+                CodeOP::ADD | CodeOP::SUB => {
+                    out.extend_from_slice(&at);
+                    out.push(Call(if let CodeOP::ADD = c.op { F_ARITH } else { F_ARITH + 1 }));
+                }
+                CodeOP::PRIM(id, _) => {
+                    let name = match vm::builtin_name(id) {
+                        Some(name) => name,
+                        None => return error(c, "cannot build a registered primitive into a module".to_string()),
+                    };
+                    let f = match ARITH.iter().position(|&(n, _, _)| n == name) {
+                        Some(i) => F_ARITH + i as u32,
+                        None if name == "number->string" => F_NUM2STR,
+                        None => return error(c, format!("cannot build {} into a module", name)),
+                    };
+                    out.extend_from_slice(&at);
+                    out.push(Call(f));
+                }
+                ref op => return error(c, format!("cannot build {:?} into a module", op)),
+            }
+        }
+        return Ok(());
+    }
+
+    fn finish(self, puts: u32, error: u32, main: u32) -> Vec<u8> {
+        let b = self;
+        let mut types = TypeSection::new();
+        for (params, results) in &b.types {
+            types.ty().function(params.iter().cloned(), results.iter().cloned());
+        }
+
+        let mut imports = ImportSection::new();
+        imports.import("secd", "puts", EntityType::Function(puts));
+        imports.import("secd", "error", EntityType::Function(error));
+
+        let mut funcs = FunctionSection::new();
+        let mut code = CodeSection::new();
+        for &(ty, ref f) in &b.funcs {
+            funcs.function(ty);
+            code.function(f);
+        }
+
+        let mut tables = TableSection::new();
+        tables.table(TableType {
+                         element_type: RefType::FUNCREF,
+                         table64: false,
+                         minimum: b.table.len() as u64,
+                         maximum: Some(b.table.len() as u64),
+                         shared: false,
+                     });
+
+        let heap = (b.data.len() as u32).next_multiple_of(CELL);
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+                            minimum: (heap as u64 / 65536) + 1,
+                            maximum: None,
+                            memory64: false,
+                            shared: false,
+                            page_size_log2: None,
+                        });
+
+        let mut globals = GlobalSection::new();
+        globals.global(GlobalType {
+                           val_type: ValType::I32,
+                           mutable: true,
+                           shared: false,
+                       },
+                       &ConstExpr::i32_const(heap as i32));
+
+        let mut exports = ExportSection::new();
+        exports.export("memory", ExportKind::Memory, 0);
+        exports.export("main", ExportKind::Func, main);
+        exports.export("show", ExportKind::Func, F_SHOW);
+
+        let mut elements = ElementSection::new();
+        elements.active(None, &ConstExpr::i32_const(0), Elements::Functions(Cow::Borrowed(&b.table)));
+
+        let mut data = DataSection::new();
+        data.active(0, &ConstExpr::i32_const(0), b.data.iter().cloned());
+
+        let mut module = Module::new();
+        module.section(&types)
+            .section(&imports)
+            .section(&funcs)
+            .section(&tables)
+            .section(&memories)
+            .section(&globals)
+            .section(&exports)
+            .section(&elements)
+            .section(&code)
+            .section(&data);
+        return module.finish();
+    }
+}
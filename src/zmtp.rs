@@ -0,0 +1,113 @@
+use std::error::Error;
+use std::io::{Read, Write};
+
+// Just enough ZMTP 3.0, the ZeroMQ wire protocol, for the Jupyter kernel: the NULL
+// mechanism, multipart messages and the socket types the kernel binds. Messages are read
+// and written on each connection directly, so a ROUTER never sees identity frames and a
+// PUB sends everything to every subscriber.
+
+const SIGNATURE: [u8; 10] = [0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0x7f];
+
+const MORE: u8 = 1;
+const LONG: u8 = 2;
+const COMMAND: u8 = 4;
+
+fn error<T>(msg: &str) -> Result<T, Box<Error>> {
+    return Err(From::from(format!("zmtp: {}", msg)));
+}
+
+// the greeting and READY both sides send; `socket_type` is ours, as ROUTER, PUB or REP
+pub fn handshake<S: Read + Write>(stream: &mut S, socket_type: &str) -> Result<(), Box<Error>> {
+    let mut greeting = vec![];
+    greeting.extend_from_slice(&SIGNATURE);
+    greeting.extend_from_slice(&[3, 0]);
+    let mut mechanism = [0; 20];
+    mechanism[..4].copy_from_slice(b"NULL");
+    greeting.extend_from_slice(&mechanism);
+    greeting.push(1);
+    greeting.extend_from_slice(&[0; 31]);
+    try!(stream.write_all(&greeting));
+
+    let mut theirs = [0; 64];
+    try!(stream.read_exact(&mut theirs));
+    if theirs[0] != 0xff || theirs[9] != 0x7f || theirs[10] < 3 {
+        return error("peer does not speak ZMTP 3");
+    }
+    if &theirs[12..16] != b"NULL" || theirs[16] != 0 {
+        return error("only the NULL mechanism is supported");
+    }
+
+    let mut ready = vec![5];
+    ready.extend_from_slice(b"READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+    ready.extend_from_slice(socket_type.as_bytes());
+    try!(write_frame(stream, COMMAND, &ready));
+
+    match try!(read_frame(stream)) {
+        Some((flags, body)) if flags & COMMAND != 0 && body.get(1..6) == Some(b"READY") => {
+            return Ok(())
+        }
+        _ => return error("expected READY"),
+    }
+}
+
+fn write_frame<W: Write>(stream: &mut W, flags: u8, body: &[u8]) -> Result<(), Box<Error>> {
+    if body.len() > 255 {
+        try!(stream.write_all(&[flags | LONG]));
+        try!(stream.write_all(&(body.len() as u64).to_be_bytes()));
+    } else {
+        try!(stream.write_all(&[flags, body.len() as u8]));
+    }
+    try!(stream.write_all(body));
+    return Ok(());
+}
+
+// a frame's flags and body, None once the peer has gone
+fn read_frame<R: Read>(stream: &mut R) -> Result<Option<(u8, Vec<u8>)>, Box<Error>> {
+    let mut flags = [0];
+    if try!(stream.read(&mut flags)) == 0 {
+        return Ok(None);
+    }
+    let len = if flags[0] & LONG != 0 {
+        let mut len = [0; 8];
+        try!(stream.read_exact(&mut len));
+        u64::from_be_bytes(len) as usize
+    } else {
+        let mut len = [0];
+        try!(stream.read_exact(&mut len));
+        len[0] as usize
+    };
+    let mut body = vec![0; len];
+    try!(stream.read_exact(&mut body));
+    return Ok(Some((flags[0], body)));
+}
+
+pub fn write_message<W: Write>(stream: &mut W, frames: &[Vec<u8>]) -> Result<(), Box<Error>> {
+    for (i, frame) in frames.iter().enumerate() {
+        let more = if i + 1 < frames.len() { MORE } else { 0 };
+        try!(write_frame(stream, more, frame));
+    }
+    try!(stream.flush());
+    return Ok(());
+}
+
+// the frames of the next message, skipping commands such as PING; None once the peer
+// has gone
+pub fn read_message<R: Read>(stream: &mut R) -> Result<Option<Vec<Vec<u8>>>, Box<Error>> {
+    let mut frames = vec![];
+    loop {
+        let (flags, body) = match try!(read_frame(stream)) {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        if flags & COMMAND != 0 {
+            continue;
+        }
+        frames.push(body);
+        if flags & MORE == 0 {
+            return Ok(Some(frames));
+        }
+    }
+}
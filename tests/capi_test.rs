@@ -0,0 +1,107 @@
+#![cfg(feature = "capi")]
+extern crate secd;
+use secd::capi::*;
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_void;
+use std::ptr;
+
+fn display(v: *const SecdValue) -> String {
+  unsafe {
+    let s = secd_value_display(v);
+    let r = CStr::from_ptr(s).to_str().unwrap().to_string();
+    secd_free_string(s);
+    r
+  }
+}
+
+extern "C" fn scale(data: *mut c_void, args: *const *const SecdValue, nargs: usize) -> *mut SecdValue {
+  unsafe {
+    let calls = &mut *(data as *mut i32);
+    *calls += 1;
+    let args = std::slice::from_raw_parts(args, nargs);
+    let (mut a, mut b) = (0, 0);
+    if secd_value_int(args[0], &mut a) == 0 || secd_value_int(args[1], &mut b) == 0 {
+      return ptr::null_mut();
+    }
+    secd_int(a * b)
+  }
+}
+
+#[test]
+fn embed() {
+  unsafe {
+    let secd = secd_new();
+    let mut calls = 0i32;
+    let name = CString::new("scale").unwrap();
+    assert_eq!(secd_register_fn(secd, name.as_ptr(), 2, scale, &mut calls as *mut i32 as *mut c_void), 0);
+
+    let src = CString::new("(let f (lambda (x) (scale x 3)) (+ (f 4) (f 1)))").unwrap();
+    let v = secd_eval(secd, src.as_ptr());
+    assert!(!v.is_null());
+    let mut n = 0;
+    assert_eq!(secd_value_int(v, &mut n), 1);
+    assert_eq!((n, calls), (15, 2));
+    assert_eq!(CStr::from_ptr(secd_value_type(v)).to_str(), Ok("int"));
+    assert!(secd_error(secd).is_null());
+    secd_free_value(v);
+
+    let src = CString::new("(cons \"a\" (scale 2 2))").unwrap();
+    let v = secd_eval(secd, src.as_ptr());
+    assert!(!v.is_null(), "{:?}", CStr::from_ptr(secd_error(secd)));
+    assert_eq!(display(v), "(cons a 4)");
+    assert_eq!(CStr::from_ptr(secd_value_type(v)).to_str(), Ok("cons"));
+    secd_free_value(v);
+
+    // a host function answering NULL fails the call
+    let src = CString::new("(scale \"a\" 2)").unwrap();
+    assert!(secd_eval(secd, src.as_ptr()).is_null());
+    let e = CStr::from_ptr(secd_error(secd)).to_str().unwrap().to_string();
+    assert!(e.contains("scale: host function failed"), "{}", e);
+
+    // a handle has no capabilities until it is given them
+    let src = CString::new("(secd-stack)").unwrap();
+    assert!(secd_eval(secd, src.as_ptr()).is_null());
+    secd_allow_all(secd);
+    let v = secd_eval(secd, src.as_ptr());
+    assert!(!v.is_null(), "{:?}", CStr::from_ptr(secd_error(secd)));
+    assert_eq!(CStr::from_ptr(secd_value_type(v)).to_str(), Ok("nil"));
+    secd_free_value(v);
+
+    // exit gives no value but a status, apart from an error
+    let src = CString::new("(begin (exit 3) 1)").unwrap();
+    assert!(secd_eval(secd, src.as_ptr()).is_null());
+    assert!(secd_error(secd).is_null());
+    let mut n = 0;
+    assert_eq!(secd_exit_status(secd, &mut n), 1);
+    assert_eq!(n, 3);
+    let src = CString::new("3").unwrap();
+    let v = secd_eval(secd, src.as_ptr());
+    assert_eq!(secd_exit_status(secd, &mut n), 0);
+    secd_free_value(v);
+
+    // another handle doesn't have the function
+    let other = secd_new();
+    let src = CString::new("(scale 1 2)").unwrap();
+    assert!(secd_eval(other, src.as_ptr()).is_null());
+    secd_free(other);
+    secd_free(secd);
+  }
+}
+
+#[test]
+fn values() {
+  unsafe {
+    let s = CString::new("hello").unwrap();
+    let v = secd_string(s.as_ptr());
+    assert_eq!(display(v), "hello");
+    assert_eq!(CStr::from_ptr(secd_value_type(v)).to_str(), Ok("string"));
+    assert_eq!(secd_value_int(v, ptr::null_mut()), 0);
+    secd_free_value(v);
+
+    let v = secd_nil();
+    assert_eq!(CStr::from_ptr(secd_value_type(v)).to_str(), Ok("nil"));
+    secd_free_value(v);
+    assert!(secd_value_type(ptr::null()).is_null());
+  }
+}
@@ -13,7 +13,18 @@ fn compile() {
 
     let code2 = vec![CodeOPInfo {
                          info: [0; 2],
-                         op: CodeOP::LDC(Rc::new(Lisp::Int(0))),
+                         op: CodeOP::HEADER(Header {
+                                                version: vm::VERSION,
+                                                features: vec![],
+                                            }),
+                     },
+                     CodeOPInfo {
+                         info: [0; 2],
+                         op: CodeOP::CONSTS(Rc::new(vec![Lisp::int(0)])),
+                     },
+                     CodeOPInfo {
+                         info: [0; 2],
+                         op: CodeOP::LDC(0),
                      },
                      CodeOPInfo {
                          info: [0; 2],
@@ -21,7 +32,7 @@ fn compile() {
                      },
                      CodeOPInfo {
                          info: [0; 2],
-                         op: CodeOP::LDC(Rc::new(Lisp::Int(0))),
+                         op: CodeOP::LDC(0),
                      },
                      CodeOPInfo {
                          info: [0; 2],
@@ -51,3 +62,247 @@ fn compile() {
     assert!(code1.is_ok());
     assert_eq!(code1.unwrap(), code2);
 }
+
+#[test]
+fn constant_pool() {
+    let code = Compiler::new().compile(&Parser::new(&r#"(cons "a" ((lambda (x) (cons x "a")) 1))"#.into())
+                                                 .parse()
+                                                 .unwrap())
+        .unwrap();
+    // lambda bodies share the program's pool, each constant once
+    assert_eq!(data::pool(&code), &[Rc::new(Lisp::Str("a".into())), Lisp::int(1)][..]);
+    assert_eq!(format!("{}", SECD::new(code).run().unwrap()), "(cons a (cons 1 a))");
+
+    let ldc = vec![CodeOPInfo {
+                       info: [1, 1],
+                       op: CodeOP::LDC(0),
+                   }];
+    let e = SECD::new(ldc).run().unwrap_err();
+    assert_eq!(format!("{}", e), "1:1:vm error: LDC: no constant 0");
+}
+
+#[test]
+fn tail_sel() {
+    let compile = |s: &str| Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap();
+    let body = |code: &Code| sourcemap::block(code, &[(2, 0)]).unwrap().clone();
+
+    // in tail position both branches return from the lambda themselves
+    let code = compile("(lambda (n) (if (eq n 0) 1 (if true 2 3)))");
+    let b = body(&code);
+    match b[3].op {
+        CodeOP::TSEL(ref t, ref f) => {
+            assert_eq!(t[1].op, CodeOP::RET);
+            assert!(matches!(f[1].op, CodeOP::TSEL(..)));
+            assert_eq!(f[2].op, CodeOP::RET);
+        }
+        ref op => panic!("{:?}", op),
+    }
+
+    // elsewhere they join back
+    let code = compile("(lambda (n) (+ 1 (if (eq n 0) 1 2)))");
+    let b = body(&code);
+    match b[4].op {
+        CodeOP::SEL(ref t, _) => assert_eq!(t[1].op, CodeOP::JOIN),
+        ref op => panic!("{:?}", op),
+    }
+    assert!(matches!(compile("(if true 1 2)")[3].op, CodeOP::SEL(..)));
+}
+
+#[test]
+fn direct_call() {
+    let compile = |s: &str| Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap();
+    let ops = |code: Code| code.into_iter().map(|c| c.op).collect::<Vec<_>>();
+
+    // a literal lambda is compiled in place, its arguments bound like let under hidden names
+    assert_eq!(ops(compile("((lambda (a b) (+ a b)) 1 2)"))[1..],
+               [CodeOP::CONSTS(Rc::new(vec![Lisp::int(1), Lisp::int(2)])),
+                    CodeOP::LDC(0),
+                    CodeOP::LDC(1),
+                    CodeOP::LET(" inline b 1:13".into()),
+                    CodeOP::LET(" inline a 1:11".into()),
+                    CodeOP::LD(" inline a 1:11".into()),
+                    CodeOP::LD(" inline b 1:13".into()),
+                    CodeOP::ADD]);
+
+    // the wrong number of arguments, or too big a body, still makes a call
+    assert_eq!(compile("((lambda (a b) a) 1)").last().unwrap().op, CodeOP::AP);
+    let big = (0..40).fold("x".to_string(), |e, _| format!("(+ {} 1)", e));
+    assert_eq!(compile(&format!("((lambda (x) {}) 1)", big)).last().unwrap().op, CodeOP::AP);
+
+    let r = SECD::new(compile("(let a 1 ((lambda (a b) (cons a b)) 2 a))")).run();
+    assert_eq!(format!("{}", r.unwrap()), "(cons 2 1)");
+
+    // the parameters don't clobber the caller's bindings of the same names
+    let run = |s: &str| format!("{}", SECD::new(compile(s)).run().unwrap());
+    assert_eq!(run("(let x 5 (let r ((lambda x (+ x 1)) 10) (cons r x)))"), "(cons 11 5)");
+    assert_eq!(run("(let x 1 (cons ((lambda (x) x) 2) x))"), "(cons 2 1)");
+    assert_eq!(run("(letrec f (lambda n (if (eq n 0) 0 (let r ((lambda n (+ n 1)) 99) (+ n (f (- n 1)))))) (f 3))"),
+               "6");
+}
+
+#[test]
+fn primitive_call() {
+    let compile = |s: &str| Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap());
+    let ops = |code: Code| code.into_iter().map(|c| c.op).collect::<Vec<_>>();
+
+    let map = vm::primitive("map").unwrap();
+    assert_eq!(ops(compile("(map (lambda (x) x) nil)").unwrap())[3..],
+               [CodeOP::LDC(1), CodeOP::PRIM(map, 2)]);
+    assert!(compile("(min 1)").is_err());
+    assert_eq!(vm::primitive("lambda"), None);
+
+    let call = |id, n| {
+        let code = vec![CodeOPInfo {
+                            info: [1, 1],
+                            op: CodeOP::CONSTS(Rc::new(vec![Lisp::int(-3)])),
+                        },
+                        CodeOPInfo {
+                            info: [1, 1],
+                            op: CodeOP::LDC(0),
+                        },
+                        CodeOPInfo {
+                            info: [1, 1],
+                            op: CodeOP::PRIM(id, n),
+                        }];
+        SECD::new(code).run().map(|r| format!("{}", r))
+    };
+    assert_eq!(call(vm::primitive("abs").unwrap(), 1).unwrap(), "3");
+    assert!(call(vm::primitive("abs").unwrap(), 2).is_err());
+    assert!(call(vm::PRIMITIVES.len(), 1).is_err());
+}
+
+#[test]
+fn lambda_lifting() {
+    let compile = |s: &str, lift: bool| {
+        let mut c = Compiler::new();
+        c.lift_lambdas = lift;
+        c.compile(&Parser::new(&s.into()).parse().unwrap()).unwrap()
+    };
+    // the first instruction after the program's header and pool
+    let lifted = |code: &[CodeOPInfo]| {
+        matches!(code.iter().find(|c| !matches!(c.op, CodeOP::HEADER(_) | CodeOP::CONSTS(_))).map(|c| &c.op),
+                 Some(&CodeOP::LDC(_)))
+    };
+
+    assert!(lifted(&compile("(lambda (x) (+ x 1))", true)));
+    assert!(lifted(&compile("(lambda (x) (let y (car x) (quote y)))", true)));
+    assert!(!lifted(&compile("(lambda (x) (+ x 1))", false)));
+    assert!(!lifted(&compile("(lambda (x) (+ x y))", true)));
+    assert!(!lifted(&compile("(lambda (x) (secd-env))", true)));
+    assert!(!lifted(&compile("(letrec f (lambda (n) (f n)) f)", true)));
+
+    // the outer lambda closes over nothing, the inner one over x
+    let code = compile("(lambda (x) (lambda (y) (+ x y)))", true);
+    assert!(lifted(&code));
+    assert!(!lifted(sourcemap::block(&code, &[(2, 0)]).unwrap()));
+
+    let s = "(let a 10 (map (lambda (x) ((lambda (y) (+ y 1)) x)) (range 0 3 1)))";
+    let r = SECD::new(compile(s, true)).run();
+    assert_eq!(format!("{}", r.unwrap()), "(cons 1 (cons 2 (cons 3 nil)))");
+}
+
+#[test]
+fn interpolation() {
+    let run = |s: &str| {
+        let code = try!(Compiler::new().compile(&try!(Parser::new(&s.into()).parse())));
+        SECD::new(code).run().map(|r| format!("{}", r))
+    };
+    let s = "(let x 3 (let name \"world\" \"hello {name}, {x} plus {(+ x 1)} is {(+ x (+ x 1))}\"))";
+    assert_eq!(run(s).unwrap(), "hello world, 3 plus 4 is 7");
+    assert_eq!(run("\"{{x}} }\"").unwrap(), "{x} }");
+    assert_eq!(run("\"{1}{2}\"").unwrap(), "12");
+    // a lambda with an interpolated string in it closes over what the string uses
+    assert_eq!(run("(let n 5 ((lambda () \"n is {n}\")))").unwrap(), "n is 5");
+
+    assert_eq!(format!("{}", run("(puts \"{x\")").unwrap_err()),
+               "1:7:parse error: unclosed { in string; {{ is a brace");
+    assert!(format!("{}", run("\"{1 2}\"").unwrap_err()).contains("{1 2} in string is not one expression"));
+    // any value may be interpolated, while number->string still takes only an int
+    assert_eq!(run("(let b true (let s (quote sym) \"{b} {s} {nil} {(cons 1 2)}\"))").unwrap(), "true sym nil (cons 1 2)");
+    assert!(format!("{}", run("(number->string \"a\")").unwrap_err()).contains("NUM2STR: expected int"));
+    assert_eq!(run("(string-append \"a\" \"b\")").unwrap(), "ab");
+}
+
+#[test]
+fn threading() {
+    let run = |s: &str| {
+        let code = try!(Compiler::new().compile(&try!(Parser::new(&s.into()).parse())));
+        SECD::new(code).run().map(|r| format!("{}", r))
+    };
+    let s = "(let inc (lambda (x) (+ x 1)) (-> 5 inc (- 1) (cons nil)))";
+    assert_eq!(run(s).unwrap(), "(cons 5 nil)");
+    let s = "(->> (range 0 4 1) (map (lambda (x) (+ x x))) (filter (lambda (x) (eq 0 (remainder x 4)))) (foldl (lambda (a x) (+ a x)) 0))";
+    assert_eq!(run(s).unwrap(), "4");
+    assert_eq!(run("(-> 1)").unwrap(), "1");
+    // in tail position the last call is a tail call, so the loop doesn't grow the dump
+    let s = "(letrec loop (lambda (n) (if (eq n 0) 0 (-> n (- 1) loop))) (loop 100000))";
+    assert_eq!(run(s).unwrap(), "0");
+
+    assert!(format!("{}", run("(->)").unwrap_err()).contains("-> syntax"));
+    assert!(format!("{}", run("(->> 1 2)").unwrap_err()).contains("->> step syntax"));
+    assert_eq!(secd::expand_lisp(&"(-> x (f 1) (->> y g))".to_string()).unwrap(), "(g (y (f x 1)))");
+}
+
+#[test]
+fn keywords() {
+    assert!(compiler::is_keyword("lambda") && compiler::is_keyword("map") && compiler::is_keyword("nil"));
+    assert!(!compiler::is_keyword("fib"));
+    let all: Vec<&str> = compiler::keywords().collect();
+    assert_eq!(all.len(), all.iter().collect::<std::collections::HashSet<_>>().len());
+    assert!(all.contains(&"secd-where") && all.contains(&"false"));
+}
+
+#[test]
+fn shadowing() {
+    let run = |s: &str| {
+        let code = Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap();
+        format!("{}", SECD::new(code).run().unwrap())
+    };
+    assert_eq!(run("(let car (lambda (x) 1) (car (cons 5 nil)))"), "1");
+    assert_eq!(run("((lambda (quote) (quote 3)) (lambda (x) (+ x 1)))"), "4");
+    assert_eq!(run("(letrec map (lambda (f l) (f l)) (map (lambda (l) (car l)) (cons 2 nil)))"), "2");
+    // the forms a do expands into are not the program's
+    assert_eq!(run("(let if (lambda (a b c) c) (do ((i 0 (+ i 1))) ((eq i 3) (if i i 7))))"), "7");
+}
+
+#[test]
+fn arity_check() {
+    let compile = |s: &str| Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap());
+    let message = |s: &str| format!("{}", compile(s).unwrap_err());
+
+    assert_eq!(message("(let f (lambda (a b) a) (f 1))"), "1:26:compile error: f takes 2 arguments, not 1");
+    assert_eq!(message("(letrec f (lambda n (f n n)) (f 1))"), "1:22:compile error: f takes 1 argument, not 2");
+    assert!(compile("(define/contract f (int? -> int?) (lambda (n) n) (f 1 2))").is_err());
+    // a closer binding of the name, or one to something else, is not checked
+    assert!(compile("(let f (lambda (a b) a) (let g (lambda (f) (f 1)) g))").is_ok());
+    assert!(compile("(let f (lambda (a b) a) (let f (car nil) (f 1)))").is_ok());
+    assert!(compile("(let f (lambda (a b) a) (f 1 2))").is_ok());
+}
+
+#[test]
+fn reuse() {
+    let parse = |s: &str| Parser::new(&s.into()).parse().unwrap();
+    let mut c = Compiler::new();
+    let first = c.compile(&parse("(cons \"a\" 1)")).unwrap();
+    assert!(c.compile(&parse("(let x)")).is_err());
+    let again = c.compile(&parse("(cons \"a\" 1)")).unwrap();
+    assert_eq!(format!("{:?}", again), format!("{:?}", first));
+    assert_eq!(format!("{:?}", c.into_code()), format!("{:?}", first));
+
+    let mut c = Compiler::new();
+    c.compile_incremental(&parse("(define f (lambda (x) x))")).unwrap();
+    assert!(c.compile_incremental(&parse("(f 1 2)")).is_err());
+    c.reset();
+    assert!(c.compile_incremental(&parse("(f 1 2)")).is_ok());
+}
+
+#[test]
+fn compiled_program() {
+    let ast = Parser::new(&"(let if 1\n  (map (lambda (x) (+ x if)) nil))".into()).parse().unwrap();
+    let p = Compiler::new().compile_program(&ast).unwrap();
+    assert_eq!(format!("{:?}", p.code), format!("{:?}", Compiler::new().compile(&ast).unwrap()));
+    assert_eq!(p.symbols, vec![("if".to_string(), [1, 6]), ("x".to_string(), [2, 17])]);
+    assert_eq!(p.warnings.len(), 1);
+    assert_eq!(p.warnings[0].message, "if shadows the form of the same name");
+    assert!(p.source_map.ranges.iter().any(|r| r.span == [2, 3]));
+}
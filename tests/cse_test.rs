@@ -0,0 +1,48 @@
+extern crate secd;
+use secd::*;
+use secd::cse;
+
+fn rewrite(s: &str) -> String {
+  return format!("{}", cse::eliminate(&Parser::new(&s.to_string()).parse().unwrap()));
+}
+
+fn run(s: &str, cse: bool) -> String {
+  let mut c = Compiler::new();
+  c.cse = cse;
+  let code = c.compile(&Parser::new(&s.to_string()).parse().unwrap()).unwrap();
+  return format!("{}", SECD::new(code).run().unwrap());
+}
+
+#[test]
+fn binds_repeated_expressions() {
+  assert_eq!(rewrite("(let x 1 (cons (+ x 1) (+ x 1)))"),
+             "(let x 1 (let  cse 1:16 (+ x 1) (cons  cse 1:16  cse 1:16)))");
+  // the largest repeated expression wins; what repeats inside it is left to the binding
+  assert_eq!(rewrite("(let x 1 (f (+ (car x) 1) (+ (car x) 1)))"),
+             "(let x 1 (let  cse 1:13 (+ (car x) 1) (f  cse 1:13  cse 1:13)))");
+  // nested lets get their own
+  assert_eq!(rewrite("(lambda (y) (let x y (- (cdr x) (cdr x))))"),
+             "(lambda (y) (let x y (let  cse 1:25 (cdr x) (-  cse 1:25  cse 1:25))))");
+}
+
+#[test]
+fn leaves_conditional_and_rebound_code_alone() {
+  for s in &["(let x 1 (if true (+ x 1) (+ x 1)))",
+             "(let x 1 (cons (+ x 1) (lambda (y) (+ x 1))))",
+             "(let x 1 (cons (+ x 1) (cons (let x 2 x) (+ x 1))))",
+             "(let x 1 (cons (+ x 1) (do ((x 0 (+ x 1))) ((eq x 2) (+ x 1)))))",
+             "(let x 1 (cons (+ x 1) (+ x 2)))"] {
+    assert_eq!(rewrite(s), *s);
+  }
+  assert_eq!(rewrite("(let x 1 (if (eq (car x) (car x)) 1 2))"),
+             "(let x 1 (let  cse 1:18 (car x) (if (eq  cse 1:18  cse 1:18) 1 2)))");
+}
+
+#[test]
+fn same_results() {
+  for s in &["(let x (cons 1 nil) (cons (car x) (cons (car x) (+ (car x) (car x)))))",
+             "(let n 5 (letrec f (lambda (k) (if (eq k 0) 0 (let m (- k 1) (+ (f m) (- k 1))))) (f n)))",
+             "(let x 3 (cons (+ x 1) (cons (let x 2 x) (+ x 1))))"] {
+    assert_eq!(run(s, true), run(s, false));
+  }
+}
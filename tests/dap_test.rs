@@ -0,0 +1,101 @@
+extern crate secd;
+use secd::dap::Debugger;
+use secd::json::Json;
+use std::env;
+use std::fs;
+
+const SRC: &'static str = "(let a 10
+  (letrec f (lambda n
+    (if (eq n 0) a (f (- n 1))))
+    (begin (puts \"hi\") (f 2))))";
+
+fn request(d: &mut Debugger, seq: i64, command: &str, args: &str) -> Vec<Json> {
+  let req = format!(r#"{{"seq":{},"type":"request","command":"{}","arguments":{}}}"#, seq, command, args);
+  return d.handle(&Json::parse(&req).unwrap());
+}
+
+fn event<'a>(msgs: &'a [Json], name: &str) -> Option<&'a Json> {
+  return msgs.iter().find(|m| m.get("event").and_then(|e| e.as_str()) == Some(name));
+}
+
+fn launch(d: &mut Debugger, name: &str, src: &str, stop_on_entry: bool) {
+  let path = env::temp_dir().join(name);
+  fs::write(&path, src).unwrap();
+  let r = request(d, 1, "initialize", "{}");
+  assert_eq!(r[0].get("success"), Some(&Json::Bool(true)));
+  assert!(event(&r, "initialized").is_some());
+  let args = format!(r#"{{"program":{},"stopOnEntry":{}}}"#, Json::str(path.to_str().unwrap()), stop_on_entry);
+  let r = request(d, 2, "launch", &args);
+  assert_eq!(r[0].get("success"), Some(&Json::Bool(true)));
+}
+
+#[test]
+fn breakpoint() {
+  let mut d = Debugger::new();
+  launch(&mut d, "secd_dap_breakpoint.lisp", SRC, false);
+
+  let r = request(&mut d, 3, "setBreakpoints", r#"{"source":{"path":"x"},"breakpoints":[{"line":3}]}"#);
+  assert_eq!(r[0].at(&["body", "breakpoints"]).and_then(|b| b.as_array()).map(|b| b.len()), Some(1));
+
+  let r = request(&mut d, 4, "configurationDone", "{}");
+  assert_eq!(event(&r, "output").and_then(|e| e.at(&["body", "output"])), Some(&Json::str("hi\n")));
+  assert_eq!(event(&r, "stopped").and_then(|e| e.at(&["body", "reason"])), Some(&Json::str("breakpoint")));
+
+  let r = request(&mut d, 5, "stackTrace", r#"{"threadId":1}"#);
+  let frames = r[0].at(&["body", "stackFrames"]).and_then(|f| f.as_array()).unwrap().clone();
+  assert_eq!(frames.len(), 2);
+  assert_eq!(frames[0].get("line"), Some(&Json::Num(3.0)));
+
+  let r = request(&mut d, 6, "variables", r#"{"variablesReference":1}"#);
+  let vars = r[0].at(&["body", "variables"]).and_then(|v| v.as_array()).unwrap().clone();
+  let names: Vec<_> = vars.iter().map(|v| v.get("name").and_then(|n| n.as_str()).unwrap()).collect();
+  assert_eq!(names, vec!["a", "f", "n"]);
+  assert_eq!(vars[2].get("value"), Some(&Json::str("2")));
+
+  let r = request(&mut d, 7, "continue", r#"{"threadId":1}"#);
+  assert_eq!(event(&r, "output").and_then(|e| e.at(&["body", "output"])), Some(&Json::str("10\n")));
+  assert_eq!(event(&r, "exited").and_then(|e| e.at(&["body", "exitCode"])), Some(&Json::Num(0.0)));
+  assert!(event(&r, "terminated").is_some());
+}
+
+#[test]
+fn stepping() {
+  let mut d = Debugger::new();
+  launch(&mut d, "secd_dap_stepping.lisp", SRC, true);
+
+  let r = request(&mut d, 3, "configurationDone", "{}");
+  assert_eq!(event(&r, "stopped").and_then(|e| e.at(&["body", "reason"])), Some(&Json::str("entry")));
+
+  let line = |d: &mut Debugger| {
+    let r = request(d, 9, "stackTrace", "{}");
+    return r[0].at(&["body", "stackFrames"]).and_then(|f| f.as_array()).unwrap()[0].get("line").cloned();
+  };
+  assert_eq!(line(&mut d), Some(Json::Num(1.0)));
+  let r = request(&mut d, 4, "stepIn", "{}");
+  assert!(event(&r, "stopped").is_some());
+  assert_ne!(line(&mut d), Some(Json::Num(1.0)));
+
+  let r = request(&mut d, 5, "bogus", "{}");
+  assert_eq!(r[0].get("success"), Some(&Json::Bool(false)));
+}
+
+#[test]
+fn breaks() {
+  let mut d = Debugger::new();
+  let src = "(let a 1
+  (begin (break-if (eq a 2))
+    (puts \"before\")
+    (break)
+    (puts \"after\")))";
+  launch(&mut d, "secd_dap_breaks.lisp", src, false);
+
+  let r = request(&mut d, 3, "configurationDone", "{}");
+  assert_eq!(event(&r, "output").and_then(|e| e.at(&["body", "output"])), Some(&Json::str("before\n")));
+  assert_eq!(event(&r, "stopped").and_then(|e| e.at(&["body", "reason"])), Some(&Json::str("breakpoint")));
+  let r = request(&mut d, 4, "continue", r#"{"threadId":1}"#);
+  assert_eq!(event(&r, "output").and_then(|e| e.at(&["body", "output"])), Some(&Json::str("after\n")));
+  assert!(event(&r, "terminated").is_some());
+
+  // with no debugger they do nothing
+  assert_eq!(format!("{}", secd::run_lisp(&"(begin (break) (break-if true) 1)".to_string()).unwrap()), "1");
+}
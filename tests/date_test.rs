@@ -0,0 +1,28 @@
+extern crate secd;
+use secd::date::Date;
+
+#[test]
+fn unix_roundtrip() {
+  let d = Date::from_unix(951782400);
+  assert_eq!(d, Date { year: 2000, month: 2, day: 29, hour: 0, minute: 0, second: 0 });
+  assert_eq!(Date::from_unix(0).format("%Y-%m-%d %H:%M:%S").unwrap(), "1970-01-01 00:00:00");
+  assert_eq!(Date::from_unix(-1).format("%Y-%m-%d %H:%M:%S").unwrap(), "1969-12-31 23:59:59");
+
+  for &t in &[0, 86399, 951782400, 1700000000, 4102444800, -2208988800] {
+    assert_eq!(Date::from_unix(t).to_unix(), t);
+  }
+}
+
+#[test]
+fn format_parse() {
+  let d = Date::from_unix(1700000000);
+  let s = d.format("%d/%m/%Y %H:%M:%S 100%%").unwrap();
+  assert_eq!(s, "14/11/2023 22:13:20 100%");
+  assert_eq!(Date::parse("%d/%m/%Y %H:%M:%S 100%%", &s).unwrap(), Some(d));
+
+  assert_eq!(Date::parse("%Y-%m-%d", "2023-7-4").unwrap().map(|d| d.to_unix()), Some(1688428800));
+  assert_eq!(Date::parse("%Y-%m-%d", "2023-02-29").unwrap(), None);
+  assert_eq!(Date::parse("%Y-%m-%d", "2023-02-28x").unwrap(), None);
+  assert!(Date::parse("%Q", "").is_err());
+  assert!(d.format("%").is_err());
+}
@@ -0,0 +1,57 @@
+extern crate secd;
+use secd::*;
+use secd::diagnostic::{self, Severity};
+
+#[test]
+fn downcast() {
+  let e = Compiler::new().compile(&Parser::new(&"(let x)".into()).parse().unwrap()).unwrap_err();
+  let d = diagnostic::from_error(&*e);
+  assert_eq!(d.phase, "compile");
+  assert_eq!(d.severity, Severity::Error);
+  assert_eq!(d.message, "let syntax");
+  assert!(d.span.is_some());
+  assert_eq!(format!("{}", e), format!("{}", d));
+
+  let e = Parser::new(&"(+ 1 2))".into()).parse().unwrap_err();
+  assert_eq!(diagnostic::from_error(&*e).phase, "parse");
+
+  let e = secd::eval_lisp(&"(car 1)".into()).unwrap_err();
+  assert_eq!(diagnostic::from_error(&*e).phase, "vm");
+}
+
+#[test]
+fn several_compile_errors() {
+  let src = "(cons (if 1 2)
+  (lambda (x) (cons (let x) x)))";
+  let e = Compiler::new().compile(&Parser::new(&src.into()).parse().unwrap()).unwrap_err();
+  let ds = diagnostic::all_from_error(&*e);
+  let found: Vec<(&str, _)> = ds.iter().map(|d| (d.message.as_str(), d.span)).collect();
+  assert_eq!(found, vec![("if syntax", Some([1, 8])), ("let syntax", Some([2, 22]))]);
+  assert_eq!(diagnostic::from_error(&*e), ds[0]);
+  assert_eq!(format!("{}", e), "1:8:compile error: if syntax\n2:22:compile error: let syntax");
+  assert_eq!(diagnostic::all_from_error(&*Parser::new(&"(".into()).parse().unwrap_err()).len(), 1);
+}
+
+#[test]
+fn to_json() {
+  let d = Diagnostic::error("vm", Some([2, 7]), "say \"hi\"\n".to_string());
+  assert_eq!(d.to_json(Some("a.lisp")),
+             r#"{"phase":"vm","severity":"error","message":"say \"hi\"\n","file":"a.lisp","span":{"line":2,"column":7}}"#);
+
+  let d = Diagnostic::warning("compile", None, "x".to_string());
+  assert_eq!(d.to_json(None), r#"{"phase":"compile","severity":"warning","message":"x"}"#);
+}
+
+#[test]
+fn render() {
+  let src = "(let a 1\n  (+ a \"x\"))";
+  let e = secd::eval_lisp(&src.into()).unwrap_err();
+  let d = diagnostic::from_error(&*e);
+  assert_eq!(d.span, Some([2, 4]));
+  assert_eq!(d.render(src, Some("a.lisp"), false),
+             "error[vm]: ADD: expected int\n --> a.lisp:2:4\n  |\n2 |   (+ a \"x\"))\n  |    ^\n");
+  assert!(d.render(src, None, true).contains("\x1b[1;31m^\x1b[0m"));
+
+  let e = Parser::new(&"(+ 1 (2".into()).parse().unwrap_err();
+  assert_eq!(diagnostic::from_error(&*e).span, Some([1, 6]));
+}
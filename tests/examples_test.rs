@@ -0,0 +1,61 @@
+extern crate secd;
+use secd::*;
+use std::fs;
+use std::path::Path;
+
+// runs one example the way the CLI would and gives back everything it would print,
+// with an exit status written as `exit N`
+fn run_example(path: &Path) -> String {
+  let src = fs::read_to_string(path).unwrap();
+  let ast = match Parser::new(&src).parse() {
+    Ok(ast) => ast,
+    Err(e) => return format!("error: {}\n", e),
+  };
+  let code = match Compiler::new().compile(&ast) {
+    Ok(code) => code,
+    Err(e) => return format!("error: {}\n", e),
+  };
+
+  let mut vm = SECD::new(code);
+  let out = vm.capture();
+  let last = match Scheduler::new().run(vm) {
+    Ok(RunResult::Value(a)) => format!("{}", a),
+    Ok(RunResult::Exit(n)) => format!("exit {}", n),
+    Ok(RunResult::Yield(a)) => format!("yield {}", a),
+    Err(e) => format!("error: {}", e),
+  };
+
+  let mut printed = out.borrow().clone();
+  printed.push_str(&last);
+  printed.push('\n');
+  return printed;
+}
+
+// every examples/*.lisp must have an adjacent .expected holding its output and result
+#[test]
+fn examples() {
+  let mut paths = fs::read_dir("examples")
+    .unwrap()
+    .map(|e| e.unwrap().path())
+    .filter(|p| p.extension().map_or(false, |ext| ext == "lisp"))
+    .collect::<Vec<_>>();
+  paths.sort();
+  assert!(paths.len() > 0);
+
+  let mut failed = vec![];
+  for path in paths {
+    let expected = match fs::read_to_string(path.with_extension("expected")) {
+      Ok(s) => s,
+      Err(_) => {
+        failed.push(format!("{}: missing .expected", path.display()));
+        continue;
+      }
+    };
+    let actual = run_example(&path);
+    if actual != expected {
+      failed.push(format!("{}:\n--- expected\n{}--- actual\n{}", path.display(), expected, actual));
+    }
+  }
+
+  assert!(failed.is_empty(), "\n{}", failed.join("\n"));
+}
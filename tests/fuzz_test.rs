@@ -0,0 +1,43 @@
+extern crate secd;
+use secd::fuzz::{fuzz_parse, fuzz_compile_run};
+use secd::testing::Gen;
+
+#[test]
+fn known_inputs() {
+  let inputs: &[&[u8]] = &[
+    b"",
+    b")",
+    b"(()",
+    b"())",
+    b"99999999999",
+    b"\"abc",
+    b"(lambda)",
+    b"(1 2)",
+    b"(let x 1)",
+    b"(letrec f (lambda x (f x)) (f 0))",
+    b"(car (cdr 1))",
+    b"(\xff)",
+    b"(f)",
+    b"((lambda (a b) a) 1)",
+    b"(+ 2147483647 1)",
+    b"(join 1)",
+    b"(recv (chan))",
+    b"(system \"true\")",
+  ];
+  for input in inputs {
+    fuzz_parse(input);
+    fuzz_compile_run(input);
+  }
+}
+
+#[test]
+fn random_bytes() {
+  let alphabet = b"()0123456789 abcdefxy+-\"letrcambdifq";
+  let mut gen = Gen::new(7);
+  for _ in 0..2000 {
+    let len = gen.below(40);
+    let bytes: Vec<u8> = (0..len).map(|_| alphabet[gen.below(alphabet.len())]).collect();
+    fuzz_parse(&bytes);
+    fuzz_compile_run(&bytes);
+  }
+}
@@ -0,0 +1,35 @@
+extern crate secd;
+use secd::*;
+use secd::data::Mutable;
+
+use std::rc::Rc;
+
+#[test]
+fn code() {
+  let dot = secd::graph_code_lisp(&"(let f (lambda (x) (if (eq x 0) 1 2)) (f 0))".to_string()).unwrap();
+  assert!(dot.starts_with("digraph code {\n"));
+  assert!(dot.contains("  n1 [shape=box, label=\"LD x\\lLDC 0\\lEQ\\lTSEL\\lRET\\l\"];\n"));
+  assert!(dot.contains("  n1 -> n2 [label=\"3 then\"];\n  n1 -> n3 [label=\"3 else\"];\n"));
+  assert!(dot.contains("  n0 -> n1 [label=\"2 body\"];\n"));
+}
+
+#[test]
+fn value() {
+  let dot = secd::graph_value_lisp(&"(let a (cons 1 nil) (cons a a))".to_string(), Capabilities::default()).unwrap();
+  assert!(dot.contains("  v0 -> v1 [label=\"car\"];\n  v0 -> v1 [label=\"cdr\"];\n"));
+
+  // a cell that holds itself is one node with an edge to itself
+  let cell = Rc::new(Lisp::Cell(Rc::new(Mutable::new(Lisp::nil()))));
+  if let Lisp::Cell(ref c) = *cell {
+    *c.borrow_mut() = cell.clone();
+  }
+  assert_eq!(secd::graph::value(&cell),
+             "digraph value {\n  v0 [shape=ellipse, label=\"cell\"];\n  v0 -> v0 [label=\"value\"];\n}\n");
+
+  let dot = secd::graph::value(&Rc::new(Lisp::Str("say \"hi\"".into())));
+  assert!(dot.contains("label=\"say \\\"hi\\\"\""));
+
+  // a long list is drawn without a call per element
+  let dot = secd::graph_value_lisp(&"(range 0 200000 1)".to_string(), Capabilities::default()).unwrap();
+  assert!(dot.contains("  v399998 -> v400000 [label=\"cdr\"];\n"));
+}
@@ -0,0 +1,55 @@
+extern crate secd;
+use secd::*;
+use secd::interp::Interp;
+
+// every program here must give the same printed result through the compiler and VM
+// as through the reference interpreter
+const CORPUS: &'static [&'static str] = &[
+  "(let a 0 a)",
+  "(let a (lambda b b) (a 0))",
+  "(if (eq 0 0) 1 0)",
+  "(let a (cons 0 1) (cons (cdr a) (car a)))",
+  "(+ (- 10 3) (quotient 17 5))",
+  "(cons (+) (cons (- 4) (+ 1 2 3 (- 10 1 2))))",
+  "(cons (min 3 4) (cons (max 3 4) (abs (- 0 9))))",
+  "(cons (bit-and 12 10) (cons (bit-xor 12 10) (shr 64 3)))",
+  "(letrec fib (lambda n (if (eq n 0) 0 (if (eq n 1) 1 (+ (fib (- n 1)) (fib (- n 2)))))) (fib 15))",
+  "(let f (lambda (x y) (cons y x)) (f 1 2))",
+  "(let k (lambda x (lambda y x)) ((k 5) 6))",
+  "(case 2 ((1) 10) ((2 3) 20) (else 30))",
+  "(case 9 ((1) 10))",
+  "(do ((i 0 (+ i 1)) (acc nil (cons i acc))) ((eq i 5) acc))",
+  "(begin 1 2 3)",
+  "(cons (number->string 12) (cons (string->number \"34\") (symbol->string (quote xy))))",
+  "(eq (string->symbol \"a\") (quote a))",
+  "(let twice (lambda f (lambda x (f (f x)))) ((twice (lambda n (+ n 3))) 1))",
+  "(letrec len (lambda l (if (eq l nil) 0 (+ 1 (len (cdr l))))) (len (cons 1 (cons 2 (cons 3 nil)))))",
+  "(let x 3 (let s \"x\" \"{s} plus {x} is {(+ x x)} {{}}\"))",
+];
+
+fn vm(s: &str) -> String {
+  let code = Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap();
+  return format!("{}", SECD::new(code).run().unwrap());
+}
+
+fn interp(s: &str) -> String {
+  return format!("{}", Interp::new().eval(&Parser::new(&s.into()).parse().unwrap()).unwrap());
+}
+
+#[test]
+fn differential() {
+  for s in CORPUS {
+    assert_eq!(vm(s), interp(s), "{}", s);
+  }
+}
+
+// what the VM raises on, the interpreter reports rather than panics on
+#[test]
+fn overflow() {
+  for s in &["(+ 2147483647 1)", "(- 0 2147483647 2)", "(- (- 0 2147483647 1))", "(+ 1 2 2147483647)"] {
+    let code = Compiler::new().compile(&Parser::new(&s.to_string()).parse().unwrap()).unwrap();
+    assert!(format!("{}", SECD::new(code).run().unwrap_err()).contains("overflow"), "{}", s);
+    let r = Interp::new().eval(&Parser::new(&s.to_string()).parse().unwrap());
+    assert!(format!("{}", r.unwrap_err()).contains("interp error: overflow"), "{}", s);
+  }
+}
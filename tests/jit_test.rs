@@ -0,0 +1,55 @@
+#![cfg(feature = "jit")]
+extern crate secd;
+use secd::*;
+use secd::jit::Jit;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+fn run(s: &str) -> (Result<String, String>, usize) {
+  let code = Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap();
+  let jit = Rc::new(RefCell::new(Jit::new(2).unwrap()));
+  let mut vm = SECD::new(code);
+  vm.jit = Some(jit.clone());
+  let r = vm.run().map(|r| format!("{}", r)).map_err(|e| format!("{}", e));
+  let compiled = jit.borrow().compiled();
+  return (r, compiled);
+}
+
+#[test]
+fn hot_functions_run_natively() {
+  let fib = "(letrec fib (lambda n (if (eq n 0) 0 (if (eq n 1) 1 (+ (fib (- n 1)) (fib (- n 2)))))) (fib 18))";
+  assert_eq!(run(fib), (Ok("2584".into()), 1));
+
+  let lp = "(letrec loop (lambda (i acc) (if (eq i 0) acc (loop (- i 1) (+ acc (remainder i 7))))) \
+            (loop 100000 0))";
+  assert_eq!(run(lp), (Ok("300000".into()), 1));
+
+  let even = "(letrec even (lambda (n) (if (eq n 0) true (if (eq n 1) false (even (- n 2))))) \
+              (cons (even 10) (even 7)))";
+  assert_eq!(run(even), (Ok("(cons true false)".into()), 1));
+}
+
+#[test]
+fn falls_back_to_the_machine() {
+  // not in the subset
+  let s = "(letrec f (lambda (n) (if (eq n 0) nil (cons n (f (- n 1))))) (f 3))";
+  assert_eq!(run(s), (Ok("(cons 3 (cons 2 (cons 1 nil)))".into()), 0));
+
+  // errors are raised by the machine, which runs the call again and keeps it from then on
+  let s = "(letrec f (lambda (n) (if (eq n 0) 2147483647 (+ 1 (f (- n 1))))) (f 3))";
+  let (r, compiled) = run(s);
+  assert!(r.unwrap_err().contains("ADD: overflow"));
+  assert_eq!(compiled, 0);
+  let s = "(letrec f (lambda (n d) (if (eq n 0) (quotient 1 d) (f (- n 1) d))) (f 3 0))";
+  assert!(run(s).0.unwrap_err().contains("QUOT: division by zero"));
+
+  // deeper than native code goes
+  let s = "(letrec sum (lambda (n) (if (eq n 0) 0 (+ n (sum (- n 1))))) (sum 20000))";
+  assert_eq!(run(s), (Ok("200010000".into()), 0));
+
+  // called where its own name means something else
+  let s = "(letrec f (lambda (n) (if (eq n 0) 0 (+ 1 (f (- n 1))))) \
+           (let a (f 2) (let a (f 2) (let g f (g 3)))))";
+  assert!(run(s).0.unwrap_err().contains("unbound"));
+}
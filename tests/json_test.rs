@@ -0,0 +1,17 @@
+extern crate secd;
+use secd::json::Json;
+
+#[test]
+fn round_trip() {
+  let s = r#"{"a":[1,2.5,-3],"b":{"c":null,"d":true},"e":"x\"y\né"}"#;
+  let v = Json::parse(s).unwrap();
+  assert_eq!(v.at(&["b", "d"]), Some(&Json::Bool(true)));
+  assert_eq!(v.get("e").and_then(|e| e.as_str()), Some("x\"y\né"));
+  assert_eq!(v.get("a").and_then(|a| a.as_array()).map(|a| a.len()), Some(3));
+  assert_eq!(Json::parse(&format!("{}", v)).unwrap(), v);
+
+  assert_eq!(Json::parse(r#""😀""#).unwrap(), Json::str("😀"));
+  assert!(Json::parse("{\"a\":}").is_err());
+  assert!(Json::parse("[1, 2").is_err());
+  assert!(Json::parse("1 2").is_err());
+}
@@ -0,0 +1,113 @@
+#![cfg(feature = "jupyter")]
+extern crate secd;
+use secd::json::Json;
+use secd::jupyter::{self, Channel, Kernel, Message};
+use secd::zmtp;
+
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+fn request(msg_type: &str, content: Json) -> Message {
+  Message {
+    header: Json::obj(vec![("msg_id", Json::str("1")), ("msg_type", Json::str(msg_type))]),
+    parent: Json::obj(vec![]),
+    metadata: Json::obj(vec![]),
+    content,
+  }
+}
+
+fn execute(code: &str) -> Message {
+  request("execute_request", Json::obj(vec![("code", Json::str(code)), ("silent", Json::Bool(false))]))
+}
+
+#[test]
+fn cells() {
+  let mut kernel = Kernel::new();
+  let (out, r) = kernel.execute("(define n 10) (define f (lambda (x) (+ x n)))");
+  assert_eq!((out, format!("{}", r.unwrap().unwrap())), ("".to_string(), "(lambda [\"x\"] Code)".to_string()));
+  let (out, r) = kernel.execute("(puts 1) (f 5)");
+  assert_eq!((out, format!("{}", r.unwrap().unwrap())), ("1\n".to_string(), "15".to_string()));
+  assert!(kernel.execute("(car 1)").1.is_err());
+  assert_eq!(format!("{}", kernel.execute("n").1.unwrap().unwrap()), "10");
+  assert!(kernel.execute("").1.unwrap().is_none());
+
+  let sent = kernel.handle(&execute("(puts 2) (f 1)"));
+  let types: Vec<(Channel, &str)> = sent.iter().map(|s| (s.0, s.1.msg_type())).collect();
+  assert_eq!(types, vec![(Channel::IOPub, "status"), (Channel::IOPub, "execute_input"),
+                         (Channel::IOPub, "stream"), (Channel::IOPub, "execute_result"),
+                         (Channel::Reply, "execute_reply"), (Channel::IOPub, "status")]);
+  assert_eq!(sent[3].1.content.at(&["data", "text/plain"]), Some(&Json::str("11")));
+  assert_eq!(sent[4].1.parent.get("msg_id"), Some(&Json::str("1")));
+
+  let sent = kernel.handle(&execute("(f"));
+  assert_eq!(sent[2].1.msg_type(), "error");
+  assert_eq!(sent[3].1.content.get("status"), Some(&Json::str("error")));
+}
+
+#[test]
+fn signing() {
+  let parts = vec![b"The quick brown fox ".to_vec(), b"jumps over the lazy dog".to_vec()];
+  assert_eq!(jupyter::sign(b"key", &parts),
+             "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8");
+  assert_eq!(jupyter::sign(b"", &parts), "");
+
+  let msg = execute("1");
+  let mut frames = vec![b"identity".to_vec()];
+  frames.extend(msg.encode(b"secret"));
+  let decoded = Message::decode(&frames, b"secret").unwrap();
+  assert_eq!(decoded.content, msg.content);
+  assert!(Message::decode(&frames, b"other").is_err());
+}
+
+fn free_port() -> u16 {
+  TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn connect(port: u16, socket_type: &str) -> TcpStream {
+  for _ in 0..100 {
+    if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)) {
+      zmtp::handshake(&mut stream, socket_type).unwrap();
+      return stream;
+    }
+    thread::sleep(Duration::from_millis(20));
+  }
+  panic!("kernel is not listening on {}", port);
+}
+
+#[test]
+fn serve() {
+  let names = ["shell_port", "control_port", "stdin_port", "hb_port", "iopub_port"];
+  let mut conn = vec![("ip", Json::str("127.0.0.1")), ("key", Json::str("k"))];
+  let ports: Vec<u16> = names.iter().map(|_| free_port()).collect();
+  for (name, port) in names.iter().zip(ports.iter()) {
+    conn.push((*name, Json::Num(*port as f64)));
+  }
+  let path = std::env::temp_dir().join(format!("secd-kernel-{}.json", ports[0]));
+  fs::write(&path, format!("{}", Json::obj(conn))).unwrap();
+  let file = path.to_str().unwrap().to_string();
+  let kernel = thread::spawn(move || jupyter::serve(&file).unwrap());
+
+  let mut hb = connect(ports[3], "REQ");
+  zmtp::write_message(&mut hb, &[b"ping".to_vec()]).unwrap();
+  assert_eq!(zmtp::read_message(&mut hb).unwrap(), Some(vec![b"ping".to_vec()]));
+
+  let mut shell = connect(ports[0], "DEALER");
+  let ask = |shell: &mut TcpStream, msg: Message| {
+    zmtp::write_message(shell, &msg.encode(b"k")).unwrap();
+    Message::decode(&zmtp::read_message(shell).unwrap().unwrap(), b"k").unwrap()
+  };
+  let reply = ask(&mut shell, request("kernel_info_request", Json::obj(vec![])));
+  assert_eq!(reply.msg_type(), "kernel_info_reply");
+  assert_eq!(reply.content.at(&["language_info", "name"]), Some(&Json::str("lisp")));
+
+  let reply = ask(&mut shell, execute("(+ 1 2)"));
+  assert_eq!(reply.content.get("status"), Some(&Json::str("ok")));
+  assert_eq!(reply.content.get("execution_count"), Some(&Json::Num(1.0)));
+
+  let reply = ask(&mut shell, request("shutdown_request", Json::obj(vec![("restart", Json::Bool(false))])));
+  assert_eq!(reply.msg_type(), "shutdown_reply");
+  kernel.join().unwrap();
+  let _ = fs::remove_file(&path);
+}
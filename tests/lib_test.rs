@@ -0,0 +1,173 @@
+extern crate secd;
+use secd::*;
+use std::rc::Rc;
+
+#[test]
+fn run_many() {
+  let programs = vec![
+    "(+ 1 2)",
+    "(exit 7)",
+    "(car 1)",
+    "(letrec f (lambda n (if (eq n 0) 0 (+ n (f (- n 1))))) (f 100))",
+  ];
+  let programs = programs.iter()
+    .map(|s| Compiler::new().compile(&Parser::new(&s.to_string()).parse().unwrap()).unwrap())
+    .collect();
+  let r = secd::run_many(programs, Capabilities::default());
+
+  assert_eq!(r.len(), 4);
+  assert_eq!(r[0], Ok(Outcome::Value("3".to_string())));
+  assert_eq!(r[1], Ok(Outcome::Exit(7)));
+  assert!(r[2].is_err());
+  assert_eq!(r[3], Ok(Outcome::Value("5050".to_string())));
+}
+
+#[test]
+fn test_lisp() {
+  let src = "(test-group \"g\"\n  (test \"one\" 1 (+ 0 1))\n  (test \"two\" (cons 1 nil) nil)\n  (car 1))";
+  let (tests, error) = secd::test_lisp(&src.to_string(), Capabilities::default());
+  let found: Vec<(&str, bool)> = tests.iter().map(|t| (t.name.as_str(), t.passed)).collect();
+  assert_eq!(found, vec![("g / one", true), ("g / two", false)]);
+  assert_eq!((tests[1].info, tests[1].expected.as_str(), tests[1].actual.as_str()),
+             ([3, 4], "(cons 1 nil)", "nil"));
+  assert_eq!(format!("{}", error.unwrap()), "4:4:vm error: CAR: expected Cons");
+
+  let (tests, error) = secd::test_lisp(&"(test 1 2 3)".to_string(), Capabilities::default());
+  assert!(tests.is_empty() && error.is_some());
+
+  // tests run under the scheduler, so threads join and their own tests count
+  let src = "(let t (spawn (lambda () (test \"in thread\" 2 (+ 1 1))))\n  (test \"joined\" true (join t)))";
+  let (tests, error) = secd::test_lisp(&src.to_string(), Capabilities::default());
+  let found: Vec<(&str, bool)> = tests.iter().map(|t| (t.name.as_str(), t.passed)).collect();
+  assert_eq!(found, vec![("in thread", true), ("joined", true)]);
+  assert!(error.is_none());
+}
+
+#[test]
+fn cover_lisp() {
+  let src = "(letrec f (lambda (n)\n  (if (eq n 0)\n    0\n    (+ n (f (- n 1)))))\n  (if (eq (f 3) 7) (car 1)\n    nil))";
+  let (r, lines) = secd::cover_lisp(&src.to_string(), Capabilities::default());
+  assert!(r.is_ok());
+  let found: Vec<(usize, usize)> = lines.into_iter().collect();
+  assert_eq!(found, vec![(1, 1), (2, 4), (3, 1), (4, 3), (5, 1), (6, 1)]);
+  assert_eq!(secd::coverage::lcov("a.lisp", &[(1, 2), (2, 0)].iter().cloned().collect()),
+             "TN:\nSF:a.lisp\nDA:1,2\nDA:2,0\nLF:2\nLH:1\nend_of_record\n");
+}
+
+#[test]
+fn stats_lisp() {
+  let src = "(let f (lambda (x) (cons x (+ x 5000))) (cons (f 1) (f 2)))";
+  let (r, stats) = secd::stats_lisp(&src.to_string(), Capabilities::default());
+  assert!(r.is_ok());
+  let allocated: Vec<(&str, usize)> = stats.allocated.into_iter().collect();
+  assert_eq!(allocated, vec![("cons", 3), ("int", 2), ("list", 2)]);
+  assert_eq!((stats.peak_stack, stats.peak_dump, stats.env_clones), (4, 1, 4));
+
+  let ast = Parser::new(&"(+ 1 2)".to_string()).parse().unwrap();
+  let mut vm = SECD::new(Compiler::new().compile(&ast).unwrap());
+  assert_eq!(vm.stats(), None);
+  vm.keep_stats();
+  vm.run().unwrap();
+  assert_eq!(vm.stats().unwrap().allocated.len(), 0);
+}
+
+#[test]
+fn budget_lisp() {
+  let budget = |src: &str, fatal: bool| {
+    let (r, warnings) = secd::budget_lisp(&src.to_string(), Capabilities::default(), 200, fatal);
+    (r.map(|r| format!("{:?}", r)).map_err(|e| format!("{}", e)),
+     warnings.into_iter().map(|w| (w.message, w.span)).collect::<Vec<_>>())
+  };
+  // the outer call of a deep recursion is reported, once however many of its calls go over
+  let src = "(letrec sum (lambda (n) (if (eq n 0) 0 (+ n (sum (- n 1)))))\n  (sum 100))";
+  let (r, warnings) = budget(src, false);
+  assert!(r.is_ok());
+  assert_eq!(warnings,
+             vec![("sum (lambda 1:33) has taken more than 200 steps in one call".to_string(), Some([2, 4]))]);
+
+  // a loop of tail calls is one call, and a short call is never reported
+  let src = "(letrec loop (lambda (n) (if (eq n 0) 0 (loop (- n 1)))) (begin (loop 3) (loop 1000)))";
+  assert_eq!(budget(src, false).1.len(), 1);
+  assert!(budget("(let f (lambda (x) (+ x 1)) (f 1))", false).1.is_empty());
+  // a function goes by the name it was first bound to, whatever the caller calls it
+  let src = "(let walk (lambda (n) (letrec loop (lambda (i) (if (eq i 0) 0 (loop (- i 1)))) (cons (loop n) nil)))
+  (let go walk (cons (go 1000) nil)))";
+  let names: Vec<String> = budget(src, false).1.into_iter().map(|w| w.0).collect();
+  assert_eq!(names,
+             vec!["walk (lambda 1:37) has taken more than 200 steps in one call",
+                  "loop (lambda 1:56) has taken more than 200 steps in one call"]);
+
+  let src = "((lambda (n) (letrec loop (lambda (n) (loop n)) (loop n))) 1)";
+  let (r, warnings) = budget(src, true);
+  assert!(warnings.is_empty());
+  assert!(r.unwrap_err().contains("loop (lambda 1:45) has taken more than 200 steps in one call"));
+}
+
+#[test]
+fn expand_lisp() {
+  let src = "(cond-expand ((not secd) 0) (else (do ((i 0 (+ i 1))) ((eq i 3) i) (puts i))))".to_string();
+  assert_eq!(secd::expand_lisp(&src).unwrap(),
+             "(begin (letrec do-1:36 (lambda (i) (if (eq i 3) i (begin (puts i) (do-1:36 (+ i 1))))) (do-1:36 0)))");
+
+  // quoted forms are data
+  let src = "(quote (do () (1 2)))".to_string();
+  assert_eq!(secd::expand_lisp(&src).unwrap(), "(quote (do () (1 2)))");
+
+  assert!(secd::expand_lisp(&"(do x)".to_string()).is_err());
+}
+
+#[test]
+fn teach_lisp() {
+  let mut out = vec![];
+  let r = secd::teach_lisp(&"(let f (lambda (x) (+ x 1)) (f 2))".to_string(), Capabilities::default(), &mut out);
+  assert_eq!(r.unwrap(), RunResult::Value(Lisp::int(3)));
+  let out = String::from_utf8(out).unwrap();
+  assert!(out.starts_with("  S: []\n  E: []\n  C: [HEADER 1 CONSTS LDC #1 LET f +4]\n  D: []\n"));
+  assert!(out.contains("step 8: AP\n  S: []\n  E: [x=2]\n  C: [LD x LDC 1 ADD RET]\n  D: [AP]\n"));
+  assert!(out.ends_with("step 12: RET\n  S: [3]\n  E: [f=(lambda [\"x\"] Code)]\n  C: []\n  D: []\n"));
+
+  // a cell holding itself is cut short
+  let cell = Rc::new(Lisp::Cell(Rc::new(secd::data::Mutable::new(Lisp::nil()))));
+  if let Lisp::Cell(ref c) = *cell {
+    *c.borrow_mut() = cell.clone();
+  }
+  assert_eq!(cell.show(20), "(cell (cell (cell (c...");
+}
+
+#[test]
+fn trace_lisp() {
+  let phases = |src: &str| {
+    let (_, json) = secd::trace_lisp(&src.to_string(), Capabilities::default());
+    let json = secd::json::Json::parse(&json).unwrap();
+    json.get("traceEvents").unwrap().as_array().unwrap().iter().map(|e| {
+      format!("{}{} {}", e.get("ph").unwrap().as_str().unwrap(), e.get("tid").unwrap().as_i64().unwrap(),
+              e.get("name").unwrap().as_str().unwrap())
+    }).collect::<Vec<_>>()
+  };
+
+  let fib = "(letrec fib (lambda (n) (if (eq n 0) 0 (if (eq n 1) 1 (+ (fib (- n 1)) (fib (- n 2)))))) (fib 2))";
+  assert_eq!(phases(fib), vec!["B0 lambda 1:33", "B0 lambda 1:33", "E0 lambda 1:33", "B0 lambda 1:33",
+                               "E0 lambda 1:33", "E0 lambda 1:33"]);
+
+  // a tail call ends the span of the call it replaces
+  let tail = "(letrec loop (lambda (n) (if (eq n 0) 0 (loop (- n 1)))) (loop 1))";
+  assert_eq!(phases(tail), vec!["B0 lambda 1:34", "E0 lambda 1:34", "B0 lambda 1:34", "E0 lambda 1:34"]);
+
+  // threads get their own track
+  let events = phases("(join (spawn (lambda () 1)))");
+  assert_eq!(events.len(), 2);
+  assert!(events[0].starts_with("B") && events[1].starts_with("E") && !events[0].starts_with("B0"));
+
+  // calls in progress when the program stops are ended there
+  assert_eq!(phases("(let f (lambda (x) (car x)) (f 1))"), vec!["B0 lambda 1:25", "E0 lambda 1:25"]);
+}
+
+#[test]
+fn compile_lisp() {
+  // the warnings come with the code, which runs as eval_lisp would run it
+  let s = "(define-variant shape (circle r) (rect w h) (match (circle 2) ((circle r) r)))".to_string();
+  let p = secd::compile_lisp(&s, Capabilities::default()).unwrap();
+  let warnings: Vec<(&str, String)> = p.warnings.iter().map(|w| (w.phase, format!("{}", w))).collect();
+  assert_eq!(warnings, vec![("compile", "1:46:compile warning: match on shape has no clause for rect".to_string())]);
+  assert_eq!(secd::eval_code_with(p.code, Capabilities::default()).unwrap(), RunResult::Value(Lisp::int(2)));
+}
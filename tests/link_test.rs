@@ -0,0 +1,95 @@
+extern crate secd;
+use secd::*;
+use secd::data::CodeOP;
+
+fn unit(name: &str, src: &str) -> link::Unit {
+  secd::compile_unit(name, &src.to_string(), Capabilities::default()).unwrap()
+}
+
+fn run(units: &[link::Unit]) -> String {
+  match secd::eval_units_with(units, Capabilities::default()).unwrap() {
+    RunResult::Value(a) => format!("{}", a),
+    r => panic!("{:?}", r),
+  }
+}
+
+fn link_errors(units: &[link::Unit]) -> Vec<String> {
+  let e = link::link(units).unwrap_err();
+  diagnostic::all_from_error(&*e).into_iter().map(|d| d.message).collect()
+}
+
+#[test]
+fn units() {
+  let a = unit("a.lisp",
+               "(define even (lambda (n) (if (eq n 0) true (odd (- n 1)))))
+                (define greeting \"hello\")");
+  let b = unit("b.lisp",
+               "(define odd (lambda (n) (if (eq n 0) false (even (- n 1)))))
+                (define inc (lambda (x) (+ x 1)))");
+  let main = unit("main.lisp", "(cons greeting (cons (even 10) (cons (odd 7) (let m map (m inc (cons 1 nil))))))");
+  assert_eq!(a.defines.iter().map(|d| d.0.as_str()).collect::<Vec<_>>(), vec!["even", "greeting"]);
+  assert_eq!(a.imports.iter().map(|i| i.0.as_str()).collect::<Vec<_>>(), vec!["odd"]);
+  assert_eq!(main.prelude.iter().map(|p| p.0.as_str()).collect::<Vec<_>>(), vec!["map"]);
+  assert!(a.entry.is_none() && main.entry.is_some());
+
+  let expected = "(cons hello (cons true (cons true (cons 2 nil))))";
+  assert_eq!(run(&[a, b, main]), expected);
+
+  // the units importing from others are bound after them whatever the order given
+  let main = unit("main.lisp", "(twice 5)");
+  let twice = unit("twice.lisp", "(define twice (lambda (x) (double (double x))))");
+  let double = unit("double.lisp", "(define double (lambda (x) (+ x x)))");
+  let code = link::link(&[main, twice, double]).unwrap();
+  let lets: Vec<&str> = code.iter()
+    .filter_map(|c| match c.op {
+      CodeOP::LET(ref id) => Some(id.as_str()),
+      _ => None,
+    })
+    .collect();
+  assert_eq!(lets, vec!["double", "twice"]);
+  match (&code[0].op, &code[1].op) {
+    (&CodeOP::HEADER(_), &CodeOP::CONSTS(_)) => {}
+    ops => panic!("{:?}", ops),
+  }
+
+  // a record's functions are defines of the unit
+  let shapes = unit("shapes.lisp", "(define-record point (x y))");
+  assert_eq!(shapes.defines.iter().map(|d| d.0.as_str()).collect::<Vec<_>>(),
+             vec!["make-point", "point?", "point-x", "point-y"]);
+  let main = unit("main.lisp", "(point-y (make-point 1 2))");
+  assert_eq!(run(&[main, shapes]), "2");
+}
+
+#[test]
+fn pools() {
+  // each unit's constants, lifted lambdas among them, load from their place in the
+  // program's pool
+  let a = unit("a.lisp", "(define a \"from a\") (define k (lambda (x) (cons \"k\" x)))");
+  let b = unit("b.lisp", "(define b \"from b\") (begin (puts b) (k (cons a nil)))");
+  assert_eq!(run(&[a, b]), "(cons k (cons from a nil))");
+
+  // a unit's define of a prelude name is the one the others use
+  let a = unit("a.lisp", "(define map (lambda (f ls) 42))");
+  let b = unit("b.lisp", "(let m map (m 1 nil))");
+  assert_eq!(run(&[a, b]), "42");
+}
+
+#[test]
+fn errors() {
+  let a = unit("a.lisp", "(define f (lambda (x) (g x)))");
+  let b = unit("b.lisp", "(define f 1)\n(f 2)");
+  let c = unit("c.lisp", "(f 3)");
+  assert_eq!(link_errors(&[a, b, c]),
+             vec!["f is defined in both a.lisp:1:9 and b.lisp:1:9",
+                  "g is used at a.lisp:1:24 but no unit defines it",
+                  "b.lisp and c.lisp both have an entry expression"]);
+  assert_eq!(link_errors(&[unit("a.lisp", "(define x 1)")]), vec!["no unit has an entry expression"]);
+
+  let compile = |src: &str| {
+    let e = secd::compile_unit("a.lisp", &src.to_string(), Capabilities::default()).err().unwrap();
+    format!("{}", e)
+  };
+  assert_eq!(compile("(define x 1)\n(define x 2)"), "2:1:compile error: x is defined twice");
+  assert!(compile("1\n2").contains("a unit has one entry expression"));
+  assert!(compile("(define (f) 1)").contains("define id syntax"));
+}
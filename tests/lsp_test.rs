@@ -0,0 +1,84 @@
+extern crate secd;
+use secd::json::Json;
+use secd::lsp;
+
+const SRC: &'static str = "(let a 1\n  (letrec f (lambda n (if (eq n 0) a (f (- n 1))))\n    (f 3)))";
+
+#[test]
+fn diagnostics() {
+  assert!(lsp::diagnostics(SRC).is_empty());
+  let ds = lsp::diagnostics("(let x)");
+  assert_eq!(ds.len(), 1);
+  assert_eq!(ds[0].phase, "compile");
+  assert_eq!(lsp::diagnostics("(+ 1").len(), 1);
+  let ds = lsp::diagnostics("(let car 1 car)");
+  assert_eq!((ds[0].severity, ds[0].span), (secd::diagnostic::Severity::Warning, Some([1, 6])));
+}
+
+#[test]
+fn hover() {
+  let h = lsp::hover(SRC, [3, 5]).unwrap();
+  assert!(h.contains("LDC(Int(3))"), "{}", h);
+  assert!(h.contains("AP"), "{}", h);
+  assert_eq!(lsp::hover("(+ 1", [1, 1]), None);
+}
+
+#[test]
+fn definition() {
+  // `a` inside the if refers to the let
+  assert_eq!(lsp::definition(SRC, [2, 36]), Some([1, 6]));
+  // the recursive call refers to letrec
+  assert_eq!(lsp::definition(SRC, [2, 39]), Some([2, 11]));
+  // lambda parameter
+  assert_eq!(lsp::definition(SRC, [2, 31]), Some([2, 21]));
+  assert_eq!(lsp::definition(SRC, [3, 8]), None);
+  // annotated lambda parameter
+  assert_eq!(lsp::definition("(lambda ((x : int)) x)", [1, 21]), Some([1, 11]));
+}
+
+#[test]
+fn completion() {
+  let names = lsp::completion(SRC, [2, 36]);
+  assert_eq!(&names[..3], &["n".to_string(), "f".to_string(), "a".to_string()]);
+  assert!(names.contains(&"lambda".to_string()));
+  assert!(!lsp::completion(SRC, [1, 2]).contains(&"a".to_string()));
+}
+
+fn frame(msg: &str) -> String {
+  return format!("Content-Length: {}\r\n\r\n{}", msg.len(), msg);
+}
+
+fn replies(out: &[u8]) -> Vec<Json> {
+  let out = String::from_utf8(out.to_vec()).unwrap();
+  return out.split("Content-Length: ")
+    .filter(|m| !m.is_empty())
+    .map(|m| Json::parse(m.split_once("\r\n\r\n").unwrap().1).unwrap())
+    .collect();
+}
+
+#[test]
+fn session() {
+  let mut input = String::new();
+  input.push_str(&frame(r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#));
+  input.push_str(&frame(r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#));
+  input.push_str(&frame(r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.lisp","text":"(let x)"}}}"#));
+  input.push_str(&frame(r#"{"jsonrpc":"2.0","method":"textDocument/didSave","params":{"textDocument":{"uri":"file:///a.lisp"},"text":"(let x 1 x)"}}"#));
+  input.push_str(&frame(r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/definition","params":{"textDocument":{"uri":"file:///a.lisp"},"position":{"line":0,"character":9}}}"#));
+  input.push_str(&frame(r#"{"jsonrpc":"2.0","id":3,"method":"bogus"}"#));
+  input.push_str(&frame(r#"{"jsonrpc":"2.0","id":4,"method":"shutdown"}"#));
+  input.push_str(&frame(r#"{"jsonrpc":"2.0","method":"exit"}"#));
+
+  let mut out = vec![];
+  lsp::serve(input.as_bytes(), &mut out).unwrap();
+  let r = replies(&out);
+  assert_eq!(r.len(), 6);
+
+  assert_eq!(r[0].at(&["result", "capabilities", "hoverProvider"]), Some(&Json::Bool(true)));
+  let ds = r[1].at(&["params", "diagnostics"]).and_then(|d| d.as_array()).unwrap();
+  assert_eq!(ds.len(), 1);
+  let ds = r[2].at(&["params", "diagnostics"]).and_then(|d| d.as_array()).unwrap();
+  assert_eq!(ds.len(), 0);
+  assert_eq!(r[3].at(&["result", "range", "start", "character"]), Some(&Json::Num(5.0)));
+  assert_eq!(r[4].at(&["error", "code"]), Some(&Json::Num(-32601.0)));
+  assert_eq!(r[5].get("result"), Some(&Json::Null));
+}
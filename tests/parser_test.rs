@@ -7,3 +7,11 @@ fn parser() {
   assert!(a.is_ok());
   assert_eq!(format!("{}", a.unwrap()), "(a 0 ab 12 (a (b) ()) () ab ())".to_string());
 }
+
+#[test]
+fn string() {
+  let a = Parser::new(&"(puts \"a \\\"b\\\"\\n c\")".into()).parse();
+  assert!(a.is_ok());
+  assert_eq!(format!("{}", a.unwrap()), "(puts \"a \\\"b\\\"\\n c\")".to_string());
+  assert!(Parser::new(&"(puts \"abc)".into()).parse().is_err());
+}
@@ -0,0 +1,41 @@
+extern crate secd;
+use secd::*;
+use secd::interp::Interp;
+use secd::testing::Gen;
+
+use std::panic;
+
+const CASES: u64 = 300;
+
+#[test]
+fn print_parse_round_trip() {
+  for seed in 1..CASES {
+    let ast = Gen::new(seed).int_expr(5, &mut vec![]);
+    let printed = format!("{}", ast);
+    let reparsed = Parser::new(&printed).parse().unwrap();
+    assert_eq!(format!("{}", reparsed), printed, "seed {}", seed);
+  }
+}
+
+#[test]
+fn vm_agrees_with_interp() {
+  for seed in 1..CASES {
+    let ast = Gen::new(seed).int_expr(5, &mut vec![]);
+    let code = Compiler::new().compile(&ast).unwrap();
+    let vm = SECD::new(code).run().unwrap();
+    let interp = Interp::new().eval(&ast).unwrap();
+    assert_eq!(format!("{}", vm), format!("{}", interp), "seed {}: {}", seed, ast);
+  }
+}
+
+#[test]
+fn vm_never_panics() {
+  for seed in 1..CASES {
+    let ast = Gen::new(seed).any_expr(4);
+    let r = panic::catch_unwind(|| match Compiler::new().compile(&ast) {
+      Ok(code) => { let _ = SECD::new(code).run(); }
+      Err(_) => {}
+    });
+    assert!(r.is_ok(), "seed {}: {}", seed, ast);
+  }
+}
@@ -0,0 +1,120 @@
+extern crate secd;
+use secd::compiler::Compiler;
+use secd::data::{CodeOP, CodeOPInfo};
+use secd::json::{self, Json};
+use secd::parser::Parser;
+use secd::serialize;
+use secd::serve;
+use secd::vm::Primitives;
+
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+fn eval(params: Vec<(&str, Json)>) -> Json {
+  serve::handle(&Json::obj(vec![("jsonrpc", Json::str("2.0")), ("id", Json::Num(7.0)),
+                                ("method", Json::str("eval")), ("params", Json::obj(params))]))
+}
+
+#[test]
+fn handle() {
+  let r = eval(vec![("source", Json::str("(begin (puts 1) (+ 1 2))"))]);
+  assert_eq!(r.get("id"), Some(&Json::Num(7.0)));
+  assert_eq!(r.at(&["result", "value"]), Some(&Json::str("3")));
+  assert_eq!(r.at(&["result", "output"]), Some(&Json::str("1\n")));
+
+  let r = eval(vec![("source", Json::str("(exit 3)"))]);
+  assert_eq!(r.at(&["result", "exit"]), Some(&Json::Num(3.0)));
+
+  let r = eval(vec![("source", Json::str("(car 1)"))]);
+  assert_eq!(r.at(&["error", "code"]), Some(&Json::Num(-32000.0)));
+  assert_eq!(r.at(&["error", "data"]).and_then(|d| d.as_array()).map(|d| d.len()), Some(1));
+
+  let r = eval(vec![("source", Json::str("(car"))]);
+  assert_eq!(r.at(&["error", "code"]), Some(&Json::Num(-32000.0)));
+
+  let r = eval(vec![("source", Json::str("(read-file \"/etc/passwd\")"))]);
+  assert_eq!(r.at(&["error", "code"]), Some(&Json::Num(-32000.0)));
+
+  let r = eval(vec![]);
+  assert_eq!(r.at(&["error", "code"]), Some(&Json::Num(-32602.0)));
+  let r = eval(vec![("bytecode", Json::str("zz"))]);
+  assert_eq!(r.at(&["error", "code"]), Some(&Json::Num(-32602.0)));
+
+  let r = serve::handle(&Json::obj(vec![("id", Json::Num(1.0)), ("method", Json::str("shutdown"))]));
+  assert_eq!(r.at(&["error", "code"]), Some(&Json::Num(-32601.0)));
+}
+
+#[test]
+fn limits() {
+  let forever = "(letrec f (lambda (n) (f (+ n 1))) (f 0))";
+  let r = eval(vec![("source", Json::str(forever)), ("fuel", Json::Num(1000.0))]);
+  assert_eq!(r.at(&["error", "code"]), Some(&Json::Num(-32000.0)));
+
+  let growing = "(letrec f (lambda (l) (f (cons 1 l))) (f nil))";
+  let r = eval(vec![("source", Json::str(growing)), ("memory", Json::Num(1000.0))]);
+  let message = r.at(&["error", "message"]).and_then(|m| m.as_str()).unwrap_or("").to_string();
+  assert!(message.contains("out of memory"), "{}", message);
+
+  let r = eval(vec![("source", Json::str("(+ 1 2)")), ("fuel", Json::Num(1000.0)), ("memory", Json::Num(1000.0))]);
+  assert_eq!(r.at(&["result", "value"]), Some(&Json::str("3")));
+
+  // a request without limits still has them, and asking for more gets the maximum
+  let forever = "(letrec f (lambda x (f x)) (f 0))";
+  let r = eval(vec![("source", Json::str(forever))]);
+  let message = r.at(&["error", "message"]).and_then(|m| m.as_str()).unwrap_or("").to_string();
+  assert!(message.contains("out of"), "{}", message);
+  let r = eval(vec![("source", Json::str(forever)), ("fuel", Json::Num(1e12))]);
+  let message = r.at(&["error", "message"]).and_then(|m| m.as_str()).unwrap_or("").to_string();
+  assert!(message.contains("out of"), "{}", message);
+}
+
+#[test]
+fn bytecode() {
+  let ast = Parser::new(&"(+ 40 2)".to_string()).parse().unwrap();
+  let code = Compiler::new().compile(&ast).unwrap();
+  let bytes = serialize::write_code(&code, &Primitives::standard()).unwrap();
+  let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+  let r = eval(vec![("bytecode", Json::Str(hex))]);
+  assert_eq!(r.at(&["result", "value"]), Some(&Json::str("42")));
+
+  // code the compiler wouldn't make fails the request rather than the server
+  let code = vec![CodeOPInfo { info: [1, 1], op: CodeOP::RET }];
+  let bytes = serialize::write_code(&code, &Primitives::standard()).unwrap();
+  let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+  let r = eval(vec![("bytecode", Json::Str(hex))]);
+  let message = r.at(&["error", "message"]).and_then(|m| m.as_str()).unwrap_or("").to_string();
+  assert!(message.contains("stack underflow"), "{}", message);
+  let code = vec![CodeOPInfo { info: [1, 1], op: CodeOP::LDC(0) }, CodeOPInfo { info: [1, 2], op: CodeOP::RET }];
+  let bytes = serialize::write_code(&code, &Primitives::standard()).unwrap();
+  let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+  let r = eval(vec![("bytecode", Json::Str(hex))]);
+  assert_eq!(r.at(&["error", "code"]), Some(&Json::Num(-32000.0)));
+}
+
+#[test]
+fn tcp() {
+  let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+  thread::spawn(move || {
+    let _ = serve::serve(&format!("127.0.0.1:{}", port));
+  });
+  let mut stream = None;
+  for _ in 0..100 {
+    if let Ok(s) = TcpStream::connect(("127.0.0.1", port)) {
+      stream = Some(s);
+      break;
+    }
+    thread::sleep(Duration::from_millis(20));
+  }
+  let mut output = stream.expect("server is not listening");
+  let mut input = BufReader::new(output.try_clone().unwrap());
+  for (i, source) in ["(+ 1 2)", "(- 7 1)"].iter().enumerate() {
+    let req = Json::obj(vec![("jsonrpc", Json::str("2.0")), ("id", Json::Num(i as f64)),
+                             ("method", Json::str("eval")),
+                             ("params", Json::obj(vec![("source", Json::str(source))]))]);
+    json::write_message(&mut output, &req).unwrap();
+    let r = json::read_message(&mut input).unwrap().unwrap();
+    assert_eq!(r.get("id"), Some(&Json::Num(i as f64)));
+  }
+}
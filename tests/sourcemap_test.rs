@@ -0,0 +1,53 @@
+extern crate secd;
+use secd::*;
+use secd::data::CodeOP;
+use secd::sourcemap;
+
+fn compile(s: &str) -> (secd::data::Code, sourcemap::SourceMap) {
+  return Compiler::new().compile_with_map(&Parser::new(&s.into()).parse().unwrap()).unwrap();
+}
+
+#[test]
+fn if_branches() {
+  // preorder: 0 (if ..), 1 if, 2 (eq 1 2), 3 eq, 4 1, 5 2, 6 (+ 1 2), 7 +, 8 1, 9 2, 10 3
+  let (code, map) = compile("(if (eq 1 2) (+ 1 2) 3)");
+
+  let whole = map.ranges_of(0);
+  assert_eq!(whole.len(), 1);
+  assert_eq!((whole[0].block.clone(), whole[0].start, whole[0].end), (vec![], 2, 6));
+  assert_eq!(whole[0].span, [1, 1]);
+
+  // the program starts with its header and by installing its constants
+  assert!(matches!(code[0].op, CodeOP::HEADER(_)));
+  assert!(matches!(code[1].op, CodeOP::CONSTS(_)));
+  assert_eq!(map.expr_at(&[], 4).map(|r| r.expr), Some(2));
+  assert_eq!(map.expr_at(&[], 5).map(|r| r.expr), Some(0));
+  assert_eq!(map.expr_at(&[(5, 0)], 2).map(|r| r.expr), Some(6));
+  assert_eq!(map.expr_at(&[(5, 1)], 0).map(|r| r.expr), Some(10));
+  assert_eq!(map.expr_at(&[(5, 1)], 1), None);
+
+  let f = sourcemap::block(&code, &[(5, 1)]).unwrap();
+  assert_eq!(f.len(), 2);
+  assert_eq!(f[1].op, CodeOP::JOIN);
+  assert!(sourcemap::block(&code, &[(0, 0)]).is_none());
+}
+
+#[test]
+fn lambda_body() {
+  // preorder: 0 (let ..), 1 let, 2 f, 3 (lambda ..), 4 lambda, 5 x, 6 (+ x 1), 7 +, 8 x, 9 1
+  let (code, map) = compile("(let f (lambda x (+ x 1)) (f 2))");
+  let body = map.ranges_of(6);
+  assert_eq!(body.len(), 1);
+  let path = body[0].block.clone();
+  let block = sourcemap::block(&code, &path).unwrap();
+  assert_eq!(block[body[0].end].op, CodeOP::RET);
+  assert_eq!(map.expr_at(&path, 0).map(|r| r.expr), Some(8));
+
+  // desugared code is attributed to the source expression around it
+  let (_, map) = compile("(do ((i 0 (+ i 1))) ((eq i 3) i))");
+  assert!(map.ranges.iter().all(|r| r.expr < 16));
+  assert!(map.ranges_of(0).len() > 0);
+
+  let json = format!("{}", map.to_json());
+  assert!(json.starts_with("{\"ranges\":[{\"expr\":"));
+}
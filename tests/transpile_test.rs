@@ -0,0 +1,62 @@
+extern crate secd;
+use secd::*;
+
+use std::env;
+use std::fs;
+use std::process::Command;
+
+// builds `s` with rustc and runs it; None when there is no rustc to build with
+fn native(name: &str, s: &str) -> Option<(String, String, bool)> {
+  if Command::new("rustc").arg("--version").output().is_err() {
+    return None;
+  }
+  let dir = env::temp_dir().join(format!("secd-transpile-{}-{}", name, std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let src = dir.join("out.rs");
+  let bin = dir.join("out");
+  fs::write(&src, build_lisp(&s.into()).unwrap()).unwrap();
+  let rustc = Command::new("rustc").arg("--edition=2021").arg(&src).arg("-o").arg(&bin).output().unwrap();
+  assert!(rustc.status.success(), "{}", String::from_utf8_lossy(&rustc.stderr));
+  let out = Command::new(&bin).output().unwrap();
+  fs::remove_dir_all(&dir).unwrap();
+  return Some((String::from_utf8(out.stdout).unwrap(), String::from_utf8(out.stderr).unwrap(), out.status.success()));
+}
+
+#[test]
+fn builds_programs_that_print_what_the_machine_returns() {
+  let programs = [("fib",
+                   "(letrec fib (lambda (n) (if (eq n 0) 0 (if (eq n 1) 1 (+ (fib (- n 1)) (fib (- n 2)))))) \
+                    (fib 15))"),
+                  ("loop",
+                   "(letrec loop (lambda (i acc) (if (eq i 0) acc (loop (- i 1) (+ acc (remainder i 7))))) \
+                    (loop 100000 0))"),
+                  ("values",
+                   "(let k (lambda (y) y) (cons (k (quote sym)) (cons \"s\" (cons (bit-and 6 3) (cons k nil)))))"),
+                  ("closures", "(let n 2 (let add (lambda (x) (+ x n)) (let n 10 (add (abs (- 0 n))))))")];
+  for &(name, s) in &programs {
+    let expected = format!("{}\n", run_lisp(&s.into()).unwrap());
+    if let Some((out, _, ok)) = native(name, s) {
+      assert_eq!((out, ok), (expected, true), "{}", name);
+    }
+  }
+}
+
+#[test]
+fn built_programs_report_errors_like_the_machine() {
+  let s = "(let x (puts (quotient 7 2)) (+ x true))";
+  let expected = format!("{}\n", run_lisp(&s.into()).unwrap_err());
+  assert_eq!(expected, "1:31:vm error: ADD: expected int\n");
+  if let Some((out, err, ok)) = native("error", s) {
+    assert_eq!((out.as_str(), err, ok), ("3\n", expected, false));
+  }
+}
+
+#[test]
+fn unsupported_instructions_are_build_errors() {
+  let e = build_lisp(&"(let t (spawn (lambda () 1)) (join t))".into()).unwrap_err();
+  let d = diagnostic::from_error(&*e);
+  assert_eq!((d.phase, d.span), ("build", Some([1, 9])));
+
+  let e = build_lisp(&"(map (lambda (x) x) nil)".into()).unwrap_err();
+  assert_eq!(format!("{}", e), "1:2:build error: cannot build map into a program");
+}
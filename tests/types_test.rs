@@ -0,0 +1,93 @@
+extern crate secd;
+use secd::{Parser, Diagnostic};
+use secd::types::{self, Type};
+
+fn check(s: &str) -> Result<String, Diagnostic> {
+  let ast = Parser::new(&s.to_string()).parse().unwrap();
+  match types::check(&ast) {
+    Ok(t) => Ok(format!("{}", t)),
+    Err(e) => Err(e.downcast_ref::<Diagnostic>().unwrap().clone()),
+  }
+}
+
+fn message(s: &str) -> String {
+  return check(s).unwrap_err().message;
+}
+
+#[test]
+fn infer() {
+  assert_eq!(check("(+ 1 2)").unwrap(), "Int");
+  assert_eq!(check("(- (+ 1 2 3))").unwrap(), "Int");
+  assert_eq!(check("(lambda (x) (+ x 1))").unwrap(), "(Int -> Int)");
+  assert_eq!(check("(let id (lambda x x) (if (id true) (id 1) 2))").unwrap(), "Int");
+  assert_eq!(check("(letrec f (lambda (n) (if (eq n 0) 1 (+ n (f (- n 1))))) (f 10))").unwrap(),
+             "Int");
+  assert_eq!(check("(map (lambda (x) (+ x 1)) (cons 1 nil))").unwrap(), "List");
+  assert_eq!(check("(foldl (lambda (a x) (+ a x)) 0 (range 0 3 1))").unwrap(), "Int");
+  assert_eq!(check("(let m map (m (lambda (x) x) nil))").unwrap(), "List");
+  assert_eq!(check("(module m ((f (lambda (x) (+ x 1))) (g (lambda (x) (f x)))) (m:g 1))").unwrap(), "Int");
+  assert_eq!(check("(if true 1 nil)").unwrap(), "Dyn");
+  assert_eq!(check("(do ((i 0 (+ i 1))) ((eq i 3) (number->string i)))").unwrap(), "Str");
+  assert_eq!(check("(try (error (quote oops) \"no\" nil) (error e 0))").unwrap(), "Int");
+  assert_eq!(check("(let p (make-parameter 1) (parameterize ((p 2)) (+ (p) 1)))").unwrap(), "Int");
+  assert_eq!(check("(+ (car (cons 1 nil)) 1)").unwrap(), "Int");
+  assert_eq!(check("(stream-take (stream-cons 1 nil) 1)").unwrap(), "Dyn");
+}
+
+#[test]
+fn errors() {
+  assert_eq!(message("(+ 1 true)"), "ADD on Bool");
+  assert_eq!(message("(- 1 2 true)"), "SUB on Bool");
+  assert_eq!(message("(1 2)"), "applying Int as a function");
+  assert_eq!(message("(let f 1 (f 2))"), "applying Int as a function");
+  assert_eq!(message("(car 1)"), "CAR on Int");
+  assert_eq!(message("(if 1 2 3)"), "SEL on Int");
+  assert_eq!(message("((lambda (x) (+ x 1)) \"a\")"), "expected Int, found Str");
+  assert_eq!(message("((lambda (x y) x) 1)"), "applying a function of 2 arguments to 1");
+  assert_eq!(message("(map (lambda (x y) x) nil)"), "MAP on (t0 t1 -> t0)");
+  assert_eq!(message("(lambda (f) (+ (f 1) (f true)))"), "expected Int, found Bool");
+  assert_eq!(message("(+ y 1)"), "unbound variable y");
+  assert_eq!(message("(delay (+ 1 true))"), "ADD on Bool");
+
+  assert!(secd::typecheck_lisp(&"(1 2)".to_string()).is_err());
+
+  let d = check("(let x 1\n  (x 2))").unwrap_err();
+  assert_eq!(d.phase, "type");
+  assert_eq!(d.span, Some([2, 4]));
+}
+
+#[test]
+fn leaves_syntax_to_the_compiler() {
+  assert_eq!(check("(-)").unwrap(), "Dyn");
+  assert_eq!(check("(let 1 2 3)").unwrap(), "Dyn");
+}
+
+#[test]
+fn display() {
+  let t = Type::Fn(vec![Type::Int, Type::Fn(vec![], Box::new(Type::Str))], Box::new(Type::List));
+  assert_eq!(format!("{}", t), "(Int (-> Str) -> List)");
+}
+
+#[test]
+fn annotations() {
+  assert_eq!(check("(lambda ((x : int) y) x)").unwrap(), "(Int t0 -> Int)");
+  assert_eq!(check("(lambda ((f : (int -> bool))) f)").unwrap(), "((Int -> Bool) -> (Int -> Bool))");
+  assert_eq!(check("(the list (cons 1 nil))").unwrap(), "List");
+  assert_eq!(check("(lambda ((x : any)) (+ x 1))").unwrap(), "(Dyn -> Int)");
+  assert_eq!(check("(lambda ((x : int)) \"doc\" x)").unwrap(), "(Int -> Int)");
+  assert_eq!(message("(lambda ((x : string)) (+ x 1))"), "ADD on Str");
+  assert_eq!(message("(the int true)"), "expected Int, found Bool");
+  assert_eq!(message("((lambda ((x : symbol)) x) 1)"), "expected Sym, found Int");
+  assert_eq!(types::annotation(&Parser::new(&"(-> int)".to_string()).parse().unwrap()),
+             Some(Type::Fn(vec![], Box::new(Type::Int))));
+  assert_eq!(types::annotation(&Parser::new(&"(int int)".to_string()).parse().unwrap()), None);
+}
+
+#[test]
+fn contracts() {
+  assert_eq!(check("(define/contract f ((lambda n true) -> (lambda n true)) (lambda (n) (+ n 1)) (f 1))")
+               .unwrap(),
+             "Int");
+  assert_eq!(message("(define/contract f (1 -> (lambda n true)) (lambda (n) n) (f 1))"),
+             "expected a predicate, found Int");
+}
@@ -1,7 +1,23 @@
 extern crate secd;
 use secd::*;
+use std::error::Error;
 use std::rc::Rc;
 
+// compiles and runs a program; an error compiling it comes back as a run's would
+fn run(s: &str) -> Result<Rc<Lisp>, Box<Error>> {
+  let code = try!(Compiler::new().compile(&try!(Parser::new(&s.into()).parse())));
+  return SECD::new(code).run();
+}
+
+// with what the program printed
+fn run_captured(s: &str) -> (Result<Rc<Lisp>, Box<Error>>, String) {
+  let mut vm = SECD::new(Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap());
+  let out = vm.capture();
+  let r = vm.run();
+  let out = out.borrow().clone();
+  return (r, out);
+}
+
 #[test]
 fn let_() {
   let s = r#"
@@ -95,3 +111,1525 @@ fn add_sub() {
   assert_eq!(*r.unwrap(), Lisp::True);
 }
 
+#[test]
+fn variadic_add_sub() {
+  let s = "(cons (+) (cons (+ 4) (cons (+ 1 2 3 4) (cons (- 10) (cons (- 10 1 2) nil)))))";
+  let r = run(s);
+  assert_eq!(format!("{}", r.unwrap()), "(cons 0 (cons 4 (cons 10 (cons -10 (cons 7 nil)))))");
+
+  let r = run("(+ 1 2 true)");
+  assert_eq!(format!("{}", r.unwrap_err()), "1:2:vm error: ADD: expected int");
+  assert!(Compiler::new().compile(&Parser::new(&"(-)".into()).parse().unwrap()).is_err());
+}
+
+
+#[test]
+fn case() {
+  let s = r#"
+    (let f (lambda n
+             (case n
+               ((0) 10)
+               ((1 2 3) 20)
+               (else 30)))
+    (cons (f 0) (cons (f 2) (cons (f 3) (cons (f 9) (case 5 ((1) 1)))))))
+  "#;
+  let r = run(s);
+
+  assert!(r.is_ok());
+  assert_eq!(format!("{}", r.unwrap()), "(cons 10 (cons 20 (cons 20 (cons 30 nil))))");
+}
+
+#[test]
+fn do_loop() {
+  let s = r#"
+    (do ((i 0 (+ i 1))
+         (acc 0 (+ acc i)))
+        ((eq i 10000) acc))
+  "#;
+  let mut vm = SECD::new(
+    Compiler::new().compile(
+      &Parser::new(&s.into()).parse().unwrap()
+    ).unwrap()
+  );
+  let r = vm.run();
+
+  assert!(r.is_ok());
+  assert_eq!(r.unwrap(), Rc::new(Lisp::Int(49995000)));
+  assert!(vm.dump.is_empty());
+}
+
+#[test]
+fn tail_call() {
+  let s = r#"
+    (letrec loop
+      (lambda (n acc)
+        (if (eq n 0)
+          acc
+          (begin (loop (- n 1) (+ acc 1)))))
+      (loop 20000 0))
+  "#;
+  let r = run(s);
+
+  assert!(r.is_ok());
+  assert_eq!(r.unwrap(), Rc::new(Lisp::Int(20000)));
+}
+
+#[test]
+fn min_max_abs_quot_rem() {
+  let s = r#"
+    (cons (min 3 (- 0 2))
+    (cons (max 3 7)
+    (cons (abs (- 0 5))
+    (cons (quotient 17 5) (remainder 17 5)))))
+  "#;
+  let r = run(s);
+
+  assert!(r.is_ok());
+  assert_eq!(format!("{}", r.unwrap()), "(cons -2 (cons 7 (cons 5 (cons 3 2))))");
+
+  let e = run("(quotient 1 0)");
+  assert!(e.is_err());
+
+  let e = run("(abs nil)");
+  assert!(e.is_err());
+}
+
+#[test]
+fn bitwise() {
+  let s = r#"
+    (cons (bit-and 12 10)
+    (cons (bit-or 12 10)
+    (cons (bit-xor 12 10)
+    (cons (bit-not 0)
+    (cons (shl 1 4) (shr (- 0 16) 2))))))
+  "#;
+  let r = run(s);
+
+  assert!(r.is_ok());
+  assert_eq!(format!("{}", r.unwrap()), "(cons 8 (cons 14 (cons 6 (cons -1 (cons 16 -4)))))");
+
+  let e = run("(shl 1 32)");
+  assert!(e.is_err());
+}
+
+#[test]
+fn time_clock() {
+  let s = r#"
+    (let t (current-time)
+    (let c (clock)
+    (cons (eq (max t 1700000000) t)
+    (cons (eq (max c 0) c) (time (+ 1 2))))))
+  "#;
+  let r = run(s);
+
+  assert!(r.is_ok());
+  assert_eq!(format!("{}", r.unwrap()), "(cons true (cons true 3))");
+
+  // past an int's milliseconds the clock raises rather than wrapping
+  let long_ago = std::time::Instant::now().checked_sub(std::time::Duration::from_millis(1 << 31));
+  if let Some(started) = long_ago {
+    let mut vm = SECD::new(Compiler::new().compile(&Parser::new(&"(clock)".into()).parse().unwrap()).unwrap());
+    vm.started = started;
+    assert!(format!("{}", vm.run().unwrap_err()).contains("CLOCK: the milliseconds since start are past what an int holds"));
+  }
+}
+
+#[test]
+fn assert() {
+  let r = run("(assert (eq 1 1))");
+  assert_eq!(r.unwrap(), Rc::new(Lisp::True));
+
+  let s = "(let a 1\n  (assert (eq a 2)))";
+  let e = run(s);
+  let msg = format!("{}", e.unwrap_err());
+  assert!(msg.contains("assertion failed: (eq a 2) at 2:"));
+}
+
+#[test]
+fn string_conversions() {
+  let s = r#"
+    (cons (number->string 42)
+    (cons (string->number "17")
+    (cons (string->number "x")
+    (cons (symbol->string (quote abc))
+          (eq (string->symbol "abc") (quote abc))))))
+  "#;
+  let r = run(s);
+
+  assert!(r.is_ok());
+  assert_eq!(format!("{}", r.unwrap()), "(cons 42 (cons 17 (cons nil (cons abc true))))");
+}
+
+#[test]
+fn exit() {
+  let s = r#"
+    (let f (lambda n (if (eq n 0) (exit 3) n))
+    (+ (f 0) 1))
+  "#;
+  let r = SECD::new(
+    Compiler::new().compile(
+      &Parser::new(&s.into()).parse().unwrap()
+    ).unwrap()
+  ).run_result();
+
+  assert!(r.is_ok());
+  assert_eq!(r.unwrap(), RunResult::Exit(3));
+}
+
+#[test]
+fn getenv() {
+  std::env::set_var("SECD_TEST_GETENV", "hello");
+  let s = r#"
+    (cons (getenv "SECD_TEST_GETENV") (getenv "SECD_TEST_GETENV_UNSET"))
+  "#;
+  let code = Compiler::new().compile(
+    &Parser::new(&s.into()).parse().unwrap()
+  ).unwrap();
+  let r = SECD::new(code.clone()).run();
+
+  assert!(r.is_ok());
+  assert_eq!(format!("{}", r.unwrap()), "(cons hello nil)");
+
+  let mut vm = SECD::new(code);
+  vm.capabilities.env_vars = false;
+  assert!(vm.run().is_err());
+}
+
+#[test]
+fn system_process() {
+  let s = r#"
+    (cons (system "echo hi; exit 2") (process "echo" (cons "a" (cons "b" nil))))
+  "#;
+  let code = Compiler::new().compile(
+    &Parser::new(&s.into()).parse().unwrap()
+  ).unwrap();
+  assert!(SECD::new(code.clone()).run().is_err());
+
+  let mut vm = SECD::new(code);
+  vm.capabilities.process = true;
+  let r = vm.run();
+  assert!(r.is_ok());
+  assert_eq!(format!("{}", r.unwrap()), "(cons (cons 2 hi\n) (cons 0 a b\n))");
+}
+
+#[test]
+fn capabilities() {
+  let code = Compiler::new().compile(
+    &Parser::new(&"(clock)".into()).parse().unwrap()
+  ).unwrap();
+  assert!(SECD::new(code.clone()).run().is_ok());
+
+  let mut vm = SECD::new(code);
+  vm.capabilities = Capabilities::none();
+  let e = vm.run();
+  assert!(format!("{}", e.unwrap_err()).contains("capability 'clock'"));
+}
+
+#[test]
+fn yield_resume() {
+  let s = r#"
+    (letrec gen
+      (lambda n (if (eq n 3) n (gen (+ n (yield n)))))
+      (gen 0))
+  "#;
+  let mut vm = SECD::new(
+    Compiler::new().compile(
+      &Parser::new(&s.into()).parse().unwrap()
+    ).unwrap()
+  );
+
+  assert_eq!(vm.run_result().unwrap(), RunResult::Yield(Rc::new(Lisp::Int(0))));
+  assert_eq!(vm.resume(Rc::new(Lisp::Int(1))).unwrap(), RunResult::Yield(Rc::new(Lisp::Int(1))));
+  assert_eq!(vm.resume(Rc::new(Lisp::Int(2))).unwrap(), RunResult::Value(Rc::new(Lisp::Int(3))));
+}
+
+#[test]
+fn run_async() {
+  use std::future::Future;
+  use std::sync::Arc;
+  use std::task::{Context, Poll, Wake};
+
+  struct Noop;
+  impl Wake for Noop {
+    fn wake(self: Arc<Self>) {}
+  }
+
+  let s = r#"
+    (do ((i 0 (+ i 1))) ((eq i 100) i))
+  "#;
+  let mut vm = SECD::new(
+    Compiler::new().compile(
+      &Parser::new(&s.into()).parse().unwrap()
+    ).unwrap()
+  );
+
+  let waker = Arc::new(Noop).into();
+  let mut cx = Context::from_waker(&waker);
+  let mut fut = Box::pin(vm.run_async().budget(10));
+  let mut polls = 0;
+  let r = loop {
+    polls += 1;
+    if let Poll::Ready(r) = fut.as_mut().poll(&mut cx) {
+      break r;
+    }
+  };
+
+  assert!(polls > 1);
+  assert_eq!(r.unwrap(), RunResult::Value(Rc::new(Lisp::Int(100))));
+}
+
+#[test]
+fn spawn_join() {
+  let s = r#"
+    (letrec count
+      (lambda (n acc) (if (eq n 0) acc (count (- n 1) (+ acc 1))))
+    (let a (spawn (lambda () (count 500 0)))
+    (let b (spawn (lambda () (count 300 0)))
+      (+ (join a) (join b)))))
+  "#;
+  let r = Scheduler::new().run(SECD::new(
+    Compiler::new().compile(
+      &Parser::new(&s.into()).parse().unwrap()
+    ).unwrap()
+  ));
+
+  assert_eq!(r.unwrap(), RunResult::Value(Rc::new(Lisp::Int(800))));
+
+  let run = |s: &str| {
+    let code = try!(Compiler::new().compile(&try!(Parser::new(&s.into()).parse())));
+    Scheduler::new().run(SECD::new(code))
+  };
+  // a thread's error stops only that thread, and is raised where it is joined
+  let s = "(let a (spawn (lambda () (car 1)))
+           (let b (spawn (lambda () 2))
+             (cons (join b) (try (join a) (type-error e (condition-message e))))))";
+  assert_eq!(run(s).unwrap(), run("(cons 2 \"CAR: expected Cons\")").unwrap());
+  let s = "(let a (spawn (lambda () (car 1))) (begin (join a) 1))";
+  assert!(format!("{}", run(s).unwrap_err()).contains("CAR: expected Cons"));
+  // a thread nobody joins fails on its own
+  assert_eq!(run("(begin (spawn (lambda () (car 1))) 3)").unwrap(), RunResult::Value(Rc::new(Lisp::Int(3))));
+  // what a thread gives is taken by the one join
+  assert!(format!("{}", run("(let a (spawn (lambda () 1)) (+ (join a) (join a)))").unwrap_err()).contains("deadlock"));
+}
+
+#[test]
+fn channels() {
+  let s = r#"
+    (let c (chan)
+    (let p (spawn (lambda ()
+             (do ((i 1 (+ i 1))) ((eq i 4) (send c 0))
+               (send c i))))
+    (letrec sum
+      (lambda acc (let n (recv c) (if (eq n 0) acc (sum (+ acc n)))))
+      (sum 0))))
+  "#;
+  let code = Compiler::new().compile(
+    &Parser::new(&s.into()).parse().unwrap()
+  ).unwrap();
+  let r = Scheduler::new().run(SECD::new(code.clone()));
+
+  assert_eq!(r.unwrap(), RunResult::Value(Rc::new(Lisp::Int(6))));
+  assert!(SECD::new(code).run().is_err());
+}
+
+#[test]
+fn record_replay() {
+  let s = r#"
+    (cons (random 1000) (cons (random 1000) (clock)))
+  "#;
+  let code = Compiler::new().compile(
+    &Parser::new(&s.into()).parse().unwrap()
+  ).unwrap();
+
+  let mut vm = SECD::new(code.clone());
+  vm.record();
+  let a = vm.run().unwrap();
+  let log = vm.replay_log();
+  assert_eq!(log.len(), 3);
+
+  let mut vm = SECD::new(code.clone());
+  vm.replay(log);
+  assert_eq!(vm.run().unwrap(), a);
+
+  let mut x = SECD::new(code.clone());
+  x.seed(42);
+  let mut y = SECD::new(code);
+  y.seed(42);
+  match (&*x.run().unwrap(), &*y.run().unwrap()) {
+    (&Lisp::Cons(ref a, _), &Lisp::Cons(ref b, _)) => assert_eq!(a, b),
+    _ => panic!("expected cons"),
+  }
+
+  // spawned threads record to and replay from the same log as the main one
+  let code = Compiler::new().compile(
+    &Parser::new(&"(let t (spawn (lambda () (random 1000000))) (cons (random 1000000) (join t)))".into()).parse().unwrap()
+  ).unwrap();
+  let mut vm = SECD::new(code.clone());
+  vm.record();
+  let log = vm.replay.clone();
+  let a = Scheduler::new().run(vm).unwrap();
+  let log = match *log.borrow() {
+    data::Replay::Record(ref log) => log.clone(),
+    ref r => panic!("{:?}", r),
+  };
+  assert_eq!(log.len(), 2);
+  let mut vm = SECD::new(code.clone());
+  vm.replay(log);
+  assert_eq!(Scheduler::new().run(vm).unwrap(), a);
+
+  let mut vm = SECD::new(code);
+  vm.replay(vec![]);
+  let r = Scheduler::new().run(vm);
+  assert!(format!("{}", r.unwrap_err()).contains("RANDOM: replay log is exhausted"));
+}
+
+#[test]
+fn fuel() {
+  let s = r#"
+    (letrec f (lambda x (f x)) (f 0))
+  "#;
+  let mut vm = SECD::new(
+    Compiler::new().compile(
+      &Parser::new(&s.into()).parse().unwrap()
+    ).unwrap()
+  );
+  vm.fuel = Some(1000);
+  assert!(vm.run().is_err());
+  assert_eq!(vm.steps, 1000);
+
+  let mut vm = SECD::new(
+    Compiler::new().compile(
+      &Parser::new(&"(+ 1 2)".into()).parse().unwrap()
+    ).unwrap()
+  );
+  assert_eq!(vm.run().unwrap(), Rc::new(Lisp::Int(3)));
+  // checking the header and installing the constants are steps too
+  assert_eq!(vm.steps, 5);
+}
+
+#[test]
+fn eq_identity() {
+  let s = r#"
+    (let f (lambda x x)
+      (let c (chan)
+        (cons (eq f f)
+          (cons (eq (lambda x x) (lambda x x))
+            (cons (eq c c)
+              (cons (eq (chan) (chan))
+                (cons (eq (cons 1 "a") (cons 1 "a"))
+                  (eq (quote a) (quote b)))))))))
+  "#;
+  let r = run(s);
+  assert_eq!(format!("{}", r.unwrap()), "(cons true (cons false (cons true (cons false (cons true false)))))");
+}
+
+// channels hash by address, so their interior mutability cannot move a key
+#[test]
+#[allow(clippy::mutable_key_type)]
+fn hash_keys() {
+  use std::collections::HashSet;
+
+  let mut set = HashSet::new();
+  set.insert(Rc::new(Lisp::Int(1)));
+  set.insert(Rc::new(Lisp::Str("a".into())));
+  set.insert(Rc::new(Lisp::Cons(Rc::new(Lisp::Int(1)), Rc::new(Lisp::Nil))));
+  assert!(set.contains(&Rc::new(Lisp::Int(1))));
+  assert!(set.contains(&Rc::new(Lisp::Cons(Rc::new(Lisp::Int(1)), Rc::new(Lisp::Nil)))));
+  assert!(!set.contains(&Rc::new(Lisp::Symbol("a".into()))));
+
+  let f = run("(lambda x x)").unwrap();
+  set.insert(f.clone());
+  assert!(set.contains(&f));
+  assert_eq!(set.len(), 4);
+}
+
+#[test]
+fn compare() {
+  let s = r#"
+    (cons (compare 1 2)
+      (cons (compare "b" "a")
+        (cons (compare (quote a) (quote a))
+          (cons (compare 99 "a")
+            (compare "z" (quote a))))))
+  "#;
+  let r = run(s);
+  assert_eq!(format!("{}", r.unwrap()), "(cons -1 (cons 1 (cons 0 (cons -1 -1))))");
+
+  let e = run("(compare 1 (lambda x x))");
+  assert!(format!("{}", e.unwrap_err()).contains("COMPARE: cannot compare int with closure"));
+  // not even a value with itself
+  let e = run("(let l (cons 1 2) (compare l l))");
+  assert!(format!("{}", e.unwrap_err()).contains("COMPARE: cannot compare cons with cons"));
+  assert_eq!(Lisp::Nil.partial_cmp(&Lisp::Nil), None);
+
+  assert!(Lisp::Int(3) < Lisp::Str("a".into()));
+  assert_eq!(Lisp::True.partial_cmp(&Lisp::Nil), None);
+}
+
+#[test]
+fn sort() {
+
+  let r = run(r#"(sort (cons 3 (cons "b" (cons 1 (cons (quote a) (cons "a" nil))))))"#);
+  assert_eq!(format!("{}", r.unwrap()), "(cons 1 (cons 3 (cons a (cons b (cons a nil)))))");
+
+  // descending by the first element of each pair; equal keys keep their order
+  let r = run(r#"
+    (sort-by (cons (cons 1 "x") (cons (cons 2 "y") (cons (cons 1 "z") nil)))
+             (lambda (a b) (compare (car b) (car a))))
+  "#);
+  assert_eq!(format!("{}", r.unwrap()),
+             "(cons (cons 2 y) (cons (cons 1 x) (cons (cons 1 z) nil)))");
+
+  let e = run("(sort (cons 1 (cons true nil)))");
+  assert!(format!("{}", e.unwrap_err()).contains("SORT: cannot compare"));
+  let e = run("(sort-by (cons 1 (cons 2 nil)) (lambda (a b) true))");
+  assert!(format!("{}", e.unwrap_err()).contains("SORT-BY: comparison must return an int"));
+
+  // exit from inside the comparison stops the whole program
+  let mut vm = SECD::new(
+    Compiler::new().compile(
+      &Parser::new(&"(begin (sort-by (cons 1 (cons 2 nil)) (lambda (a b) (exit 3))) 0)".into()).parse().unwrap()
+    ).unwrap()
+  );
+  assert_eq!(vm.run_result().unwrap(), RunResult::Exit(3));
+}
+
+#[test]
+fn map_filter_fold() {
+  let show = |s: &str| format!("{}", run(s).unwrap());
+  let ls = "(cons 1 (cons 2 (cons 3 (cons 4 nil))))";
+
+  assert_eq!(show(&format!("(map (lambda x (+ x 10)) {})", ls)),
+             "(cons 11 (cons 12 (cons 13 (cons 14 nil))))");
+  assert_eq!(show(&format!("(filter (lambda x (eq (remainder x 2) 0)) {})", ls)), "(cons 2 (cons 4 nil))");
+  assert_eq!(show(&format!("(foldl (lambda (acc x) (- acc x)) 0 {})", ls)), "-10");
+  assert_eq!(show(&format!("(foldr (lambda (x acc) (cons x acc)) nil {})", ls)),
+             "(cons 1 (cons 2 (cons 3 (cons 4 nil))))");
+
+  // as values they come from the prelude
+  assert_eq!(show(&format!("(let m map (m (lambda x (+ x 1)) {}))", ls)),
+             "(cons 2 (cons 3 (cons 4 (cons 5 nil))))");
+  assert_eq!(show(&format!("(let f foldr (f (lambda (x acc) (+ x acc)) 0 (filter (lambda x true) {})))", ls)),
+             "10");
+
+  // a letrec of the same name is called instead
+  assert_eq!(show("(letrec map (lambda (f ls) 42) (map 1 2))"), "42");
+}
+
+#[test]
+fn range() {
+
+  assert_eq!(format!("{}", run("(range 0 4 1)").unwrap()), "(cons 0 (cons 1 (cons 2 (cons 3 nil))))");
+  assert_eq!(format!("{}", run("(range 5 0 (- 0 2))").unwrap()), "(cons 5 (cons 3 (cons 1 nil)))");
+  assert_eq!(format!("{}", run("(range 3 3 1)").unwrap()), "nil");
+  assert_eq!(format!("{}", run("(range 2147483646 2147483647 5)").unwrap()), "(cons 2147483646 nil)");
+  assert!(format!("{}", run("(range 0 4 0)").unwrap_err()).contains("RANGE: step must not be 0"));
+}
+
+#[test]
+fn with_output_to_string() {
+  let s = r#"
+    (let s (with-output-to-string (lambda () (begin (puts "a") (puts 1) 0)))
+      (begin (puts "after") s))
+  "#;
+  let mut vm = SECD::new(
+    Compiler::new().compile(
+      &Parser::new(&s.into()).parse().unwrap()
+    ).unwrap()
+  );
+  let out = vm.capture();
+  assert_eq!(vm.run().unwrap(), Rc::new(Lisp::Str("a\n1\n".into())));
+  assert_eq!(*out.borrow(), "after\n");
+}
+
+#[test]
+fn tcp() {
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::{TcpListener, TcpStream};
+  use std::thread;
+
+  let vm = |s: String| {
+    let mut vm = SECD::new(
+      Compiler::new().compile(
+        &Parser::new(&s).parse().unwrap()
+      ).unwrap()
+    );
+    vm.capabilities = Capabilities::all();
+    vm
+  };
+
+  // a client against an echo server on the host
+  let server = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = server.local_addr().unwrap().port();
+  let echo = thread::spawn(move || {
+    let (s, _) = server.accept().unwrap();
+    let mut line = String::new();
+    BufReader::new(s.try_clone().unwrap()).read_line(&mut line).unwrap();
+    (&s).write_all(format!("echo {}", line).as_bytes()).unwrap();
+  });
+  let mut client = vm(format!(r#"
+    (let p (tcp-connect "127.0.0.1" {})
+      (let line (tcp-read (tcp-write p "hi
+"))
+        (cons line (begin (tcp-close p) p))))
+  "#, port));
+  client.record();
+  let r = client.run();
+  echo.join().unwrap();
+  assert_eq!(format!("{}", r.unwrap()), "(cons echo hi (port closed))");
+  assert_eq!(client.replay_log(), vec![Rc::new(Lisp::Str("echo hi".into()))]);
+
+  // a host driving the machine answers the read itself
+  let server = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut client = vm(format!("(tcp-read (tcp-connect \"127.0.0.1\" {}))", server.local_addr().unwrap().port()));
+  {
+    let mut effects = client.effects();
+    while let Some(e) = effects.next() {
+      if let Effect::NativeCall(name, _) = e.unwrap() {
+        assert_eq!(name, "tcp-read");
+        effects.answer(Rc::new(Lisp::Str("canned".into())));
+      }
+    }
+  }
+  assert_eq!(client.result(), RunResult::Value(Rc::new(Lisp::Str("canned".into()))));
+
+  // a server the host connects to
+  let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+  let client = thread::spawn(move || {
+    let mut s = loop {
+      if let Ok(s) = TcpStream::connect(("127.0.0.1", port)) {
+        break s;
+      }
+      thread::sleep(std::time::Duration::from_millis(10));
+    };
+    s.write_all(b"ping\r\n").unwrap();
+    let mut line = String::new();
+    BufReader::new(s).read_line(&mut line).unwrap();
+    line
+  });
+  let r = vm(format!(r#"
+    (let c (tcp-accept (tcp-listen "127.0.0.1" {}))
+      (let line (tcp-read c)
+        (begin (tcp-write c "pong
+") (tcp-close c) (tcp-read c))))
+  "#, port)).run();
+  assert_eq!(client.join().unwrap(), "pong\n");
+  assert!(format!("{}", r.unwrap_err()).contains("TCP-READ: expected open stream"));
+
+  let e = run("(tcp-connect \"127.0.0.1\" 1)");
+  assert!(format!("{}", e.unwrap_err()).contains("capability 'network'"));
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn http_get() {
+  use std::io::{Read, Write};
+  use std::net::TcpListener;
+  use std::thread;
+
+  let server = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = server.local_addr().unwrap().port();
+  thread::spawn(move || {
+    let (mut s, _) = server.accept().unwrap();
+    let mut buf = [0; 1024];
+    let _ = s.read(&mut buf).unwrap();
+    s.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\nX-Test: yes\r\n\r\ngone").unwrap();
+  });
+
+  let code = Compiler::new().compile(
+    &Parser::new(&format!("(http-get \"http://127.0.0.1:{}/\")", port)).parse().unwrap()
+  ).unwrap();
+  let mut vm = SECD::new(code.clone());
+  vm.capabilities = Capabilities::all();
+  vm.record();
+  let r = vm.run().unwrap();
+  assert_eq!(format!("{}", r),
+             "(cons 404 (cons (cons (cons content-length 4) (cons (cons x-test yes) nil)) (cons gone nil)))");
+
+  // the response is recorded, so a replay doesn't need the server
+  let log = vm.replay_log();
+  assert_eq!(log.len(), 1);
+  let mut vm = SECD::new(code);
+  vm.capabilities = Capabilities::all();
+  vm.replay(log);
+  assert_eq!(vm.run().unwrap(), r);
+}
+
+#[cfg(not(feature = "http"))]
+#[test]
+fn http_get() {
+  let mut vm = SECD::new(
+    Compiler::new().compile(
+      &Parser::new(&"(http-get \"http://127.0.0.1/\")".into()).parse().unwrap()
+    ).unwrap()
+  );
+  vm.capabilities = Capabilities::all();
+  assert!(format!("{}", vm.run().unwrap_err()).contains("HTTP-GET: built without the http feature"));
+}
+
+#[test]
+fn date() {
+
+  let r = run(r#"(date->string "%Y/%m/%d %H:%M" (string->date "%Y-%m-%dT%H:%M:%S" "2024-02-29T13:05:09"))"#);
+  assert_eq!(format!("{}", r.unwrap()), "2024/02/29 13:05");
+  let r = run(r#"(string->date "%Y-%m-%d" "2024-02-30")"#);
+  assert_eq!(format!("{}", r.unwrap()), "nil");
+  let r = run(r#"(car (date-now))"#);
+  assert!(match *r.unwrap() { Lisp::Int(y) => y >= 2024, _ => false });
+
+  let e = run(r#"(date->string "%Y" (cons 2024 nil))"#);
+  assert!(format!("{}", e.unwrap_err()).contains("DATE->STRING: expected date"));
+}
+
+#[test]
+fn try_error() {
+
+  // user conditions carry a kind, message, payload and location
+  let r = run(r#"
+    (try (+ 1 (error (quote not-found) "no such key" 42))
+      (type-error e 0)
+      (not-found e (cons (condition-type e)
+                     (cons (condition-message e)
+                       (cons (condition-payload e) (condition-location e))))))
+  "#);
+  assert_eq!(format!("{}", r.unwrap()), "(cons not-found (cons no such key (cons 42 (cons 2 16))))");
+
+  // VM errors are conditions too, caught by the nearest handler of their kind or above it
+  let r = run(r#"
+    (try (try (car 1)
+           (arithmetic-error e 1))
+      (vm-error e (condition-type e)))
+  "#);
+  assert_eq!(format!("{}", r.unwrap()), "type-error");
+  let r = run("(try (quotient 1 0) (error e (condition-message e)))");
+  assert_eq!(format!("{}", r.unwrap()), "QUOT: division by zero");
+
+  // the stack and environment of the try are back, whatever frames the error came from
+  let r = run(r#"
+    (letrec f (lambda n (if (eq n 0) (raise (quote done)) (+ 1 (f (- n 1)))))
+      (cons 5 (try (f 10) (error e (condition-payload e)))))
+  "#);
+  assert_eq!(format!("{}", r.unwrap()), "(cons 5 done)");
+
+  // callbacks of primitives raise through them
+  let r = run(r#"(try (map (lambda x (car x)) (cons 1 nil)) (type-error e (condition-message e)))"#);
+  assert_eq!(format!("{}", r.unwrap()), "CAR: expected Cons");
+
+  // without a handler the error stops the machine
+  let e = run(r#"(try (error (quote oops) "bad" nil) (type-error e 0))"#);
+  assert!(format!("{}", e.unwrap_err()).contains("oops: bad"));
+  let e = run("(try (car 1) (arity-error e 0))");
+  assert!(format!("{}", e.unwrap_err()).contains("CAR: expected Cons"));
+}
+
+#[test]
+fn unwind_protect() {
+  let (r, out) = run_captured(r#"(cons (unwind-protect (+ 1 2) (puts "cleanup")) 0)"#);
+  assert_eq!(format!("{}", r.unwrap()), "(cons 3 0)");
+  assert_eq!(out, "cleanup\n");
+
+  // cleanups run innermost first on the way to the handler
+  let (r, out) = run_captured(r#"
+    (try (unwind-protect (unwind-protect (car 1) (puts "inner")) (puts "outer"))
+      (type-error e (condition-message e)))
+  "#);
+  assert_eq!(format!("{}", r.unwrap()), "CAR: expected Cons");
+  assert_eq!(out, "inner\nouter\n");
+
+  // and when nothing catches, the error is the one the body raised
+  let (r, out) = run_captured(r#"
+    (letrec f (lambda n (if (eq n 0) (error (quote boom) "deep" n) (f (- n 1))))
+      (unwind-protect (f 3) (puts "cleanup")))
+  "#);
+  assert!(format!("{}", r.unwrap_err()).contains("boom: deep"));
+  assert_eq!(out, "cleanup\n");
+  let (r, _) = run_captured(r#"(unwind-protect (car 1) 0)"#);
+  assert!(format!("{}", r.unwrap_err()).ends_with("vm error: CAR: expected Cons"));
+}
+
+#[test]
+fn dynamic_wind_parameterize() {
+  let (r, out) = run_captured(r#"
+    (dynamic-wind (lambda () (puts "before")) (lambda () (begin (puts "during") 1)) (lambda () (puts "after")))
+  "#);
+  assert_eq!(format!("{}", r.unwrap()), "1");
+  assert_eq!(out, "before\nduring\nafter\n");
+  let (r, out) = run_captured(r#"
+    (try (dynamic-wind (lambda () 0) (lambda () (car 1)) (lambda () (puts "after")))
+      (error e 2))
+  "#);
+  assert_eq!(format!("{}", r.unwrap()), "2");
+  assert_eq!(out, "after\n");
+
+  // the body and whatever it calls see the new value, until it is left either way
+  let (r, _) = run_captured(r#"
+    (let p (make-parameter 1)
+      (let show (lambda () (p))
+        (cons (show)
+          (cons (parameterize ((p 2)) (cons (show) (parameterize ((p 3) (p 4)) (show))))
+            (cons (try (parameterize ((p 5)) (car (p))) (error e (p)))
+              (p))))))
+  "#);
+  assert_eq!(format!("{}", r.unwrap()), "(cons 1 (cons (cons 2 4) (cons 1 1)))");
+
+  let (r, _) = run_captured("(parameterize (((lambda () 1) 2)) 0)");
+  assert!(format!("{}", r.unwrap_err()).contains("PARAMETERIZE: expected parameter"));
+}
+
+#[test]
+fn annotations() {
+  let s = r#"
+    (let add (lambda ((x : int) y (f : (int -> int))) (the int (f (+ x y))))
+      (add 1 2 (lambda (n) (+ n 1))))
+  "#;
+  let r = run(s);
+  assert_eq!(format!("{}", r.unwrap()), "4");
+
+  let r = Compiler::new().compile(&Parser::new(&"(the integer 1)".into()).parse().unwrap());
+  assert!(format!("{}", r.unwrap_err()).contains("unknown type"));
+  let r = Compiler::new().compile(&Parser::new(&"(lambda ((x : integer)) x)".into()).parse().unwrap());
+  assert!(format!("{}", r.unwrap_err()).contains("lambda args"));
+}
+
+#[test]
+fn define_contract() {
+
+  let positive = "(lambda n (eq (compare n 0) 1))";
+  let r = run(&format!(r#"
+    (define/contract sum ({p} -> {p})
+      (lambda (n) (if (eq n 1) 1 (+ n (sum (- n 1)))))
+      (cons (sum 4)
+        (try (sum 0)
+          (contract-violation e (cons (condition-message e) (condition-payload e))))))
+  "#, p = positive));
+  assert_eq!(format!("{}", r.unwrap()),
+             format!("(cons 10 (cons sum: argument 1 failed {} 0))", positive));
+
+  // recursive calls are checked as well, and the error points at the predicate
+  let r = run(r#"
+    (define/contract down ((lambda n true) -> (lambda r (eq r 0)))
+      (lambda (n) (if (eq n 0) 5 (down (- n 1))))
+      (down 2))
+  "#);
+  let e = format!("{}", r.unwrap_err());
+  assert!(e.contains("2:47") && e.contains("down: result failed (lambda r (eq r 0))"), "{}", e);
+
+  let r = Compiler::new().compile(&Parser::new(&"(define/contract f (a b) f 1)".into()).parse().unwrap());
+  assert!(format!("{}", r.unwrap_err()).contains("define/contract contract syntax"));
+}
+
+#[test]
+fn help() {
+  let s = r#"
+    (let twice (lambda (x) "x added to itself" (+ x x))
+      (cons (twice 2)
+        (cons (help twice)
+          (cons (help map) (help (lambda (a b) a))))))
+  "#;
+  let mut vm = SECD::new(
+    Compiler::new().compile(
+      &Parser::new(&s.into()).parse().unwrap()
+    ).unwrap()
+  );
+  let out = vm.capture();
+  let r = vm.run();
+  assert_eq!(format!("{}", r.unwrap()),
+             "(cons 4 (cons x added to itself (cons the list of f applied to each element of ls nil)))");
+  assert_eq!(*out.borrow(),
+             "(twice x)\n  x added to itself\n(map f ls)\n  the list of f applied to each element of ls\n(lambda a b)\n");
+
+  // a lone string is the body, not a doc string
+  let r = run("(help (lambda () \"body\"))");
+  assert_eq!(format!("{}", r.unwrap()), "nil");
+
+  let r = Compiler::new().compile(&Parser::new(&"(lambda (x) 1 x)".into()).parse().unwrap());
+  assert!(format!("{}", r.unwrap_err()).contains("lambda syntax"));
+}
+
+#[test]
+fn procedure_reflection() {
+  let s = r#"
+    (let f (lambda (a b) (+ a b))
+      (cons (procedure? f)
+        (cons (procedure? 1)
+          (cons (procedure-arity f)
+            (cons (procedure-arity (lambda x x))
+              (procedure-source f))))))
+  "#;
+  let ast = Parser::new(&s.into()).parse().unwrap();
+  let r = SECD::new(Compiler::new().compile(&ast).unwrap()).run();
+  assert_eq!(format!("{}", r.unwrap()), "(cons true (cons false (cons 2 (cons 1 nil))))");
+
+  let mut c = Compiler::new();
+  c.retain_source = true;
+  let r = SECD::new(c.compile(&ast).unwrap()).run();
+  assert_eq!(format!("{}", r.unwrap()),
+             "(cons true (cons false (cons 2 (cons 1 \
+              (cons lambda (cons (cons a (cons b nil)) (cons (cons + (cons a (cons b nil))) nil)))))))");
+
+  let r = run("(procedure-arity 1)");
+  assert!(format!("{}", r.unwrap_err()).contains("PROCARITY: expected Closure"));
+}
+
+#[test]
+fn machine_introspection() {
+  let s = r#"
+    (let a 1
+      (let f (lambda (x) (cons (secd-where) (cons (secd-env) (secd-stack))))
+        (f 2)))
+  "#;
+  let code = Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap();
+  let mut vm = SECD::new(code.clone());
+  vm.capabilities.debug = true;
+  // the stack holds what was evaluated of the enclosing conses so far
+  assert_eq!(format!("{}", vm.run().unwrap()),
+             "(cons (cons 3 (cons 33 (cons 1 nil))) \
+              (cons (cons (cons a 1) (cons (cons x 2) nil)) \
+              (cons (cons 3 (cons 33 (cons 1 nil))) (cons (cons (cons a 1) (cons (cons x 2) nil)) nil))))");
+
+  let r = SECD::new(code).run();
+  assert!(format!("{}", r.unwrap_err()).contains("SECDWHERE: capability 'debug' is not granted"));
+}
+
+// a call's values go on top of its caller's, and returning or throwing out of it
+// leaves the caller's as they were
+#[test]
+fn calls_share_one_stack() {
+  let s = "(cons 1 (let f (lambda (x) (cons x (car x))) (try (cons 2 (f 3)) (error e 4))))";
+  let mut vm = SECD::new(Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap());
+  while !vm.dump.iter().any(|d| matches!(*d, data::DumpOP::DumpAP(..))) {
+    vm.step().unwrap();
+  }
+  assert_eq!(vm.stack.len(), vm.base);
+  assert_eq!(vm.stack.iter().map(|v| format!("{}", v)).collect::<Vec<_>>(), vec!["1", "2"]);
+  assert_eq!(format!("{}", vm.run().unwrap()), "(cons 1 4)");
+  assert_eq!((vm.stack.len(), vm.base), (1, 0));
+}
+
+#[test]
+fn small_values_are_shared() {
+  assert!(Rc::ptr_eq(&run("(+ 1 2)").unwrap(), &Lisp::int(3)));
+  assert!(Rc::ptr_eq(&run("(eq 1 1)").unwrap(), &Lisp::bool(true)));
+  assert!(!Rc::ptr_eq(&run("(+ 1000000 0)").unwrap(), &Lisp::int(1000000)));
+  assert_eq!(Lisp::int(1000000), Rc::new(Lisp::Int(1000000)));
+}
+
+// code replaced behind the machine's back still runs; an instruction whose function
+// is for another kind of instruction falls back to the match
+#[cfg(feature = "threaded")]
+#[test]
+fn threaded_dispatch() {
+  let compile = |s: &str| Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap();
+  let mut vm = SECD::new(compile("(+ 1 2)"));
+  vm.step().unwrap();
+  vm.step().unwrap();
+  assert_eq!(vm.threaded.live.len(), 1);
+  // the other program's pool, then its LDC of nil and CONS
+  let mut code = compile("(car (cons 3 nil))");
+  code.remove(2);
+  code.remove(0);
+  code.pop();
+  vm.code = code;
+  vm.stack.push(Rc::new(Lisp::Int(4)));
+  assert_eq!(vm.run().unwrap(), Rc::new(Lisp::Cons(Rc::new(Lisp::Int(4)), Rc::new(Lisp::Nil))));
+
+  // a block is threaded once however often the machine moves to it: here the body of f
+  // and the two branches of its if
+  let mut vm = SECD::new(compile("(letrec f (lambda (n) (if (eq n 0) 0 (+ 1 (f (- n 1))))) (f 100))"));
+  assert_eq!(vm.run().unwrap(), Lisp::int(100));
+  assert_eq!(vm.threaded.blocks.len(), 3);
+}
+
+#[test]
+fn incremental() {
+  let mut compiler = Compiler::new();
+  let mut vm = SECD::new(vec![]);
+  let mut entry = |s: &str| {
+    let code = try!(compiler.compile_incremental(&Parser::new(&s.into()).parse().unwrap()));
+    vm.run_code(code).map(|v| format!("{}", v))
+  };
+  assert_eq!(entry("(define n 10)").unwrap(), "10");
+  assert!(entry("(define f (lambda (x) (if (eq x 0) 0 (+ n (f (- x 1))))))").is_ok());
+  assert_eq!(entry("(f 3)").unwrap(), "30");
+  assert!(entry("(f 1 2)").is_err());
+  assert!(entry("(define g (car 1))").is_err());
+  assert!(entry("(let k \"s\" (define k 1))").is_err());
+  assert!(entry("(define map (lambda (a b) (cons b a)))").is_ok());
+  assert_eq!(entry("(map 1 (quote x))").unwrap(), "(cons x 1)");
+  assert_eq!(entry("(let n 1 (f n))").unwrap(), "10");
+  assert_eq!(entry("(define-record pair (a b))").unwrap(), "pair");
+  assert_eq!(entry("(pair-b (make-pair 1 2))").unwrap(), "2");
+  assert_eq!(entry("(define-variant maybe (none) (some x))").unwrap(), "maybe");
+  assert_eq!(entry("(match (some 5) ((none) 0) ((some x) x))").unwrap(), "5");
+  assert!(entry("(match (some 5) ((some x y) x))").is_err());
+}
+
+#[test]
+fn env_order() {
+  let s = "(let zeta 1 (let alpha 2 (let mid 3 (lambda () zeta))))";
+  let debug = || {
+    let code = Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap();
+    format!("{:?}", SECD::new(code).run().unwrap())
+  };
+  let shown = debug();
+  assert_eq!(shown, debug());
+  let at = |name: &str| shown.find(&format!("{:?}: Int", name)).unwrap();
+  assert!(at("alpha") < at("mid") && at("mid") < at("zeta"));
+}
+
+#[test]
+fn copy_freeze() {
+
+  let r = run("(let c (chan) (let d (copy c) (begin (send c 1) (send d 2) (cons (recv c) (eq c d)))))");
+  assert_eq!(format!("{}", r.unwrap()), "(cons 1 false)");
+
+  let r = run("(let p (make-parameter 1) (let q (copy p) (parameterize ((p 2)) (cons (p) (q)))))");
+  assert_eq!(format!("{}", r.unwrap()), "(cons 2 1)");
+
+  let r = run("(let c (chan) (let l (freeze (cons 1 c)) (try (send c 1) (frozen-error e (condition-message e)))))");
+  assert_eq!(format!("{}", r.unwrap()), "SEND: chan is frozen");
+
+  let r = run("(let p (freeze (make-parameter 1)) (parameterize ((p 2)) (p)))");
+  assert!(format!("{}", r.unwrap_err()).contains("PARAMETERIZE: parameter is frozen"));
+
+  // a copy of something frozen can change again
+  let r = run("(let c (freeze (chan)) (let d (copy c) (begin (send d 3) (recv d))))");
+  assert_eq!(format!("{}", r.unwrap()), "3");
+
+  // a long list is walked in a loop, not a call per element
+  let r = run("(let l (range 0 100000 1) (eq (copy l) (freeze l)))");
+  assert_eq!(r.unwrap(), Lisp::bool(true));
+}
+
+#[test]
+fn weak_refs() {
+
+  let r = run("(let x (cons 1 2) (let w (make-weak-ref x) (weak-deref w)))");
+  assert_eq!(format!("{}", r.unwrap()), "(cons 1 2)");
+
+  let r = run("(let w (make-weak-ref (cons 1 2)) (weak-deref w))");
+  assert_eq!(r.unwrap(), Lisp::nil());
+
+  let r = run("(weak-deref 1)");
+  assert!(format!("{}", r.unwrap_err()).contains("WEAK-DEREF: expected weak reference"));
+}
+
+#[test]
+fn effects() {
+  let s = "(begin (puts 1) (let x (yield 2) (puts (+ x (random 10)))))";
+  let mut vm = SECD::new(Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap());
+  let mut seen = vec![];
+  {
+    let mut effects = vm.effects();
+    while let Some(e) = effects.next() {
+      let e = e.unwrap();
+      match e {
+        Effect::Yield(_) => effects.resume(Lisp::int(5)),
+        Effect::NativeCall(..) => effects.answer(Lisp::int(3)),
+        _ => {}
+      }
+      seen.push(e);
+    }
+  }
+  assert_eq!(seen, vec![Effect::Puts(Lisp::int(1)),
+                        Effect::Yield(Lisp::int(2)),
+                        Effect::NativeCall("random".to_string(), vec![Lisp::int(10)]),
+                        Effect::Puts(Lisp::int(8))]);
+  assert_eq!(vm.result(), RunResult::Value(Lisp::int(8)));
+}
+
+#[test]
+fn registered_primitives() {
+  fn double(vm: &mut SECD, c: &data::CodeOPInfo) -> Result<(), Box<dyn std::error::Error>> {
+    match *vm.stack.pop().unwrap() {
+      Lisp::Int(n) => vm.stack.push(Lisp::int(n * 2)),
+      _ => return Err(From::from(Diagnostic::error("vm", Some(c.info), "DOUBLE: expected int".to_string()))),
+    }
+    return Ok(());
+  }
+  let mut primitives = Primitives::standard();
+  primitives.register("double", 1, double);
+  let primitives = Rc::new(primitives);
+
+  let mut c = Compiler::new();
+  c.primitives = primitives.clone();
+  let code = c.compile(&Parser::new(&"(let f (lambda (x) (double x)) (+ (f 3) (min 1 5)))".into()).parse().unwrap()).unwrap();
+  let mut vm = SECD::new(code.clone());
+  vm.primitives = primitives;
+  assert_eq!(vm.run().unwrap(), Lisp::int(7));
+
+  // a machine without the primitive rejects the call
+  let r = SECD::new(code).run();
+  assert!(format!("{}", r.unwrap_err()).contains("PRIM: no primitive"));
+}
+
+#[test]
+fn modules() {
+
+  let r = run("(module m ((double (lambda x (+ x x))) (quad (lambda x (double (double x))))) (m:quad 3))");
+  assert_eq!(r.unwrap(), Lisp::int(12));
+
+  let r = run("(module m ((sum (lambda n (if (eq n 0) 0 (+ n (sum (- n 1))))))) (m:sum 4))");
+  assert_eq!(r.unwrap(), Lisp::int(10));
+
+  // a module's names don't shadow the program's, and a parameter shadows the module's
+  let r = run("(let a 1 (module m ((a 2) (f (lambda a a)) (g (lambda x a))) (cons a (cons (m:f 5) (m:g 0)))))");
+  assert_eq!(format!("{}", r.unwrap()), "(cons 1 (cons 5 2))");
+
+  let r = run("(module m ((a 1)) m:b)");
+  assert!(format!("{}", r.unwrap_err()).contains("no module binds m:b"));
+
+  let r = run("(let map 1 (cons map (prelude:map (lambda x (+ x 1)) (cons 1 nil))))");
+  assert_eq!(format!("{}", r.unwrap()), "(cons 1 (cons 2 nil))");
+
+  let r = run("(let abs (lambda x 0) (native:abs 5))");
+  assert_eq!(r.unwrap(), Lisp::int(5));
+}
+
+#[test]
+fn cond_expand() {
+  let run = |s: &str, features: &[&str]| {
+    let mut c = Compiler::new();
+    c.features.extend(features.iter().map(|f| f.to_string()));
+    SECD::new(
+      c.compile(
+        &Parser::new(&s.into()).parse().unwrap()
+      )?
+    ).run()
+  };
+
+  let r = run("(cond-expand (fast 1) (else 2))", &["fast"]);
+  assert_eq!(r.unwrap(), Lisp::int(1));
+
+  let r = run("(cond-expand (fast 1) (else 2))", &[]);
+  assert_eq!(r.unwrap(), Lisp::int(2));
+
+  let r = run("(cond-expand ((and secd (not fast)) 1 2) ((or fast slow) 3))", &[]);
+  assert_eq!(r.unwrap(), Lisp::int(2));
+
+  // clauses not taken are never compiled
+  let r = run("(cond-expand (fast (undefined-thing)) ((or fast slow) 3))", &["slow"]);
+  assert_eq!(r.unwrap(), Lisp::int(3));
+
+  let r = run("(cond-expand (fast 1))", &[]);
+  assert!(format!("{}", r.unwrap_err()).contains("no clause matches"));
+
+  let r = run("(cond-expand ((not a b) 1))", &[]);
+  assert!(format!("{}", r.unwrap_err()).contains("requirement syntax"));
+
+  let mut caps = Capabilities::none();
+  caps.filesystem = true;
+  let r = secd::eval_lisp_with(&"(cond-expand (filesystem 1) (else 2))".to_string(), caps);
+  assert_eq!(r.unwrap(), RunResult::Value(Lisp::int(1)));
+}
+
+#[test]
+fn checkpoint_restore() {
+  let path = std::env::temp_dir().join(format!("secd-checkpoint-{}", std::process::id()));
+  let path = path.to_str().unwrap().to_string();
+  let run = |s: &str| {
+    let mut vm = SECD::new(Compiler::new().compile(&Parser::new(&s.replace("PATH", &path)).parse().unwrap()).unwrap());
+    vm.capabilities = Capabilities::all();
+    vm.run()
+  };
+
+  // a closure keeps its captured environment and the constants its code loads, though
+  // the restoring program has constants of its own
+  run("(let k \"suffix\" (let n 40 (checkpoint \"PATH\" (lambda (x) (cons (+ x n) k)))))").unwrap();
+  let r = run("(let other \"a\" (let f (restore \"PATH\") (cons other (f 2))))");
+  assert_eq!(format!("{}", r.unwrap()), "(cons a (cons 42 suffix))");
+
+  // sharing, parameters and a channel holding itself come back as they were
+  run("(let c (chan) (let p (make-parameter 5) (begin (send c c) (checkpoint \"PATH\" (cons c (cons p p))))))").unwrap();
+  let r = run("(let v (restore \"PATH\") (let c (car v) (cons (eq (recv c) c) (cons (eq (car (cdr v)) (cdr (cdr v))) ((car (cdr v)))))))");
+  assert_eq!(format!("{}", r.unwrap()), "(cons true (cons true 5))");
+
+  // a condition keeps its payload and where it was raised
+  run("(try (error (quote oops) \"bad\" (cons 1 2)) (oops e (checkpoint \"PATH\" e)))").unwrap();
+  let r = run("(let e (restore \"PATH\") (cons (condition-payload e) (condition-location e)))");
+  assert_eq!(format!("{}", r.unwrap()), "(cons (cons 1 2) (cons 1 7))");
+
+  // a long list is written and read without a call per element
+  run("(checkpoint \"PATH\" (range 0 100000 1))").unwrap();
+  let r = run("(eq (restore \"PATH\") (range 0 100000 1))");
+  assert_eq!(r.unwrap(), Lisp::bool(true));
+
+  let r = run("(checkpoint \"PATH\" (spawn (lambda () 1)))");
+  assert!(format!("{}", r.unwrap_err()).contains("CHECKPOINT: cannot serialize thread"));
+
+  let r = SECD::new(Compiler::new().compile(&Parser::new(&"(restore \"x\")".into()).parse().unwrap()).unwrap()).run();
+  assert!(format!("{}", r.unwrap_err()).contains("capability 'filesystem' is not granted"));
+
+  let _ = std::fs::remove_file(&path);
+
+  // compiled code round trips too
+  let code = Compiler::new().compile(&Parser::new(&"(letrec f (lambda (n) (if (eq n 0) \"done\" (f (- n 1)))) (f (max 3 2)))".into()).parse().unwrap()).unwrap();
+  let primitives = Primitives::standard();
+  let bytes = secd::serialize::write_code(&code, &primitives).unwrap();
+  let read = secd::serialize::read_code(&bytes, &primitives).unwrap();
+  assert_eq!(read, code);
+  assert_eq!(format!("{}", SECD::new(read).run().unwrap()), "done");
+}
+
+#[test]
+fn restore_in_incremental_session() {
+  let path = std::env::temp_dir().join(format!("secd-checkpoint-session-{}", std::process::id()));
+  let path = path.to_str().unwrap().to_string();
+  let mut compiler = Compiler::new();
+  let mut vm = SECD::new(vec![]);
+  vm.capabilities = Capabilities::all();
+  let mut entry = |s: &str| {
+    let code = try!(compiler.compile_incremental(&Parser::new(&s.replace("PATH", &path)).parse().unwrap()));
+    vm.run_code(code).map(|v| format!("{}", v))
+  };
+
+  // the restored closure loads its constants from the saved pool even after later
+  // entries install pools of their own, and it checkpoints again as it was
+  entry("(checkpoint \"PATH\" (lambda (x) (cons x \"saved\")))").unwrap();
+  entry("(define g (restore \"PATH\"))").unwrap();
+  assert_eq!(entry("(g 1)").unwrap(), "(cons 1 saved)");
+  assert_eq!(entry("(let k \"one\" (let j \"two\" (cons k j)))").unwrap(), "(cons one two)");
+  assert_eq!(entry("(g 2)").unwrap(), "(cons 2 saved)");
+  entry("(checkpoint \"PATH\" g)").unwrap();
+  assert_eq!(entry("(let h (restore \"PATH\") (cons \"other\" (h 3)))").unwrap(), "(cons other (cons 3 saved))");
+  assert_eq!(entry("(g 4)").unwrap(), "(cons 4 saved)");
+
+  let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn stackless() {
+  let run = |s: &str| {
+    let mut vm = SECD::new(
+      Compiler::new().compile(
+        &Parser::new(&s.into()).parse().unwrap()
+      ).unwrap()
+    );
+    let out = vm.capture();
+    let r = vm.run_result();
+    (r, out)
+  };
+
+  // a callback calling back again nests on the dump, not the Rust stack
+  let (r, _) = run(r#"
+    (letrec nest (lambda n (if (eq n 0) 0 (car (map (lambda x (+ 1 (nest (- x 1)))) (cons n nil)))))
+      (nest 20000))
+  "#);
+  assert_eq!(r.unwrap(), RunResult::Value(Lisp::int(20000)));
+
+  // long cons chains are dropped, compared and printed without recursing along the cdrs
+  let (r, _) = run(r#"
+    (letrec build (lambda (n acc) (if (eq n 0) acc (build (- n 1) (cons n acc))))
+      (let l (build 100000 nil) (if (eq l (build 100000 nil)) (car (cdr l)) 0)))
+  "#);
+  assert_eq!(r.unwrap(), RunResult::Value(Lisp::int(2)));
+  let mut l = Lisp::nil();
+  for i in 0..200000 {
+    l = Rc::new(Lisp::Cons(Lisp::int(i), l));
+  }
+  assert!(format!("{}", l).starts_with("(cons 199999 (cons 199998 "));
+  drop(l);
+
+  let value = |s: &str| match run(s).0.unwrap() {
+    RunResult::Value(a) => format!("{}", a),
+    r => panic!("{:?}", r),
+  };
+  assert_eq!(value("(sort-by (cons 3 (cons 1 (cons 2 (cons 1 nil)))) (lambda (a b) (- a b)))"),
+             "(cons 1 (cons 1 (cons 2 (cons 3 nil))))");
+  assert_eq!(value("(foldr (lambda (x acc) (cons x acc)) nil (filter (lambda x (eq x x)) (cons 1 (cons 2 nil))))"),
+             "(cons 1 (cons 2 nil))");
+
+  // a callback may yield, as its frames are the machine's own
+  let (r, _) = run("(map (lambda x (yield x)) (cons 1 (cons 2 nil)))");
+  assert_eq!(r.unwrap(), RunResult::Yield(Lisp::int(1)));
+
+  // the output goes back when an error or exit leaves with-output-to-string
+  let (r, out) = run(r#"
+    (begin
+      (try (with-output-to-string (lambda () (begin (puts 1) (car 1)))) (error e 0))
+      (puts 2))
+  "#);
+  assert!(r.is_ok());
+  assert_eq!(*out.borrow(), "2\n");
+  let (r, out) = run("(with-output-to-string (lambda () (map (lambda x (if (eq x 2) (exit 3) (puts x))) (cons 1 (cons 2 nil)))))");
+  assert_eq!(r.unwrap(), RunResult::Exit(3));
+  assert_eq!(*out.borrow(), "");
+}
+
+#[test]
+fn deep_values() {
+  let deep = "(do ((i 0 (+ i 1)) (acc nil (cons acc nil))) ((eq i 100000) acc))";
+  let long = "(range 0 100000 1)";
+
+  // dropping, comparing and printing walk a deep nest of cars as they do a long chain of
+  // cdrs, without a call per cons
+  for v in [deep, long].iter() {
+    assert_eq!(run(&format!("(begin {} 1)", v)).unwrap(), Lisp::int(1));
+    assert_eq!(run(&format!("(let v {} (eq v (copy v)))", v)).unwrap(), Lisp::bool(true));
+    assert_eq!(run(&format!("(let v {} (eq v (cons v nil)))", v)).unwrap(), Lisp::bool(false));
+    let r = run(&format!("(let v {} (compare v (cons v nil)))", v));
+    assert!(format!("{}", r.unwrap_err()).contains("COMPARE: cannot compare cons with cons"));
+  }
+  let shown = format!("{}", run(deep).unwrap());
+  assert!(shown.starts_with("(cons (cons (cons ") && shown.ends_with(" nil) nil)"));
+  assert_eq!(shown.len(), "(cons  nil)".len() * 100000 + "nil".len());
+}
+
+#[test]
+fn instruction_set_header() {
+  use secd::serialize;
+  use secd::data::{CodeOP, Header};
+  let compile = |s: &str| Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap();
+
+  let code = compile("(try (spawn (lambda () (map (lambda x x) nil))) (error e 0))");
+  let header = data::header(&code).unwrap();
+  assert_eq!(header.version, vm::VERSION);
+  assert_eq!(header.features, vec!["conditions", "primitives", "threads"]);
+  assert_eq!(data::header(&compile("(+ 1 2)")).unwrap().features, Vec::<String>::new());
+
+  // code for a later instruction set or with a feature this machine lacks stops at once
+  let mut code = compile("(puts 1)");
+  code[0].op = CodeOP::HEADER(Header { version: vm::VERSION + 1, features: vec![] });
+  let mut vm = SECD::new(code.clone());
+  let out = vm.capture();
+  let e = vm.run().unwrap_err();
+  assert!(format!("{}", e).contains("HEADER: code is for instruction set version 2"), "{}", e);
+  assert_eq!((vm.steps, out.borrow().as_str()), (1, ""));
+  code[0].op = CodeOP::HEADER(Header { version: 1, features: vec!["vectors".into()] });
+  let e = SECD::new(code).run().unwrap_err();
+  assert!(format!("{}", e).contains("needs instruction feature vectors"), "{}", e);
+
+  // and so does serialized code, before the rest is read
+  let primitives = vm::Primitives::standard();
+  let bytes = serialize::write_code(&compile("(car (cons 1 nil))"), &primitives).unwrap();
+  assert_eq!(serialize::read_code(&bytes, &primitives).unwrap(), compile("(car (cons 1 nil))"));
+  let mut later = bytes.clone();
+  later[5] = 2;
+  let e = serialize::read_code(&later, &primitives).unwrap_err();
+  assert!(format!("{}", e).contains("instruction set version 2"), "{}", e);
+  let mut older = bytes.clone();
+  older[4] = 1;
+  assert_eq!(format!("{}", serialize::read_code(&older, &primitives).unwrap_err()),
+             "serialized in another version of the format");
+}
+
+#[test]
+fn keyword_arguments() {
+  let show = |s: &str| run(s).map(|r| format!("{}", r));
+  let connect = "(lambda (host #:key (port 80) (tls (eq port 443))) (cons host (cons port (cons tls nil))))";
+  let call = |args: &str| show(&format!("(let connect {} (connect {}))", connect, args));
+  assert_eq!(call("1").unwrap(), "(cons 1 (cons 80 (cons false nil)))");
+  // a default sees the parameters before it
+  assert_eq!(call("1 #:port 443").unwrap(), "(cons 1 (cons 443 (cons true nil)))");
+  assert_eq!(call("1 #:tls 7 #:port 8").unwrap(), "(cons 1 (cons 8 (cons 7 nil)))");
+  assert_eq!(show(&format!("(procedure-arity {})", connect)).unwrap(), "1");
+
+  // through recursion, tail calls and callbacks
+  let s = "(letrec count (lambda (n #:key (acc 0)) (if (eq n 0) acc (count (- n 1) #:acc (+ acc 2)))) (count 5))";
+  assert_eq!(show(s).unwrap(), "10");
+  let s = "(map (lambda (x #:key (y 1)) (+ x y)) (cons 1 (cons 2 nil)))";
+  assert_eq!(show(s).unwrap(), "(cons 2 (cons 3 nil))");
+
+  assert_eq!(format!("{}", call("1 #:timeout 3").unwrap_err()), "1:106:vm error: AP: unknown keyword #:timeout");
+  assert!(format!("{}", call("1 #:port 1 #:port 2").unwrap_err()).contains("keyword #:port given twice"));
+  assert!(format!("{}", call("1 #:port").unwrap_err()).contains("keyword #:port without a value"));
+  assert!(format!("{}", call("").unwrap_err()).contains("wrong number of arguments"));
+  assert!(format!("{}", show("((lambda (x) x) 1 #:y 2)").unwrap_err()).contains("unknown keyword #:y"));
+  let s = "(try ((lambda (x) x) 1 #:y 2) (arity-error e (condition-message e)))";
+  assert_eq!(show(s).unwrap(), "AP: unknown keyword #:y");
+  assert!(format!("{}", show("(lambda (x #:key y) x)").unwrap_err()).contains("keyword parameter syntax"));
+}
+
+#[test]
+fn default_parameters() {
+  let show = |s: &str| run(s).map(|r| format!("{}", r));
+  // a default is evaluated in the closure's environment, after the parameters before it
+  let f = "(let base 100 (lambda (a (b 2) (c (+ base b)) #:key (k 0)) (cons a (cons b (cons c (cons k nil))))))";
+  let call = |args: &str| show(&format!("(let f {} (f {}))", f, args));
+  assert_eq!(call("1").unwrap(), "(cons 1 (cons 2 (cons 102 (cons 0 nil))))");
+  assert_eq!(call("1 5").unwrap(), "(cons 1 (cons 5 (cons 105 (cons 0 nil))))");
+  assert_eq!(call("1 5 6").unwrap(), "(cons 1 (cons 5 (cons 6 (cons 0 nil))))");
+  // a keyword ends the positional arguments
+  assert_eq!(call("1 #:k 9").unwrap(), "(cons 1 (cons 2 (cons 102 (cons 9 nil))))");
+  assert!(format!("{}", call("").unwrap_err()).contains("wrong number of arguments"));
+  assert!(format!("{}", call("1 2 3 4").unwrap_err()).contains("wrong number of arguments"));
+  assert_eq!(show(&format!("(procedure-arity {})", f)).unwrap(), "3");
+
+  let s = "(letrec sum (lambda (n (acc 0)) (if (eq n 0) acc (sum (- n 1) (+ acc n)))) (sum 4))";
+  assert_eq!(show(s).unwrap(), "10");
+  let s = "(cons (map (lambda (x (y 10)) (+ x y)) (cons 1 nil)) (foldl (lambda (a x (y 1)) (+ a (+ x y))) 0 (cons 1 nil)))";
+  assert_eq!(show(s).unwrap(), "(cons (cons 11 nil) 2)");
+  assert!(format!("{}", show("(lambda ((a 1) b) a)").unwrap_err()).contains("a parameter without a default after one with"));
+
+  // no argument can pass for one left out
+  let s = "(let f (lambda (a (b 2)) b) (f 5 (string->symbol \" missing\")))";
+  assert!(format!("{}", show(s).unwrap_err()).contains("STR2SYM: a symbol cannot have a space"));
+}
+
+#[test]
+fn currying() {
+  let run = |s: &str, curry: bool| {
+    let mut c = Compiler::new();
+    c.curry = curry;
+    let code = try!(c.compile(&try!(Parser::new(&s.into()).parse())));
+    SECD::new(code).run().map(|r| format!("{}", r))
+  };
+  let s = "(let add3 (lambda (a b c) (+ a (+ b c)))
+             (let add1 (curry add3 1)
+               (cons ((curry add1 2) 3) (cons (add1 2 3) (cons (procedure-arity add1) (map (curry add3 1 2) (cons 10 nil)))))))";
+  assert_eq!(run(s, false).unwrap(), "(cons 6 (cons 6 (cons 2 (cons 13 nil))))");
+  // the parameters left keep their defaults
+  let s = "(let f (lambda (a (b 2) #:key (k 0)) (+ a (+ b k))) (cons ((curry f 1)) ((curry f 1) 5 #:k 10)))";
+  assert_eq!(run(s, false).unwrap(), "(cons 3 16)");
+  assert!(format!("{}", run("(curry (lambda (a) a) 1 2)", false).unwrap_err()).contains("CURRY: the closure takes 1 arguments, not 2"));
+  assert!(format!("{}", run("(curry 1 2)", false).unwrap_err()).contains("CURRY: expected Closure"));
+
+  // with the option, a call with too few arguments is a curry
+  let s = "(let add (lambda (a b) (+ a b)) (let inc (add 1) (cons (inc 2) (((lambda (x y z) (+ x (+ y z))) 1) 2 3))))";
+  assert_eq!(run(s, true).unwrap(), "(cons 3 6)");
+  assert!(format!("{}", run(s, false).unwrap_err()).contains("add takes 2 arguments, not 1"));
+}
+
+#[test]
+fn destructuring() {
+  let show = |s: &str| run(s).map(|r| format!("{}", r));
+  assert_eq!(show("(let (((a . b) (cons 1 2))) (+ a b))").unwrap(), "3");
+  assert_eq!(show("(let (a (b c) . rest) (cons 1 (cons (cons 2 (cons 3 nil)) (cons 4 nil))) (cons (+ a (+ b c)) rest))").unwrap(),
+             "(cons 6 (cons 4 nil))");
+  // each binding sees the ones before it
+  assert_eq!(show("(let ((x 1) ((y z) (cons x (cons 2 nil)))) (+ y z))").unwrap(), "3");
+  let s = "(map (lambda ((k . v) n) (+ k (+ v n))) (cons (cons 1 2) nil))";
+  assert!(format!("{}", show(s).unwrap_err()).contains("expected Closure of 1 argument"));
+  let s = "(let f (lambda ((k . v) n) (+ k (+ v n))) (cons (f (cons 1 2) 3) (procedure-arity f)))";
+  assert_eq!(show(s).unwrap(), "(cons 6 2)");
+  assert_eq!(show("(let (a b . ()) (cons 1 (cons 2 nil)) b)").unwrap(), "2");
+
+  // a value of another shape raises a type-error at the pattern
+  let e = show("(let f (lambda (x) x)\n  (let (a b) (cons 1 nil) a))").unwrap_err();
+  assert_eq!(format!("{}", e), "2:8:vm error: SHAPE: expected (a b), got (cons 1 nil)");
+  assert_eq!(show("(try (let (a . b) 5 a) (type-error e (condition-message e)))").unwrap(),
+             "SHAPE: expected (a . b), got 5");
+  assert!(format!("{}", show("((lambda ((a b c)) a) (cons 1 nil))").unwrap_err()).contains("1:11:vm error: SHAPE: expected (a b c)"));
+  // as a parameter, (a b) is a with the default b, warned about when nothing binds b
+  assert_eq!(show("(let f (lambda ((a b . ())) b) (f (cons 1 (cons 2 nil))))").unwrap(), "2");
+  let warnings = |s: &str| {
+    let p = Compiler::new().compile_program(&Parser::new(&s.into()).parse().unwrap()).unwrap();
+    p.warnings.into_iter().map(|w| (w.message, w.span)).collect::<Vec<_>>()
+  };
+  assert_eq!(warnings("(lambda ((a b)) (+ a b))"),
+             vec![("(a b) is the parameter a with the default b, which nothing binds; (a b . ()) is the pattern of a two-element list".to_string(),
+                   Some([1, 10]))]);
+  assert!(warnings("(let b 1 (lambda (x (a b) (c x) (d a) (e nil) (f map)) a))").is_empty());
+  assert!(format!("{}", show("(let (a a) nil a)").unwrap_err()).contains("a appears twice in the pattern"));
+  assert!(format!("{}", show("(let (. a) nil a)").unwrap_err()).contains("pattern syntax"));
+}
+
+#[test]
+fn records() {
+  let show = |s: &str| run(s).map(|r| format!("{}", r));
+  let s = "(define-record point (x y)
+             (let p (make-point 1 2)
+               (cons (+ (point-x p) (point-y p)) (cons (point? p) (cons (point? (cons 1 2)) (point? 3))))))";
+  assert_eq!(show(s).unwrap(), "(cons 3 (cons true (cons false false)))");
+  // a record of one kind is not another's, whatever its fields
+  let s = "(define-record a (x) (define-record b (x) (cons (a? (make-b 1)) (b-x (make-b 2)))))";
+  assert_eq!(show(s).unwrap(), "(cons false 2)");
+
+  let s = "(define-record point (x y) (try (point-y (cons 1 2)) (type-error e (condition-message e))))";
+  assert_eq!(show(s).unwrap(), "point-y: expected point");
+  // nor can a program write a record's tag itself
+  let s = "(define-record point (x y) (point? (cons (string->symbol \" point\") (cons 1 (cons 2 nil)))))";
+  assert!(format!("{}", show(s).unwrap_err()).contains("STR2SYM: a symbol cannot have a space"));
+  assert!(format!("{}", show("(define-record point (x y) (make-point 1))").unwrap_err()).contains("make-point takes 2 arguments, not 1"));
+  assert!(format!("{}", show("(define-record point (x x) 1)").unwrap_err()).contains("field x appears twice"));
+  assert!(format!("{}", show("(define-record point (x y))").unwrap_err()).contains("in a program it takes a body"));
+}
+
+#[test]
+fn variants() {
+  let compile = |s: &str| Compiler::new().compile_program(&Parser::new(&s.into()).parse().unwrap());
+  let show = |s: &str| run(s).map(|r| format!("{}", r));
+  let area = "(match s ((circle r) (+ r r)) ((rect w h) (+ w (+ w h))))";
+  let s = format!("(define-variant shape (circle r) (rect w h)
+                     (let area (lambda (s) {})
+                       (cons (area (circle 2)) (cons (area (rect 2 3)) (cons (shape? (rect 1 1)) (circle? (rect 1 1)))))))",
+                  area);
+  assert_eq!(show(&s).unwrap(), "(cons 4 (cons 7 (cons true false)))");
+  assert!(compile(&s).unwrap().warnings.is_empty());
+
+  // a record matches as a case of its fields, and else takes what no clause does
+  let s = "(define-record point (x y)
+             (let f (lambda (v) (match v ((point x y) (+ x y)) (else 0)))
+               (cons (f (make-point 1 2)) (f 3))))";
+  assert_eq!(show(s).unwrap(), "(cons 3 0)");
+
+  // a case without a clause is warned about, and raises a type-error when it comes
+  let s = "(define-variant shape (circle r) (rect w h) (square s)
+             (try (match (rect 1 2) ((circle r) r)) (type-error e (condition-message e))))";
+  assert_eq!(show(s).unwrap(), "match: no clause for the value");
+  let warnings: Vec<String> = compile(s).unwrap().warnings.into_iter().map(|w| w.message).collect();
+  assert_eq!(warnings, vec!["match on shape has no clause for rect, square"]);
+
+  let s = "(define-variant shape (circle r) (match (cons (string->symbol \" circle\") (cons 1 nil)) ((circle r) r)))";
+  assert!(format!("{}", show(s).unwrap_err()).contains("STR2SYM: a symbol cannot have a space"));
+  assert!(format!("{}", show("(define-variant shape (circle r) (match (circle 1) ((circle r s) r)))").unwrap_err()).contains("circle has 1 field, not 2"));
+  assert!(format!("{}", show("(match 1 (else 0) ((circle r) r))").unwrap_err()).contains("else must be the last match clause"));
+  assert!(format!("{}", show("(define-variant shape (circle r) (circle s) 1)").unwrap_err()).contains("case circle appears twice"));
+}
+
+#[test]
+fn streams() {
+  let show = |s: &str| run(s).map(|r| format!("{}", r));
+  // a promise runs its expression once, when first forced
+  let s = "(let p (delay (begin (puts \"once\") 1))
+             (cons (promise? p) (cons (+ (force p) (force p)) (cons (force 2) (force (make-promise 3))))))";
+  let mut vm = SECD::new(Compiler::new().compile(&Parser::new(&s.into()).parse().unwrap()).unwrap());
+  let out = vm.capture();
+  assert_eq!(format!("{}", vm.run().unwrap()), "(cons true (cons 2 (cons 2 3)))");
+  assert_eq!(*out.borrow(), "once\n");
+  // a program cannot make a promise of its own cons
+  let s = "(promise? (cons (string->symbol \" promise\") (cdr (make-promise 1))))";
+  assert!(format!("{}", show(s).unwrap_err()).contains("STR2SYM: a symbol cannot have a space"));
+
+  let naturals = "(letrec from (lambda (n) (stream-cons n (from (+ n 1))))";
+  assert_eq!(show(&format!("{} (stream-take (from 0) 5))", naturals)).unwrap(),
+             "(cons 0 (cons 1 (cons 2 (cons 3 (cons 4 nil)))))");
+  let s = format!("{} (stream-car (stream-cdr (stream-map (lambda (x) (+ x x)) (from 5)))))", naturals);
+  assert_eq!(show(&s).unwrap(), "12");
+
+  // the sieve of Eratosthenes
+  let s = format!("{}
+                   (letrec sieve (lambda (s)
+                                   (let p (stream-car s)
+                                     (stream-cons p (sieve (stream-filter (lambda (n) (if (eq (remainder n p) 0) false true))
+                                                                          (stream-cdr s))))))
+                     (stream-take (sieve (from 2)) 6)))",
+                  naturals);
+  assert_eq!(show(&s).unwrap(), "(cons 2 (cons 3 (cons 5 (cons 7 (cons 11 (cons 13 nil))))))");
+
+  assert!(format!("{}", show("(delay 1 2)").unwrap_err()).contains("delay syntax"));
+}
+
+#[test]
+fn tracing() {
+  let show = |s: &str| {
+    let (r, out) = run_captured(s);
+    (r.map(|r| format!("{}", r)), out)
+  };
+  let s = "(letrec sum (lambda (n) (if (eq n 0) 0 (+ n (sum (- n 1)))))
+             (begin (trace sum) (cons (sum 2) (begin (untrace sum) (sum 3)))))";
+  let (r, out) = show(s);
+  assert_eq!(r.unwrap(), "(cons 3 6)");
+  assert_eq!(out, "(sum 2)\n  (sum 1)\n    (sum 0)\n    => 0\n  => 1\n=> 3\n");
+
+  // a tail call is traced like any other, and returns from its caller after
+  let s = "(letrec loop (lambda (n acc) (if (eq n 0) acc (loop (- n 1) (+ acc n))))
+             (let f (lambda (x) (loop x 0))
+               (begin (trace loop) (trace f) (f 1))))";
+  let (r, out) = show(s);
+  assert_eq!(r.unwrap(), "1");
+  assert_eq!(out, "(f 1)\n  (loop 1 0)\n    (loop 0 1)\n    => 1\n  => 1\n=> 1\n");
+
+  assert_eq!(show("(untrace (lambda (x) x))").0.unwrap(), "nil");
+  assert!(format!("{}", show("(let f 1 (trace f))").0.unwrap_err()).contains("TRACE: expected Closure"));
+}
@@ -0,0 +1,104 @@
+#![cfg(feature = "wasm")]
+extern crate secd;
+extern crate wasmi;
+use secd::*;
+
+use wasmi::{Caller, Config, Engine, Linker, Module, Store};
+
+// the lines put and the error raised
+type Host = (Vec<String>, Option<String>);
+
+fn word(mem: &[u8], at: usize) -> usize {
+  let mut b = [0; 4];
+  b.copy_from_slice(&mem[at..at + 4]);
+  return u32::from_le_bytes(b) as usize;
+}
+
+fn text(mem: &[u8], ptr: i32, len: i32) -> String {
+  return String::from_utf8(mem[ptr as usize..(ptr + len) as usize].to_vec()).unwrap();
+}
+
+// instantiates the module with a host that collects what it puts and raises; gives
+// the lines written and the text of the value or the error
+fn run(s: &str) -> (Vec<String>, Result<String, String>) {
+  let wasm = build_wasm(&s.into()).unwrap();
+  let mut config = Config::default();
+  config.wasm_tail_call(true);
+  let engine = Engine::new(&config);
+  let module = Module::new(&engine, &wasm).unwrap();
+  let mut store = Store::new(&engine, (vec![], None));
+  let mut linker = <Linker<Host>>::new(&engine);
+  let puts = |mut caller: Caller<Host>, ptr: i32, len: i32| {
+    let mem = caller.get_export("memory").unwrap().into_memory().unwrap();
+    let line = text(mem.data(&caller), ptr, len);
+    caller.data_mut().0.push(line);
+  };
+  let error = |mut caller: Caller<Host>, ptr: i32, len: i32, line: i32, column: i32| {
+    let mem = caller.get_export("memory").unwrap().into_memory().unwrap();
+    let msg = text(mem.data(&caller), ptr, len);
+    caller.data_mut().1 = Some(format!("{}:{}:vm error: {}", line, column, msg));
+  };
+  linker.func_wrap("secd", "puts", puts).unwrap();
+  linker.func_wrap("secd", "error", error).unwrap();
+  let instance = linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+  let main = instance.get_typed_func::<(), i32>(&store, "main").unwrap();
+  let show = instance.get_typed_func::<i32, i32>(&store, "show").unwrap();
+  let memory = instance.get_memory(&store, "memory").unwrap();
+
+  let r = match main.call(&mut store, ()) {
+    Ok(v) => {
+      let s = show.call(&mut store, v).unwrap();
+      let mem = memory.data(&store);
+      Ok(text(mem, word(mem, s as usize + 4) as i32, word(mem, s as usize + 8) as i32))
+    }
+    Err(_) => Err(store.data().1.clone().expect("a trap without an error")),
+  };
+  return (store.data().0.clone(), r);
+}
+
+fn machine(s: &str) -> Result<String, String> {
+  return run_lisp(&s.into()).map(|v| format!("{}", v)).map_err(|e| format!("{}", e));
+}
+
+#[test]
+fn modules_compute_what_the_machine_does() {
+  let programs =
+    ["(letrec fib (lambda (n) (if (eq n 0) 0 (if (eq n 1) 1 (+ (fib (- n 1)) (fib (- n 2)))))) (fib 15))",
+     "(letrec loop (lambda (i acc) (if (eq i 0) acc (loop (- i 1) (+ acc (remainder i 7))))) (loop 10000 0))",
+     "(let k (lambda (y) y) (cons (k (quote sym)) (cons \"s\" (cons (bit-and 6 3) (cons k nil)))))",
+     "(let n 2 (let add (lambda (x) (+ x n)) (let n 10 (add (abs (- 0 n))))))",
+     "(cons (eq (cons 1 (cons \"a\" nil)) (cons 1 (cons \"a\" nil))) (cons (number->string -2147483648) (shr -8 1)))",
+     "(letrec even (lambda (n) (if (eq n 0) true (if (eq n 1) false (even (- n 2))))) (cons (even 10) (even 7)))"];
+  for s in &programs {
+    assert_eq!(run(s).1, machine(s), "{}", s);
+  }
+}
+
+#[test]
+fn puts_goes_to_the_import() {
+  let (out, r) = run("(let x (puts (quotient 7 2)) (puts (cons x \"b\")))");
+  assert_eq!(out, vec!["3".to_string(), "(cons 3 b)".to_string()]);
+  assert_eq!(r, Ok("(cons 3 b)".into()));
+}
+
+#[test]
+fn errors_are_the_machines() {
+  let programs = ["(let x (puts (quotient 7 2)) (+ x true))",
+                  "(+ 2147483647 1)",
+                  "(quotient 1 0)",
+                  "(car 1)",
+                  "(let f 1 (f 2))",
+                  "(let f (lambda (a b) a) ((lambda (g) (g 1)) f))",
+                  "(shl 1 32)",
+                  "(if 1 2 3)",
+                  "(+ x 1)"];
+  for s in &programs {
+    assert_eq!(run(s).1, machine(s), "{}", s);
+  }
+}
+
+#[test]
+fn unsupported_instructions_are_build_errors() {
+  let e = build_wasm(&"(let t (spawn (lambda () 1)) (join t))".into()).unwrap_err();
+  assert_eq!(format!("{}", e), "1:9:build error: cannot build SPAWN into a module");
+}